@@ -1,16 +1,34 @@
-use serde::Serialize;
+use mongodb::error::{ErrorKind, WriteFailure};
 use thiserror::Error;
 
-#[derive(Error, Debug, Serialize)]
-pub enum ApiError {
-    #[error("User with id {0} not found")]
-    UserNotFound(String),
-    #[error("Password with hash {0} not found")]
-    PasswordNotFound(String),
-    #[error("User already exists: {0}")]
-    UserAlreadyExists(String),
-    #[error("Password already exists: {0}")]
-    PasswordAlreadyExists(String),
-    #[error("Internal server error: {0}")]
-    InternalServerError(String),
+/// MongoDB error code for a duplicate key on insert
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Errors returned by the services, mapped to a status code by the handlers
+#[derive(Error, Debug)]
+pub enum ServiceError {
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0} already exists")]
+    Conflict(String),
+    #[error("Invalid request: {0}")]
+    Validation(String),
+    #[error("{0} reached the maximum number of passwords")]
+    LimitReached(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Database error: {0}")]
+    Database(#[from] mongodb::error::Error),
+}
+
+impl ServiceError {
+    /// `Conflict` for a duplicate key error on insert, `Database` otherwise
+    pub fn from_insert(err: mongodb::error::Error, what: String) -> Self {
+        match err.kind.as_ref() {
+            ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == DUPLICATE_KEY => {
+                Self::Conflict(what)
+            }
+            _ => Self::Database(err),
+        }
+    }
 }
@@ -1,12 +1,13 @@
-use crate::error::common::ApiError;
+use crate::error::common::ServiceError;
 use axum::{
-    http::StatusCode,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 use serde::Serialize;
 
 pub struct HandlerResponse {
     pub status: StatusCode,
+    pub headers: HeaderMap,
     pub body: Option<String>,
 }
 
@@ -15,32 +16,65 @@ impl HandlerResponse {
         match serde_json::to_string(&body) {
             Ok(body) => Self {
                 status,
+                headers: HeaderMap::new(),
                 body: Some(body),
             },
             Err(err) => Self {
                 status: StatusCode::INTERNAL_SERVER_ERROR,
+                headers: HeaderMap::new(),
                 body: Some(err.to_string()),
             },
         }
     }
-}
 
-impl From<ApiError> for HandlerResponse {
-    fn from(value: ApiError) -> Self {
-        match value {
-            ApiError::UserNotFound(_) => Self::new(StatusCode::NOT_FOUND, value),
-            ApiError::PasswordNotFound(_) => Self::new(StatusCode::NOT_FOUND, value),
-            ApiError::UserAlreadyExists(_) => Self::new(StatusCode::BAD_REQUEST, value),
-            _ => Self::new(StatusCode::INTERNAL_SERVER_ERROR, value),
+    /// Empty response with the given status code
+    pub fn empty(status: StatusCode) -> Self {
+        Self {
+            status,
+            ..Self::default()
         }
     }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+/// Whether an `If-None-Match` header value matches `etag`, using the weak
+/// comparison HTTP requires for it (the `W/` prefixes are ignored)
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl From<ServiceError> for HandlerResponse {
+    fn from(value: ServiceError) -> Self {
+        let status = match value {
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::Conflict(_) => StatusCode::CONFLICT,
+            ServiceError::Validation(_) => StatusCode::BAD_REQUEST,
+            ServiceError::LimitReached(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ServiceError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::Database(ref err) => {
+                // Logged here rather than sent, it may describe the database setup
+                eprintln!("Database error: {err}");
+                return Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error");
+            }
+        };
+
+        Self::new(status, value.to_string())
+    }
 }
 
 impl IntoResponse for HandlerResponse {
     fn into_response(self) -> Response {
         match self.body {
-            Some(body) => (self.status, body).into_response(),
-            None => self.status.into_response(),
+            Some(body) => (self.status, self.headers, body).into_response(),
+            None => (self.status, self.headers).into_response(),
         }
     }
 }
@@ -49,7 +83,70 @@ impl Default for HandlerResponse {
     fn default() -> Self {
         Self {
             status: StatusCode::OK,
+            headers: HeaderMap::new(),
             body: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes() {
+        let cases = [
+            (
+                ServiceError::NotFound("User id".into()),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                ServiceError::Conflict("User id".into()),
+                StatusCode::CONFLICT,
+            ),
+            (
+                ServiceError::Validation("Invalid public key".into()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ServiceError::LimitReached("User id".into()),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                ServiceError::TooManyRequests("Unused challenges".into()),
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+        ];
+
+        for (err, status) in cases {
+            assert_eq!(HandlerResponse::from(err).status, status);
+        }
+    }
+
+    #[test]
+    fn etag_matching() {
+        let etag = "W/\"abc\"";
+
+        assert!(etag_matches("W/\"abc\"", etag));
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("\"xyz\", W/\"abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("W/\"xyz\"", etag));
+        assert!(!etag_matches("", etag));
+    }
+
+    #[test]
+    fn headers_override_defaults() {
+        let response = HandlerResponse::new(StatusCode::OK, "body")
+            .with_header(
+                axum::http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )
+            .into_response();
+
+        assert_eq!(
+            response.headers()[axum::http::header::CONTENT_TYPE],
+            "application/json"
+        );
+    }
+}
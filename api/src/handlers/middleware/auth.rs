@@ -1,17 +1,36 @@
-use axum::extract::{Path, TypedHeader};
+use crate::error::common::ServiceError;
+use crate::AppData;
+use axum::extract::{Path, State, TypedHeader};
 use axum::headers::authorization::{Authorization, Bearer};
 use axum::http::{Request, StatusCode};
 use axum::middleware::Next;
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::RequestPartsExt;
-use passphrasex_common::crypto::asymmetric::{public_key_from_base64, verify};
-use passphrasex_common::crypto::common::EncryptedValue;
+use passphrasex_common::crypto::asymmetric::public_key_from_base64;
+use passphrasex_common::model::auth::AuthToken;
 use std::collections::HashMap;
 use std::time::SystemTime;
 
 const SECS_TOLERANCE: u64 = 3;
 
+/// Sent to accounts without a signing key, which can't prove who they are
+const NO_SIGNING_KEY: &str =
+    "This account was created before requests were signed, register it again to keep using it";
+
+/// Signing key an authenticated request was signed with, added to the request
+/// extensions by [`only_user`]
+#[derive(Clone, Debug)]
+pub struct ClientKey(pub String);
+
+/// Lets requests through when they carry an [`AuthToken`] signed by the owner
+/// of the path's `user_id`, with the signing key stored for them. Requests
+/// that change data must sign a challenge from `GET /users/:user_id/challenge`,
+/// each usable once; reads may still sign the current timestamp instead.
+///
+/// Accounts created before signing keys were stored are refused: the key a
+/// token carries proves nothing about who made it.
 pub async fn only_user<B>(
+    State(state): State<AppData>,
     Path(params): Path<HashMap<String, String>>,
     request: Request<B>,
     next: Next<B>,
@@ -24,25 +43,55 @@ pub async fn only_user<B>(
         .await
         .map_err(|_| StatusCode::UNAUTHORIZED)?;
 
-    let enc = EncryptedValue::from(auth.token().to_string());
-    let public_key = public_key_from_base64(user_id);
-    let dec = match verify(&public_key, enc) {
-        Ok(dec) => dec,
-        Err(_) => return Err(StatusCode::UNAUTHORIZED),
-    };
+    let token: AuthToken = auth.token().parse().map_err(|_| StatusCode::UNAUTHORIZED)?;
+    public_key_from_base64(user_id).map_err(|_| StatusCode::BAD_REQUEST)?;
+    token.verify().map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let is_timestamp = match token.message.parse::<u64>() {
+        Ok(dec_time) => {
+            // A signed timestamp can be replayed while it's current, so only for reads
+            if !parts.method.is_safe() {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
 
-    let dec_time: u64 = dec.parse().map_err(|_| StatusCode::UNAUTHORIZED)?;
+            // dec should be current timestamp in seconds (with some tolerance)
+            let time = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs();
 
-    // dec should be current timestamp in seconds (with some tolerance)
-    let time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs();
+            if time.abs_diff(dec_time) > SECS_TOLERANCE {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            true
+        }
+        Err(_) => false,
+    };
+
+    let user = match state.user_service.get_user(user_id.clone()).await {
+        Ok(user) => user,
+        Err(ServiceError::NotFound(_)) => return Err(StatusCode::UNAUTHORIZED),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    match user.signing_key {
+        Some(signing_key) if signing_key == token.signing_key => (),
+        Some(_) => return Err(StatusCode::UNAUTHORIZED),
+        None => return Ok((StatusCode::UNAUTHORIZED, NO_SIGNING_KEY).into_response()),
+    }
 
-    if time - dec_time > SECS_TOLERANCE {
-        return Err(StatusCode::UNAUTHORIZED);
+    if !is_timestamp {
+        match state
+            .challenge_service
+            .consume(user_id, &token.message)
+            .await
+        {
+            Ok(true) => (),
+            Ok(false) => return Err(StatusCode::UNAUTHORIZED),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
     }
 
+    parts.extensions.insert(ClientKey(token.signing_key));
     let response = next.run(Request::from_parts(parts, body)).await;
 
     Ok(response)
@@ -0,0 +1,32 @@
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use std::time::Instant;
+
+/// Record the count, latency and concurrency of requests, labelled by route
+/// (the matched pattern, not the actual path, to keep user ids out of labels)
+pub async fn track_metrics<B>(request: Request<B>, next: Next<B>) -> Response {
+    let path = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => request.uri().path().to_string(),
+    };
+    let method = request.method().to_string();
+
+    let labels = [("method", method), ("path", path)];
+    metrics::increment_gauge!("http_requests_active", 1.0, &labels);
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    let latency = start.elapsed().as_secs_f64();
+    metrics::decrement_gauge!("http_requests_active", 1.0, &labels);
+
+    let status = response.status().as_u16().to_string();
+    let [method, path] = labels;
+    let labels = [method, path, ("status", status)];
+    metrics::increment_counter!("http_requests_total", &labels);
+    metrics::histogram!("http_request_duration_seconds", latency, &labels);
+
+    response
+}
@@ -1,4 +1,11 @@
+use axum::body::Body;
+use http_body::Limited;
+
 mod common;
 pub mod middleware;
 pub mod password;
+pub mod share;
 pub mod user;
+
+/// Request body as seen by the handlers, capped by the body limit layer in `main`
+pub type RequestBody = Limited<Body>;
@@ -1,54 +1,156 @@
-use crate::handlers::common::HandlerResponse;
-use crate::handlers::middleware::auth::only_user;
+use crate::error::common::ServiceError;
+use crate::handlers::common::{etag_matches, HandlerResponse};
+use crate::handlers::middleware::auth::{only_user, ClientKey};
+use crate::handlers::RequestBody;
 use crate::AppData;
-use axum::body::Body;
 use axum::routing::{delete, get, post, put};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Extension, Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     middleware, Json, Router,
 };
-use passphrasex_common::model::password::Password;
+use passphrasex_common::model::audit::AuditAction;
+use passphrasex_common::model::password::{Password, PasswordPatch};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Fields set by the server or making up the id, which patches can't change
+const PROTECTED_FIELDS: [&str; 6] = [
+    "_id",
+    "user_id",
+    "site",
+    "username",
+    "created_at",
+    "updated_at",
+];
+
+/// New id and site for a password moved by `rename_password`
+#[derive(Deserialize)]
+pub struct RenamePassword {
+    pub _id: String,
+    pub site: String,
+}
 
 pub struct PasswordController {
-    pub router: Router<AppData, Body>,
+    pub router: Router<AppData, RequestBody>,
 }
 
 impl PasswordController {
-    pub fn new() -> Self {
+    pub fn new(state: &AppData) -> Self {
         let router = Router::new()
             .route("/users/:user_id/passwords", post(Self::add_password))
             .route("/users/:user_id/passwords", get(Self::list_passwords))
+            .route(
+                "/users/:user_id/passwords/count",
+                get(Self::count_passwords),
+            )
             .route(
                 "/users/:user_id/passwords/:password_id",
-                delete(Self::delete_password),
+                delete(Self::delete_password).patch(Self::patch_password),
             )
             .route(
                 "/users/:user_id/passwords/:password_id/password",
                 put(Self::modify_password),
             )
-            .route_layer(middleware::from_fn(only_user));
+            .route(
+                "/users/:user_id/passwords/:password_id/rename",
+                post(Self::rename_password),
+            )
+            .route("/users/:user_id/audit", get(Self::audit_log))
+            .route_layer(middleware::from_fn_with_state(state.clone(), only_user));
 
         Self { router }
     }
 
+    /// Stores a new password. Retries sending the same `Idempotency-Key` header
+    /// get the first response again, instead of a conflict.
     pub async fn add_password(
         State(state): State<AppData>,
-        Json(payload): Json<Password>,
+        Path(user_id): Path<String>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+        headers: HeaderMap,
+        Json(mut payload): Json<Password>,
     ) -> HandlerResponse {
-        match state.password_service.add_password(payload).await {
-            Ok(password) => HandlerResponse::new(StatusCode::CREATED, password),
+        // The owner is the authenticated user, whatever the body says
+        payload.user_id = user_id;
+        let idempotency_key = match headers.get(IDEMPOTENCY_KEY).map(|key| key.to_str()) {
+            Some(Ok(key)) => Some(key.to_string()),
+            Some(Err(_)) => {
+                return HandlerResponse::from(ServiceError::Validation(
+                    "Idempotency keys must be visible ASCII".to_string(),
+                ))
+            }
+            None => None,
+        };
+
+        let service = state.password_service;
+        match service.add_password(payload, idempotency_key).await {
+            Ok(password) => {
+                service
+                    .record_access(
+                        &password.user_id,
+                        Some(&password._id),
+                        AuditAction::Add,
+                        &client_pk,
+                    )
+                    .await;
+                HandlerResponse::new(StatusCode::CREATED, password)
+            }
             Err(err) => HandlerResponse::from(err),
         }
     }
 
+    /// How many passwords the user has, by site, without sending them
+    pub async fn count_passwords(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+    ) -> HandlerResponse {
+        match state.password_service.count_passwords(user_id).await {
+            Ok(count) => HandlerResponse::new(StatusCode::OK, count),
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    /// Lists the user's passwords, or answers `304 Not Modified` when they didn't
+    /// change since the client got the `ETag` it sends in `If-None-Match`
     pub async fn list_passwords(
         State(state): State<AppData>,
         Path(user_id): Path<String>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+        headers: HeaderMap,
     ) -> HandlerResponse {
         let service = state.password_service;
-        match service.list_passwords(user_id).await {
-            Ok(passwords) => HandlerResponse::new(StatusCode::OK, passwords),
+
+        // Computed before listing, a change in between only costs the client a refetch
+        let etag = match service.passwords_etag(user_id.clone()).await {
+            Ok(etag) => etag,
+            Err(err) => return HandlerResponse::from(err),
+        };
+        let etag_header = match HeaderValue::from_str(&etag) {
+            Ok(etag_header) => etag_header,
+            Err(err) => {
+                return HandlerResponse::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            }
+        };
+
+        let if_none_match = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok());
+        if if_none_match.is_some_and(|value| etag_matches(value, &etag)) {
+            return HandlerResponse::empty(StatusCode::NOT_MODIFIED)
+                .with_header(header::ETAG, etag_header);
+        }
+
+        match service.list_passwords(user_id.clone()).await {
+            Ok(passwords) => {
+                service
+                    .record_access(&user_id, None, AuditAction::List, &client_pk)
+                    .await;
+                HandlerResponse::new(StatusCode::OK, passwords)
+                    .with_header(header::ETAG, etag_header)
+            }
             Err(err) => HandlerResponse::from(err),
         }
     }
@@ -56,10 +158,24 @@ impl PasswordController {
     pub async fn delete_password(
         State(state): State<AppData>,
         Path((user_id, password_id)): Path<(String, String)>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
     ) -> HandlerResponse {
         let service = state.password_service;
-        match service.delete_password(user_id, password_id).await {
-            Ok(_) => HandlerResponse::new(StatusCode::NO_CONTENT, ()),
+        match service
+            .delete_password(user_id.clone(), password_id.clone())
+            .await
+        {
+            Ok(_) => {
+                service
+                    .record_access(
+                        &user_id,
+                        Some(&password_id),
+                        AuditAction::Delete,
+                        &client_pk,
+                    )
+                    .await;
+                HandlerResponse::new(StatusCode::NO_CONTENT, ())
+            }
             Err(err) => HandlerResponse::from(err),
         }
     }
@@ -67,12 +183,136 @@ impl PasswordController {
     pub async fn modify_password(
         State(state): State<AppData>,
         Path((user_id, password_id)): Path<(String, String)>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
         payload: String,
     ) -> HandlerResponse {
         let service = state.password_service;
-        match service.modify_password(user_id, password_id, payload).await {
-            Ok(_) => HandlerResponse::new(StatusCode::NO_CONTENT, ()),
+        match service
+            .modify_password(user_id.clone(), password_id.clone(), payload)
+            .await
+        {
+            Ok(_) => {
+                service
+                    .record_access(
+                        &user_id,
+                        Some(&password_id),
+                        AuditAction::Modify,
+                        &client_pk,
+                    )
+                    .await;
+                HandlerResponse::new(StatusCode::NO_CONTENT, ())
+            }
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    /// Change only the fields in the body, see [`PasswordPatch`]
+    pub async fn patch_password(
+        State(state): State<AppData>,
+        Path((user_id, password_id)): Path<(String, String)>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+        Json(payload): Json<Map<String, Value>>,
+    ) -> HandlerResponse {
+        let patch = match parse_patch(payload) {
+            Ok(patch) => patch,
+            Err(err) => return HandlerResponse::from(err),
+        };
+
+        let service = state.password_service;
+        match service.patch_password(user_id, password_id, patch).await {
+            Ok(password) => {
+                service
+                    .record_access(
+                        &password.user_id,
+                        Some(&password._id),
+                        AuditAction::Patch,
+                        &client_pk,
+                    )
+                    .await;
+                HandlerResponse::new(StatusCode::OK, password)
+            }
             Err(err) => HandlerResponse::from(err),
         }
     }
+
+    pub async fn rename_password(
+        State(state): State<AppData>,
+        Path((user_id, password_id)): Path<(String, String)>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+        Json(payload): Json<RenamePassword>,
+    ) -> HandlerResponse {
+        let service = state.password_service;
+        match service
+            .rename_password(user_id, password_id.clone(), payload._id, payload.site)
+            .await
+        {
+            Ok(password) => {
+                service
+                    .record_rename(&password.user_id, &password_id, &password._id, &client_pk)
+                    .await;
+                HandlerResponse::new(StatusCode::OK, password)
+            }
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    /// Who read or changed the user's passwords and when, newest first
+    pub async fn audit_log(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+    ) -> HandlerResponse {
+        match state.password_service.audit_log(user_id).await {
+            Ok(entries) => HandlerResponse::new(StatusCode::OK, entries),
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+}
+
+/// Read a patch, naming the protected field it tries to change, if any
+fn parse_patch(payload: Map<String, Value>) -> Result<PasswordPatch, ServiceError> {
+    if let Some(field) = PROTECTED_FIELDS
+        .iter()
+        .find(|field| payload.contains_key(**field))
+    {
+        return Err(ServiceError::Validation(format!(
+            "{field} can't be changed"
+        )));
+    }
+
+    serde_json::from_value(Value::Object(payload))
+        .map_err(|err| ServiceError::Validation(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(value: Value) -> Result<PasswordPatch, ServiceError> {
+        match value {
+            Value::Object(payload) => parse_patch(payload),
+            _ => panic!("Not an object"),
+        }
+    }
+
+    #[test]
+    fn patch_fields() {
+        let patch = parse(json!({"password": "enc"})).expect("Valid patch");
+        assert_eq!(patch.password.as_deref(), Some("enc"));
+
+        let patch = parse(json!({})).expect("Valid patch");
+        assert!(patch.password.is_none());
+    }
+
+    #[test]
+    fn patch_rejects_protected_and_unknown_fields() {
+        for body in [
+            json!({"_id": "other"}),
+            json!({"password": "enc", "updated_at": 0}),
+            json!({"site": "other.com"}),
+            json!({"color": "red"}),
+        ] {
+            assert!(matches!(parse(body), Err(ServiceError::Validation(_))));
+        }
+    }
 }
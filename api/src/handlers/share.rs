@@ -0,0 +1,95 @@
+use crate::handlers::common::HandlerResponse;
+use crate::handlers::middleware::auth::{only_user, ClientKey};
+use crate::handlers::RequestBody;
+use crate::AppData;
+use axum::routing::{delete, get, post};
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    middleware, Json, Router,
+};
+use passphrasex_common::model::audit::AuditAction;
+use passphrasex_common::model::share::SharedCredential;
+
+pub struct ShareController {
+    pub router: Router<AppData, RequestBody>,
+}
+
+impl ShareController {
+    pub fn new(state: &AppData) -> Self {
+        let router = Router::new()
+            .route("/users/:user_id/shares", post(Self::share_credential))
+            .route("/users/:user_id/shares/received", get(Self::list_received))
+            .route(
+                "/users/:user_id/shares/:share_id/recipients/:recipient_id",
+                delete(Self::revoke_share),
+            )
+            .route_layer(middleware::from_fn_with_state(state.clone(), only_user));
+
+        Self { router }
+    }
+
+    /// Shares a credential, or adds recipients to an existing share
+    pub async fn share_credential(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+        Json(mut payload): Json<SharedCredential>,
+    ) -> HandlerResponse {
+        // The sender is the authenticated user, whatever the body says
+        payload.sender_id = user_id;
+        match state.share_service.share(payload).await {
+            Ok(shared) => {
+                state
+                    .password_service
+                    .record_access(
+                        &shared.sender_id,
+                        Some(&shared._id),
+                        AuditAction::Share,
+                        &client_pk,
+                    )
+                    .await;
+                HandlerResponse::new(StatusCode::OK, shared)
+            }
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    pub async fn list_received(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+    ) -> HandlerResponse {
+        match state.share_service.list_received(user_id.clone()).await {
+            Ok(shared) => {
+                state
+                    .password_service
+                    .record_access(&user_id, None, AuditAction::ListShared, &client_pk)
+                    .await;
+                HandlerResponse::new(StatusCode::OK, shared)
+            }
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    pub async fn revoke_share(
+        State(state): State<AppData>,
+        Path((user_id, share_id, recipient_id)): Path<(String, String, String)>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+    ) -> HandlerResponse {
+        let service = state.share_service;
+        match service
+            .revoke(user_id.clone(), share_id.clone(), recipient_id)
+            .await
+        {
+            Ok(_) => {
+                state
+                    .password_service
+                    .record_access(&user_id, Some(&share_id), AuditAction::Revoke, &client_pk)
+                    .await;
+                HandlerResponse::new(StatusCode::NO_CONTENT, ())
+            }
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+}
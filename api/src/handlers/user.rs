@@ -1,27 +1,41 @@
 use crate::handlers::common::HandlerResponse;
+use crate::handlers::middleware::auth::{only_user, ClientKey};
 use crate::handlers::password::PasswordController;
+use crate::handlers::share::ShareController;
+use crate::handlers::RequestBody;
 use crate::AppData;
-use axum::body::Body;
-use axum::extract::State;
-use axum::http::StatusCode;
-use axum::routing::post;
-use axum::{Json, Router};
+use axum::extract::{Extension, Path, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::routing::{get, post};
+use axum::{middleware, Json, Router};
+use passphrasex_common::model::audit::AuditAction;
 use passphrasex_common::model::user::User;
 
 pub struct UserController {
-    pub router: Router<AppData, Body>,
+    pub router: Router<AppData, RequestBody>,
 }
 
 impl UserController {
-    pub fn new() -> Self {
+    pub fn new(state: &AppData) -> Self {
         let router = Router::new().route("/users", post(Self::create_user));
 
-        let password_router = PasswordController::new().router;
+        let user_router = Router::new()
+            .route("/users/:user_id", get(Self::get_user))
+            .route("/users/:user_id/challenge", get(Self::create_challenge))
+            .route("/users/:user_id/export", get(Self::export_user))
+            .route_layer(middleware::from_fn_with_state(state.clone(), only_user));
+
+        let password_router = PasswordController::new(state).router;
+        let share_router = ShareController::new(state).router;
 
         Self {
-            router: router.merge(password_router),
+            router: router
+                .merge(user_router)
+                .merge(password_router)
+                .merge(share_router),
         }
     }
+
     pub async fn create_user(
         State(state): State<AppData>,
         Json(payload): Json<User>,
@@ -31,4 +45,56 @@ impl UserController {
             Err(err) => HandlerResponse::from(err),
         }
     }
+
+    /// Issues a challenge to sign for the next request that changes data. Only
+    /// to the user, with a signed timestamp like reads, so nobody else can use up
+    /// their unused challenges.
+    pub async fn create_challenge(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+    ) -> HandlerResponse {
+        match state.challenge_service.create_challenge(user_id).await {
+            Ok(challenge) => HandlerResponse::new(StatusCode::OK, challenge),
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    /// The account, with its email and display name when it has them
+    pub async fn get_user(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+    ) -> HandlerResponse {
+        match state.user_service.get_user(user_id).await {
+            Ok(user) => HandlerResponse::new(StatusCode::OK, user),
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
+
+    /// Downloads everything stored for the user as a JSON file
+    pub async fn export_user(
+        State(state): State<AppData>,
+        Path(user_id): Path<String>,
+        Extension(ClientKey(client_pk)): Extension<ClientKey>,
+    ) -> HandlerResponse {
+        let service = state.password_service;
+        match service.export_user(user_id.clone()).await {
+            Ok(export) => {
+                service
+                    .record_access(&user_id, None, AuditAction::Export, &client_pk)
+                    .await;
+                HandlerResponse::new(StatusCode::OK, export)
+                    .with_header(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json"),
+                    )
+                    .with_header(
+                        header::CONTENT_DISPOSITION,
+                        HeaderValue::from_static(
+                            "attachment; filename=\"passphrasex-export.json\"",
+                        ),
+                    )
+            }
+            Err(err) => HandlerResponse::from(err),
+        }
+    }
 }
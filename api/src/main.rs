@@ -5,38 +5,92 @@ mod service;
 
 use model::common::DatabaseConfig;
 
+use service::challenge::ChallengeService;
 use service::password::PasswordService;
+use service::share::ShareService;
 use service::user::UserService;
 
 use handlers::user::UserController;
 
-use axum::Router;
+use handlers::middleware::metrics::track_metrics;
+
+use axum::extract::{DefaultBodyLimit, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{middleware, Router};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use mongodb::bson::doc;
+use std::env;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+use tower_http::limit::RequestBodyLimitLayer;
+
+/// Prefix of the current version of the API routes
+const API_VERSION_PREFIX: &str = "/v1";
+
+/// Largest request body accepted, bigger ones get a 413
+const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Smaller responses are sent uncompressed, compressing them saves next to nothing
+const MIN_COMPRESSED_BYTES: u16 = 1024;
 
 #[derive(Clone)]
 pub struct AppData {
     user_service: UserService,
     password_service: PasswordService,
+    share_service: ShareService,
+    challenge_service: ChallengeService,
+    metrics: PrometheusHandle,
 }
 
 #[tokio::main]
 async fn main() {
-    let user_controller = UserController::new();
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let metrics = recorder.handle();
+    metrics::set_boxed_recorder(Box::new(recorder)).expect("Failed to install metrics recorder");
 
     let client = DatabaseConfig::new()
         .into_client()
         .await
         .expect("Failed to connect to database");
 
+    // The client connects lazily, fail here rather than on the first request
+    client
+        .database("admin")
+        .run_command(doc! {"ping": 1}, None)
+        .await
+        .expect("Failed to connect to database");
+
     println!("Connected to database");
 
     let user_service = UserService::new(&client);
+    let password_service = PasswordService::new(&client, user_service.clone());
+    let share_service = ShareService::new(&client, user_service.clone());
+    let challenge_service = ChallengeService::new(&client, user_service.clone());
+
+    password_service
+        .create_indexes()
+        .await
+        .expect("Failed to create database indexes");
+    share_service
+        .create_indexes()
+        .await
+        .expect("Failed to create database indexes");
+    challenge_service
+        .create_indexes()
+        .await
+        .expect("Failed to create database indexes");
 
-    let app = Router::new()
-        .merge(user_controller.router)
-        .with_state(AppData {
-            user_service: user_service.clone(),
-            password_service: PasswordService::new(&client, user_service),
-        });
+    let app = router(
+        AppData {
+            user_service,
+            password_service,
+            share_service,
+            challenge_service,
+            metrics,
+        },
+        max_body_bytes(),
+    );
 
     // run it with hyper on localhost:3000
     axum::Server::bind(&"0.0.0.0:3000".parse().expect("Failed to parse address"))
@@ -44,3 +98,235 @@ async fn main() {
         .await
         .expect("Failed to start server");
 }
+
+fn router(app_data: AppData, max_body_bytes: usize) -> Router {
+    Router::new()
+        .route("/health", get(|| async { StatusCode::OK }))
+        .nest(API_VERSION_PREFIX, UserController::new(&app_data).router)
+        // Unversioned alias for clients from before versioning, to be dropped later
+        .merge(UserController::new(&app_data).router)
+        .route_layer(middleware::from_fn(track_metrics))
+        // Added after the layer above, scrapes don't show up in the request metrics
+        .route("/metrics", get(render_metrics))
+        .with_state(app_data)
+        // Replaced by the limit below, which applies to every request body
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(max_body_bytes))
+        // gzip or brotli, as negotiated through Accept-Encoding
+        .layer(
+            CompressionLayer::new()
+                .compress_when(DefaultPredicate::new().and(SizeAbove::new(MIN_COMPRESSED_BYTES))),
+        )
+}
+
+/// Metrics in the Prometheus text format, left public like `/health`
+async fn render_metrics(State(state): State<AppData>) -> String {
+    state.metrics.render()
+}
+
+/// `MAX_BODY_BYTES` or the default limit
+fn max_body_bytes() -> usize {
+    env::var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::common::GetCollection;
+    use axum::body::{Body, HttpBody};
+    use axum::http::Request;
+    use mongodb::Collection;
+    use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
+    use passphrasex_common::model::auth::AuthToken;
+    use passphrasex_common::model::user::User;
+    use std::time::SystemTime;
+    use tower::ServiceExt;
+
+    /// Services for requests that never reach the database, the client only
+    /// connects on first use
+    async fn app_data() -> AppData {
+        let client = DatabaseConfig::new()
+            .into_client()
+            .await
+            .expect("Failed to create client");
+        let user_service = UserService::new(&client);
+        AppData {
+            password_service: PasswordService::new(&client, user_service.clone()),
+            share_service: ShareService::new(&client, user_service.clone()),
+            challenge_service: ChallengeService::new(&client, user_service.clone()),
+            user_service,
+            // Not installed as the global recorder, renders no metrics
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_large_bodies() {
+        let request = Request::post("/users")
+            .header("Content-Type", "application/json")
+            .body(Body::from(vec![b' '; 1025]))
+            .expect("Failed to build request");
+        let response = router(app_data().await, 1024)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn small_responses_are_not_compressed() {
+        let request = Request::get("/health")
+            .header("Accept-Encoding", "gzip, br")
+            .body(Body::empty())
+            .expect("Failed to build request");
+        let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("Content-Encoding").is_none());
+    }
+
+    #[tokio::test]
+    async fn routes_are_versioned() {
+        for uri in ["/v1/users/user_id/passwords", "/users/user_id/passwords"] {
+            let request = Request::get(uri)
+                .body(Body::empty())
+                .expect("Failed to build request");
+            let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+                .oneshot(request)
+                .await
+                .expect("Failed to send request");
+
+            // Rejected by the auth middleware, before reaching the database
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_need_a_challenge() {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let token = AuthToken::new(&key_pair, &time.to_string());
+
+        let uri = format!("/v1/users/{}/passwords/password_id", key_pair.get_pk());
+        let request = Request::delete(uri)
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .expect("Failed to build request");
+        let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        // A signed timestamp is only enough for reads
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn box_signed_tokens_are_rejected() {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        // Anyone knowing the public key can make these
+        let token = key_pair.sign(&time.to_string());
+
+        let uri = format!("/v1/users/{}/passwords", key_pair.get_pk());
+        let request = Request::get(uri)
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .expect("Failed to build request");
+        let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn legacy_accounts_are_refused() {
+        let client = DatabaseConfig::new()
+            .into_client()
+            .await
+            .expect("Failed to create client");
+        let users: Collection<User> = client.get_collection("users");
+        // Created before signing keys were stored
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        users
+            .insert_one(User::new(key_pair.get_pk()), None)
+            .await
+            .expect("Failed to insert test user");
+
+        // Signed with a key of the signer's choosing, not proof of the account's key
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let token = AuthToken::new(&key_pair, &time.to_string());
+        let uri = format!("/v1/users/{}/passwords", key_pair.get_pk());
+        let request = Request::get(uri)
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .expect("Failed to build request");
+        let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = response
+            .into_body()
+            .data()
+            .await
+            .expect("Empty body")
+            .expect("Failed to read body");
+        assert!(String::from_utf8_lossy(&body).contains("register it again"));
+
+        let user = users
+            .find_one(doc! {"_id": key_pair.get_pk()}, None)
+            .await
+            .expect("Failed to find test user")
+            .expect("Test user is gone");
+        assert_eq!(user.signing_key, None);
+    }
+
+    #[tokio::test]
+    async fn challenges_need_a_signed_request() {
+        let user_id = KeyPair::new(SeedPhrase::new(), None).get_pk();
+
+        let request = Request::get(format!("/v1/users/{user_id}/challenge"))
+            .body(Body::empty())
+            .expect("Failed to build request");
+        let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        // Anyone could use up the user's challenges otherwise
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_public() {
+        let request = Request::get("/metrics")
+            .body(Body::empty())
+            .expect("Failed to build request");
+        let response = router(app_data().await, DEFAULT_MAX_BODY_BYTES)
+            .oneshot(request)
+            .await
+            .expect("Failed to send request");
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,27 +1,94 @@
 use mongodb::error::Result;
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
 use mongodb::options::{ClientOptions, ServerApi, ServerApiVersion};
 use mongodb::{Client, Collection};
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct DatabaseConfig {
     pub uri: String,
+    /// Most connections open at once, the driver's default when `None`
+    pub max_pool_size: Option<u32>,
+    /// How long to wait for a connection to be established
+    pub connect_timeout: Option<Duration>,
+    /// How long a query waits for a suitable server before failing
+    pub server_selection_timeout: Option<Duration>,
 }
 
 impl DatabaseConfig {
+    /// Read the configuration from the environment. Panics on values that
+    /// aren't positive numbers, so a typo stops the server on start instead of
+    /// being silently ignored.
     pub fn new() -> Self {
         let uri = env::var("MONGODB_URI").unwrap_or("mongodb://localhost:27017".to_string());
 
-        Self { uri }
+        Self {
+            uri,
+            max_pool_size: positive_env("MONGODB_MAX_POOL_SIZE").map(|size| size as u32),
+            connect_timeout: positive_env("MONGODB_CONNECT_TIMEOUT_MS").map(Duration::from_millis),
+            server_selection_timeout: positive_env("MONGODB_SERVER_SELECTION_TIMEOUT_MS")
+                .map(Duration::from_millis),
+        }
     }
 
     pub async fn into_client(self) -> Result<Client> {
         let mut client_options = ClientOptions::parse(self.uri).await?;
         let server_api = ServerApi::builder().version(ServerApiVersion::V1).build();
         client_options.server_api = Some(server_api);
+        client_options.command_event_handler = Some(Arc::new(CommandMetrics));
+        // Only override what the URI's options set when configured
+        if let Some(max_pool_size) = self.max_pool_size {
+            client_options.max_pool_size = Some(max_pool_size);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            client_options.connect_timeout = Some(connect_timeout);
+        }
+        if let Some(server_selection_timeout) = self.server_selection_timeout {
+            client_options.server_selection_timeout = Some(server_selection_timeout);
+        }
         Client::with_options(client_options)
     }
 }
 
+/// The value of the `name` environment variable, `None` when unset. Panics
+/// when it's set to anything but a positive number that fits a `u32`.
+fn positive_env(name: &str) -> Option<u64> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse::<u64>() {
+        Ok(number) if number > 0 && number <= u32::MAX as u64 => Some(number),
+        _ => panic!("{name} must be a positive number, got {value:?}"),
+    }
+}
+
+/// Records how long each database command takes, by command name
+struct CommandMetrics;
+
+impl CommandMetrics {
+    fn record(command: String, duration: Duration, outcome: &'static str) {
+        metrics::histogram!(
+            "mongodb_command_duration_seconds",
+            duration.as_secs_f64(),
+            "command" => command,
+            "outcome" => outcome
+        );
+    }
+}
+
+impl CommandEventHandler for CommandMetrics {
+    fn handle_command_started_event(&self, _event: CommandStartedEvent) {}
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        Self::record(event.command_name, event.duration, "success");
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        Self::record(event.command_name, event.duration, "failure");
+    }
+}
+
 pub trait GetCollection {
     fn get_collection<T>(&self, name: &str) -> Collection<T>;
 }
@@ -0,0 +1,188 @@
+use crate::error::common::ServiceError;
+use crate::model::common::GetCollection;
+use crate::service::user::UserService;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Client, Collection, IndexModel};
+use passphrasex_common::crypto::asymmetric::public_key_from_base64;
+use passphrasex_common::crypto::symmetric::generate_key;
+use passphrasex_common::model::auth::Challenge;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// How long a client has to sign and use a challenge
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+/// Most challenges a user can have issued and not used yet, so that a client
+/// can't fill the database with them
+const MAX_UNUSED_CHALLENGES: u64 = 16;
+
+/// A challenge issued to a user and not used yet
+#[derive(Debug, Serialize, Deserialize)]
+struct IssuedChallenge {
+    /// The challenge itself
+    _id: String,
+    user_id: String,
+    created_at: DateTime,
+}
+
+#[derive(Clone)]
+pub struct ChallengeService {
+    challenge_collection: Collection<IssuedChallenge>,
+    user_service: UserService,
+}
+
+impl ChallengeService {
+    pub fn new(client: &Client, user_service: UserService) -> Self {
+        Self {
+            challenge_collection: client.get_collection("challenges"),
+            user_service,
+        }
+    }
+
+    /// Create the index dropping unused challenges once they expire, safe on
+    /// every start like [`PasswordService::create_indexes`](crate::service::password::PasswordService::create_indexes)
+    pub async fn create_indexes(&self) -> Result<(), ServiceError> {
+        let expiry = IndexModel::builder()
+            .keys(doc! {"created_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .name("expiry".to_string())
+                    .expire_after(CHALLENGE_TTL)
+                    .build(),
+            )
+            .build();
+
+        self.challenge_collection.create_index(expiry, None).await?;
+        Ok(())
+    }
+
+    /// Issue a new challenge for `user_id` to sign, if they exist and haven't
+    /// reached [`MAX_UNUSED_CHALLENGES`]
+    pub async fn create_challenge(&self, user_id: String) -> Result<Challenge, ServiceError> {
+        public_key_from_base64(&user_id)
+            .map_err(|err| ServiceError::Validation(format!("Invalid public key: {err}")))?;
+        self.user_service.get_user(user_id.clone()).await?;
+
+        let filter = doc! {"user_id": &user_id, "created_at": {"$gte": issued_after()}};
+        let unused = self
+            .challenge_collection
+            .count_documents(filter, None)
+            .await?;
+        if unused >= MAX_UNUSED_CHALLENGES {
+            return Err(ServiceError::TooManyRequests(format!(
+                "{MAX_UNUSED_CHALLENGES} unused challenges, use or let them expire first"
+            )));
+        }
+
+        // 32 random bytes, URL-safe base64 encoded
+        let challenge = generate_key().expect("Failed to generate challenge");
+        let issued = IssuedChallenge {
+            _id: challenge.clone(),
+            user_id,
+            created_at: DateTime::now(),
+        };
+        self.challenge_collection.insert_one(issued, None).await?;
+
+        Ok(Challenge {
+            challenge,
+            expires_in: CHALLENGE_TTL.as_secs(),
+        })
+    }
+
+    /// Use up `challenge`, returning whether it was issued to `user_id` and is
+    /// still valid. Valid ones are deleted, so each is only accepted once.
+    pub async fn consume(&self, user_id: &str, challenge: &str) -> Result<bool, ServiceError> {
+        let filter = doc! {
+            "_id": challenge,
+            "user_id": user_id,
+            "created_at": {"$gte": issued_after()},
+        };
+
+        let result = self.challenge_collection.delete_one(filter, None).await?;
+        Ok(result.deleted_count == 1)
+    }
+}
+
+/// Creation time of the oldest challenges still valid. Expired ones may still
+/// be around, the TTL index only purges once a minute.
+fn issued_after() -> DateTime {
+    DateTime::from_system_time(SystemTime::now() - CHALLENGE_TTL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::common::DatabaseConfig;
+    use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
+    use passphrasex_common::model::user::User;
+
+    /// Service and the id of a new user of its own, so tests don't share challenges
+    async fn setup() -> (ChallengeService, String) {
+        let client = DatabaseConfig::new()
+            .into_client()
+            .await
+            .expect("Failed to connect to database");
+        let user_service = UserService::new(&client);
+
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let user = User {
+            signing_key: Some(key_pair.get_signing_pk()),
+            ..User::new(key_pair.get_pk())
+        };
+        user_service
+            .create_user(user)
+            .await
+            .expect("Failed to create test user");
+
+        (
+            ChallengeService::new(&client, user_service),
+            key_pair.get_pk(),
+        )
+    }
+
+    #[tokio::test]
+    async fn challenges_are_used_once() {
+        let (service, user_id) = setup().await;
+        let challenge = service
+            .create_challenge(user_id.clone())
+            .await
+            .expect("Failed to create challenge");
+
+        let other_user = KeyPair::new(SeedPhrase::new(), None).get_pk();
+        assert!(!service
+            .consume(&other_user, &challenge.challenge)
+            .await
+            .unwrap());
+        assert!(service
+            .consume(&user_id, &challenge.challenge)
+            .await
+            .unwrap());
+        assert!(!service
+            .consume(&user_id, &challenge.challenge)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn unused_challenges_are_capped() {
+        let (service, user_id) = setup().await;
+        for _ in 0..MAX_UNUSED_CHALLENGES {
+            service
+                .create_challenge(user_id.clone())
+                .await
+                .expect("Failed to create challenge");
+        }
+
+        let result = service.create_challenge(user_id).await;
+        assert!(matches!(result, Err(ServiceError::TooManyRequests(_))));
+    }
+
+    #[tokio::test]
+    async fn unknown_users_get_no_challenges() {
+        let (service, _) = setup().await;
+        let unknown = KeyPair::new(SeedPhrase::new(), None).get_pk();
+
+        let result = service.create_challenge(unknown).await;
+        assert!(matches!(result, Err(ServiceError::NotFound(_))));
+    }
+}
@@ -1,2 +1,14 @@
+use std::time::SystemTime;
+
+pub mod challenge;
 pub mod password;
+pub mod share;
 pub mod user;
+
+/// Seconds since the epoch, for the timestamps the services set
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
@@ -1,70 +1,370 @@
-use crate::error::common::ApiError;
+use crate::error::common::ServiceError;
 use crate::model::common::GetCollection;
+use crate::service::now_secs;
 use crate::service::user::UserService;
 use futures_util::TryStreamExt;
-use mongodb::bson::doc;
-use mongodb::error::ErrorKind;
-use mongodb::error::WriteFailure::WriteError;
-use mongodb::{Client, Collection};
-use passphrasex_common::model::password::Password;
+use mongodb::bson::{doc, to_document, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use passphrasex_common::model::audit::{AuditAction, AuditEntry};
+use passphrasex_common::model::password::{
+    Password, PasswordCount, PasswordPatch, MAX_PASSWORD_HISTORY,
+};
+use passphrasex_common::model::user::UserExport;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+const DEFAULT_MAX_PASSWORDS_PER_USER: u64 = 10_000;
+/// How long a replayed add is answered with the original result
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+const DEFAULT_AUDIT_RETENTION_DAYS: u64 = 90;
+/// Most audit entries returned at once, the newest ones
+const MAX_AUDIT_ENTRIES: i64 = 1000;
 
 #[derive(Clone)]
 pub struct PasswordService {
     user_service: UserService,
     password_collection: Collection<Password>,
+    idempotency_collection: Collection<IdempotentAdd>,
+    audit_collection: Collection<AuditEntry>,
+    audit_retention: Duration,
+    max_passwords: u64,
+}
+
+/// A password added with an idempotency key, to answer retries of the same add
+#[derive(Debug, Serialize, Deserialize)]
+struct IdempotentAdd {
+    /// The user's id and the key, as keys are only unique per client
+    _id: String,
+    password: Password,
+    /// Expires the record, through a TTL index
+    created_at: DateTime,
 }
 
 impl PasswordService {
     pub fn new(client: &Client, user_service: UserService) -> Self {
+        let max_passwords = env::var("MAX_PASSWORDS_PER_USER")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_MAX_PASSWORDS_PER_USER);
+        let audit_retention_days = env::var("AUDIT_LOG_RETENTION_DAYS")
+            .ok()
+            .and_then(|days| days.parse().ok())
+            .unwrap_or(DEFAULT_AUDIT_RETENTION_DAYS);
+
         Self {
             user_service,
             password_collection: client.get_collection("passwords"),
+            idempotency_collection: client.get_collection("idempotency_keys"),
+            audit_collection: client.get_collection("audit_log"),
+            audit_retention: Duration::from_secs(audit_retention_days * 24 * 60 * 60),
+            max_passwords,
+        }
+    }
+
+    /// Create the indexes for the password queries, which every request filters by
+    /// user. Creating an index that already exists is a no-op, so this is safe on
+    /// every start.
+    pub async fn create_indexes(&self) -> Result<(), ServiceError> {
+        let indexes = [
+            ("user_site", doc! {"user_id": 1, "site": 1}),
+            ("user_updated_at", doc! {"user_id": 1, "updated_at": 1}),
+        ]
+        .into_iter()
+        .map(|(name, keys)| {
+            IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().name(name.to_string()).build())
+                .build()
+        });
+
+        self.password_collection
+            .create_indexes(indexes, None)
+            .await?;
+
+        let expiry = IndexModel::builder()
+            .keys(doc! {"created_at": 1})
+            .options(
+                IndexOptions::builder()
+                    .name("expiry".to_string())
+                    .expire_after(IDEMPOTENCY_KEY_TTL)
+                    .build(),
+            )
+            .build();
+        self.idempotency_collection
+            .create_index(expiry, None)
+            .await?;
+
+        // Rotates the audit log, dropping entries older than the retention period
+        let audit_indexes = [
+            IndexModel::builder()
+                .keys(doc! {"user_id": 1, "timestamp": -1})
+                .options(
+                    IndexOptions::builder()
+                        .name("user_timestamp".to_string())
+                        .build(),
+                )
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"created_at": 1})
+                .options(
+                    IndexOptions::builder()
+                        .name("expiry".to_string())
+                        .expire_after(self.audit_retention)
+                        .build(),
+                )
+                .build(),
+        ];
+        self.audit_collection
+            .create_indexes(audit_indexes, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Append an entry to the audit log. Failures are only logged, the action
+    /// it records already happened.
+    pub async fn record_access(
+        &self,
+        user_id: &str,
+        password_id: Option<&str>,
+        action: AuditAction,
+        client_pk: &str,
+    ) {
+        self.record(AuditEntry {
+            user_id: user_id.to_string(),
+            password_id: password_id.map(String::from),
+            previous_id: None,
+            action,
+            timestamp: now_secs(),
+            client_pk: client_pk.to_string(),
+        })
+        .await
+    }
+
+    /// [`PasswordService::record_access`] for a password moved from `previous_id`
+    /// to `password_id`
+    pub async fn record_rename(
+        &self,
+        user_id: &str,
+        previous_id: &str,
+        password_id: &str,
+        client_pk: &str,
+    ) {
+        self.record(AuditEntry {
+            user_id: user_id.to_string(),
+            password_id: Some(password_id.to_string()),
+            previous_id: Some(previous_id.to_string()),
+            action: AuditAction::Rename,
+            timestamp: now_secs(),
+            client_pk: client_pk.to_string(),
+        })
+        .await
+    }
+
+    async fn record(&self, entry: AuditEntry) {
+        // The TTL index needs a date, which entries only have in seconds
+        let result = match to_document(&entry) {
+            Ok(mut record) => {
+                record.insert("created_at", DateTime::now());
+                self.audit_collection
+                    .clone_with_type::<Document>()
+                    .insert_one(record, None)
+                    .await
+                    .map(|_| ())
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        if let Err(err) = result {
+            eprintln!("Failed to write audit log: {err}");
         }
     }
 
-    pub async fn list_passwords(&self, user_id: String) -> Result<Vec<Password>, ApiError> {
+    /// The user's audit log, newest first and capped to [`MAX_AUDIT_ENTRIES`]
+    pub async fn audit_log(&self, user_id: String) -> Result<Vec<AuditEntry>, ServiceError> {
+        let filter = doc! {"user_id": user_id};
+        let options = FindOptions::builder()
+            .sort(doc! {"timestamp": -1, "_id": -1})
+            .limit(MAX_AUDIT_ENTRIES)
+            .build();
+
+        let cursor = self.audit_collection.find(filter, options).await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    pub async fn list_passwords(&self, user_id: String) -> Result<Vec<Password>, ServiceError> {
         let filter = doc! {"user_id": user_id.clone()};
 
-        match self.password_collection.find(filter, None).await {
-            Ok(result) => result.try_collect().await.map_err(|err| {
-                ApiError::InternalServerError(format!("Error while collecting passwords: {}", err))
-            }),
-            Err(err) => Err(ApiError::InternalServerError(err.to_string())),
+        let cursor = self.password_collection.find(filter, None).await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Count the user's passwords by site in the database, without reading them
+    pub async fn count_passwords(&self, user_id: String) -> Result<PasswordCount, ServiceError> {
+        #[derive(Deserialize)]
+        struct SiteCount {
+            _id: String,
+            count: u64,
+        }
+
+        let pipeline = [
+            doc! {"$match": {"user_id": user_id}},
+            doc! {"$group": {"_id": "$site", "count": {"$sum": 1}}},
+        ];
+        let cursor = self.password_collection.aggregate(pipeline, None).await?;
+        let sites: Vec<SiteCount> = cursor.with_type().try_collect().await?;
+
+        let mut count = PasswordCount::default();
+        for site in sites {
+            count.total += site.count;
+            count.sites.insert(site._id, site.count);
         }
+        Ok(count)
+    }
+
+    /// The user along with all their passwords, for backups and data portability
+    pub async fn export_user(&self, user_id: String) -> Result<UserExport, ServiceError> {
+        let user = self.user_service.get_user(user_id.clone()).await?;
+        let passwords = self.list_passwords(user_id).await?;
+
+        Ok(UserExport {
+            public_key: user._id,
+            email: user.email,
+            display_name: user.display_name,
+            passwords,
+            exported_at: now_secs(),
+        })
+    }
+
+    /// Entity tag of the user's passwords, as a quoted weak `ETag` value. It
+    /// changes whenever a password is added, deleted, moved or modified, and only
+    /// reads the ids and timestamps, so clients can check for changes cheaply.
+    pub async fn passwords_etag(&self, user_id: String) -> Result<String, ServiceError> {
+        let filter = doc! {"user_id": user_id};
+        let options = FindOptions::builder()
+            .projection(doc! {"_id": 1, "updated_at": 1})
+            .sort(doc! {"_id": 1})
+            .build();
+
+        let cursor = self
+            .password_collection
+            .clone_with_type::<Document>()
+            .find(filter, options)
+            .await?;
+        let passwords: Vec<Document> = cursor.try_collect().await?;
+
+        let mut hasher = DefaultHasher::new();
+        passwords.len().hash(&mut hasher);
+        for password in passwords {
+            // Renames keep the timestamps, hence the ids
+            password
+                .get("_id")
+                .map(|id| id.to_string())
+                .hash(&mut hasher);
+            password
+                .get("updated_at")
+                .map(|updated_at| updated_at.to_string())
+                .hash(&mut hasher);
+        }
+
+        Ok(format!("W/\"{:x}\"", hasher.finish()))
     }
 
-    pub async fn add_password(&self, password: Password) -> Result<Password, ApiError> {
+    /// Store a new password, stamping its creation time (client-sent timestamps are ignored).
+    /// Adding again with the same `idempotency_key` returns the first result
+    /// instead, so clients can retry adds whose response they didn't get.
+    pub async fn add_password(
+        &self,
+        mut password: Password,
+        idempotency_key: Option<String>,
+    ) -> Result<Password, ServiceError> {
         self.user_service.get_user(password.user_id.clone()).await?;
-        let result = self.password_collection.insert_one(&password, None).await;
-        match result {
-            Ok(_) => Ok(password),
-            Err(err) => match err.kind.as_ref() {
-                ErrorKind::Write(WriteError(error)) => match error.code {
-                    11000 => Err(ApiError::PasswordAlreadyExists(password._id)),
-                    _ => Err(ApiError::InternalServerError(err.to_string())),
-                },
-                _ => Err(ApiError::InternalServerError(err.to_string())),
-            },
+
+        let record_id = match idempotency_key {
+            Some(key) if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH => {
+                return Err(ServiceError::Validation(format!(
+                    "Idempotency keys must have between 1 and {MAX_IDEMPOTENCY_KEY_LENGTH} characters"
+                )));
+            }
+            Some(key) => Some(format!("{}:{}", password.user_id, key)),
+            None => None,
+        };
+        if let Some(added) = self.idempotent_add(&record_id).await? {
+            return Ok(added);
         }
+
+        let now = now_secs();
+        password.created_at = Some(now);
+        password.updated_at = Some(now);
+
+        let filter = doc! {"user_id": password.user_id.clone()};
+        let count = self
+            .password_collection
+            .count_documents(filter, None)
+            .await?;
+        if count >= self.max_passwords {
+            return Err(ServiceError::LimitReached(format!(
+                "User {}",
+                password.user_id
+            )));
+        }
+
+        if let Err(err) = self.password_collection.insert_one(&password, None).await {
+            // A concurrent retry of the same add may have stored it first
+            if let Some(added) = self.idempotent_add(&record_id).await? {
+                return Ok(added);
+            }
+            return Err(ServiceError::from_insert(
+                err,
+                format!("Password {}", password._id),
+            ));
+        }
+
+        if let Some(record_id) = record_id {
+            let record = IdempotentAdd {
+                _id: record_id,
+                password: password.clone(),
+                created_at: DateTime::now(),
+            };
+            self.idempotency_collection.insert_one(record, None).await?;
+        }
+
+        Ok(password)
+    }
+
+    /// The password a previous add with the same idempotency key stored
+    async fn idempotent_add(
+        &self,
+        record_id: &Option<String>,
+    ) -> Result<Option<Password>, ServiceError> {
+        let Some(record_id) = record_id else {
+            return Ok(None);
+        };
+
+        let record = self
+            .idempotency_collection
+            .find_one(doc! {"_id": record_id}, None)
+            .await?;
+        Ok(record.map(|record| record.password))
     }
 
     pub async fn delete_password(
         &self,
         user_id: String,
         password_id: String,
-    ) -> Result<(), ApiError> {
+    ) -> Result<(), ServiceError> {
         let filter = doc! {"user_id": user_id.clone(), "_id": password_id.clone()};
 
-        match self.password_collection.delete_one(filter, None).await {
-            Ok(result) => {
-                if result.deleted_count == 0 {
-                    Err(ApiError::PasswordNotFound(password_id))
-                } else {
-                    Ok(())
-                }
-            }
-            Err(err) => Err(ApiError::InternalServerError(err.to_string())),
+        let result = self.password_collection.delete_one(filter, None).await?;
+        if result.deleted_count == 0 {
+            return Err(ServiceError::NotFound(format!("Password {password_id}")));
         }
+
+        Ok(())
     }
 
     pub async fn modify_password(
@@ -72,29 +372,119 @@ impl PasswordService {
         user_id: String,
         password_id: String,
         password: String,
-    ) -> Result<(), ApiError> {
+    ) -> Result<(), ServiceError> {
         let filter = doc! {"user_id": user_id.clone(), "_id": password_id.clone()};
 
-        let update = doc! {
-            "$set": {
-                "password": password.clone()
-            }
-        };
+        let now = now_secs() as i64;
+        let update = vec![
+            push_history_stage(now),
+            doc! {
+                "$set": {
+                    "password": {"$literal": password},
+                    "updated_at": now,
+                }
+            },
+        ];
 
         let result = self
             .password_collection
             .update_one(filter, update, None)
-            .await;
-
-        match result {
-            Ok(result) => {
-                if result.modified_count == 0 || result.matched_count == 0 {
-                    Err(ApiError::PasswordNotFound(password_id))
-                } else {
-                    Ok(())
-                }
+            .await?;
+        if result.modified_count == 0 || result.matched_count == 0 {
+            return Err(ServiceError::NotFound(format!("Password {password_id}")));
+        }
+
+        Ok(())
+    }
+
+    /// Change the fields given in `patch`, leaving the others as they are. A new
+    /// password keeps the previous one in the history, as [`Self::modify_password`] does.
+    pub async fn patch_password(
+        &self,
+        user_id: String,
+        password_id: String,
+        patch: PasswordPatch,
+    ) -> Result<Password, ServiceError> {
+        let patch_document = to_document(&patch)
+            .map_err(|err| ServiceError::Validation(format!("Invalid patch: {err}")))?;
+        if patch_document.is_empty() {
+            return Err(ServiceError::Validation("Nothing to update".to_string()));
+        }
+
+        let now = now_secs() as i64;
+        // Values are taken as they are, not as field paths or expressions
+        let mut set: Document = patch_document
+            .into_iter()
+            .map(|(field, value)| (field, doc! {"$literal": value}.into()))
+            .collect();
+        set.insert("updated_at", now);
+
+        let mut update = Vec::new();
+        if patch.password.is_some() {
+            update.push(push_history_stage(now));
+        }
+        update.push(doc! {"$set": set});
+
+        let filter = doc! {"user_id": user_id, "_id": password_id.clone()};
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.password_collection
+            .find_one_and_update(filter, update, options)
+            .await?
+            .ok_or_else(|| ServiceError::NotFound(format!("Password {password_id}")))
+    }
+
+    /// Move a password to a new site, keeping its encrypted fields and timestamps.
+    /// Ids can't be updated in place, so the password is copied under the new id
+    /// and the old document removed.
+    pub async fn rename_password(
+        &self,
+        user_id: String,
+        password_id: String,
+        new_id: String,
+        new_site: String,
+    ) -> Result<Password, ServiceError> {
+        let filter = doc! {"user_id": user_id.clone(), "_id": password_id.clone()};
+
+        let mut password = self
+            .password_collection
+            .find_one(filter.clone(), None)
+            .await?
+            .ok_or_else(|| ServiceError::NotFound(format!("Password {password_id}")))?;
+
+        password._id = new_id;
+        password.site = new_site;
+
+        if let Err(err) = self.password_collection.insert_one(&password, None).await {
+            return Err(ServiceError::from_insert(
+                err,
+                format!("Password {}", password._id),
+            ));
+        }
+
+        self.password_collection.delete_one(filter, None).await?;
+
+        Ok(password)
+    }
+}
+
+/// Update pipeline stage moving the current password to the front of the
+/// history, keeping the newest [`MAX_PASSWORD_HISTORY`] entries. Must come
+/// before the stage setting the new password.
+fn push_history_stage(now: i64) -> Document {
+    doc! {
+        "$set": {
+            "history": {
+                "$slice": [
+                    {"$concatArrays": [
+                        [{"value": "$password", "changed_at": now}],
+                        {"$ifNull": ["$history", []]},
+                    ]},
+                    MAX_PASSWORD_HISTORY as i64,
+                ]
             }
-            Err(err) => Err(ApiError::InternalServerError(err.to_string())),
         }
     }
 }
@@ -132,12 +522,7 @@ mod tests {
                 .expect("Failed to delete test user");
 
             collection
-                .insert_one(
-                    User {
-                        _id: USER_ID.to_string(),
-                    },
-                    None,
-                )
+                .insert_one(User::new(USER_ID.to_string()), None)
                 .await
                 .expect("Failed to insert test user");
 
@@ -156,6 +541,11 @@ mod tests {
                         site: "site".to_string(),
                         username: "username".to_string(),
                         password: "password".to_string(),
+                        created_at: None,
+                        updated_at: None,
+                        favorite: false,
+                        history: Vec::new(),
+                        folder: None,
                     },
                     None,
                 )
@@ -167,21 +557,55 @@ mod tests {
         client
     }
 
+    /// Insert a password only the calling test uses, replacing one left behind by
+    /// an earlier run, so tests writing to it don't change the shared fixture
+    async fn insert_password(client: &Client, password_id: &str) -> Collection<Password> {
+        let collection: Collection<Password> = client.get_collection("passwords");
+
+        collection
+            .delete_one(doc! {"_id": password_id}, None)
+            .await
+            .expect("Failed to delete test password");
+
+        collection
+            .insert_one(
+                Password {
+                    _id: password_id.to_string(),
+                    user_id: USER_ID.to_string(),
+                    site: "site".to_string(),
+                    username: "username".to_string(),
+                    password: "password".to_string(),
+                    created_at: None,
+                    updated_at: None,
+                    favorite: false,
+                    history: Vec::new(),
+                    folder: None,
+                },
+                None,
+            )
+            .await
+            .expect("Failed to insert test password");
+
+        collection
+    }
+
     mod add_password {
         use super::{PASSWORD_ID, USER_ID};
         use std::sync::Mutex;
 
-        use mongodb::bson::doc;
+        use mongodb::bson::{doc, Document};
         use mongodb::{Client, Collection};
 
         use passphrasex_common::model::password::Password;
 
-        use crate::error::common::ApiError;
+        use crate::error::common::ServiceError;
         use crate::model::common::GetCollection;
         use crate::service::password::PasswordService;
         use crate::service::user::UserService;
 
         const NEW_PASSWORD_ID: &str = "new_password_id";
+        const IDEMPOTENT_PASSWORD_ID: &str = "idempotent_password_id";
+        const IDEMPOTENCY_KEY: &str = "idempotency_key";
 
         static INIT_MUTEX: Mutex<bool> = Mutex::new(false);
 
@@ -191,12 +615,19 @@ mod tests {
             let mut initialized = INIT_MUTEX.lock().expect("Failed to get mutex");
             if !*initialized {
                 let collection: Collection<Password> = client.get_collection("passwords");
-                let filter = doc! {"_id": NEW_PASSWORD_ID.to_string()};
+                let filter = doc! {"_id": {"$in": [NEW_PASSWORD_ID, IDEMPOTENT_PASSWORD_ID]}};
                 collection
-                    .delete_one(filter, None)
+                    .delete_many(filter, None)
                     .await
                     .expect("Failed to delete passwords");
 
+                let collection: Collection<Document> = client.get_collection("idempotency_keys");
+                let filter = doc! {"_id": format!("{USER_ID}:{IDEMPOTENCY_KEY}")};
+                collection
+                    .delete_one(filter, None)
+                    .await
+                    .expect("Failed to delete idempotency keys");
+
                 *initialized = true;
             }
 
@@ -235,7 +666,7 @@ mod tests {
                     .await;
 
             assert!(result.is_err());
-            assert!(matches!(result, Err(ApiError::UserNotFound(_))));
+            assert!(matches!(result, Err(ServiceError::NotFound(_))));
         }
 
         #[tokio::test]
@@ -246,14 +677,58 @@ mod tests {
                 add_password_internal(&client, USER_ID.to_string(), PASSWORD_ID.to_string()).await;
 
             assert!(result.is_err());
-            assert!(matches!(result, Err(ApiError::PasswordAlreadyExists(_))));
+            assert!(matches!(result, Err(ServiceError::Conflict(_))));
+        }
+
+        #[tokio::test]
+        async fn add_password_limit_reached() {
+            let client = setup().await;
+
+            let mut service = PasswordService::new(&client, UserService::new(&client));
+            service.max_passwords = 1;
+
+            let password = Password {
+                _id: NEW_PASSWORD_ID.to_string(),
+                user_id: USER_ID.to_string(),
+                site: "site".to_string(),
+                username: "username".to_string(),
+                password: "password".to_string(),
+                created_at: None,
+                updated_at: None,
+                favorite: false,
+                history: Vec::new(),
+                folder: None,
+            };
+
+            let result = service.add_password(password, None).await;
+
+            assert!(result.is_err());
+            assert!(matches!(result, Err(ServiceError::LimitReached(_))));
+        }
+
+        #[tokio::test]
+        async fn add_password_replayed() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let key = Some(IDEMPOTENCY_KEY.to_string());
+            let first = service
+                .add_password(new_password(IDEMPOTENT_PASSWORD_ID), key.clone())
+                .await?;
+            let replayed = service
+                .add_password(new_password(IDEMPOTENT_PASSWORD_ID), key)
+                .await?;
+
+            assert_eq!(first._id, replayed._id);
+            assert_eq!(first.created_at, replayed.created_at);
+            Ok(())
         }
 
         async fn add_password_internal(
             client: &Client,
             user_id: String,
             password_id: String,
-        ) -> Result<Password, ApiError> {
+        ) -> Result<Password, ServiceError> {
             let service = PasswordService::new(&client, UserService::new(&client));
 
             let password = Password {
@@ -262,22 +737,75 @@ mod tests {
                 site: "site".to_string(),
                 username: "username".to_string(),
                 password: "password".to_string(),
+                created_at: None,
+                updated_at: None,
+                favorite: false,
+                history: Vec::new(),
+                folder: None,
             };
 
-            service.add_password(password).await
+            service.add_password(password, None).await
+        }
+
+        fn new_password(password_id: &str) -> Password {
+            Password {
+                _id: password_id.to_string(),
+                user_id: USER_ID.to_string(),
+                site: "site".to_string(),
+                username: "username".to_string(),
+                password: "password".to_string(),
+                created_at: None,
+                updated_at: None,
+                favorite: false,
+                history: Vec::new(),
+                folder: None,
+            }
         }
     }
 
-    mod modify_password {
+    mod count_passwords {
         use super::setup;
+        use super::USER_ID;
+        use crate::service::password::PasswordService;
+        use crate::service::user::UserService;
+
+        #[tokio::test]
+        async fn count_passwords() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let count = service.count_passwords(USER_ID.to_string()).await?;
+
+            assert!(count.total >= 1);
+            assert_eq!(count.total, count.sites.values().sum::<u64>());
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn count_passwords_missing_user() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let count = service.count_passwords("wrong_id".to_string()).await?;
+
+            assert_eq!(count.total, 0);
+            assert!(count.sites.is_empty());
+            Ok(())
+        }
+    }
+
+    mod modify_password {
+        use super::{insert_password, setup};
         use super::{PASSWORD_ID, USER_ID};
-        use crate::error::common::ApiError;
+        use crate::error::common::ServiceError;
         use crate::model::common::GetCollection;
         use crate::service::password::PasswordService;
         use crate::service::user::UserService;
         use mongodb::bson::doc;
         use mongodb::Collection;
-        use passphrasex_common::model::password::Password;
+        use passphrasex_common::model::password::{Password, MAX_PASSWORD_HISTORY};
+
+        const HISTORY_PASSWORD_ID: &str = "history_password_id";
 
         #[tokio::test]
         async fn modify_password() -> anyhow::Result<()> {
@@ -298,12 +826,47 @@ mod tests {
             let password: Password = collection
                 .find_one(filter, None)
                 .await?
-                .ok_or(ApiError::PasswordNotFound(PASSWORD_ID.to_string()))?;
+                .ok_or(ServiceError::NotFound(PASSWORD_ID.to_string()))?;
 
             assert!(password.password == "new_password");
             Ok(())
         }
 
+        #[tokio::test]
+        async fn modify_password_keeps_history() -> anyhow::Result<()> {
+            let client = setup().await;
+            let collection = insert_password(&client, HISTORY_PASSWORD_ID).await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            for _ in 0..=MAX_PASSWORD_HISTORY {
+                service
+                    .modify_password(
+                        USER_ID.to_string(),
+                        HISTORY_PASSWORD_ID.to_string(),
+                        "history_password".to_string(),
+                    )
+                    .await?;
+            }
+
+            let filter = doc! {"_id": HISTORY_PASSWORD_ID, "user_id": USER_ID.to_string()};
+            let password = collection
+                .find_one(filter, None)
+                .await?
+                .ok_or(ServiceError::NotFound(HISTORY_PASSWORD_ID.to_string()))?;
+
+            // Capped, newest first
+            assert_eq!(password.history.len(), MAX_PASSWORD_HISTORY);
+            assert!(password
+                .history
+                .windows(2)
+                .all(|entries| entries[0].changed_at >= entries[1].changed_at));
+
+            collection
+                .delete_one(doc! {"_id": HISTORY_PASSWORD_ID}, None)
+                .await?;
+            Ok(())
+        }
+
         #[tokio::test]
         async fn modify_password_missing_user() -> anyhow::Result<()> {
             let client = setup().await;
@@ -318,7 +881,7 @@ mod tests {
                 .await;
 
             assert!(result.is_err());
-            assert!(matches!(result, Err(ApiError::PasswordNotFound(_))));
+            assert!(matches!(result, Err(ServiceError::NotFound(_))));
             Ok(())
         }
 
@@ -336,7 +899,248 @@ mod tests {
                 .await;
 
             assert!(result.is_err());
-            assert!(matches!(result, Err(ApiError::PasswordNotFound(_))));
+            assert!(matches!(result, Err(ServiceError::NotFound(_))));
+            Ok(())
+        }
+    }
+
+    mod patch_password {
+        use super::{insert_password, setup};
+        use super::{PASSWORD_ID, USER_ID};
+        use crate::error::common::ServiceError;
+        use crate::service::password::PasswordService;
+        use crate::service::user::UserService;
+        use mongodb::bson::doc;
+        use passphrasex_common::model::password::PasswordPatch;
+
+        const PATCHED_PASSWORD_ID: &str = "patched_password_id";
+
+        #[tokio::test]
+        async fn patch_password() -> anyhow::Result<()> {
+            let client = setup().await;
+            let collection = insert_password(&client, PATCHED_PASSWORD_ID).await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let patch = PasswordPatch {
+                password: Some("patched_password".to_string()),
+                ..Default::default()
+            };
+            let password = service
+                .patch_password(USER_ID.to_string(), PATCHED_PASSWORD_ID.to_string(), patch)
+                .await?;
+
+            assert_eq!(password.password, "patched_password");
+            assert_eq!(password.site, "site");
+            assert!(password.updated_at.is_some());
+
+            collection
+                .delete_one(doc! {"_id": PATCHED_PASSWORD_ID}, None)
+                .await?;
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn patch_password_empty() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let result = service
+                .patch_password(
+                    USER_ID.to_string(),
+                    PASSWORD_ID.to_string(),
+                    PasswordPatch::default(),
+                )
+                .await;
+
+            assert!(matches!(result, Err(ServiceError::Validation(_))));
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn patch_password_missing_password() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let patch = PasswordPatch {
+                password: Some("patched_password".to_string()),
+                ..Default::default()
+            };
+            let result = service
+                .patch_password(USER_ID.to_string(), "wrong_id".to_string(), patch)
+                .await;
+
+            assert!(matches!(result, Err(ServiceError::NotFound(_))));
+            Ok(())
+        }
+    }
+
+    mod rename_password {
+        use super::setup;
+        use super::{PASSWORD_ID, USER_ID};
+        use crate::error::common::ServiceError;
+        use crate::service::password::PasswordService;
+        use crate::service::user::UserService;
+
+        #[tokio::test]
+        async fn rename_password_missing_password() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let result = service
+                .rename_password(
+                    USER_ID.to_string(),
+                    "wrong_id".to_string(),
+                    "renamed_id".to_string(),
+                    "new_site".to_string(),
+                )
+                .await;
+
+            assert!(result.is_err());
+            assert!(matches!(result, Err(ServiceError::NotFound(_))));
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn rename_password_existing_id() -> anyhow::Result<()> {
+            let client = setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let result = service
+                .rename_password(
+                    USER_ID.to_string(),
+                    PASSWORD_ID.to_string(),
+                    PASSWORD_ID.to_string(),
+                    "site".to_string(),
+                )
+                .await;
+
+            assert!(result.is_err());
+            assert!(matches!(result, Err(ServiceError::Conflict(_))));
+            Ok(())
+        }
+    }
+
+    mod create_indexes {
+        use crate::service::password::PasswordService;
+        use crate::service::user::UserService;
+
+        #[tokio::test]
+        async fn create_indexes_twice() -> anyhow::Result<()> {
+            let client = super::setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            service.create_indexes().await?;
+            service.create_indexes().await?;
+            Ok(())
+        }
+    }
+    mod passwords_etag {
+        use super::USER_ID;
+        use crate::service::password::PasswordService;
+        use crate::service::user::UserService;
+
+        #[tokio::test]
+        async fn passwords_etag_empty_vaults() -> anyhow::Result<()> {
+            let client = super::setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let empty = service.passwords_etag("wrong_id".to_string()).await?;
+            let other_empty = service.passwords_etag("other_wrong_id".to_string()).await?;
+            let not_empty = service.passwords_etag(USER_ID.to_string()).await?;
+
+            assert_eq!(empty, other_empty);
+            assert_ne!(empty, not_empty);
+            assert!(empty.starts_with("W/\""));
+            Ok(())
+        }
+    }
+
+    mod audit_log {
+        use super::PASSWORD_ID;
+        use crate::model::common::GetCollection;
+        use crate::service::password::PasswordService;
+        use crate::service::user::UserService;
+        use mongodb::bson::{doc, Document};
+        use mongodb::{Client, Collection};
+        use passphrasex_common::model::audit::AuditAction;
+
+        // Each test reads the whole log of its own user, so entries recorded by
+        // the other tests don't end up first
+        const NEWEST_FIRST_USER_ID: &str = "audit_newest_first_user_id";
+        const RENAMES_USER_ID: &str = "audit_renames_user_id";
+
+        async fn setup(user_id: &str) -> Client {
+            let client = super::setup().await;
+
+            let collection: Collection<Document> = client.get_collection("audit_log");
+            collection
+                .delete_many(doc! {"user_id": user_id}, None)
+                .await
+                .expect("Failed to delete audit entries");
+
+            client
+        }
+
+        #[tokio::test]
+        async fn audit_log_newest_first() -> anyhow::Result<()> {
+            let client = setup(NEWEST_FIRST_USER_ID).await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            service
+                .record_access(
+                    NEWEST_FIRST_USER_ID,
+                    None,
+                    AuditAction::List,
+                    NEWEST_FIRST_USER_ID,
+                )
+                .await;
+            service
+                .record_access(
+                    NEWEST_FIRST_USER_ID,
+                    Some(PASSWORD_ID),
+                    AuditAction::Delete,
+                    NEWEST_FIRST_USER_ID,
+                )
+                .await;
+
+            let entries = service.audit_log(NEWEST_FIRST_USER_ID.to_string()).await?;
+
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].action, AuditAction::Delete);
+            assert_eq!(entries[0].password_id.as_deref(), Some(PASSWORD_ID));
+            assert_eq!(entries[1].action, AuditAction::List);
+            assert!(entries
+                .iter()
+                .all(|entry| entry.user_id == NEWEST_FIRST_USER_ID));
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn audit_log_renames_keep_both_ids() -> anyhow::Result<()> {
+            let client = setup(RENAMES_USER_ID).await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            service
+                .record_rename(RENAMES_USER_ID, PASSWORD_ID, "renamed_id", "signing_key")
+                .await;
+
+            let entries = service.audit_log(RENAMES_USER_ID.to_string()).await?;
+
+            assert_eq!(entries[0].action, AuditAction::Rename);
+            assert_eq!(entries[0].password_id.as_deref(), Some("renamed_id"));
+            assert_eq!(entries[0].previous_id.as_deref(), Some(PASSWORD_ID));
+            assert_eq!(entries[0].client_pk, "signing_key");
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn audit_log_other_user() -> anyhow::Result<()> {
+            let client = super::setup().await;
+            let service = PasswordService::new(&client, UserService::new(&client));
+
+            let entries = service.audit_log("wrong_id".to_string()).await?;
+
+            assert!(entries.is_empty());
             Ok(())
         }
     }
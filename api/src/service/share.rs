@@ -0,0 +1,143 @@
+use crate::error::common::ServiceError;
+use crate::model::common::GetCollection;
+use crate::service::now_secs;
+use crate::service::user::UserService;
+use futures_util::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use passphrasex_common::crypto::asymmetric::public_key_from_base64;
+use passphrasex_common::model::share::SharedCredential;
+
+#[derive(Clone)]
+pub struct ShareService {
+    user_service: UserService,
+    share_collection: Collection<SharedCredential>,
+}
+
+impl ShareService {
+    pub fn new(client: &Client, user_service: UserService) -> Self {
+        Self {
+            user_service,
+            share_collection: client.get_collection("shares"),
+        }
+    }
+
+    /// Create the index for listing the credentials shared with a user, safe on
+    /// every start like [`PasswordService::create_indexes`](crate::service::password::PasswordService::create_indexes).
+    /// Recipients are the keys of a map, so it's a wildcard index over them.
+    pub async fn create_indexes(&self) -> Result<(), ServiceError> {
+        let index = IndexModel::builder()
+            .keys(doc! {"recipients.$**": 1})
+            .options(
+                IndexOptions::builder()
+                    .name("recipients".to_string())
+                    .build(),
+            )
+            .build();
+
+        self.share_collection.create_index(index, None).await?;
+        Ok(())
+    }
+
+    /// Share a credential with existing users. Sharing it again adds the new
+    /// recipients and replaces the copies of the ones it was already shared with.
+    pub async fn share(&self, shared: SharedCredential) -> Result<SharedCredential, ServiceError> {
+        if shared.recipients.is_empty() {
+            return Err(ServiceError::Validation(
+                "Credentials must be shared with someone".to_string(),
+            ));
+        }
+        if shared.recipients.contains_key(&shared.sender_id) {
+            return Err(ServiceError::Validation(
+                "Credentials can't be shared with their owner".to_string(),
+            ));
+        }
+        // Also keeps anything but public keys out of the field names below
+        for recipient_id in shared.recipients.keys() {
+            self.user_service.get_user(recipient_id.clone()).await?;
+        }
+
+        // In the compact form `SharedCredential` reads and writes
+        let mut recipients = Document::new();
+        for (recipient_id, content) in &shared.recipients {
+            recipients.insert(format!("recipients.{recipient_id}"), content.to_string());
+        }
+
+        let filter = doc! {"_id": shared._id.clone(), "sender_id": shared.sender_id.clone()};
+        let update = doc! {
+            "$set": recipients,
+            "$setOnInsert": {"created_at": now_secs() as i64},
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        match self
+            .share_collection
+            .find_one_and_update(filter, update, options)
+            .await
+        {
+            Ok(shared) => shared.ok_or(ServiceError::NotFound(String::from("Shared credential"))),
+            // An id taken by a share of another user
+            Err(err) => Err(ServiceError::from_insert(
+                err,
+                format!("Shared credential {}", shared._id),
+            )),
+        }
+    }
+
+    /// The credentials other users shared with `user_id`, with only their copy
+    /// of each
+    pub async fn list_received(
+        &self,
+        user_id: String,
+    ) -> Result<Vec<SharedCredential>, ServiceError> {
+        let filter = doc! {format!("recipients.{user_id}"): {"$exists": true}};
+
+        let cursor = self.share_collection.find(filter, None).await?;
+        let mut shares: Vec<SharedCredential> = cursor.try_collect().await?;
+        for shared in &mut shares {
+            shared
+                .recipients
+                .retain(|recipient_id, _| recipient_id == &user_id);
+        }
+        Ok(shares)
+    }
+
+    /// Stop sharing a credential with `recipient_id` by dropping their copy, and
+    /// the whole share once nobody is left
+    pub async fn revoke(
+        &self,
+        sender_id: String,
+        share_id: String,
+        recipient_id: String,
+    ) -> Result<(), ServiceError> {
+        public_key_from_base64(&recipient_id)
+            .map_err(|err| ServiceError::Validation(format!("Invalid public key: {err}")))?;
+
+        let field = format!("recipients.{recipient_id}");
+        let filter = doc! {
+            "_id": share_id.clone(),
+            "sender_id": sender_id.clone(),
+            field.clone(): {"$exists": true},
+        };
+        let update = doc! {"$unset": {field: ""}};
+
+        let result = self
+            .share_collection
+            .update_one(filter, update, None)
+            .await?;
+        if result.matched_count == 0 {
+            return Err(ServiceError::NotFound(format!(
+                "Shared credential {share_id} for {recipient_id}"
+            )));
+        }
+
+        let filter = doc! {"_id": share_id, "sender_id": sender_id, "recipients": {}};
+        self.share_collection.delete_one(filter, None).await?;
+
+        Ok(())
+    }
+}
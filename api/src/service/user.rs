@@ -1,11 +1,14 @@
-use crate::error::common::ApiError;
+use crate::error::common::ServiceError;
 use crate::model::common::GetCollection;
 use mongodb::bson::doc;
-use mongodb::error::ErrorKind;
-use mongodb::error::WriteFailure::WriteError;
 use mongodb::{Client, Collection};
+use passphrasex_common::crypto::asymmetric::{public_key_from_base64, signing_key_from_base64};
 use passphrasex_common::model::user::User;
 
+/// Longest address SMTP accepts
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+
 #[derive(Clone)]
 pub struct UserService {
     user_collection: Collection<User>,
@@ -18,26 +21,111 @@ impl UserService {
         }
     }
 
-    pub async fn create_user(&self, user: User) -> Result<User, ApiError> {
+    /// Store a new account, with its email and display name trimmed. Empty ones
+    /// are left out, they're both optional, the signing key isn't.
+    pub async fn create_user(&self, mut user: User) -> Result<User, ServiceError> {
+        public_key_from_base64(&user._id)
+            .map_err(|err| ServiceError::Validation(format!("Invalid public key: {err}")))?;
+        let signing_key = user
+            .signing_key
+            .as_deref()
+            .ok_or(ServiceError::Validation("Missing signing key".to_string()))?;
+        signing_key_from_base64(signing_key)
+            .map_err(|err| ServiceError::Validation(format!("Invalid signing key: {err}")))?;
+
+        user.email = trimmed(user.email);
+        if let Some(email) = &user.email {
+            validate_email(email)?;
+        }
+        user.display_name = trimmed(user.display_name);
+        if let Some(display_name) = &user.display_name {
+            if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+                return Err(ServiceError::Validation(format!(
+                    "Display name can't be longer than {MAX_DISPLAY_NAME_LENGTH} characters"
+                )));
+            }
+        }
+
         match self.user_collection.insert_one(&user, None).await {
             Ok(_) => Ok(user),
-            Err(err) => match err.kind.as_ref() {
-                ErrorKind::Write(WriteError(error)) => match error.code {
-                    11000 => Err(ApiError::UserAlreadyExists(user._id)),
-                    _ => Err(ApiError::InternalServerError(err.to_string())),
-                },
-                _ => Err(ApiError::InternalServerError(err.to_string())),
-            },
+            Err(err) => Err(ServiceError::from_insert(err, format!("User {}", user._id))),
         }
     }
 
-    pub async fn get_user(&self, user_id: String) -> Result<User, ApiError> {
+    pub async fn get_user(&self, user_id: String) -> Result<User, ServiceError> {
         let collection = &self.user_collection;
         let filter = doc! {"_id": user_id.clone()};
-        match collection.find_one(filter, None).await {
-            Ok(Some(user)) => Ok(user),
-            Ok(None) => Err(ApiError::UserNotFound(user_id)),
-            Err(err) => Err(ApiError::InternalServerError(err.to_string())),
+        collection
+            .find_one(filter, None)
+            .await?
+            .ok_or(ServiceError::NotFound(format!("User {user_id}")))
+    }
+}
+
+fn trimmed(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Only checks the address has the shape of one, whether it exists is up to
+/// whoever sends mail to it
+fn validate_email(email: &str) -> Result<(), ServiceError> {
+    let invalid = || ServiceError::Validation(format!("Invalid email: {email}"));
+
+    if email.len() > MAX_EMAIL_LENGTH || email.chars().any(char::is_whitespace) {
+        return Err(invalid());
+    }
+    let (local, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+    if local.is_empty()
+        || !domain.contains('.')
+        || domain.starts_with('.')
+        || domain.ends_with('.')
+        || domain.contains('@')
+    {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::common::DatabaseConfig;
+    use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
+
+    #[test]
+    fn accepts_email_addresses() {
+        assert!(validate_email("user@example.com").is_ok());
+        assert!(validate_email("first.last+tag@mail.example.co.uk").is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_email_addresses() {
+        for email in [
+            "user",
+            "@example.com",
+            "user@",
+            "user@example",
+            "user@.example.com",
+            "user@example.com.",
+            "user name@example.com",
+        ] {
+            assert!(validate_email(email).is_err(), "{email}");
         }
     }
+
+    #[tokio::test]
+    async fn new_users_need_a_signing_key() {
+        let client = DatabaseConfig::new()
+            .into_client()
+            .await
+            .expect("Failed to connect to database");
+        let service = UserService::new(&client);
+        let user = User::new(KeyPair::new(SeedPhrase::new(), None).get_pk());
+
+        let result = service.create_user(user).await;
+        assert!(matches!(result, Err(ServiceError::Validation(_))));
+    }
 }
@@ -1,126 +1,556 @@
 // Wrapper functions to call api
-use anyhow::format_err;
+use crate::config::Config;
 use passphrasex_common::crypto::asymmetric::KeyPair;
-use passphrasex_common::model::password::Password;
-use reqwest::{Client, Response, StatusCode, Url};
+use passphrasex_common::model::auth::{AuthToken, Challenge};
+use passphrasex_common::model::password::{Password, PasswordCount, PasswordPatch};
+use passphrasex_common::model::share::SharedCredential;
+use passphrasex_common::model::user::User;
+use rand::Rng;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Client, RequestBuilder, Response, StatusCode, Url};
 use std::collections::HashMap;
 use std::env;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Version of the API endpoints this client speaks
+const API_VERSION_PREFIX: &str = "/v1";
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 200;
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("User already exists")]
+    UserAlreadyExists,
+    #[error("Unable to reach the API: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Already exists: {0}")]
+    Conflict(String),
+    #[error("Invalid request: {0}")]
+    BadRequest(String),
+    #[error("Error from API: {0}")]
+    Response(String),
+}
+
+/// Passwords returned by [`Api::get_passwords`]
+pub enum Passwords {
+    /// Nothing changed since the `ETag` sent along with the request
+    NotModified,
+    Modified {
+        passwords: Vec<Password>,
+        etag: Option<String>,
+    },
+}
+
+impl ApiError {
+    /// Error for an unexpected response, by its status code
+    async fn from_response(res: Response) -> Self {
+        let status = res.status();
+        let text = match res.text().await {
+            Ok(text) => text,
+            Err(err) => return ApiError::Network(err),
+        };
+
+        match status {
+            StatusCode::NOT_FOUND => ApiError::NotFound(text),
+            StatusCode::CONFLICT => ApiError::Conflict(text),
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+                ApiError::BadRequest(text)
+            }
+            _ => ApiError::Response(text),
+        }
+    }
+}
+
+/// How many times requests are attempted when they fail with a network error
+/// or a 5xx, waiting `base_delay * 2^n` (with jitter) between attempts.
+pub struct RetryConfig {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryConfig {
+    /// Read from `API_RETRY_ATTEMPTS` and `API_RETRY_BASE_DELAY_MS`
+    pub fn from_env() -> Self {
+        let attempts = env::var("API_RETRY_ATTEMPTS")
+            .ok()
+            .and_then(|attempts| attempts.parse().ok())
+            .unwrap_or(DEFAULT_RETRY_ATTEMPTS)
+            .max(1);
+        let base_delay = env::var("API_RETRY_BASE_DELAY_MS")
+            .ok()
+            .and_then(|delay| delay.parse().ok())
+            .unwrap_or(DEFAULT_RETRY_BASE_DELAY_MS);
+
+        Self {
+            attempts,
+            base_delay: Duration::from_millis(base_delay),
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+        // Wait between half and the full delay so clients don't retry in lockstep
+        let jitter = rand::thread_rng().gen_range(0.5..=1.0);
+        delay.mul_f64(jitter)
+    }
+}
 
 pub struct Api {
     client: Client,
     base_url: Url,
     key_pair: KeyPair,
+    retry: RetryConfig,
+}
+
+/// A random key identifying one add, see [`Api::add_password`]
+pub fn new_idempotency_key() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+/// Url of the API server: `API_URI`, the one in the config file or the public instance
+pub fn api_url() -> String {
+    env::var("API_URI")
+        .ok()
+        .or_else(|| Config::load().ok().and_then(|config| config.server_url))
+        .unwrap_or("https://api.passphrasex.srosati.xyz".to_string())
+}
+
+/// Check whether the API server is up, without authenticating
+pub async fn check_health() -> Result<(), ApiError> {
+    let url = Url::parse(&api_url())
+        .and_then(|url| url.join("/health"))
+        .map_err(|err| ApiError::Response(err.to_string()))?;
+
+    let res = build_client().get(url).send().await?;
+    match res.status() {
+        StatusCode::OK => Ok(()),
+        status => Err(ApiError::Response(status.to_string())),
+    }
+}
+
+fn build_client() -> Client {
+    Client::builder()
+        .connect_timeout(env_duration(
+            "API_CONNECT_TIMEOUT_SECS",
+            DEFAULT_CONNECT_TIMEOUT_SECS,
+        ))
+        .timeout(env_duration(
+            "API_TIMEOUT_SECS",
+            DEFAULT_REQUEST_TIMEOUT_SECS,
+        ))
+        .build()
+        .expect("Failed to build HTTP client")
 }
 
 impl Api {
     pub fn new(key_pair: KeyPair) -> Self {
-        let base_url =
-            env::var("API_URI").unwrap_or("https://api.passphrasex.srosati.xyz".to_string());
+        Self::with_base_url(key_pair, &api_url()).expect("Invalid API_URI")
+    }
 
-        Self {
-            client: Client::new(),
-            base_url: Url::parse(&base_url).unwrap(),
+    /// Client for the API server at `base_url`, e.g. a self-hosted instance
+    pub fn with_base_url(key_pair: KeyPair, base_url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: build_client(),
+            base_url: Url::parse(base_url)?,
             key_pair,
+            retry: RetryConfig::from_env(),
+        })
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// URL of an API endpoint, given its unversioned path
+    fn endpoint(&self, path: &str) -> anyhow::Result<Url> {
+        Ok(self
+            .base_url
+            .join(&format!("{}{}", API_VERSION_PREFIX, path))?)
+    }
+
+    /// Send the request built by `build`, retrying timeouts, connection errors
+    /// and 5xx responses. The request is rebuilt on every attempt so it gets a
+    /// fresh auth token. 4xx responses are returned right away.
+    async fn send_with_retry<F>(&self, build: F) -> Result<Response, ApiError>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let result = build().send().await;
+
+            if !is_retryable(&result) || attempt >= self.retry.attempts {
+                return result.map_err(ApiError::Network);
+            }
+
+            tokio::time::sleep(self.retry.delay(attempt - 1)).await;
         }
     }
 
-    pub async fn create_user(&self, public_key: String) -> anyhow::Result<()> {
-        let url = self.base_url.join("/users")?;
+    /// Like [`Api::send_with_retry`], for requests that change data. `build`
+    /// gets the auth header, signing a new challenge on every attempt as the
+    /// server only accepts each once.
+    async fn send_signed_with_retry<F>(
+        &self,
+        public_key: &str,
+        build: F,
+    ) -> Result<Response, ApiError>
+    where
+        F: Fn(String) -> RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let auth_header = self.challenge_auth_header(public_key).await?;
+            let result = build(auth_header).send().await;
 
-        let mut body = HashMap::new();
-        body.insert("public_key", public_key);
-        let res = self.client.post(url).json(&body).send().await?;
-        validate_response(res, StatusCode::CREATED).await
+            if !is_retryable(&result) || attempt >= self.retry.attempts {
+                return result.map_err(ApiError::Network);
+            }
+
+            tokio::time::sleep(self.retry.delay(attempt - 1)).await;
+        }
     }
 
-    pub async fn add_password(&self, public_key: String, password: Password) -> anyhow::Result<()> {
+    pub async fn create_user(&self, user: &User) -> Result<(), ApiError> {
         let url = self
-            .base_url
-            .join(&format!("/users/{}/passwords", public_key))?;
+            .endpoint("/users")
+            .map_err(|err| ApiError::Response(err.to_string()))?;
 
-        let mut body = HashMap::new();
-        body.insert("_id", password._id);
-        body.insert("user_id", password.user_id);
-        body.insert("site", password.site);
-        body.insert("username", password.username);
-        body.insert("password", password.password);
+        let res = self
+            .send_with_retry(|| self.client.post(url.clone()).json(user))
+            .await?;
+
+        match res.status() {
+            StatusCode::CREATED => Ok(()),
+            StatusCode::CONFLICT => Err(ApiError::UserAlreadyExists),
+            _ => Err(ApiError::from_response(res).await),
+        }
+    }
+
+    /// Store an (already encrypted) password, returning it as stored by the API.
+    /// Adds sent again with the same `idempotency_key` (see [`new_idempotency_key`])
+    /// get the first result back instead of a conflict.
+    pub async fn add_password(
+        &self,
+        public_key: String,
+        password: Password,
+        idempotency_key: Option<&str>,
+    ) -> anyhow::Result<Password> {
+        let url = self.endpoint(&format!("/users/{}/passwords", public_key))?;
 
         let res = self
-            .client
-            .post(url)
-            .header("Authorization", self.auth_header())
-            .json(&body)
-            .send()
+            .send_signed_with_retry(&public_key, |auth_header| {
+                let request = self
+                    .client
+                    .post(url.clone())
+                    .header("Authorization", auth_header)
+                    .json(&password);
+                match idempotency_key {
+                    Some(key) => request.header(IDEMPOTENCY_KEY, key),
+                    None => request,
+                }
+            })
             .await?;
 
-        validate_response(res, StatusCode::CREATED).await
+        if res.status() != StatusCode::CREATED {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        let body = res.json::<Password>().await?;
+        Ok(body)
     }
 
-    pub async fn get_passwords(&self, public_key: String) -> anyhow::Result<Vec<Password>> {
-        let url = self
-            .base_url
-            .join(&format!("/users/{}/passwords", public_key))?;
+    /// List the user's passwords, or get [`Passwords::NotModified`] when they
+    /// still match `etag`, the `ETag` of a previous listing
+    pub async fn get_passwords(
+        &self,
+        public_key: String,
+        etag: Option<&str>,
+    ) -> anyhow::Result<Passwords> {
+        let url = self.endpoint(&format!("/users/{}/passwords", public_key))?;
 
         let res = self
-            .client
-            .get(url)
-            .header("Authorization", self.auth_header())
-            .send()
+            .send_with_retry(|| {
+                let req = self
+                    .client
+                    .get(url.clone())
+                    .header("Authorization", self.auth_header());
+                match etag {
+                    Some(etag) => req.header(IF_NONE_MATCH, etag),
+                    None => req,
+                }
+            })
             .await?;
 
         match res.status() {
             StatusCode::OK => (),
+            StatusCode::NOT_MODIFIED => return Ok(Passwords::NotModified),
             _ => {
-                return Err(format_err!("Error from API: {}", res.text().await?));
+                return Err(ApiError::from_response(res).await.into());
             }
         }
 
-        let body = res.json::<Vec<Password>>().await?;
+        let etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        let passwords = res.json::<Vec<Password>>().await?;
+        Ok(Passwords::Modified { passwords, etag })
+    }
+
+    /// Everything the API stores for the user, as the JSON document it serves
+    pub async fn export_user(&self, public_key: String) -> anyhow::Result<String> {
+        let url = self.endpoint(&format!("/users/{}/export", public_key))?;
+
+        let res = self
+            .send_with_retry(|| {
+                self.client
+                    .get(url.clone())
+                    .header("Authorization", self.auth_header())
+            })
+            .await?;
+
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        Ok(res.text().await?)
+    }
+
+    /// Share a credential (already encrypted for each recipient) or add
+    /// recipients to it, returning it as stored by the API
+    pub async fn share_credential(
+        &self,
+        public_key: String,
+        shared: SharedCredential,
+    ) -> anyhow::Result<SharedCredential> {
+        let url = self.endpoint(&format!("/users/{}/shares", public_key))?;
+
+        let res = self
+            .send_signed_with_retry(&public_key, |auth_header| {
+                self.client
+                    .post(url.clone())
+                    .header("Authorization", auth_header)
+                    .json(&shared)
+            })
+            .await?;
+
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        let body = res.json::<SharedCredential>().await?;
+        Ok(body)
+    }
+
+    /// How many passwords the user has on the server, by site
+    pub async fn count_passwords(&self, public_key: String) -> anyhow::Result<PasswordCount> {
+        let url = self.endpoint(&format!("/users/{}/passwords/count", public_key))?;
+
+        let res = self
+            .send_with_retry(|| {
+                self.client
+                    .get(url.clone())
+                    .header("Authorization", self.auth_header())
+            })
+            .await?;
+
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        let body = res.json::<PasswordCount>().await?;
         Ok(body)
     }
 
+    /// The credentials other users shared with the user
+    pub async fn get_received_shares(
+        &self,
+        public_key: String,
+    ) -> anyhow::Result<Vec<SharedCredential>> {
+        let url = self.endpoint(&format!("/users/{}/shares/received", public_key))?;
+
+        let res = self
+            .send_with_retry(|| {
+                self.client
+                    .get(url.clone())
+                    .header("Authorization", self.auth_header())
+            })
+            .await?;
+
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        let body = res.json::<Vec<SharedCredential>>().await?;
+        Ok(body)
+    }
+
+    /// Drop the recipient's copy of a shared credential
+    pub async fn revoke_share(
+        &self,
+        public_key: String,
+        share_id: String,
+        recipient_id: String,
+    ) -> anyhow::Result<()> {
+        let url = self.endpoint(&format!(
+            "/users/{}/shares/{}/recipients/{}",
+            public_key, share_id, recipient_id
+        ))?;
+
+        let auth_header = self.challenge_auth_header(&public_key).await?;
+        let res = self
+            .client
+            .delete(url)
+            .header("Authorization", auth_header)
+            .send()
+            .await
+            .map_err(ApiError::Network)?;
+
+        validate_response(res, StatusCode::NO_CONTENT).await
+    }
+
     pub async fn edit_password(
         &self,
         public_key: String,
         password_id: String,
         password: String,
     ) -> anyhow::Result<()> {
-        let url = self.base_url.join(&format!(
+        let url = self.endpoint(&format!(
             "/users/{}/passwords/{}/password",
             public_key, password_id
         ))?;
 
+        let auth_header = self.challenge_auth_header(&public_key).await?;
         let res = self
             .client
             .put(url)
-            .header("Authorization", self.auth_header())
+            .header("Authorization", auth_header)
             .body(password)
             .send()
-            .await?;
+            .await
+            .map_err(ApiError::Network)?;
 
         validate_response(res, StatusCode::NO_CONTENT).await
     }
 
+    /// Move a password to a new id and site, returning it as stored by the API
+    pub async fn rename_password(
+        &self,
+        public_key: String,
+        password_id: String,
+        new_id: String,
+        new_site: String,
+    ) -> anyhow::Result<Password> {
+        let url = self.endpoint(&format!(
+            "/users/{}/passwords/{}/rename",
+            public_key, password_id
+        ))?;
+
+        let mut body = HashMap::new();
+        body.insert("_id", new_id);
+        body.insert("site", new_site);
+
+        let auth_header = self.challenge_auth_header(&public_key).await?;
+        let res = self
+            .client
+            .post(url)
+            .header("Authorization", auth_header)
+            .json(&body)
+            .send()
+            .await
+            .map_err(ApiError::Network)?;
+
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        let body = res.json::<Password>().await?;
+        Ok(body)
+    }
+
+    /// Change only the fields set in `patch`, returning the password as stored
+    /// by the API
+    pub async fn patch_password(
+        &self,
+        public_key: String,
+        password_id: String,
+        patch: &PasswordPatch,
+    ) -> anyhow::Result<Password> {
+        let url = self.endpoint(&format!("/users/{}/passwords/{}", public_key, password_id))?;
+
+        let auth_header = self.challenge_auth_header(&public_key).await?;
+        let res = self
+            .client
+            .patch(url)
+            .header("Authorization", auth_header)
+            .json(patch)
+            .send()
+            .await
+            .map_err(ApiError::Network)?;
+
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await.into());
+        }
+
+        let body = res.json::<Password>().await?;
+        Ok(body)
+    }
+
     pub async fn delete_password(
         &self,
         public_key: String,
         password_id: String,
     ) -> anyhow::Result<()> {
-        let url = self
-            .base_url
-            .join(&format!("/users/{}/passwords/{}", public_key, password_id))?;
+        let url = self.endpoint(&format!("/users/{}/passwords/{}", public_key, password_id))?;
 
+        let auth_header = self.challenge_auth_header(&public_key).await?;
         let res = self
             .client
             .delete(url)
-            .header("Authorization", self.auth_header())
+            .header("Authorization", auth_header)
             .send()
-            .await?;
+            .await
+            .map_err(ApiError::Network)?;
 
         validate_response(res, StatusCode::NO_CONTENT).await
     }
 
+    /// Auth header for one request that changes data, signing a challenge the
+    /// server just issued
+    async fn challenge_auth_header(&self, public_key: &str) -> Result<String, ApiError> {
+        let url = self
+            .endpoint(&format!("/users/{}/challenge", public_key))
+            .map_err(|err| ApiError::Response(err.to_string()))?;
+
+        let res = self
+            .send_with_retry(|| {
+                self.client
+                    .get(url.clone())
+                    .header("Authorization", self.auth_header())
+            })
+            .await?;
+        if res.status() != StatusCode::OK {
+            return Err(ApiError::from_response(res).await);
+        }
+
+        let challenge = res.json::<Challenge>().await?;
+        Ok(format!(
+            "Bearer {}",
+            AuthToken::new(&self.key_pair, &challenge.challenge)
+        ))
+    }
+
+    /// Auth header for reads, signing the current time
     fn auth_header(&self) -> String {
         format!("Bearer {}", self.auth_token())
     }
@@ -130,14 +560,30 @@ impl Api {
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        self.key_pair.sign(&time.to_string()).to_string()
+        AuthToken::new(&self.key_pair, &time.to_string()).to_string()
     }
 }
 
+/// Whether a failed attempt may succeed if sent again: timeouts, connection
+/// errors and 5xx responses
+fn is_retryable(result: &Result<Response, reqwest::Error>) -> bool {
+    match result {
+        Ok(res) => res.status().is_server_error(),
+        Err(err) => err.is_timeout() || err.is_connect(),
+    }
+}
+
+fn env_duration(key: &str, default_secs: u64) -> Duration {
+    let secs = env::var(key)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(default_secs);
+    Duration::from_secs(secs)
+}
+
 async fn validate_response(res: Response, status_code: StatusCode) -> anyhow::Result<()> {
     if res.status() != status_code {
-        let text = res.text().await?;
-        return Err(format_err!("Error from API: {}", text));
+        return Err(ApiError::from_response(res).await.into());
     }
 
     Ok(())
@@ -0,0 +1,36 @@
+use crate::APP_INFO;
+use app_dirs2::{app_root, AppDataType};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "config.json";
+
+/// Settings kept in `config.json` under the user's config directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// API server to use instead of the public one, `API_URI` takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_url: Option<String>,
+}
+
+impl Config {
+    pub fn path() -> anyhow::Result<PathBuf> {
+        Ok(app_root(AppDataType::UserConfig, &APP_INFO)?.join(CONFIG_FILE))
+    }
+
+    /// The saved config, or the default one when none was saved
+    pub fn load() -> anyhow::Result<Self> {
+        let path = Self::path()?;
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        fs::write(Self::path()?, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
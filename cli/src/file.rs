@@ -1,64 +1,434 @@
+use crate::storage::{CorruptedAppData, NewerAppData, PendingChange, VaultStorage};
 use crate::{CredentialsMap, APP_INFO};
+use anyhow::format_err;
 use app_dirs2::{app_dir, AppDataType};
 use passphrasex_common::crypto::common::EncryptedValue;
-use passphrasex_common::crypto::symmetric::{decrypt_data, encrypt_data};
-use std::fs::File;
+use passphrasex_common::crypto::symmetric::encode_legacy_hash;
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 const DATA_DIR: &str = "data";
 const PASSWORD_HASH_FILE: &str = "device_pass";
 const PRIVATE_KEY_FILE: &str = "private_key";
+const SEED_PHRASE_FILE: &str = "seed_phrase";
 const DATA_FILE: &str = "data.json";
+const DATA_BACKUP_FILE: &str = "data.json.bak";
+const DATA_TEMP_FILE: &str = "data.json.tmp";
+const ETAG_FILE: &str = "data.etag";
+const PENDING_FILE: &str = "pending.json";
+const PENDING_TEMP_FILE: &str = "pending.json.tmp";
+/// Every file a vault may leave behind, removed by `purge`
+const VAULT_FILES: [&str; 9] = [
+    PASSWORD_HASH_FILE,
+    PRIVATE_KEY_FILE,
+    SEED_PHRASE_FILE,
+    DATA_FILE,
+    DATA_BACKUP_FILE,
+    DATA_TEMP_FILE,
+    ETAG_FILE,
+    PENDING_FILE,
+    PENDING_TEMP_FILE,
+];
 
-fn write_bytes(file_name: &str, bytes: Vec<u8>) -> anyhow::Result<()> {
-    let path_to_file = app_dir(AppDataType::UserData, &APP_INFO, DATA_DIR)?.join(file_name);
+/// Version of the app data format written, older ones are upgraded on load by
+/// [`migrate_app_data`]
+const APP_DATA_VERSION: u64 = 1;
 
-    let mut file = File::create(path_to_file)?;
-    file.write_all(&bytes)?;
-
-    Ok(())
+/// Vault stored in files under the user's data directory, or the directory in
+/// `PASSPHRASEX_DATA_DIR` when set (e.g. for tests or a portable install)
+#[derive(Default)]
+pub struct FileStorage {
+    /// Overrides both, see [`FileStorage::in_dir`]
+    dir: Option<PathBuf>,
 }
 
-fn read_bytes(file_name: &str) -> anyhow::Result<Vec<u8>> {
-    let path_to_file = app_dir(AppDataType::UserData, &APP_INFO, DATA_DIR)?.join(file_name);
+impl FileStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Vault stored in `dir`, whatever `PASSPHRASEX_DATA_DIR` says
+    pub fn in_dir(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+        }
+    }
+
+    fn path(&self, file_name: &str) -> anyhow::Result<PathBuf> {
+        let dir = match self
+            .dir
+            .clone()
+            .or_else(|| env::var_os("PASSPHRASEX_DATA_DIR").map(PathBuf::from))
+        {
+            Some(dir) => {
+                fs::create_dir_all(&dir)?;
+                dir
+            }
+            None => app_dir(AppDataType::UserData, &APP_INFO, DATA_DIR)?,
+        };
+        Ok(dir.join(file_name))
+    }
+
+    fn write_bytes(&self, file_name: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut file = create_private(&self.path(file_name)?)?;
+        file.write_all(bytes)?;
+
+        Ok(())
+    }
+
+    /// Write to a temporary file first, so the file is never left half written
+    fn write_bytes_atomic(
+        &self,
+        file_name: &str,
+        temp_name: &str,
+        bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let temp_path = self.path(temp_name)?;
+        let mut file = create_private(&temp_path)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+
+        fs::rename(temp_path, self.path(file_name)?)?;
+        Ok(())
+    }
+
+    /// Remove `file_name` if it exists, overwriting its contents with zeros first
+    /// when `overwrite`. Only a best effort on SSDs and copy-on-write file
+    /// systems, which may keep the old blocks around.
+    fn remove(&self, file_name: &str, overwrite: bool) -> anyhow::Result<()> {
+        let path = self.path(file_name)?;
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        if overwrite {
+            let len = fs::metadata(&path)?.len() as usize;
+            let mut file = OpenOptions::new().write(true).open(&path)?;
+            file.write_all(&vec![0; len])?;
+            file.sync_all()?;
+        }
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    fn read_bytes(&self, file_name: &str) -> anyhow::Result<Vec<u8>> {
+        let mut file = File::open(self.path(file_name)?)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        Ok(bytes)
+    }
 
-    let mut file = File::open(path_to_file)?;
-    let mut bytes = Vec::new();
-    file.read_to_end(&mut bytes)?;
+    /// The credentials in `file_name`, upgraded to [`APP_DATA_VERSION`], and the
+    /// version they were stored in
+    fn load_app_data(&self, file_name: &str) -> anyhow::Result<(CredentialsMap, u64)> {
+        let mut data: Value = serde_json::from_slice(&self.read_bytes(file_name)?)?;
 
-    Ok(bytes)
+        // Versions are only stored since version 1, a bare credentials map is version 0
+        let version = data.get("version").and_then(Value::as_u64).unwrap_or(0);
+        if version > APP_DATA_VERSION {
+            return Err(NewerAppData { version }.into());
+        }
+
+        for from in version..APP_DATA_VERSION {
+            data = migrate_app_data(from, data)?;
+        }
+
+        Ok((serde_json::from_value(data["credentials"].take())?, version))
+    }
 }
 
-pub fn write_password_hash(hash: &EncryptedValue) -> anyhow::Result<()> {
-    write_bytes(PASSWORD_HASH_FILE, hash.to_string().as_bytes().to_vec())
+impl VaultStorage for FileStorage {
+    fn exists(&self) -> anyhow::Result<bool> {
+        Ok(self.path(PASSWORD_HASH_FILE)?.is_file() && self.path(PRIVATE_KEY_FILE)?.is_file())
+    }
+
+    fn write_password_hash(&self, hash: &str) -> anyhow::Result<()> {
+        self.write_bytes(PASSWORD_HASH_FILE, hash.as_bytes())
+    }
+
+    fn read_password_hash(&self) -> anyhow::Result<String> {
+        let hash = String::from_utf8(self.read_bytes(PASSWORD_HASH_FILE)?)?;
+        if hash.starts_with('$') {
+            return Ok(hash);
+        }
+
+        // Written as `hash;salt` before the hash parameters were stored
+        encode_legacy_hash(&hash.parse::<EncryptedValue>()?)
+    }
+
+    fn write_encrypted_sk(&self, enc: &[u8]) -> anyhow::Result<()> {
+        self.write_bytes(PRIVATE_KEY_FILE, enc)
+    }
+
+    fn read_encrypted_sk(&self) -> anyhow::Result<Vec<u8>> {
+        self.read_bytes(PRIVATE_KEY_FILE)
+    }
+
+    fn write_encrypted_seed(&self, enc: &[u8]) -> anyhow::Result<()> {
+        self.write_bytes(SEED_PHRASE_FILE, enc)
+    }
+
+    fn read_encrypted_seed(&self) -> anyhow::Result<Vec<u8>> {
+        self.read_bytes(SEED_PHRASE_FILE)
+    }
+
+    fn write_app_data(&self, data: &CredentialsMap) -> anyhow::Result<()> {
+        // Only readable credentials replace the backup
+        if self.load_app_data(DATA_FILE).is_ok() {
+            self.write_bytes(DATA_BACKUP_FILE, &self.read_bytes(DATA_FILE)?)?;
+        }
+
+        let data = json!({"version": APP_DATA_VERSION, "credentials": data});
+        self.write_bytes_atomic(
+            DATA_FILE,
+            DATA_TEMP_FILE,
+            serde_json::to_string(&data)?.as_bytes(),
+        )
+    }
+
+    /// Credentials stored in an older format are rewritten in the current one
+    fn read_app_data(&self) -> anyhow::Result<CredentialsMap> {
+        match self.load_app_data(DATA_FILE) {
+            Ok((data, version)) => {
+                if version < APP_DATA_VERSION {
+                    self.write_app_data(&data)?;
+                }
+                Ok(data)
+            }
+            // Not corrupted, restoring a backup or downloading would lose the newer data
+            Err(err) if err.is::<NewerAppData>() => Err(err),
+            Err(err) => {
+                let backup = self.path(DATA_BACKUP_FILE)?;
+                Err(CorruptedAppData {
+                    path: self.path(DATA_FILE)?,
+                    backup: Some(backup).filter(|path| path.is_file()),
+                    reason: err.to_string(),
+                }
+                .into())
+            }
+        }
+    }
+
+    fn restore_app_data_backup(&self) -> anyhow::Result<()> {
+        self.load_app_data(DATA_BACKUP_FILE)
+            .map_err(|err| format_err!("The backup is corrupted too: {}", err))?;
+
+        let bytes = self.read_bytes(DATA_BACKUP_FILE)?;
+        self.write_bytes_atomic(DATA_FILE, DATA_TEMP_FILE, &bytes)
+    }
+
+    fn write_etag(&self, etag: Option<&str>) -> anyhow::Result<()> {
+        match etag {
+            Some(etag) => self.write_bytes(ETAG_FILE, etag.as_bytes()),
+            None => {
+                let path = self.path(ETAG_FILE)?;
+                if path.is_file() {
+                    fs::remove_file(path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn read_etag(&self) -> anyhow::Result<Option<String>> {
+        if !self.path(ETAG_FILE)?.is_file() {
+            return Ok(None);
+        }
+
+        let bytes = self.read_bytes(ETAG_FILE)?;
+        Ok(Some(String::from_utf8(bytes)?))
+    }
+
+    fn write_pending(&self, changes: &[PendingChange]) -> anyhow::Result<()> {
+        if changes.is_empty() {
+            let path = self.path(PENDING_FILE)?;
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+
+        self.write_bytes_atomic(
+            PENDING_FILE,
+            PENDING_TEMP_FILE,
+            serde_json::to_string(changes)?.as_bytes(),
+        )
+    }
+
+    fn read_pending(&self) -> anyhow::Result<Vec<PendingChange>> {
+        if !self.path(PENDING_FILE)?.is_file() {
+            return Ok(Vec::new());
+        }
+
+        Ok(serde_json::from_slice(&self.read_bytes(PENDING_FILE)?)?)
+    }
+
+    fn purge(&self, overwrite: bool) -> anyhow::Result<()> {
+        for file_name in VAULT_FILES {
+            self.remove(file_name, overwrite)?;
+        }
+        Ok(())
+    }
 }
 
-pub fn read_password_hash() -> anyhow::Result<EncryptedValue> {
-    let bytes = read_bytes(PASSWORD_HASH_FILE)?;
-    Ok(EncryptedValue::from(String::from_utf8(bytes)?))
+/// Open `path` for writing, truncating it, readable by the current user only.
+/// Files created by earlier versions lose the permissions they had for others.
+fn create_private(path: &Path) -> anyhow::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
 }
 
-pub fn write_sk(sk: &[u8; 32], device_pass_hash: &str) -> anyhow::Result<()> {
-    let enc = encrypt_data(device_pass_hash, sk)?;
-    write_bytes(PRIVATE_KEY_FILE, enc)
+/// Upgrade app data stored in `version` of the format to the next one. Fields
+/// added to [`Password`](passphrasex_common::model::password::Password) with a
+/// serde default need no migration.
+fn migrate_app_data(version: u64, data: Value) -> anyhow::Result<Value> {
+    match version {
+        0 => Ok(json!({"version": 1, "credentials": data})),
+        _ => Err(format_err!(
+            "No migration from app data version {}",
+            version
+        )),
+    }
 }
 
-pub fn read_sk(device_pass_hash: &str) -> anyhow::Result<[u8; 32]> {
-    let bytes = read_bytes(PRIVATE_KEY_FILE)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let dec = decrypt_data(device_pass_hash, bytes)?;
+    #[cfg(unix)]
+    #[test]
+    fn vault_files_are_private() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
 
-    let mut content: [u8; 32] = [0; 32];
-    content.copy_from_slice(&dec[..32]);
-    Ok(content)
-}
+        let dir = tempfile::tempdir()?;
+        // Left readable by others by an earlier version
+        fs::write(dir.path().join(SEED_PHRASE_FILE), b"")?;
+        fs::set_permissions(
+            dir.path().join(SEED_PHRASE_FILE),
+            fs::Permissions::from_mode(0o644),
+        )?;
 
-pub fn write_app_data(data: &CredentialsMap) -> anyhow::Result<()> {
-    write_bytes(DATA_FILE, serde_json::to_string(&data)?.as_bytes().to_vec())
-}
+        let storage = FileStorage::in_dir(dir.path());
+        storage.write_password_hash("$argon2id$hash")?;
+        storage.write_encrypted_sk(&[1; 48])?;
+        storage.write_encrypted_seed(&[2; 48])?;
+        storage.write_app_data(&CredentialsMap::new())?;
+        storage.write_app_data(&CredentialsMap::new())?;
+
+        for file_name in [
+            PASSWORD_HASH_FILE,
+            PRIVATE_KEY_FILE,
+            SEED_PHRASE_FILE,
+            DATA_FILE,
+            DATA_BACKUP_FILE,
+        ] {
+            let mode = fs::metadata(dir.path().join(file_name))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{file_name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_app_data_is_restored_from_the_backup() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::in_dir(dir.path());
+        let mut data = CredentialsMap::new();
+        data.insert("example.com".to_string(), Default::default());
+        storage.write_app_data(&data)?;
+        storage.write_app_data(&CredentialsMap::new())?;
+
+        // Cut short by a crash or a manual edit
+        fs::write(dir.path().join(DATA_FILE), b"{\"version\": 1, \"creden")?;
+        let err = storage.read_app_data().unwrap_err();
+        let corrupted = err.downcast_ref::<CorruptedAppData>().unwrap();
+        assert_eq!(corrupted.path, dir.path().join(DATA_FILE));
+        assert_eq!(
+            corrupted.backup.as_deref(),
+            Some(dir.path().join(DATA_BACKUP_FILE).as_path())
+        );
+
+        storage.restore_app_data_backup()?;
+        assert!(storage.read_app_data()?.contains_key("example.com"));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_app_data_is_kept_without_a_readable_backup() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::in_dir(dir.path());
+        fs::write(dir.path().join(DATA_FILE), b"not json")?;
+
+        let err = storage.read_app_data().unwrap_err();
+        assert!(err
+            .downcast_ref::<CorruptedAppData>()
+            .unwrap()
+            .backup
+            .is_none());
+
+        fs::write(dir.path().join(DATA_BACKUP_FILE), b"not json either")?;
+        assert!(storage.restore_app_data_backup().is_err());
+        // Never replaced by an empty vault
+        assert_eq!(fs::read(dir.path().join(DATA_FILE))?, b"not json");
+        Ok(())
+    }
+
+    #[test]
+    fn old_app_data_is_migrated_and_rewritten() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::in_dir(dir.path());
+        // Version 0: a bare credentials map
+        fs::write(dir.path().join(DATA_FILE), br#"{"example.com": {}}"#)?;
+
+        assert!(storage.read_app_data()?.contains_key("example.com"));
+
+        let stored: Value = serde_json::from_slice(&fs::read(dir.path().join(DATA_FILE))?)?;
+        assert_eq!(
+            stored,
+            json!({"version": 1, "credentials": {"example.com": {}}})
+        );
+        // The old file is kept as the backup
+        let backup: Value = serde_json::from_slice(&fs::read(dir.path().join(DATA_BACKUP_FILE))?)?;
+        assert_eq!(backup, json!({"example.com": {}}));
+        Ok(())
+    }
+
+    #[test]
+    fn newer_app_data_is_refused() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let storage = FileStorage::in_dir(dir.path());
+        let newer = json!({"version": APP_DATA_VERSION + 1, "credentials": {}});
+        fs::write(dir.path().join(DATA_FILE), newer.to_string())?;
 
-pub fn read_app_data() -> anyhow::Result<CredentialsMap> {
-    let bytes = read_bytes(DATA_FILE)?;
-    let data: CredentialsMap = serde_json::from_slice(&bytes)?;
-    Ok(data)
+        let err = storage.read_app_data().unwrap_err();
+        assert!(err.to_string().contains("update PassPhraseX"));
+        // Nothing to recover, so no restore or download is offered
+        assert!(err.downcast_ref::<CorruptedAppData>().is_none());
+        assert_eq!(
+            err.downcast_ref::<NewerAppData>().map(|err| err.version),
+            Some(APP_DATA_VERSION + 1)
+        );
+        // Left as is for the newer version
+        assert_eq!(
+            fs::read(dir.path().join(DATA_FILE))?,
+            newer.to_string().as_bytes()
+        );
+        Ok(())
+    }
 }
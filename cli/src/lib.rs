@@ -1,19 +1,39 @@
-mod api;
-mod file;
+pub mod api;
+pub mod config;
+pub mod file;
+pub mod storage;
 
 use anyhow::format_err;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
 use std::string::String;
+use std::time::SystemTime;
 
 use app_dirs2::AppInfo;
 
-use crate::file::{
-    read_app_data, read_password_hash, read_sk, write_app_data, write_password_hash, write_sk,
+use crate::config::Config;
+use crate::storage::{default_storage, PendingChange, VaultStorage};
+pub use api::check_health;
+use api::{api_url, new_idempotency_key, Api, ApiError, Passwords};
+use passphrasex_common::crypto::asymmetric::{verify_detached, KeyPair, SeedPhrase};
+use passphrasex_common::crypto::common::EncryptedValue;
+use passphrasex_common::crypto::symmetric::{
+    decrypt_stream, encrypt_stream, generate_salt, hash_password, password_hash_key,
+    verify_password,
 };
-use api::Api;
-use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
-use passphrasex_common::crypto::symmetric::{encrypt_data, generate_salt, hash, verify_password};
-use passphrasex_common::model::password::Password;
+use passphrasex_common::generator::estimate_entropy;
+use passphrasex_common::model::password::{Password, PasswordPatch};
+use passphrasex_common::model::share::{SharedContent, SharedCredential};
+use passphrasex_common::model::user::{SignedExport, User, UserExport};
+use passphrasex_common::site::{matching_sites, normalize_site, site_host};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Passwords with fewer estimated bits of entropy count as weak
+const WEAK_PASSWORD_BITS: f64 = 50.0;
 
 pub const APP_INFO: AppInfo = AppInfo {
     name: "PassPhraseX",
@@ -27,52 +47,168 @@ pub struct App {
     key_pair: KeyPair,
     credentials: CredentialsMap,
     api: Api,
+    storage: Box<dyn VaultStorage>,
+    /// Validate and print changes instead of making them, see [`App::with_dry_run`]
+    dry_run: bool,
+}
+
+/// How an account's key pair is derived from its seed phrase, besides the phrase itself
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyDerivation<'a> {
+    /// BIP39 passphrase on top of the seed phrase
+    pub passphrase: Option<&'a str>,
+    /// Account index, for separate vaults (e.g. personal and work) from the same
+    /// seed phrase. `None` is the vault of the root key.
+    pub account: Option<u32>,
+}
+
+/// Optional details of a new account besides its key
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AccountDetails<'a> {
+    /// Contact address, for account recovery
+    pub email: Option<&'a str>,
+    /// Name to show for the account instead of its public key
+    pub display_name: Option<&'a str>,
+}
+
+impl KeyDerivation<'_> {
+    fn key_pair(&self, seed_phrase: SeedPhrase) -> anyhow::Result<KeyPair> {
+        match self.account {
+            Some(index) => {
+                KeyPair::from_seed_phrase_with_account(seed_phrase, self.passphrase, index)
+            }
+            None => KeyPair::try_new(seed_phrase, self.passphrase),
+        }
+    }
 }
 
-pub async fn register(device_pass: &str) -> anyhow::Result<SeedPhrase> {
+/// Create an account from a new seed phrase, derived as `derivation` says.
+/// Neither the passphrase nor the account index are stored anywhere, logging
+/// in on another device takes them along with the seed phrase.
+pub async fn register(
+    device_pass: &str,
+    derivation: KeyDerivation<'_>,
+    details: AccountDetails<'_>,
+) -> anyhow::Result<SeedPhrase> {
+    register_with(
+        default_storage().as_ref(),
+        device_pass,
+        derivation,
+        details,
+        &api_url(),
+    )
+    .await
+}
+
+/// [`register`] into `storage`, against the API server at `api_url`
+pub async fn register_with(
+    storage: &dyn VaultStorage,
+    device_pass: &str,
+    derivation: KeyDerivation<'_>,
+    details: AccountDetails<'_>,
+    api_url: &str,
+) -> anyhow::Result<SeedPhrase> {
     let salt = generate_salt()?;
-    let pass_hash = hash(device_pass, &salt)?;
+    let pass_hash = hash_password(device_pass, &salt)?;
+    let key = password_hash_key(&pass_hash)?;
 
     let seed_phrase = SeedPhrase::new();
-    let key_pair = KeyPair::new(seed_phrase.clone());
+    let key_pair = derivation.key_pair(seed_phrase.clone())?;
 
-    let api = Api::new(key_pair.clone());
+    let api = Api::with_base_url(key_pair.clone(), api_url)?;
 
-    write_password_hash(&pass_hash)?;
+    storage.write_password_hash(&pass_hash)?;
 
-    let enc = encrypt_data(&pass_hash.cipher, key_pair.private_key.as_bytes())?;
+    storage.write_sk(key_pair.private_key.as_bytes(), &key)?;
 
-    let mut sk_bytes: [u8; 32] = [0; 32];
-    sk_bytes.copy_from_slice(enc.as_slice());
-    write_sk(key_pair.private_key.as_bytes(), &pass_hash.cipher)?;
+    storage.write_seed(&seed_phrase, &key)?;
 
-    write_app_data(&HashMap::new())?;
+    storage.write_app_data(&HashMap::new())?;
+    storage.write_etag(None)?;
 
-    api.create_user(key_pair.get_pk()).await?;
+    let user = User {
+        email: details.email.map(str::to_string),
+        display_name: details.display_name.map(str::to_string),
+        signing_key: Some(key_pair.get_signing_pk()),
+        ..User::new(key_pair.get_pk())
+    };
+    match api.create_user(&user).await {
+        // The key was already registered, e.g. by a previous attempt whose response was lost
+        Ok(()) | Err(ApiError::UserAlreadyExists) => Ok(seed_phrase),
+        Err(err) => Err(format_err!("Failed to register account: {}", err)),
+    }
+}
 
-    Ok(seed_phrase)
+/// Set this device up for the account of `seed_phrase`, derived as
+/// `derivation` says, returning how many passwords it has. A wrong passphrase
+/// or account index isn't an error, it opens another account, most likely empty.
+pub async fn auth_device(
+    seed_phrase: &str,
+    derivation: KeyDerivation<'_>,
+    device_pass: &str,
+) -> anyhow::Result<usize> {
+    auth_device_with(
+        default_storage().as_ref(),
+        seed_phrase,
+        derivation,
+        device_pass,
+        &api_url(),
+    )
+    .await
 }
 
-pub async fn auth_device(seed_phrase: &str, device_pass: &str) -> anyhow::Result<()> {
+/// [`auth_device`] into `storage`, against the API server at `api_url`
+pub async fn auth_device_with(
+    storage: &dyn VaultStorage,
+    seed_phrase: &str,
+    derivation: KeyDerivation<'_>,
+    device_pass: &str,
+    api_url: &str,
+) -> anyhow::Result<usize> {
     let salt = generate_salt()?;
-    let pass_hash = hash(device_pass, &salt)?;
+    let pass_hash = hash_password(device_pass, &salt)?;
+    let key = password_hash_key(&pass_hash)?;
 
-    let seed_phrase = SeedPhrase::from(seed_phrase.to_string());
-    let key_pair = KeyPair::new(seed_phrase.clone());
+    let seed_phrase = SeedPhrase::validate(seed_phrase)?;
+    let key_pair = derivation.key_pair(seed_phrase.clone())?;
 
-    let api = Api::new(key_pair.clone());
+    let api = Api::with_base_url(key_pair.clone(), api_url)?;
 
-    write_password_hash(&pass_hash)?;
+    storage.write_password_hash(&pass_hash)?;
 
-    write_sk(key_pair.private_key.as_bytes(), &pass_hash.cipher)?;
+    storage.write_sk(key_pair.private_key.as_bytes(), &key)?;
 
-    sync_with_api(api, key_pair.clone()).await?;
+    storage.write_seed(&seed_phrase, &key)?;
 
-    Ok(())
+    // Whatever was synced before belongs to another vault
+    storage.write_etag(None)?;
+
+    let credentials = sync_with_api(storage, &api, key_pair.clone()).await?;
+
+    Ok(credentials.values().map(|passwords| passwords.len()).sum())
 }
 
-async fn sync_with_api(api: Api, key_pair: KeyPair) -> anyhow::Result<CredentialsMap> {
-    let passwords = api.get_passwords(key_pair.get_pk()).await?;
+async fn sync_with_api(
+    storage: &dyn VaultStorage,
+    api: &Api,
+    key_pair: KeyPair,
+) -> anyhow::Result<CredentialsMap> {
+    // The stored ETag describes the local credentials, only send it when they can be read
+    let cached = match (storage.read_etag().ok().flatten(), storage.read_app_data()) {
+        (Some(etag), Ok(credentials)) => Some((etag, credentials)),
+        _ => None,
+    };
+    let etag = cached.as_ref().map(|(etag, _)| etag.as_str());
+
+    let (passwords, etag) = match api.get_passwords(key_pair.get_pk(), etag).await? {
+        Passwords::Modified { passwords, etag } => (passwords, etag),
+        Passwords::NotModified => {
+            return cached
+                .map(|(_, credentials)| credentials)
+                .ok_or(format_err!("Unexpected Not Modified response from API"));
+        }
+    };
+
     let mut credentials: CredentialsMap = HashMap::new();
 
     for password in passwords {
@@ -82,91 +218,699 @@ async fn sync_with_api(api: Api, key_pair: KeyPair) -> anyhow::Result<Credential
             .insert(password._id.clone(), password.clone());
     }
 
-    write_app_data(&credentials)?;
+    storage.write_app_data(&credentials)?;
+    storage.write_etag(etag.as_deref())?;
 
     Ok(credentials)
 }
 
+/// Send the changes queued while the API was unreachable, oldest first,
+/// returning how many were sent. Each is dropped from the queue once sent, and
+/// the first one that fails stops the replay, keeping it and the rest queued.
+async fn replay_pending(
+    storage: &dyn VaultStorage,
+    api: &Api,
+    user_id: &str,
+) -> anyhow::Result<usize> {
+    let pending = storage.read_pending()?;
+
+    for (sent, change) in pending.iter().enumerate() {
+        send_change(api, user_id, change).await?;
+        storage.write_pending(&pending[sent + 1..])?;
+    }
+
+    Ok(pending.len())
+}
+
+async fn send_change(api: &Api, user_id: &str, change: &PendingChange) -> anyhow::Result<()> {
+    let user_id = user_id.to_string();
+    let result = match change.clone() {
+        PendingChange::Add {
+            password,
+            idempotency_key,
+        } => api
+            .add_password(user_id, password, idempotency_key.as_deref())
+            .await
+            .map(|_| ()),
+        PendingChange::Edit {
+            password_id,
+            password,
+        } => api.edit_password(user_id, password_id, password).await,
+        PendingChange::Delete { password_id } => api.delete_password(user_id, password_id).await,
+    };
+
+    let Err(err) = result else {
+        return Ok(());
+    };
+
+    // Already applied, by an attempt whose response was lost
+    let applied = matches!(
+        (change, err.downcast_ref::<ApiError>()),
+        (PendingChange::Add { .. }, Some(ApiError::Conflict(_)))
+            | (PendingChange::Delete { .. }, Some(ApiError::NotFound(_)))
+    );
+    if applied {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Whether `err` is the API being unreachable, rather than refusing a request
+fn is_offline(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ApiError>(), Some(ApiError::Network(_)))
+}
+
+/// Unlock the key pair of the vault in `storage` with the device password
+fn unlock(storage: &dyn VaultStorage, device_pass: &str) -> anyhow::Result<KeyPair> {
+    let pass_hash = storage.read_password_hash()?;
+    verify_password(device_pass, &pass_hash)?;
+
+    let private_key = storage.read_sk(&password_hash_key(&pass_hash)?)?;
+    Ok(KeyPair::from_sk(private_key))
+}
+
+/// Which way [`App::sync`] moves passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Push, then pull
+    Both,
+    /// Replace the local passwords with the API's, leaving the queued changes
+    /// for a later sync
+    Pull,
+    /// Send the changes queued while the API was unreachable
+    Push,
+}
+
+/// What `status` reports about this device
+pub struct Status {
+    pub registered: bool,
+    /// Only known when the vault was unlocked
+    pub public_key: Option<String>,
+    pub server_url: String,
+}
+
+/// Download every password of this device's vault again, replacing the local
+/// credentials (e.g. when they're corrupted), returning how many there are
+pub async fn pull_app_data(device_pass: &str) -> anyhow::Result<usize> {
+    let storage = default_storage();
+    let key_pair = unlock(storage.as_ref(), device_pass)?;
+    let api = Api::with_base_url(key_pair.clone(), &api_url())?;
+
+    // Without an ETag the API sends every password
+    storage.write_etag(None)?;
+    let credentials = sync_with_api(storage.as_ref(), &api, key_pair).await?;
+
+    Ok(credentials.values().map(|passwords| passwords.len()).sum())
+}
+
+/// The seed phrase of this device's vault, given its device password
+pub fn show_seed(device_pass: &str) -> anyhow::Result<SeedPhrase> {
+    show_seed_with(default_storage().as_ref(), device_pass)
+}
+
+/// [`show_seed`] from `storage`
+pub fn show_seed_with(storage: &dyn VaultStorage, device_pass: &str) -> anyhow::Result<SeedPhrase> {
+    let pass_hash = storage.read_password_hash()?;
+    verify_password(device_pass, &pass_hash)?;
+
+    storage
+        .read_seed(&password_hash_key(&pass_hash)?)
+        .map_err(|_| {
+            format_err!(
+                "No seed phrase stored for this vault, it was created before they were kept"
+            )
+        })
+}
+
+/// Read an export saved by [`App::export`], decrypting it with `key` when it
+/// was encrypted, checking that it was signed by the owner of the `signer_pk`
+/// signing key and wasn't changed since. Nothing in it should be trusted when
+/// this fails.
+pub fn verify_export(
+    path: &Path,
+    signer_pk: &str,
+    key: Option<&str>,
+) -> anyhow::Result<UserExport> {
+    let signed: SignedExport = match key {
+        Some(key) => {
+            let mut json = Vec::new();
+            decrypt_stream(key, &mut fs::File::open(path)?, &mut json)?;
+            serde_json::from_slice(&json)?
+        }
+        None => serde_json::from_str(&fs::read_to_string(path)?)?,
+    };
+    if signed.signer != signer_pk {
+        return Err(format_err!(
+            "Export is signed by {}, not {}",
+            signed.signer,
+            signer_pk
+        ));
+    }
+
+    verify_detached(signer_pk, signed.export.as_bytes(), &signed.signature)
+        .map_err(|err| format_err!("Export failed verification: {}", err))?;
+
+    Ok(serde_json::from_str(&signed.export)?)
+}
+
+/// Security overview of the stored passwords, see [`App::health_report`]
+#[derive(Debug, Default)]
+pub struct HealthReport {
+    /// How many passwords were checked
+    pub total: usize,
+    /// Site and username of the credentials sharing a password, one group per password
+    pub reused: Vec<Vec<(String, String)>>,
+    /// Site, username and estimated bits of entropy of weak passwords, weakest first
+    pub weak: Vec<(String, String, f64)>,
+    /// Site, username and age in days (`None` when unknown) of old passwords, oldest first
+    pub expiring: Vec<(String, String, Option<u64>)>,
+}
+
+/// Counts of the vault, see [`App::stats`]
+pub struct VaultStats {
+    /// Passwords stored on the server
+    pub total: u64,
+    /// Sites with passwords stored on the server
+    pub sites: usize,
+    /// Passwords on this device that are also used by another credential
+    pub reused: usize,
+}
+
+/// What `App::merge_sites` did with the passwords it was asked to fold
+#[derive(Default)]
+pub struct MergeReport {
+    /// Passwords moved to the canonical site
+    pub moved: usize,
+    /// Passwords dropped because the canonical site has the same one
+    pub duplicates: usize,
+    /// Site and username of passwords left in place because the canonical
+    /// site has a different password for the same username
+    pub conflicts: Vec<(String, String)>,
+}
+
+/// Remove the vault and settings from this device, overwriting the files with
+/// zeros first when `overwrite`. The account and its passwords stay on the
+/// server, logging in with the seed phrase brings them back.
+pub fn purge(overwrite: bool) -> anyhow::Result<()> {
+    default_storage().purge(overwrite)?;
+
+    let config = Config::path()?;
+    if config.is_file() {
+        fs::remove_file(config)?;
+    }
+    Ok(())
+}
+
+/// Check whether this device has a vault and, given the device password,
+/// which public key it belongs to. Doesn't contact the API.
+pub fn status(device_pass: Option<&str>) -> anyhow::Result<Status> {
+    let storage = default_storage();
+    let server_url = api_url();
+    if !storage.exists()? {
+        return Ok(Status {
+            registered: false,
+            public_key: None,
+            server_url,
+        });
+    }
+
+    let public_key = match device_pass {
+        Some(device_pass) => Some(unlock(storage.as_ref(), device_pass)?.get_pk()),
+        None => None,
+    };
+
+    Ok(Status {
+        registered: true,
+        public_key,
+        server_url,
+    })
+}
+
+/// Check `device_pass` against the hash stored on this device, without
+/// decrypting the private key or contacting the API
+pub fn verify_device_password(device_pass: &str) -> anyhow::Result<()> {
+    let storage = default_storage();
+    if !storage.exists()? {
+        return Err(format_err!("No vault on this device"));
+    }
+
+    verify_password(device_pass, &storage.read_password_hash()?)
+}
+
 impl App {
+    /// Unlock the vault stored on this device and sync it with the API
     pub async fn new(device_pass: &str) -> anyhow::Result<App> {
-        let pass_hash = read_password_hash()?;
-        verify_password(device_pass, &pass_hash.cipher, &pass_hash.nonce)?;
-
-        let private_key = read_sk(&pass_hash.cipher)?;
-        let key_pair = KeyPair::from_sk(private_key);
+        Self::new_with(default_storage(), device_pass, &api_url()).await
+    }
 
-        let api = Api::new(key_pair.clone());
+    /// [`App::new`] with the vault in `storage`, against the API server at `api_url`
+    pub async fn new_with(
+        storage: Box<dyn VaultStorage>,
+        device_pass: &str,
+        api_url: &str,
+    ) -> anyhow::Result<App> {
+        let key_pair = unlock(storage.as_ref(), device_pass)?;
+        let api = Api::with_base_url(key_pair.clone(), api_url)?;
 
-        let credentials = sync_with_api(api, key_pair.clone()).await.or_else(|_| {
-            println!("Failed to sync with API, using local data");
-            read_app_data()
-        })?;
+        // Queued changes go first, the pull would hide them until they're sent
+        let synced = match replay_pending(storage.as_ref(), &api, &key_pair.get_pk()).await {
+            Ok(_) => sync_with_api(storage.as_ref(), &api, key_pair.clone()).await,
+            Err(err) => Err(err),
+        };
+        let credentials = match synced {
+            Ok(credentials) => credentials,
+            Err(_) => {
+                eprintln!("Failed to sync with API, using local data");
+                storage.read_app_data()?
+            }
+        };
 
         Ok(App {
-            key_pair: key_pair.clone(),
+            key_pair,
             credentials,
-            api: Api::new(key_pair),
+            api,
+            storage,
+            dry_run: false,
         })
     }
 
+    /// Unlock the vault stored on this device with the credentials last synced,
+    /// without syncing it first
+    pub fn open(device_pass: &str) -> anyhow::Result<App> {
+        let storage = default_storage();
+        let key_pair = unlock(storage.as_ref(), device_pass)?;
+        let api = Api::with_base_url(key_pair.clone(), &api_url())?;
+        let credentials = storage.read_app_data()?;
+
+        Ok(Self::from_parts(key_pair, credentials, api, storage))
+    }
+
+    /// Build an app from an already unlocked key pair, e.g. when embedding the
+    /// vault in another tool. Nothing is read from storage or the API.
+    pub fn from_parts(
+        key_pair: KeyPair,
+        credentials: CredentialsMap,
+        api: Api,
+        storage: Box<dyn VaultStorage>,
+    ) -> App {
+        App {
+            key_pair,
+            credentials,
+            api,
+            storage,
+            dry_run: false,
+        }
+    }
+
+    /// In dry-run mode `add`, `edit` and `delete` validate their arguments and
+    /// print what they would do, without touching the API or the stored data
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn public_key(&self) -> String {
+        self.key_pair.get_pk()
+    }
+
+    /// Sites with stored passwords, sorted
+    pub fn sites(&self) -> Vec<String> {
+        let mut sites: Vec<String> = self.credentials.keys().cloned().collect();
+        sites.sort();
+        sites
+    }
+
+    /// Decrypted copies of every stored password
+    pub fn list(&self) -> Vec<Password> {
+        self.credentials
+            .values()
+            .flat_map(|passwords| passwords.values())
+            .map(|password| password.decrypt(&self.key_pair))
+            .collect()
+    }
+
+    /// Sync the local credentials with the API in the given direction,
+    /// returning how many changes and passwords were pushed
+    ///
+    /// With `restore_missing` the local passwords the API doesn't have are
+    /// uploaded too, before pulling. Only for when the API lost them: passwords
+    /// deleted on other devices come back as well.
+    pub async fn sync(
+        &mut self,
+        direction: SyncDirection,
+        restore_missing: bool,
+    ) -> anyhow::Result<usize> {
+        let mut pushed = 0;
+        if direction != SyncDirection::Pull {
+            pushed +=
+                replay_pending(self.storage.as_ref(), &self.api, &self.key_pair.get_pk()).await?;
+            if restore_missing {
+                pushed += self.restore_missing().await?;
+            }
+        }
+
+        if direction != SyncDirection::Push {
+            self.credentials =
+                sync_with_api(self.storage.as_ref(), &self.api, self.key_pair.clone()).await?;
+        }
+
+        Ok(pushed)
+    }
+
+    /// Upload the local passwords the API doesn't have, e.g. ones it lost or
+    /// ones from a vault synced with another server
+    async fn restore_missing(&mut self) -> anyhow::Result<usize> {
+        let user_id = self.key_pair.get_pk();
+        let remote = match self.api.get_passwords(user_id.clone(), None).await? {
+            Passwords::Modified { passwords, .. } => passwords,
+            Passwords::NotModified => Vec::new(),
+        };
+        let remote_ids: HashSet<String> = remote.into_iter().map(|password| password._id).collect();
+
+        let missing: Vec<Password> = self
+            .credentials
+            .values()
+            .flat_map(|passwords| passwords.values())
+            .filter(|password| !remote_ids.contains(&password._id))
+            .cloned()
+            .collect();
+
+        let mut pushed = 0;
+        let mut result = Ok(());
+        for password in missing {
+            let idempotency_key = new_idempotency_key();
+            match self
+                .api
+                .add_password(user_id.clone(), password, Some(&idempotency_key))
+                .await
+            {
+                Ok(password) => {
+                    // The server's copy carries the timestamps
+                    self.credentials
+                        .entry(password.site.clone())
+                        .or_default()
+                        .insert(password._id.clone(), password);
+                    pushed += 1;
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
+
+        result.map(|_| pushed)
+    }
+
+    /// Save everything the API stores for this account to `path`, which must not
+    /// exist yet, signed so that whoever gets the file can check it with
+    /// [`verify_export`]. The passwords in it stay encrypted with the account's key.
+    /// With a `key` (from `generate_key`) the whole file is encrypted with it too,
+    /// hiding sites and usernames, and is written a chunk at a time.
+    pub async fn export(&self, path: &Path, key: Option<&str>) -> anyhow::Result<()> {
+        let export = self.api.export_user(self.key_pair.get_pk()).await?;
+        let signed = SignedExport {
+            signature: self.key_pair.sign_detached(export.as_bytes()),
+            signer: self.key_pair.get_signing_pk(),
+            export,
+        };
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)?;
+        let json = serde_json::to_string(&signed)?;
+        match key {
+            Some(key) => encrypt_stream(key, &mut json.as_bytes(), &mut file)?,
+            None => file.write_all(json.as_bytes())?,
+        }
+
+        Ok(())
+    }
+
+    /// Base64 Ed25519 public key the exports of this account are signed with
+    pub fn signing_key(&self) -> String {
+        self.key_pair.get_signing_pk()
+    }
+
+    /// Share the password of `site` for `username` with the owners of the
+    /// `recipient_pks` public keys, encrypted so that only each of them can read
+    /// their copy. Sharing it again adds recipients.
+    pub async fn share(
+        &self,
+        site: String,
+        username: String,
+        recipient_pks: &[String],
+    ) -> anyhow::Result<()> {
+        let site = self.stored_site(&site);
+        let password_id = Password::id_for(&self.key_pair, &site, &username)?;
+        let password = self
+            .credentials
+            .get(&site)
+            .and_then(|passwords| passwords.get(&password_id))
+            .ok_or(ApiError::NotFound(format!("password for {}", site)))?
+            .decrypt(&self.key_pair);
+
+        let content = SharedContent {
+            site: password.site,
+            username: password.username,
+            password: password.password,
+        };
+        let shared = SharedCredential::new(&self.key_pair, recipient_pks, &content)?;
+        self.api
+            .share_credential(self.key_pair.get_pk(), shared)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stop sharing the password of `site` for `username` with the owner of the
+    /// `recipient_pk` public key
+    pub async fn revoke_share(
+        &self,
+        site: String,
+        username: String,
+        recipient_pk: String,
+    ) -> anyhow::Result<()> {
+        // Shares have the id of the password they share
+        let site = self.stored_site(&site);
+        let share_id = Password::id_for(&self.key_pair, &site, &username)?;
+        self.api
+            .revoke_share(self.key_pair.get_pk(), share_id, recipient_pk)
+            .await
+    }
+
+    /// The passwords other users shared with this account, along with the
+    /// public key of who shared each. Shares that fail to decrypt are left out.
+    pub async fn shared_with_me(&self) -> anyhow::Result<Vec<(String, SharedContent)>> {
+        let shares = self.api.get_received_shares(self.key_pair.get_pk()).await?;
+
+        Ok(shares
+            .into_iter()
+            .filter_map(|shared| {
+                let content = shared.decrypt(&self.key_pair).ok()?;
+                Some((shared.sender_id, content))
+            })
+            .collect())
+    }
+
+    /// Store a new password, in `folder` when given. When `site` already has one
+    /// for `username` (compared like [`App::get`] does) it's replaced if
+    /// `overwrite`, like [`App::edit`] does (only the password changes, not the
+    /// folder), or refused otherwise.
     pub async fn add(
         &mut self,
         site: String,
         username: String,
         password: String,
+        folder: Option<String>,
+        overwrite: bool,
     ) -> anyhow::Result<()> {
-        self.verify_credentials_dont_exist(&site, &username)?;
+        let site = self.stored_site(&site);
+        if let Some(stored) = self.stored_username(&site, &username) {
+            if !overwrite {
+                return Err(format_err!(
+                    "Credentials already exist, use edit or --force to replace them"
+                ));
+            }
+            return self.edit(site, stored, password).await;
+        }
 
         let user_id = self.key_pair.get_pk();
 
-        let password_id = self.key_pair.hash(&format!("{}{}", site, username))?;
+        let password =
+            Password::new(&self.key_pair, site.clone(), username, password)?.with_folder(folder);
+        if self.skip_in_dry_run("add", &site, &password.username) {
+            return Ok(());
+        }
 
-        let password = Password {
-            _id: password_id.clone(),
-            user_id: user_id.clone(),
-            site: site.clone(),
-            username,
-            password,
-        };
+        let password_id = password._id.clone();
         let password = password.encrypt(&self.key_pair);
 
-        self.api.add_password(user_id, password.clone()).await?;
+        // Keep the server's copy, which carries the timestamps
+        let idempotency_key = new_idempotency_key();
+        let password = match self
+            .api
+            .add_password(user_id, password.clone(), Some(&idempotency_key))
+            .await
+        {
+            Ok(stored) => stored,
+            Err(err) => {
+                self.queue_if_offline(
+                    err,
+                    PendingChange::Add {
+                        password: password.clone(),
+                        idempotency_key: Some(idempotency_key),
+                    },
+                )?;
+                password
+            }
+        };
 
         self.credentials
             .entry(site)
             .or_insert(HashMap::new())
             .insert(password_id, password);
 
-        write_app_data(&self.credentials).expect("Failed to save app data to file");
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
         Ok(())
     }
 
+    /// Passwords last changed more than `max_age_days` ago, oldest first, with
+    /// their age in days. Passwords without timestamps have an unknown (`None`)
+    /// age and come first.
+    pub fn expiring(&self, max_age_days: u64) -> Vec<(Password, Option<u64>)> {
+        let now = now_secs();
+
+        let mut result: Vec<(Password, Option<u64>)> = self
+            .list()
+            .into_iter()
+            .map(|password| {
+                let age = age_days(&password, now);
+                (password, age)
+            })
+            .filter(|(_, age)| is_expiring(*age, max_age_days))
+            .collect();
+
+        result.sort_by_key(|(_, age)| Reverse(age.unwrap_or(u64::MAX)));
+        result
+    }
+
+    /// Count the passwords and sites on the server, without downloading them,
+    /// and the reused passwords on this device
+    pub async fn stats(&self) -> anyhow::Result<VaultStats> {
+        let count = self.api.count_passwords(self.key_pair.get_pk()).await?;
+
+        let mut uses: HashMap<String, usize> = HashMap::new();
+        for password in self.list() {
+            *uses.entry(password.password).or_default() += 1;
+        }
+
+        Ok(VaultStats {
+            total: count.total,
+            sites: count.sites.len(),
+            reused: uses.values().filter(|uses| **uses > 1).sum(),
+        })
+    }
+
+    /// Reused, weak and old passwords, found in a single pass over the
+    /// decrypted credentials. Old ones are those [`App::expiring`] returns.
+    pub fn health_report(&self, max_age_days: u64) -> HealthReport {
+        let now = now_secs();
+        let mut report = HealthReport::default();
+        let mut by_password: HashMap<String, Vec<(String, String)>> = HashMap::new();
+
+        for password in self.list() {
+            report.total += 1;
+
+            let bits = estimate_entropy(&password.password);
+            if bits < WEAK_PASSWORD_BITS {
+                report
+                    .weak
+                    .push((password.site.clone(), password.username.clone(), bits));
+            }
+
+            let age = age_days(&password, now);
+            if is_expiring(age, max_age_days) {
+                report
+                    .expiring
+                    .push((password.site.clone(), password.username.clone(), age));
+            }
+
+            by_password
+                .entry(password.password)
+                .or_default()
+                .push((password.site, password.username));
+        }
+
+        report.reused = by_password
+            .into_values()
+            .filter(|credentials| credentials.len() > 1)
+            .map(|mut credentials| {
+                credentials.sort();
+                credentials
+            })
+            .collect();
+        report.reused.sort();
+        report.weak.sort_by(|a, b| {
+            a.2.total_cmp(&b.2)
+                .then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1)))
+        });
+        report
+            .expiring
+            .sort_by_key(|(_, _, age)| Reverse(age.unwrap_or(u64::MAX)));
+        report
+    }
+
+    /// Get the passwords stored for a site, ignoring scheme and port.
+    /// Unless `strict`, passwords of other hosts under the same domain match too.
+    /// Favorites first, then sorted by site and username, fails with
+    /// [`ApiError::NotFound`] when there are none.
     pub async fn get(
         &mut self,
         site: String,
         username: Option<String>,
+        strict: bool,
     ) -> anyhow::Result<Vec<Password>> {
-        match self.credentials.get(&site) {
-            Some(passwords) => match username {
-                Some(username) => {
-                    let id = self.key_pair.hash(&format!("{}{}", site, username))?;
-                    let password = passwords
-                        .get(&id)
-                        .ok_or(format_err!("Password not found"))?;
-
-                    Ok(vec![password.decrypt(&self.key_pair)])
-                }
-                None => {
-                    let result = passwords
-                        .iter()
-                        .map(|(_, password)| password.decrypt(&self.key_pair))
-                        .collect();
+        let sites = matching_sites(self.credentials.keys(), &site, strict);
+        if sites.is_empty() {
+            return Err(ApiError::NotFound(format!("passwords for {}", site)).into());
+        }
 
-                    Ok(result)
-                }
-            },
-            None => Err(format_err!("No passwords found")),
+        // Compared decrypted rather than by id, which differs for usernames
+        // saved with other casing or whitespace
+        let mut result: Vec<Password> = sites
+            .into_iter()
+            .flat_map(|site| self.credentials[site].values())
+            .map(|password| password.decrypt(&self.key_pair))
+            .filter(|password| {
+                username
+                    .as_ref()
+                    .is_none_or(|username| same_username(&password.username, username))
+            })
+            .collect();
+
+        if result.is_empty() {
+            return Err(ApiError::NotFound(format!("password for {}", site)).into());
         }
+
+        result.sort_by(|a, b| {
+            (Reverse(a.favorite), &a.site, &a.username).cmp(&(
+                Reverse(b.favorite),
+                &b.site,
+                &b.username,
+            ))
+        });
+        Ok(result)
     }
 
     pub async fn edit(
@@ -175,51 +919,356 @@ impl App {
         username: String,
         password: String,
     ) -> anyhow::Result<()> {
+        let site = self.stored_site(&site);
         self.verify_credentials_exist(&site, &username)?;
 
         let user_id = self.key_pair.get_pk();
-        let password_id = self.key_pair.hash(&format!("{}{}", site, username))?;
+        let password_id = Password::id_for(&self.key_pair, &site, &username)?;
+        if self.skip_in_dry_run("edit", &site, &username) {
+            return Ok(());
+        }
 
         let password_enc = self.key_pair.encrypt(&password);
-        self.api
+        if let Err(err) = self
+            .api
             .edit_password(user_id, password_id.clone(), password_enc.clone().into())
-            .await?;
+            .await
+        {
+            self.queue_if_offline(
+                err,
+                PendingChange::Edit {
+                    password_id: password_id.clone(),
+                    password: password_enc.clone().into(),
+                },
+            )?;
+        }
 
         self.credentials
             .entry(site)
             .or_insert(HashMap::new()) // Should never happen
             .entry(password_id)
-            .and_modify(|e| e.password = password_enc.clone().into());
+            .and_modify(|e| {
+                // Until the next sync brings the server's history
+                e.push_history(now_secs());
+                e.password = password_enc.clone().into();
+            });
 
-        write_app_data(&self.credentials).expect("Failed to save app data to file");
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
+
+        Ok(())
+    }
+
+    /// Previous passwords of a credential, newest first, along with how many
+    /// days ago each was replaced
+    pub fn history(&self, site: &str, username: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let site = &self.stored_site(site);
+        self.verify_credentials_exist(site, username)?;
+
+        let password_id = Password::id_for(&self.key_pair, site, username)?;
+        let password = &self.credentials[site][&password_id];
+
+        let now = now_secs();
+        Ok(password
+            .history
+            .iter()
+            .map(|entry| {
+                let value = EncryptedValue::from(entry.value.clone());
+                let age = now.saturating_sub(entry.changed_at) / SECS_PER_DAY;
+                (self.key_pair.decrypt(&value), age)
+            })
+            .collect())
+    }
+
+    /// Pin a password so it's listed before the others, or unpin it
+    pub async fn set_favorite(
+        &mut self,
+        site: String,
+        username: String,
+        favorite: bool,
+    ) -> anyhow::Result<()> {
+        let site = self.stored_site(&site);
+        self.verify_credentials_exist(&site, &username)?;
+
+        let user_id = self.key_pair.get_pk();
+        let password_id = Password::id_for(&self.key_pair, &site, &username)?;
+        let patch = PasswordPatch {
+            favorite: Some(favorite),
+            ..Default::default()
+        };
+        let password = self
+            .api
+            .patch_password(user_id, password_id.clone(), &patch)
+            .await?;
+
+        self.credentials
+            .entry(site)
+            .or_default() // Should never happen
+            .insert(password_id, password);
+
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
 
         Ok(())
     }
 
     pub async fn delete(&mut self, site: String, username: String) -> anyhow::Result<()> {
+        let site = self.stored_site(&site);
         self.verify_credentials_exist(&site, &username)?;
 
         let user_id = self.key_pair.get_pk();
-        let password_id = self.key_pair.hash(&format!("{}{}", site, username))?;
+        let password_id = Password::id_for(&self.key_pair, &site, &username)?;
+        if self.skip_in_dry_run("delete", &site, &username) {
+            return Ok(());
+        }
 
-        self.api
-            .delete_password(user_id, password_id.clone())
-            .await?;
+        if let Err(err) = self.api.delete_password(user_id, password_id.clone()).await {
+            self.queue_if_offline(
+                err,
+                PendingChange::Delete {
+                    password_id: password_id.clone(),
+                },
+            )?;
+        }
 
         self.credentials
             .entry(site)
             .or_insert(HashMap::new()) // Should never happen
             .remove(&password_id);
 
-        write_app_data(&self.credentials).expect("Failed to save app data to file");
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
 
         Ok(())
     }
 
+    /// Move the passwords of `old_site` (or only `username`'s) to `new_site`,
+    /// returning how many were moved. Timestamps are kept as they are.
+    pub async fn rename(
+        &mut self,
+        old_site: String,
+        new_site: String,
+        username: Option<String>,
+    ) -> anyhow::Result<usize> {
+        let old_site = self.stored_site(&old_site);
+        let new_site = normalize_site(&new_site);
+        let passwords = self
+            .credentials
+            .get(&old_site)
+            .ok_or(format_err!("No passwords found for {}", old_site))?;
+
+        let mut moves = Vec::new();
+        for (password_id, password) in passwords {
+            let password_username = password.decrypt(&self.key_pair).username;
+            if username
+                .as_ref()
+                .is_some_and(|u| !same_username(u, &password_username))
+            {
+                continue;
+            }
+
+            self.verify_credentials_dont_exist(&new_site, &password_username)?;
+            let new_id = Password::id_for(&self.key_pair, &new_site, &password_username)?;
+            moves.push((password_id.clone(), new_id));
+        }
+
+        if moves.is_empty() {
+            return Err(format_err!("Credentials not found"));
+        }
+
+        let mut moved = 0;
+        let mut result = Ok(());
+        for (password_id, new_id) in moves {
+            if let Err(err) = self
+                .move_password(&old_site, &password_id, &new_site, new_id)
+                .await
+            {
+                result = Err(err);
+                break;
+            }
+            moved += 1;
+        }
+
+        // Save whatever was moved before a failure, the server already has it
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
+
+        result.map(|_| moved)
+    }
+
+    /// Fold the passwords of `others`, and of any site with the same host as
+    /// `canonical` (e.g. `https://www.example.com` for `example.com`), into `canonical`.
+    /// Passwords the canonical site already has are dropped, and ones it has a
+    /// different password for are left in place and reported as conflicts.
+    pub async fn merge_sites(
+        &mut self,
+        canonical: String,
+        others: Vec<String>,
+    ) -> anyhow::Result<MergeReport> {
+        let canonical = site_host(&canonical);
+        let hosts: Vec<String> = others.iter().map(|site| site_host(site)).collect();
+
+        let sites: Vec<String> = self
+            .credentials
+            .keys()
+            .filter(|site| **site != canonical)
+            .filter(|site| {
+                let host = site_host(site);
+                host == canonical || others.contains(site) || hosts.contains(&host)
+            })
+            .cloned()
+            .collect();
+
+        let mut report = MergeReport::default();
+        let mut result = Ok(());
+        'sites: for site in sites {
+            let passwords: Vec<(String, Password)> = self.credentials[&site]
+                .iter()
+                .map(|(id, password)| (id.clone(), password.decrypt(&self.key_pair)))
+                .collect();
+
+            for (password_id, password) in passwords {
+                let new_id = Password::id_for(&self.key_pair, &canonical, &password.username)?;
+                let existing = self
+                    .credentials
+                    .get(&canonical)
+                    .and_then(|passwords| passwords.get(&new_id))
+                    .map(|existing| existing.decrypt(&self.key_pair));
+
+                let step = match existing {
+                    Some(existing) if existing.password == password.password => self
+                        .remove_password(&site, &password_id)
+                        .await
+                        .map(|_| report.duplicates += 1),
+                    Some(_) => {
+                        report.conflicts.push((site.clone(), password.username));
+                        Ok(())
+                    }
+                    None => self
+                        .move_password(&site, &password_id, &canonical, new_id)
+                        .await
+                        .map(|_| report.moved += 1),
+                };
+
+                if let Err(err) = step {
+                    result = Err(err);
+                    break 'sites;
+                }
+            }
+        }
+
+        self.storage
+            .write_app_data(&self.credentials)
+            .expect("Failed to save app data to file");
+
+        result.map(|_| report)
+    }
+
+    /// Move a password to another site and id, on the API and locally
+    async fn move_password(
+        &mut self,
+        old_site: &str,
+        password_id: &str,
+        new_site: &str,
+        new_id: String,
+    ) -> anyhow::Result<()> {
+        let password = self
+            .api
+            .rename_password(
+                self.key_pair.get_pk(),
+                password_id.to_string(),
+                new_id.clone(),
+                new_site.to_string(),
+            )
+            .await?;
+
+        self.forget_password(old_site, password_id);
+        self.credentials
+            .entry(new_site.to_string())
+            .or_default()
+            .insert(new_id, password);
+
+        Ok(())
+    }
+
+    /// Delete a password on the API and locally
+    async fn remove_password(&mut self, site: &str, password_id: &str) -> anyhow::Result<()> {
+        self.api
+            .delete_password(self.key_pair.get_pk(), password_id.to_string())
+            .await?;
+        self.forget_password(site, password_id);
+        Ok(())
+    }
+
+    /// Drop a password from the local credentials, and its site once it's empty
+    fn forget_password(&mut self, site: &str, password_id: &str) {
+        if let Some(passwords) = self.credentials.get_mut(site) {
+            passwords.remove(password_id);
+            if passwords.is_empty() {
+                self.credentials.remove(site);
+            }
+        }
+    }
+
+    /// Whether `site` has a password stored for `username`, ignoring case and
+    /// surrounding whitespace like [`App::get`]
+    pub fn exists(&self, site: &str, username: &str) -> bool {
+        self.stored_username(&self.stored_site(site), username)
+            .is_some()
+    }
+
+    /// The username a password of `site` is stored under that is the same as
+    /// `username` by [`same_username`], as it was typed when stored
+    fn stored_username(&self, site: &str, username: &str) -> Option<String> {
+        self.credentials
+            .get(site)?
+            .values()
+            .map(|password| password.decrypt(&self.key_pair).username)
+            .find(|stored| same_username(stored, username))
+    }
+
+    /// Key the passwords of `site` are stored under: [`normalize_site`]'s, the
+    /// same the extension uses, unless there are some under `site` as given
+    /// (saved before sites were normalized)
+    fn stored_site(&self, site: &str) -> String {
+        if self.credentials.contains_key(site) {
+            site.to_string()
+        } else {
+            normalize_site(site)
+        }
+    }
+
+    /// Queue `change` for the next sync when `err` is the API being unreachable,
+    /// so it's only made locally for now, or fail with `err` otherwise
+    fn queue_if_offline(&self, err: anyhow::Error, change: PendingChange) -> anyhow::Result<()> {
+        if !is_offline(&err) {
+            return Err(err);
+        }
+
+        let mut pending = self.storage.read_pending()?;
+        pending.push(change);
+        self.storage.write_pending(&pending)?;
+
+        eprintln!("{}, the change will be sent on the next sync", err);
+        Ok(())
+    }
+
+    /// Print the planned operation in dry-run mode, returning whether to skip it
+    fn skip_in_dry_run(&self, action: &str, site: &str, username: &str) -> bool {
+        if self.dry_run {
+            println!("Would {} {} on {}", action, username, site);
+        }
+        self.dry_run
+    }
+
     fn verify_credentials_exist(&self, site: &str, username: &str) -> anyhow::Result<()> {
         match self.credentials.get(site) {
             Some(passwords) => {
-                let id = self.key_pair.hash(&format!("{}{}", site, username))?;
+                let id = Password::id_for(&self.key_pair, site, username)?;
                 passwords
                     .get(&id)
                     .ok_or(format_err!("Credentials not found"))?;
@@ -236,3 +1285,310 @@ impl App {
         }
     }
 }
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Days since `password` was last changed, `None` when it has no timestamps
+fn age_days(password: &Password, now: u64) -> Option<u64> {
+    let changed_at = password.updated_at.or(password.created_at);
+    changed_at.map(|changed_at| now.saturating_sub(changed_at) / SECS_PER_DAY)
+}
+
+/// Whether a password of `age` days should be changed, unknown ages included
+fn is_expiring(age: Option<u64>, max_age_days: u64) -> bool {
+    !matches!(age, Some(age) if age <= max_age_days)
+}
+
+/// Whether two usernames are the same, ignoring case and surrounding whitespace
+fn same_username(a: &str, b: &str) -> bool {
+    a.trim().to_lowercase() == b.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FileStorage;
+    use crate::storage::MemoryStorage;
+    use passphrasex_common::crypto::symmetric::generate_key;
+
+    /// App with an empty vault in memory and an API that can't be reached, so
+    /// changes are only made locally and queued
+    fn offline_app() -> App {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let api = Api::with_base_url(key_pair.clone(), "http://127.0.0.1:9").unwrap();
+        App::from_parts(
+            key_pair,
+            CredentialsMap::new(),
+            api,
+            Box::new(MemoryStorage::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn add_compares_usernames_like_get() -> anyhow::Result<()> {
+        let mut app = offline_app();
+        app.add(
+            "example.com".to_string(),
+            "alice".to_string(),
+            "first".to_string(),
+            None,
+            false,
+        )
+        .await?;
+
+        assert!(app.exists("example.com", " Alice "));
+        let refused = app
+            .add(
+                "example.com".to_string(),
+                "Alice".to_string(),
+                "second".to_string(),
+                None,
+                false,
+            )
+            .await;
+        assert!(refused.is_err());
+
+        app.add(
+            "example.com".to_string(),
+            "Alice".to_string(),
+            "second".to_string(),
+            None,
+            true,
+        )
+        .await?;
+        let passwords = app.get("example.com".to_string(), None, false).await?;
+        assert_eq!(passwords.len(), 1);
+        assert_eq!(passwords[0].username, "alice");
+        assert_eq!(passwords[0].password, "second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_compares_usernames_like_get() -> anyhow::Result<()> {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let api = Api::with_base_url(key_pair.clone(), &fake_api())?;
+        let mut app = App::from_parts(
+            key_pair,
+            CredentialsMap::new(),
+            api,
+            Box::new(MemoryStorage::new()),
+        );
+        app.add(
+            "example.com".to_string(),
+            "alice".to_string(),
+            "secret".to_string(),
+            None,
+            false,
+        )
+        .await?;
+
+        let moved = app
+            .rename(
+                "example.com".to_string(),
+                "example.org".to_string(),
+                Some(" Alice ".to_string()),
+            )
+            .await?;
+
+        assert_eq!(moved, 1);
+        assert!(!app.credentials.contains_key("example.com"));
+        assert!(app.credentials.contains_key("example.org"));
+        Ok(())
+    }
+
+    /// API storing no passwords but accepting adds and renames, answering deletes
+    /// as already applied, refusing edits and exporting an account without
+    /// passwords, at the returned base URL
+    fn fake_api() -> String {
+        use axum::extract::Path;
+        use axum::http::StatusCode;
+        use axum::routing::{delete, get, post, put};
+        use axum::{Json, Router};
+        use passphrasex_common::model::auth::Challenge;
+
+        let router = Router::new()
+            .route(
+                "/v1/users/:user_id/challenge",
+                get(|| async {
+                    Json(Challenge {
+                        challenge: "challenge".to_string(),
+                        expires_in: 60,
+                    })
+                }),
+            )
+            .route(
+                "/v1/users/:user_id/passwords",
+                get(|| async { Json(Vec::<Password>::new()) }).post(
+                    |Json(password): Json<Password>| async {
+                        (StatusCode::CREATED, Json(password))
+                    },
+                ),
+            )
+            .route(
+                "/v1/users/:user_id/passwords/:password_id",
+                delete(|| async { StatusCode::NOT_FOUND }),
+            )
+            .route(
+                "/v1/users/:user_id/passwords/:password_id/password",
+                put(|| async { StatusCode::BAD_REQUEST }),
+            )
+            .route(
+                "/v1/users/:user_id/passwords/:password_id/rename",
+                post(
+                    |Path((user_id, _)): Path<(String, String)>,
+                     Json(mut body): Json<HashMap<String, String>>| async move {
+                        Json(Password {
+                            _id: body.remove("_id").unwrap_or_default(),
+                            user_id,
+                            site: body.remove("site").unwrap_or_default(),
+                            username: String::new(),
+                            password: String::new(),
+                            created_at: None,
+                            updated_at: None,
+                            favorite: false,
+                            history: Vec::new(),
+                            folder: None,
+                        })
+                    },
+                ),
+            )
+            .route(
+                "/v1/users/:user_id/export",
+                get(|Path(user_id): Path<String>| async move {
+                    Json(UserExport {
+                        public_key: user_id,
+                        email: None,
+                        display_name: None,
+                        passwords: Vec::new(),
+                        exported_at: 0,
+                    })
+                }),
+            );
+
+        let server =
+            axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(router.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn queued_changes_survive_until_replayed() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let user_id = key_pair.get_pk();
+        let password = Password::new(
+            &key_pair,
+            "example.com".to_string(),
+            "alice".to_string(),
+            "secret".to_string(),
+        )?;
+        let delete = |password_id: &str| PendingChange::Delete {
+            password_id: password_id.to_string(),
+        };
+        FileStorage::in_dir(dir.path()).write_pending(&[
+            PendingChange::Add {
+                password,
+                idempotency_key: Some(new_idempotency_key()),
+            },
+            delete("deleted"),
+            PendingChange::Edit {
+                password_id: "refused".to_string(),
+                password: "new".to_string(),
+            },
+            delete("after"),
+        ])?;
+
+        // Still offline, nothing is dropped
+        let offline = Api::with_base_url(key_pair.clone(), "http://127.0.0.1:9")?;
+        let storage = FileStorage::in_dir(dir.path());
+        assert!(replay_pending(&storage, &offline, &user_id).await.is_err());
+        assert_eq!(storage.read_pending()?.len(), 4);
+
+        // Sent in order, stopping at the refused edit
+        let online = Api::with_base_url(key_pair, &fake_api())?;
+        assert!(replay_pending(&storage, &online, &user_id).await.is_err());
+        let pending = FileStorage::in_dir(dir.path()).read_pending()?;
+        assert!(matches!(
+            pending.as_slice(),
+            [
+                PendingChange::Edit { password_id, .. },
+                PendingChange::Delete { password_id: after },
+            ] if password_id == "refused" && after == "after"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_exports_need_the_key() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let api = Api::with_base_url(key_pair.clone(), &fake_api())?;
+        let app = App::from_parts(
+            key_pair,
+            CredentialsMap::new(),
+            api,
+            Box::new(MemoryStorage::new()),
+        );
+        let path = dir.path().join("export.json");
+        let key = generate_key()?;
+
+        app.export(&path, Some(&key)).await?;
+
+        assert!(!String::from_utf8_lossy(&fs::read(&path)?).contains(&app.public_key()));
+        let export = verify_export(&path, &app.signing_key(), Some(&key))?;
+        assert_eq!(export.public_key, app.public_key());
+        assert!(verify_export(&path, &app.signing_key(), None).is_err());
+        assert!(verify_export(&path, &app.signing_key(), Some(&generate_key()?)).is_err());
+        Ok(())
+    }
+
+    /// App with a password stored locally only, against [`fake_api`]
+    fn app_with_local_password() -> anyhow::Result<App> {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let password = Password::new(
+            &key_pair,
+            "example.com".to_string(),
+            "alice".to_string(),
+            "secret".to_string(),
+        )?;
+        let mut credentials = CredentialsMap::new();
+        credentials
+            .entry(password.site.clone())
+            .or_default()
+            .insert(password._id.clone(), password);
+        let api = Api::with_base_url(key_pair.clone(), &fake_api())?;
+
+        Ok(App::from_parts(
+            key_pair,
+            credentials,
+            api,
+            Box::new(MemoryStorage::new()),
+        ))
+    }
+
+    #[tokio::test]
+    async fn sync_does_not_bring_back_remote_deletions() -> anyhow::Result<()> {
+        // Deleted on another device, the API no longer has it
+        let mut app = app_with_local_password()?;
+
+        assert_eq!(app.sync(SyncDirection::Push, false).await?, 0);
+        assert_eq!(app.sites().len(), 1);
+        assert_eq!(app.sync(SyncDirection::Both, false).await?, 0);
+        assert!(app.sites().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_restores_missing_passwords_when_asked() -> anyhow::Result<()> {
+        let mut app = app_with_local_password()?;
+
+        assert_eq!(app.sync(SyncDirection::Push, true).await?, 1);
+        Ok(())
+    }
+}
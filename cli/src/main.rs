@@ -2,13 +2,49 @@
 * Password Manager
 * Stores passwords encrypted via a private - public key pair
 */
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use std::env;
 use std::error::Error;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
 use std::string::String;
 
-use passphrasex::{auth_device, register, App};
+use passphrasex::api::{api_url, ApiError};
+use passphrasex::config::Config;
+use passphrasex::storage::{default_storage, CorruptedAppData};
+use passphrasex::{
+    auth_device, check_health, pull_app_data, purge, register, register_with, show_seed, status, verify_device_password, verify_export, App,
+    AccountDetails, KeyDerivation, SyncDirection,
+};
+use passphrasex_common::api::parse_base_url;
+use passphrasex_common::crypto::symmetric::generate_key;
 use passphrasex_common::generator::generate_password;
 
+mod output;
+mod native_host;
+mod prompt;
+mod qr;
+mod serve;
+use native_host::{is_extension_origin, HOST_NAME};
+use output::{copy_to_clipboard, print_credentials, print_health_report, print_history, print_shared, Format};
+use prompt::{
+    confirm, hide_seed_phrase, is_interactive, new_secret_or_prompt, optional_or_prompt, secret_or_prompt, select,
+    verify_seed_phrase,
+};
+use qr::{print_qr, save_qr_png};
+use serve::{generate_token, serve};
+
+/// Exit code of `get` when there's no matching password, real failures exit with 1
+const EXIT_NOT_FOUND: i32 = 2;
+
+/// Printed after registering with a passphrase or account index, which are stored nowhere
+const DERIVATION_REMINDER: &str = "Remember your passphrase and account index too: the seed phrase alone doesn't open this account, and different ones open a different, empty, one";
+
+/// Port `serve` listens on unless told otherwise
+const DEFAULT_SERVE_PORT: u16 = 7878;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 /// A simple password manager
@@ -19,17 +55,65 @@ struct Args {
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Set up this device: register a new account and choose the server to use
+    Init {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// API server to use, e.g. a self-hosted one. Prompted for when omitted, empty for the default one
+        #[clap(long)]
+        server_url: Option<String>,
+        /// Replace the vault already stored on this device
+        #[clap(long)]
+        force: bool,
+        /// Add a BIP39 passphrase on top of the seed phrase, prompted for when no value is given
+        #[clap(long, num_args = 0..=1)]
+        passphrase: Option<Option<String>>,
+        /// Use this account of the seed phrase, for separate vaults (e.g. personal and work)
+        #[clap(long)]
+        account: Option<u32>,
+        /// Contact address for the account, for recovery
+        #[clap(long)]
+        email: Option<String>,
+        /// Name to show for the account instead of its public key
+        #[clap(long)]
+        display_name: Option<String>,
+    },
     /// Create your credentials
     Register {
+        /// Prompted for when omitted
         #[clap(short, long)]
-        device_pass: String,
+        device_pass: Option<String>,
+        /// Ask for some words of the seed phrase to make sure it was written down
+        #[clap(long)]
+        verify: bool,
+        /// Add a BIP39 passphrase on top of the seed phrase, prompted for when no value is given
+        #[clap(long, num_args = 0..=1)]
+        passphrase: Option<Option<String>>,
+        /// Use this account of the seed phrase, for separate vaults (e.g. personal and work)
+        #[clap(long)]
+        account: Option<u32>,
+        /// Contact address for the account, for recovery
+        #[clap(long)]
+        email: Option<String>,
+        /// Name to show for the account instead of its public key
+        #[clap(long)]
+        display_name: Option<String>,
     },
     /// Authenticate device using your seed phrase
     Login {
+        /// Prompted for when omitted
         #[clap(short, long)]
-        seed_phrase: String,
+        seed_phrase: Option<String>,
+        /// Prompted for when omitted
         #[clap(short, long)]
-        device_pass: String,
+        device_pass: Option<String>,
+        /// BIP39 passphrase the account was created with, prompted for when no value is given
+        #[clap(long, num_args = 0..=1)]
+        passphrase: Option<Option<String>>,
+        /// Account of the seed phrase to log in to, if it was created with one
+        #[clap(long)]
+        account: Option<u32>,
     },
     /// Add a new password
     Add {
@@ -37,10 +121,21 @@ enum Commands {
         site: String,
         #[clap(short, long)]
         username: String,
+        /// Prompted for when omitted
         #[clap(short, long)]
-        password: String,
+        password: Option<String>,
+        /// Prompted for when omitted
         #[clap(short, long)]
-        device_pass: String,
+        device_pass: Option<String>,
+        /// Print what would change without changing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// Replace the stored password if the site already has one for the username
+        #[clap(short, long)]
+        force: bool,
+        /// Folder to put the password in, e.g. Work/Cloud
+        #[clap(long)]
+        folder: Option<String>,
     },
     /// Get a password
     Get {
@@ -48,8 +143,66 @@ enum Commands {
         site: String,
         #[clap(short, long)]
         username: Option<String>,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Only match the exact host, not other subdomains of the site
+        #[clap(long)]
+        strict: bool,
+        /// Print the passwords instead of masking them
+        #[clap(long)]
+        show: bool,
+        /// How to print the credentials
+        #[clap(long, value_enum, default_value_t)]
+        format: Format,
+        /// Only get the nth matching credential, as numbered in the table
         #[clap(short, long)]
-        device_pass: String,
+        index: Option<usize>,
+        /// Copy the password to the clipboard instead of printing the credential, never showing it
+        #[clap(short, long, conflicts_with_all = ["show", "field"])]
+        copy: bool,
+        /// Only print the username, e.g. for scripts
+        #[clap(long, group = "field")]
+        print_username: bool,
+        /// Only print the password, e.g. for scripts
+        #[clap(long, group = "field")]
+        print_password: bool,
+        /// Don't end the printed field with a newline
+        #[clap(long, requires = "field")]
+        no_newline: bool,
+    },
+    /// Sync this device's passwords with the server, both ways unless told otherwise
+    Sync {
+        /// Only replace the local passwords with the server's
+        #[clap(long, conflicts_with_all = ["push_only", "restore_missing"])]
+        pull_only: bool,
+        /// Only send the changes made while the server was unreachable
+        #[clap(long)]
+        push_only: bool,
+        /// Also upload the local passwords the server doesn't have, e.g. after it lost them. Passwords deleted on other devices come back too
+        #[clap(long)]
+        restore_missing: bool,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// List every stored credential
+    List {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Print the passwords instead of masking them
+        #[clap(long)]
+        show: bool,
+        /// Only list the favorite passwords
+        #[clap(long)]
+        favorites: bool,
+        /// Only list the passwords in this folder or its subfolders
+        #[clap(long)]
+        folder: Option<String>,
+        /// How to print the credentials
+        #[clap(long, value_enum, default_value_t)]
+        format: Format,
     },
     /// Modify a password
     Edit {
@@ -57,10 +210,15 @@ enum Commands {
         site: String,
         #[clap(short, long)]
         username: String,
+        /// Prompted for when omitted
         #[clap(short, long)]
-        password: String,
+        password: Option<String>,
+        /// Prompted for when omitted
         #[clap(short, long)]
-        device_pass: String,
+        device_pass: Option<String>,
+        /// Print what would change without changing anything
+        #[clap(long)]
+        dry_run: bool,
     },
     /// Delete a password
     Delete {
@@ -68,74 +226,662 @@ enum Commands {
         site: String,
         #[clap(short, long)]
         username: String,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Print what would change without changing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// List the previous values of a password, newest first
+    History {
+        #[clap(short, long)]
+        site: String,
+        #[clap(short, long)]
+        username: String,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Print the passwords instead of masking them
+        #[clap(long)]
+        show: bool,
+    },
+    /// Pin a password, listing it before the others
+    Favorite {
+        #[clap(short, long)]
+        site: String,
+        #[clap(short, long)]
+        username: String,
+        /// Prompted for when omitted
         #[clap(short, long)]
-        device_pass: String,
+        device_pass: Option<String>,
+    },
+    /// Unpin a favorite password
+    Unfavorite {
+        #[clap(short, long)]
+        site: String,
+        #[clap(short, long)]
+        username: String,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Move passwords to a new site, e.g. after a service changes domains
+    Rename {
+        /// Site the passwords are stored under
+        #[clap(short, long)]
+        from: String,
+        /// Site to move them to
+        #[clap(short, long)]
+        to: String,
+        /// Only move this username's password
+        #[clap(short, long)]
+        username: Option<String>,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Fold duplicate sites (e.g. www. or https:// variants) into one
+    MergeSites {
+        /// Site to keep, sites with the same host are merged into it
+        #[clap(short, long)]
+        site: String,
+        /// Other sites to merge, e.g. an old domain of the service
+        #[clap(short, long)]
+        other: Vec<String>,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
     },
     /// Generate a random password
     Generate {
         #[clap(short, long)]
         length: Option<usize>,
     },
+    /// List passwords that haven't been changed in a while, oldest first
+    Expiring {
+        /// Report passwords older than this many days
+        #[clap(long, default_value_t = 90)]
+        days: u64,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Count the stored passwords and sites, and the reused passwords
+    Stats {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Show reused, weak and old passwords at a glance
+    Dashboard {
+        /// Report passwords older than this many days
+        #[clap(long, default_value_t = 90)]
+        days: u64,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Download everything the server stores for your account (passwords stay encrypted)
+    Export {
+        /// New file to save the export to
+        #[clap(short, long, default_value = "passphrasex-export.json")]
+        output: PathBuf,
+        /// Also encrypt the whole file, sites and usernames included, with a new key printed once
+        #[clap(long)]
+        encrypt: bool,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Share a password with other users, who can read it with `shared`
+    Share {
+        #[clap(short, long)]
+        site: String,
+        #[clap(short, long)]
+        username: String,
+        /// Public key of a user to share it with, as `status` shows it. Can be repeated
+        #[clap(short, long, required = true)]
+        recipient: Vec<String>,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Stop sharing a password with a user
+    Revoke {
+        #[clap(short, long)]
+        site: String,
+        #[clap(short, long)]
+        username: String,
+        /// Public key of the user to stop sharing it with
+        #[clap(short, long)]
+        recipient: String,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// List the passwords other users shared with you
+    Shared {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Print the passwords instead of masking them
+        #[clap(long)]
+        show: bool,
+    },
+    /// Check that an export was signed by who you expect and wasn't changed since
+    VerifyExport {
+        /// Export file to check
+        #[clap(short, long)]
+        input: PathBuf,
+        /// Signing key of the account that exported it, as `export` printed it
+        #[clap(short, long)]
+        signer: String,
+        /// Key the file was encrypted with, as `export --encrypt` printed it
+        #[clap(short, long)]
+        key: Option<String>,
+    },
+    /// Serve the vault's passwords over HTTP to scripts and tools on this machine only
+    Serve {
+        /// Port to listen on, always on 127.0.0.1
+        #[clap(long, default_value_t = DEFAULT_SERVE_PORT)]
+        port: u16,
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Talk to the browser extension over stdin and stdout, as its native messaging host
+    NativeHost {
+        /// Print the manifest registering this host for the extension with this id instead
+        #[clap(long)]
+        manifest: Option<String>,
+    },
+    /// Show the seed phrase of this device's vault again
+    ShowSeed {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Don't ask for confirmation before printing
+        #[clap(long)]
+        yes: bool,
+        /// Show it as a QR code, e.g. to scan it with a phone
+        #[clap(long)]
+        qr: bool,
+        /// Save it as a QR code PNG to this (new) file instead of printing it
+        #[clap(long)]
+        qr_png: Option<PathBuf>,
+    },
+    /// Check that you have the right seed phrase by entering some of its words
+    VerifySeed {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Check the device password without unlocking the vault, exiting with 1 when it's wrong
+    VerifyPassword {
+        /// Prompted for when omitted
+        #[clap(short, long)]
+        device_pass: Option<String>,
+    },
+    /// Print a shell completion script, e.g. `passphrasex completions bash > /etc/bash_completion.d/passphrasex`
+    Completions {
+        shell: Shell,
+    },
+    /// Show whether this device is registered, its public key and the server in use
+    Status {
+        /// Unlock the vault to show its public key
+        #[clap(short, long)]
+        device_pass: Option<String>,
+        /// Check that the server is reachable
+        #[clap(long)]
+        ping: bool,
+    },
+    /// Remove the vault and settings from this device, leaving the account on the server
+    Purge {
+        /// Overwrite the files with zeros before deleting them
+        #[clap(long)]
+        overwrite: bool,
+        /// Don't ask for confirmation
+        #[clap(long)]
+        yes: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+    // Browsers start native hosts with the extension's origin as their only argument
+    let args = match env::args().nth(1) {
+        Some(origin) if is_extension_origin(&origin) => Args { command: Commands::NativeHost { manifest: None } },
+        _ => Args::parse(),
+    };
 
     match args.command {
-        Commands::Register { device_pass } => match register(&device_pass).await {
-            Ok(seed_phrase) => println!(
-                "Successfully registered!\nYour seed phrase is: \n{}",
-                seed_phrase.get_phrase()
-            ),
-            Err(e) => println!("Failed to create user: {}", e),
+        Commands::Init { device_pass, server_url, force, passphrase, account, email, display_name } => {
+            if status(None)?.registered && !force {
+                // An error, so scripts see the nonzero exit code
+                return Err(anyhow::format_err!("This device already has a vault, use --force to replace it (its passwords stay on the server)").into());
+            }
+
+            let device_pass = new_secret_or_prompt(device_pass, "Device password: ")?;
+            let passphrase = passphrase.map(|value| new_secret_or_prompt(value, "Passphrase: ")).transpose()?;
+            let server_url = optional_or_prompt(server_url, "Server URL (empty for the default server): ")?;
+            if let Some(server_url) = &server_url {
+                parse_base_url(server_url)?;
+            }
+
+            let api_url = server_url.clone().unwrap_or_else(api_url);
+            let derivation = KeyDerivation { passphrase: passphrase.as_deref(), account };
+            let details = AccountDetails { email: email.as_deref(), display_name: display_name.as_deref() };
+            match register_with(default_storage().as_ref(), &device_pass, derivation, details, &api_url).await {
+                Ok(seed_phrase) => {
+                    Config { server_url }.save()?;
+                    println!("Successfully registered!\nYour seed phrase is: \n{}", seed_phrase.get_phrase());
+                    if passphrase.is_some() || account.is_some() {
+                        println!("{}", DERIVATION_REMINDER);
+                    }
+                    hide_seed_phrase()?;
+                    verify_seed_phrase(&seed_phrase.get_phrase())?;
+                    println!("Settings saved to {}", Config::path()?.display());
+                },
+                Err(e) => println!("Failed to create user: {}", e),
+            }
         },
-        Commands::Login { seed_phrase, device_pass } => {
-            match auth_device(&seed_phrase, &device_pass).await {
+        Commands::Register { device_pass, verify, passphrase, account, email, display_name } => {
+            let device_pass = new_secret_or_prompt(device_pass, "Device password: ")?;
+            let passphrase = passphrase.map(|value| new_secret_or_prompt(value, "Passphrase: ")).transpose()?;
+            let details = AccountDetails { email: email.as_deref(), display_name: display_name.as_deref() };
+            match register(&device_pass, KeyDerivation { passphrase: passphrase.as_deref(), account }, details).await {
+                Ok(seed_phrase) => {
+                    println!(
+                        "Successfully registered!\nYour seed phrase is: \n{}",
+                        seed_phrase.get_phrase()
+                    );
+                    if passphrase.is_some() || account.is_some() {
+                        println!("{}", DERIVATION_REMINDER);
+                    }
+                    if verify {
+                        hide_seed_phrase()?;
+                        verify_seed_phrase(&seed_phrase.get_phrase())?;
+                    }
+                },
+                Err(e) => println!("Failed to create user: {}", e),
+            }
+        },
+        Commands::Login { seed_phrase, device_pass, passphrase, account } => {
+            let seed_phrase = secret_or_prompt(seed_phrase, "Seed phrase: ")?;
+            let passphrase = passphrase.map(|value| secret_or_prompt(value, "Passphrase: ")).transpose()?;
+            let device_pass = new_secret_or_prompt(device_pass, "Device password: ")?;
+            let derivation = KeyDerivation { passphrase: passphrase.as_deref(), account };
+            match auth_device(&seed_phrase, derivation, &device_pass).await {
+                // Any passphrase or account opens an account, a mistyped one an empty account
+                Ok(0) if passphrase.is_some() || account.is_some() => {
+                    println!("Successfully authenticated, but this account has no passwords.");
+                    println!("If you expected some, check the passphrase and account: different ones open a different, empty, account");
+                }
                 Ok(_) => println!("Successfully authenticated!"),
                 Err(e) => println!("Failed to authenticate: {}", e),
             }
         },
-        Commands::Add { site, username, password, device_pass} => {
-            match App::new(&device_pass).await?.add(site, username, password).await {
+        Commands::Add { site, username, password, device_pass, dry_run, force, folder } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let password = secret_or_prompt(password, "Password: ")?;
+            let mut app = open_app(&device_pass).await?.with_dry_run(dry_run);
+            let overwrite = force
+                || (app.exists(&site, &username)
+                    && confirm(&format!("{} already has a password for {}, replace it?", site, username))?);
+            match app.add(site, username, password, folder, overwrite).await {
+                Ok(_) if dry_run => (),
                 Ok(_) => println!("Password added successfully"),
                 Err(e) => println!("Failed to add password: {}", e),
             }
         }
-        Commands::Get { site, username, device_pass, } => {
-            match App::new(&device_pass).await?.get(site, username).await {
-                Ok(passwords) => {
-                    for credential in passwords {
-                        println!(
-                            "username: {}\npassword: {}\n",
-                            credential.username, credential.password
-                        );
+        Commands::Get { site, username, device_pass, strict, show, format, index, copy, print_username, print_password, no_newline } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.get(site, username, strict).await {
+                Ok(mut passwords) => {
+                    if let Some(index) = index {
+                        if index == 0 || index > passwords.len() {
+                            eprintln!("No password #{}, {} matched", index, passwords.len());
+                            process::exit(EXIT_NOT_FOUND);
+                        }
+                        passwords = vec![passwords.swap_remove(index - 1)];
+                    } else if passwords.len() > 1 && matches!(format, Format::Table) && is_interactive() {
+                        // Same order as the table, so the numbers match `--index`
+                        let options: Vec<String> = passwords.iter().map(|password| format!("{} ({})", password.username, password.site)).collect();
+                        let choice = select("Which credential?", &options)?;
+                        passwords = vec![passwords.swap_remove(choice)];
+                    }
+
+                    if !copy && !print_username && !print_password {
+                        print_credentials(passwords, format, show)?
+                    } else if let [password] = passwords.as_slice() {
+                        if copy {
+                            copy_to_clipboard(&password.password)?;
+                            println!("Copied the password for {} on {}", password.username, password.site);
+                        } else {
+                            let field = if print_username { &password.username } else { &password.password };
+                            print!("{}{}", field, if no_newline { "" } else { "\n" });
+                            io::stdout().flush()?;
+                        }
+                    } else {
+                        // A single field or the clipboard can only take one credential
+                        eprintln!("{} passwords matched, pick one with --username or --index", passwords.len());
+                        process::exit(1);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Failed to get password: {}", e);
+                    match e.downcast_ref::<ApiError>() {
+                        Some(ApiError::NotFound(_)) => process::exit(EXIT_NOT_FOUND),
+                        _ => process::exit(1),
                     }
                 }
-                Err(e) => println!("Failed to get password: {}", e),
             }
         },
+        Commands::Sync { pull_only, push_only, restore_missing, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let direction = match (pull_only, push_only) {
+                (true, _) => SyncDirection::Pull,
+                (_, true) => SyncDirection::Push,
+                _ => SyncDirection::Both,
+            };
+            // Opened without the pull App::new does, push-only never pulls
+            let mut app = match App::open(&device_pass) {
+                Ok(app) => app,
+                Err(err) => {
+                    recover_app_data(err, &device_pass).await?;
+                    App::open(&device_pass)?
+                }
+            };
+            match app.sync(direction, restore_missing).await {
+                Ok(_) if direction == SyncDirection::Pull => println!("Synced {} password(s)", app.list().len()),
+                Ok(pushed) => println!("Pushed {} change(s), {} password(s) stored locally", pushed, app.list().len()),
+                Err(e) => println!("Failed to sync: {}", e),
+            }
+        },
+        Commands::List { device_pass, show, favorites, folder, format } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let mut credentials = open_app(&device_pass).await?.list();
+            if favorites {
+                credentials.retain(|credential| credential.favorite);
+            }
+            if let Some(folder) = folder {
+                credentials.retain(|credential| credential.in_folder(&folder));
+            }
+            print_credentials(credentials, format, show)?;
+        },
         Commands::Edit {
             site,
             username,
             password,
             device_pass,
+            dry_run,
         } => {
-            match App::new(&device_pass).await?.edit(site, username, password).await {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let password = secret_or_prompt(password, "New password: ")?;
+            match open_app(&device_pass).await?.with_dry_run(dry_run).edit(site, username, password).await {
+                Ok(_) if dry_run => (),
                 Ok(_) => println!("Password edited successfully"),
                 Err(e) => println!("Failed to edit password: {}", e),
             }
         },
-        Commands::Delete { site, username, device_pass } => {
-            match App::new(&device_pass).await?.delete(site, username).await {
+        Commands::Delete { site, username, device_pass, dry_run } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.with_dry_run(dry_run).delete(site, username).await {
+                Ok(_) if dry_run => (),
                 Ok(_) => println!("Password deleted successfully"),
                 Err(e) => println!("Failed to delete password: {}", e)
             }
         },
+        Commands::History { site, username, device_pass, show } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.history(&site, &username) {
+                Ok(history) => print_history(history, show),
+                Err(e) => println!("Failed to get password history: {}", e)
+            }
+        },
+        Commands::Favorite { site, username, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.set_favorite(site, username, true).await {
+                Ok(_) => println!("Password added to favorites"),
+                Err(e) => println!("Failed to add favorite: {}", e)
+            }
+        },
+        Commands::Unfavorite { site, username, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.set_favorite(site, username, false).await {
+                Ok(_) => println!("Password removed from favorites"),
+                Err(e) => println!("Failed to remove favorite: {}", e)
+            }
+        },
+        Commands::Rename { from, to, username, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.rename(from, to, username).await {
+                Ok(moved) => println!("Moved {} password(s)", moved),
+                Err(e) => println!("Failed to rename site: {}", e)
+            }
+        },
+        Commands::MergeSites { site, other, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.merge_sites(site, other).await {
+                Ok(report) => {
+                    println!("Moved {} password(s), removed {} duplicate(s)", report.moved, report.duplicates);
+                    for (site, username) in report.conflicts {
+                        println!("Conflict: {} / {} has a different password, left in place", site, username);
+                    }
+                },
+                Err(e) => println!("Failed to merge sites: {}", e)
+            }
+        },
         Commands::Generate {length} => {
             println!("{}", generate_password(length.unwrap_or(16)));
         }
+        Commands::Expiring { days, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let expiring = open_app(&device_pass).await?.expiring(days);
+            if expiring.is_empty() {
+                println!("No passwords older than {} days", days);
+            }
+            for (credential, age) in expiring {
+                let age = match age {
+                    Some(age) => format!("{} days", age),
+                    None => "unknown age".to_string(),
+                };
+                println!("{} / {}: {}", credential.site, credential.username, age);
+            }
+        }
+        Commands::Stats { device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            // Counted by the server, nothing to sync first
+            match App::open(&device_pass)?.stats().await {
+                Ok(stats) => {
+                    println!("Passwords: {}", stats.total);
+                    println!("Sites: {}", stats.sites);
+                    println!("Reused passwords: {}", stats.reused);
+                },
+                Err(e) => println!("Failed to get stats: {}", e)
+            }
+        }
+        Commands::Dashboard { days, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let report = open_app(&device_pass).await?.health_report(days);
+            print_health_report(&report, days);
+        }
+        Commands::Export { output, encrypt, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let app = open_app(&device_pass).await?;
+            let key = if encrypt { Some(generate_key()?) } else { None };
+            match app.export(&output, key.as_deref()).await {
+                Ok(()) => {
+                    println!("Exported your account to {}", output.display());
+                    println!("Signed with key {}, give it to whoever checks the file with `verify-export`", app.signing_key());
+                    if let Some(key) = key {
+                        println!("Encrypted with key {}, keep it apart from the file: it can't be read without it", key);
+                    }
+                }
+                Err(e) => println!("Failed to export account: {}", e),
+            }
+        }
+        Commands::VerifyExport { input, signer, key } => {
+            match verify_export(&input, &signer, key.as_deref()) {
+                Ok(export) => println!("Valid export of {} with {} password(s)", export.public_key, export.passwords.len()),
+                Err(e) => {
+                    eprintln!("WARNING: {} can't be trusted: {}", input.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Share { site, username, recipient, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.share(site, username, &recipient).await {
+                Ok(_) => println!("Password shared successfully"),
+                Err(e) => println!("Failed to share password: {}", e),
+            }
+        }
+        Commands::Revoke { site, username, recipient, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.revoke_share(site, username, recipient).await {
+                Ok(_) => println!("Stopped sharing the password"),
+                Err(e) => println!("Failed to revoke share: {}", e),
+            }
+        }
+        Commands::Shared { device_pass, show } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match open_app(&device_pass).await?.shared_with_me().await {
+                Ok(shared) => print_shared(shared, show),
+                Err(e) => println!("Failed to get shared passwords: {}", e),
+            }
+        }
+        Commands::Serve { port, device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            let app = open_app(&device_pass).await?;
+            let token = generate_token();
+            println!("Listening on http://127.0.0.1:{}", port);
+            println!("Send `Authorization: Bearer {}` with every request", token);
+            println!("GET /sites lists the sites, GET /sites/<site>?username=<username> gets their passwords");
+            serve(app, port, token).await?;
+        }
+        Commands::NativeHost { manifest: Some(extension_id) } => {
+            println!("{}", native_host::manifest(&extension_id)?);
+            eprintln!("Save it as {}.json in the browser's NativeMessagingHosts directory", HOST_NAME);
+        }
+        Commands::NativeHost { manifest: None } => {
+            native_host::run().await?;
+        }
+        Commands::Status { device_pass, ping } => {
+            match status(device_pass.as_deref()) {
+                Ok(status) => {
+                    if status.registered {
+                        println!("Registered: yes");
+                    } else {
+                        println!("Not registered: no vault found on this device, run `register` or `login` first");
+                    }
+                    if let Some(public_key) = status.public_key {
+                        println!("Public key: {}", public_key);
+                    }
+                    println!("Server: {}", status.server_url);
+                }
+                Err(e) => println!("Failed to read vault: {}", e),
+            }
+
+            if ping {
+                match check_health().await {
+                    Ok(()) => println!("Server status: reachable"),
+                    Err(e) => println!("Server status: unreachable ({})", e),
+                }
+            }
+        }
+        Commands::Purge { overwrite, yes } => {
+            println!("The account stays on the server, logging in to it again needs the seed phrase");
+            if yes || confirm("Remove the vault from this device?")? {
+                match purge(overwrite) {
+                    Ok(()) => println!("Removed the vault from this device"),
+                    Err(e) => println!("Failed to purge: {}", e),
+                }
+            }
+        }
+        Commands::ShowSeed { device_pass, yes, qr, qr_png } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match show_seed(&device_pass) {
+                Ok(seed_phrase) => {
+                    eprintln!("WARNING: anyone who sees your seed phrase can access all your passwords.");
+                    eprintln!("Make sure no one is watching your screen and your terminal isn't being recorded.");
+                    if yes || confirm("Show the seed phrase?")? {
+                        match (qr_png, qr) {
+                            (Some(path), _) => {
+                                save_qr_png(&seed_phrase.get_phrase(), &path)?;
+                                println!("Saved the seed phrase QR code to {}, delete it once it's been scanned or printed", path.display());
+                            }
+                            (None, true) => print_qr(&seed_phrase.get_phrase())?,
+                            (None, false) => println!("{}", seed_phrase.get_phrase()),
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to show seed phrase: {}", e),
+            }
+        }
+        Commands::VerifySeed { device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match show_seed(&device_pass) {
+                Ok(seed_phrase) => verify_seed_phrase(&seed_phrase.get_phrase())?,
+                Err(e) => println!("Failed to verify seed phrase: {}", e),
+            }
+        }
+        Commands::VerifyPassword { device_pass } => {
+            let device_pass = secret_or_prompt(device_pass, "Device password: ")?;
+            match verify_device_password(&device_pass) {
+                Ok(()) => println!("Device password is correct"),
+                Err(e) => {
+                    eprintln!("Failed to verify device password: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Completions { shell } => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
     };
 
     Ok(())
 }
+
+/// Unlock the vault with [`App::new`], offering to recover the local credentials
+/// when they're corrupted
+async fn open_app(device_pass: &str) -> anyhow::Result<App> {
+    match App::new(device_pass).await {
+        Ok(app) => Ok(app),
+        Err(err) => {
+            recover_app_data(err, device_pass).await?;
+            App::new(device_pass).await
+        }
+    }
+}
+
+/// Offer to restore corrupted local credentials from their backup or, failing
+/// that, to download them from the server again. Any other error, or declining
+/// both, returns `err` and leaves the credentials untouched.
+async fn recover_app_data(err: anyhow::Error, device_pass: &str) -> anyhow::Result<()> {
+    let Some(corrupted) = err.downcast_ref::<CorruptedAppData>() else {
+        return Err(err);
+    };
+    eprintln!("{}", corrupted);
+
+    if let Some(backup) = &corrupted.backup {
+        if confirm(&format!("Restore the credentials saved before from {}?", backup.display()))? {
+            match default_storage().restore_app_data_backup() {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("Failed to restore the backup: {}", e),
+            }
+        }
+    }
+
+    if confirm("Download the credentials from the server again?")? {
+        let count = pull_app_data(device_pass).await?;
+        println!("Downloaded {} password(s)", count);
+        return Ok(());
+    }
+
+    Err(err)
+}
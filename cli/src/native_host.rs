@@ -0,0 +1,258 @@
+use anyhow::format_err;
+use passphrasex::App;
+use passphrasex_common::model::password::Password;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Name browsers know the host by, in its manifest file name and `connectNative` calls
+pub const HOST_NAME: &str = "xyz.srosati.passphrasex";
+
+/// Largest message browsers accept from a native host
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+/// Largest message read from the extension, checked before allocating it.
+/// Requests are tiny, the same limit as responses is plenty.
+const MAX_REQUEST_BYTES: usize = MAX_RESPONSE_BYTES;
+
+/// Message from the extension
+#[derive(Debug, Deserialize)]
+enum HostRequest {
+    Unlock {
+        device_password: String,
+    },
+    ListCredentials {},
+    GetCredential {
+        site: String,
+        username: Option<String>,
+    },
+}
+
+/// Message to the extension
+#[derive(Debug, Serialize)]
+enum HostResponse {
+    Ok,
+    Credentials(Vec<Credential>),
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize)]
+struct Credential {
+    site: String,
+    username: String,
+    password: String,
+}
+
+impl From<Password> for Credential {
+    fn from(password: Password) -> Self {
+        Self {
+            site: password.site,
+            username: password.username,
+            password: password.password,
+        }
+    }
+}
+
+/// Whether `arg` is the origin browsers pass native hosts they start, e.g.
+/// `chrome-extension://<id>/`
+pub fn is_extension_origin(arg: &str) -> bool {
+    arg.starts_with("chrome-extension://") || arg.starts_with("moz-extension://")
+}
+
+/// Answer the extension's messages on stdin until the browser closes it. The
+/// vault is opened from this device only, without contacting the API, once
+/// an `Unlock` message brings the device password.
+pub async fn run() -> anyhow::Result<()> {
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    let mut app: Option<App> = None;
+
+    while let Some(message) = read_message(&mut stdin)? {
+        let response = match serde_json::from_slice::<HostRequest>(&message) {
+            Ok(request) => handle(&mut app, request).await,
+            Err(err) => HostResponse::Error {
+                message: format!("Invalid message: {}", err),
+            },
+        };
+        write_message(&mut stdout, &response)?;
+    }
+
+    Ok(())
+}
+
+async fn handle(app: &mut Option<App>, request: HostRequest) -> HostResponse {
+    let result = match request {
+        HostRequest::Unlock { device_password } => App::open(&device_password).map(|unlocked| {
+            *app = Some(unlocked);
+            HostResponse::Ok
+        }),
+        request => match app.as_mut() {
+            Some(app) => handle_unlocked(app, request).await,
+            None => Err(format_err!("Vault is locked")),
+        },
+    };
+
+    result.unwrap_or_else(|err| HostResponse::Error {
+        message: err.to_string(),
+    })
+}
+
+async fn handle_unlocked(app: &mut App, request: HostRequest) -> anyhow::Result<HostResponse> {
+    let passwords = match request {
+        HostRequest::ListCredentials {} => app.list(),
+        HostRequest::GetCredential { site, username } => app.get(site, username, false).await?,
+        // Handled by `handle`, whether the vault is locked or not
+        HostRequest::Unlock { .. } => return Ok(HostResponse::Ok),
+    };
+
+    Ok(HostResponse::Credentials(
+        passwords.into_iter().map(Credential::from).collect(),
+    ))
+}
+
+/// Read a message framed as native messaging does, with its length as a
+/// native-endian `u32` first. `None` once stdin is closed.
+fn read_message(reader: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => (),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let length = u32::from_ne_bytes(length) as usize;
+    if length > MAX_REQUEST_BYTES {
+        return Err(format_err!(
+            "Message of {} bytes is over the {} bytes limit",
+            length,
+            MAX_REQUEST_BYTES
+        ));
+    }
+
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+fn write_message(writer: &mut impl Write, response: &HostResponse) -> anyhow::Result<()> {
+    let mut message = serde_json::to_vec(response)?;
+    if message.len() > MAX_RESPONSE_BYTES {
+        message = serde_json::to_vec(&HostResponse::Error {
+            message: "Response is too large, get a single site instead".to_string(),
+        })?;
+    }
+
+    writer.write_all(&(message.len() as u32).to_ne_bytes())?;
+    writer.write_all(&message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Manifest registering this executable as the native host of the extension
+/// with `extension_id`, for the browser's `NativeMessagingHosts` directory.
+/// Firefox ids (`name@domain` or `{uuid}`) get the `allowed_extensions` it
+/// reads, any other is taken for a Chromium one.
+pub fn manifest(extension_id: &str) -> anyhow::Result<String> {
+    let path = env::current_exe()?;
+    let mut manifest = json!({
+        "name": HOST_NAME,
+        "description": "PassPhraseX",
+        "path": path,
+        "type": "stdio",
+    });
+    if is_firefox_id(extension_id) {
+        manifest["allowed_extensions"] = json!([extension_id]);
+    } else {
+        manifest["allowed_origins"] = json!([format!("chrome-extension://{}/", extension_id)]);
+    }
+
+    Ok(serde_json::to_string_pretty(&manifest)?)
+}
+
+fn is_firefox_id(extension_id: &str) -> bool {
+    extension_id.contains('@') || extension_id.starts_with('{')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn framed(message: &[u8]) -> Vec<u8> {
+        let mut bytes = (message.len() as u32).to_ne_bytes().to_vec();
+        bytes.extend_from_slice(message);
+        bytes
+    }
+
+    #[test]
+    fn messages_roundtrip() -> anyhow::Result<()> {
+        let mut written = Vec::new();
+        write_message(&mut written, &HostResponse::Ok)?;
+        assert_eq!(written, framed(br#""Ok""#));
+
+        let mut reader = &written[..];
+        assert_eq!(read_message(&mut reader)?, Some(br#""Ok""#.to_vec()));
+        assert_eq!(read_message(&mut reader)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_parsed() -> anyhow::Result<()> {
+        let bytes = framed(br#"{"GetCredential": {"site": "example.com", "username": null}}"#);
+        let message = read_message(&mut &bytes[..])?.unwrap();
+
+        let request: HostRequest = serde_json::from_slice(&message)?;
+        assert!(
+            matches!(request, HostRequest::GetCredential { site, .. } if site == "example.com")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn closed_stdin_ends_the_messages() -> anyhow::Result<()> {
+        assert_eq!(read_message(&mut &[][..])?, None);
+        // Closed halfway through a message
+        assert!(read_message(&mut &framed(b"{}")[..5]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let header = u32::MAX.to_ne_bytes();
+        assert!(read_message(&mut &header[..]).is_err());
+
+        let header = (MAX_REQUEST_BYTES as u32 + 1).to_ne_bytes();
+        assert!(read_message(&mut &header[..]).is_err());
+    }
+
+    #[test]
+    fn oversized_responses_become_errors() -> anyhow::Result<()> {
+        let credential = Credential {
+            site: "example.com".to_string(),
+            username: "alice".to_string(),
+            password: "x".repeat(MAX_RESPONSE_BYTES),
+        };
+        let mut written = Vec::new();
+        write_message(&mut written, &HostResponse::Credentials(vec![credential]))?;
+
+        let message = read_message(&mut &written[..])?.unwrap();
+        let response: Value = serde_json::from_slice(&message)?;
+        assert!(response["Error"]["message"].is_string());
+        Ok(())
+    }
+
+    #[test]
+    fn manifests_for_each_browser() -> anyhow::Result<()> {
+        let chrome: Value = serde_json::from_str(&manifest("abcdefghijklmnopabcdefghijklmnop")?)?;
+        assert_eq!(
+            chrome["allowed_origins"][0],
+            "chrome-extension://abcdefghijklmnopabcdefghijklmnop/"
+        );
+        assert!(chrome.get("allowed_extensions").is_none());
+
+        let firefox: Value = serde_json::from_str(&manifest("passphrasex@srosati.xyz")?)?;
+        assert_eq!(firefox["allowed_extensions"][0], "passphrasex@srosati.xyz");
+        assert!(firefox.get("allowed_origins").is_none());
+        Ok(())
+    }
+}
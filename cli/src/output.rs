@@ -0,0 +1,186 @@
+use anyhow::format_err;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::{Attribute, Cell, Color, ContentArrangement, Table};
+use passphrasex::HealthReport;
+use passphrasex_common::model::password::Password;
+use passphrasex_common::model::share::SharedContent;
+use serde_json::json;
+use std::cmp::Reverse;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+
+/// Shown instead of passwords unless asked for, always the same length to not give theirs away
+const MASKED_PASSWORD: &str = "********";
+
+/// How credentials are printed
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Format {
+    /// A table for people to read
+    #[default]
+    Table,
+    /// A JSON array of objects with `site`, `username` and `password`, for scripts
+    Json,
+}
+
+/// Print decrypted credentials sorted by site and username, with their
+/// passwords masked unless `show`
+pub fn print_credentials(
+    mut credentials: Vec<Password>,
+    format: Format,
+    show: bool,
+) -> anyhow::Result<()> {
+    // Same order as `App::get`, favorites first
+    credentials.sort_by(|a, b| {
+        (Reverse(a.favorite), &a.site, &a.username).cmp(&(
+            Reverse(b.favorite),
+            &b.site,
+            &b.username,
+        ))
+    });
+    let password = |credential: &Password| {
+        if show {
+            credential.password.clone()
+        } else {
+            MASKED_PASSWORD.to_string()
+        }
+    };
+
+    match format {
+        Format::Json => {
+            let credentials: Vec<_> = credentials
+                .iter()
+                .map(|credential| {
+                    json!({
+                        "site": credential.site,
+                        "username": credential.username,
+                        "password": password(credential),
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&credentials)?);
+        }
+        Format::Table => {
+            let mut table = new_table(["#", "Site", "Username", "Password"]);
+            // Numbered from 1, as `get --index` takes them
+            for (index, credential) in credentials.iter().enumerate() {
+                table.add_row([
+                    Cell::new(index + 1),
+                    Cell::new(&credential.site).fg(Color::Cyan),
+                    Cell::new(&credential.username),
+                    Cell::new(password(credential)),
+                ]);
+            }
+            println!("{}", table);
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy `text` to the clipboard through the terminal, by the OSC 52 escape
+/// sequence most terminal emulators (and tmux with `set-clipboard on`) support
+pub fn copy_to_clipboard(text: &str) -> anyhow::Result<()> {
+    if !io::stdout().is_terminal() {
+        return Err(format_err!(
+            "Can only copy to the clipboard from a terminal"
+        ));
+    }
+
+    let mut stdout = io::stdout();
+    write!(stdout, "\x1B]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Print the credentials shared with the user as a table, along with who shared
+/// each, with their passwords masked unless `show`
+pub fn print_shared(mut shared: Vec<(String, SharedContent)>, show: bool) {
+    shared.sort_by(|(_, a), (_, b)| (&a.site, &a.username).cmp(&(&b.site, &b.username)));
+
+    let mut table = new_table(["Site", "Username", "Password", "Shared by"]);
+    for (sender, content) in shared {
+        let password = if show {
+            content.password
+        } else {
+            MASKED_PASSWORD.to_string()
+        };
+        table.add_row([
+            Cell::new(content.site).fg(Color::Cyan),
+            Cell::new(content.username),
+            Cell::new(password),
+            Cell::new(sender),
+        ]);
+    }
+    println!("{}", table);
+}
+
+/// Print the previous passwords of a credential, newest first, masked unless `show`
+pub fn print_history(history: Vec<(String, u64)>, show: bool) {
+    if history.is_empty() {
+        println!("No previous passwords");
+        return;
+    }
+
+    let mut table = new_table(["#", "Replaced", "Password"]);
+    for (index, (password, age)) in history.into_iter().enumerate() {
+        let password = if show {
+            password
+        } else {
+            MASKED_PASSWORD.to_string()
+        };
+        table.add_row([
+            Cell::new(index + 1),
+            Cell::new(format!("{} days ago", age)),
+            Cell::new(password),
+        ]);
+    }
+    println!("{}", table);
+}
+
+/// Print the security dashboard: reused, weak and old passwords, in that order
+pub fn print_health_report(report: &HealthReport, max_age_days: u64) {
+    println!("Checked {} password(s)", report.total);
+
+    println!("\nReused passwords: {}", report.reused.len());
+    for credentials in &report.reused {
+        let credentials: Vec<String> = credentials
+            .iter()
+            .map(|(site, username)| format!("{} / {}", site, username))
+            .collect();
+        println!("  Same password on {}", credentials.join(", "));
+    }
+
+    println!("\nWeak passwords: {}", report.weak.len());
+    for (site, username, bits) in &report.weak {
+        println!("  {} / {}: about {:.0} bits", site, username, bits);
+    }
+
+    println!(
+        "\nPasswords older than {} days: {}",
+        max_age_days,
+        report.expiring.len()
+    );
+    for (site, username, age) in &report.expiring {
+        let age = match age {
+            Some(age) => format!("{} days", age),
+            None => "unknown age".to_string(),
+        };
+        println!("  {} / {}: {}", site, username, age);
+    }
+}
+
+fn new_table<const N: usize>(headers: [&str; N]) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(ContentArrangement::Dynamic);
+    // Colors are already left out when stdout isn't a terminal
+    if env::var_os("NO_COLOR").is_some() {
+        table.force_no_tty();
+    }
+
+    table.set_header(headers.map(|header| Cell::new(header).add_attribute(Attribute::Bold)));
+    table
+}
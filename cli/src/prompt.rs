@@ -0,0 +1,140 @@
+use anyhow::format_err;
+use rand::seq::index::sample;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// How many words of the seed phrase [`verify_seed_phrase`] asks for
+const SEED_WORDS_TO_VERIFY: usize = 3;
+
+/// Use the secret given on the command line or, when omitted, ask for it.
+///
+/// On a terminal the secret is read without echo, otherwise the next line of
+/// stdin is used so secrets can be piped in, one per line in the order asked.
+pub fn secret_or_prompt(value: Option<String>, prompt: &str) -> anyhow::Result<String> {
+    match value {
+        Some(value) => Ok(value),
+        None => read_secret(prompt),
+    }
+}
+
+/// Like [`secret_or_prompt`], but asks twice on a terminal to catch typos
+pub fn new_secret_or_prompt(value: Option<String>, prompt: &str) -> anyhow::Result<String> {
+    if value.is_some() || !io::stdin().is_terminal() {
+        return secret_or_prompt(value, prompt);
+    }
+
+    let secret = read_secret(prompt)?;
+    if read_secret("Confirm: ")? != secret {
+        return Err(format_err!("Values don't match"));
+    }
+
+    Ok(secret)
+}
+
+/// Use the value given on the command line or, when omitted, ask for it.
+/// An empty answer means no value.
+pub fn optional_or_prompt(value: Option<String>, prompt: &str) -> anyhow::Result<Option<String>> {
+    if value.is_some() {
+        return Ok(value);
+    }
+
+    let answer = read_line(prompt)?;
+    Ok(Some(answer.trim().to_string()).filter(|answer| !answer.is_empty()))
+}
+
+/// Ask a yes/no question, anything but `y` or `yes` counts as no
+pub fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    let answer = read_line(&format!("{} [y/N] ", prompt))?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Whether the user can answer prompts and see the menus they show, i.e. both
+/// stdin and stdout are terminals
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal()
+}
+
+/// Show `options` as a numbered menu and ask for one until a listed number is
+/// given, returning its position in `options`
+pub fn select(prompt: &str, options: &[String]) -> anyhow::Result<usize> {
+    for (index, option) in options.iter().enumerate() {
+        eprintln!("{:>3}) {}", index + 1, option);
+    }
+
+    loop {
+        let answer = read_line(&format!("{} [1-{}] ", prompt, options.len()))?;
+        match answer.trim().parse::<usize>() {
+            Ok(number) if (1..=options.len()).contains(&number) => return Ok(number - 1),
+            _ => eprintln!("Pick a number from 1 to {}", options.len()),
+        }
+    }
+}
+
+/// Ask for a few randomly picked words of `phrase` until they're all right,
+/// showing the phrase again after each miss
+pub fn verify_seed_phrase(phrase: &str) -> anyhow::Result<()> {
+    while !ask_seed_words(phrase)? {
+        println!("Some words don't match, your seed phrase is:\n{}", phrase);
+        hide_seed_phrase()?;
+    }
+
+    println!("Seed phrase verified");
+    Ok(())
+}
+
+/// Wait until the user wrote the seed phrase down, then clear it from the terminal
+pub fn hide_seed_phrase() -> anyhow::Result<()> {
+    read_line("Press enter once you've written it down")?;
+    if io::stdout().is_terminal() {
+        print!("\x1B[2J\x1B[1;1H");
+        io::stdout().flush()?;
+    }
+
+    Ok(())
+}
+
+/// Whether every asked word matches, without telling which one didn't
+fn ask_seed_words(phrase: &str) -> anyhow::Result<bool> {
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    let count = SEED_WORDS_TO_VERIFY.min(words.len());
+    let mut positions = sample(&mut rand::thread_rng(), words.len(), count).into_vec();
+    positions.sort();
+
+    let mut all_match = true;
+    for position in positions {
+        let answer = read_line(&format!("Word #{}: ", position + 1))?;
+        all_match &= answer.trim().eq_ignore_ascii_case(words[position]);
+    }
+
+    Ok(all_match)
+}
+
+fn read_line(prompt: &str) -> anyhow::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(format_err!(
+            "Expected a value on stdin for: {}",
+            prompt.trim()
+        ));
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_secret(prompt: &str) -> anyhow::Result<String> {
+    if io::stdin().is_terminal() {
+        return Ok(rpassword::prompt_password(prompt)?);
+    }
+
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(format_err!(
+            "Expected a value on stdin for: {}",
+            prompt.trim()
+        ));
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
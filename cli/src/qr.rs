@@ -0,0 +1,51 @@
+use anyhow::format_err;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+
+/// Pixels per QR module in saved images
+const PNG_MODULE_SIZE: u32 = 8;
+
+/// Print `data` as a QR code, only to a terminal so it doesn't end up in a
+/// file or pipe the user didn't ask for
+pub fn print_qr(data: &str) -> anyhow::Result<()> {
+    if !io::stdout().is_terminal() {
+        return Err(format_err!(
+            "Not printing a QR code outside a terminal, save it to a file instead"
+        ));
+    }
+
+    let code = QrCode::new(data)?;
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    println!("{}", image);
+
+    Ok(())
+}
+
+/// Save `data` as a QR code PNG at `path`, readable only by the current user
+pub fn save_qr_png(data: &str, path: &Path) -> anyhow::Result<()> {
+    let code = QrCode::new(data)?;
+    let image = code
+        .render::<image::Luma<u8>>()
+        .module_dimensions(PNG_MODULE_SIZE, PNG_MODULE_SIZE)
+        .build();
+
+    let mut bytes = Vec::new();
+    image.write_to(&mut io::Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(&bytes)?;
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use passphrasex::api::ApiError;
+use passphrasex::App;
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Random bytes in a bearer token
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Clone)]
+struct ServeState {
+    app: Arc<Mutex<App>>,
+    token: Arc<String>,
+}
+
+#[derive(Deserialize)]
+struct GetQuery {
+    username: Option<String>,
+    #[serde(default)]
+    strict: bool,
+}
+
+/// Random token clients have to send as `Authorization: Bearer <token>`
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Serve the unlocked vault's passwords to local tools until stopped. Only
+/// ever bound to the loopback interface, never to a public one.
+pub async fn serve(app: App, port: u16, token: String) -> anyhow::Result<()> {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    axum::Server::try_bind(&address)?
+        .serve(router(app, token).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+/// Routes answering only requests that carry `token`
+fn router(app: App, token: String) -> Router {
+    let state = ServeState {
+        app: Arc::new(Mutex::new(app)),
+        token: Arc::new(token),
+    };
+
+    Router::new()
+        .route("/sites", get(list_sites))
+        .route("/sites/:site", get(get_site))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+async fn require_token<B>(
+    State(state): State<ServeState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if !constant_time_eq(token.as_bytes(), state.token.as_bytes()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Compare without returning early, so response times don't give the token away
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn list_sites(State(state): State<ServeState>) -> Json<Vec<String>> {
+    Json(state.app.lock().await.sites())
+}
+
+/// The credentials of a site, in the same shape `get --format json` prints them
+async fn get_site(
+    State(state): State<ServeState>,
+    Path(site): Path<String>,
+    Query(query): Query<GetQuery>,
+) -> Response {
+    let mut app = state.app.lock().await;
+    match app.get(site, query.username, query.strict).await {
+        Ok(passwords) => {
+            let credentials: Vec<_> = passwords
+                .into_iter()
+                .map(|password| {
+                    json!({
+                        "site": password.site,
+                        "username": password.username,
+                        "password": password.password,
+                    })
+                })
+                .collect();
+            Json(credentials).into_response()
+        }
+        Err(err) => match err.downcast_ref::<ApiError>() {
+            Some(ApiError::NotFound(_)) => (StatusCode::NOT_FOUND, err.to_string()).into_response(),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use passphrasex::api::Api;
+    use passphrasex::storage::MemoryStorage;
+    use passphrasex::CredentialsMap;
+    use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
+    use tower::ServiceExt;
+
+    async fn status(authorization: Option<&str>) -> StatusCode {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let api = Api::with_base_url(key_pair.clone(), "http://127.0.0.1:9").unwrap();
+        let app = App::from_parts(
+            key_pair,
+            CredentialsMap::new(),
+            api,
+            Box::new(MemoryStorage::new()),
+        );
+
+        let mut request = Request::get("/sites");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let response = router(app, "token".to_string())
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_token() {
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer other")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Some("Bearer tokenx")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(Some("token")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some("Bearer token")).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn constant_time_eq_compares() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"", b"token"));
+    }
+}
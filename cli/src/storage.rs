@@ -0,0 +1,394 @@
+use crate::file::FileStorage;
+use crate::{CredentialsMap, APP_INFO};
+use anyhow::format_err;
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use passphrasex_common::crypto::asymmetric::SeedPhrase;
+use passphrasex_common::crypto::symmetric::{decrypt_data, encrypt_data};
+use passphrasex_common::model::password::Password;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use thiserror::Error;
+
+const KEYRING_USER: &str = "secret_key";
+const KEYRING_SEED_USER: &str = "seed_phrase";
+/// AES block size `encrypt_data` works in
+const SEED_BLOCK_SIZE: usize = 16;
+/// Bytes of the secret key's SHA-256 stored after it, a whole AES block
+const SK_CHECKSUM_BYTES: usize = 16;
+
+/// The storage selected with `PASSPHRASEX_STORAGE`: `file` (default) or
+/// `keyring`, which falls back to files when no keychain is available
+pub fn default_storage() -> Box<dyn VaultStorage> {
+    match env::var("PASSPHRASEX_STORAGE").as_deref() {
+        Ok("keyring") => match KeyringStorage::new() {
+            Ok(storage) => Box::new(storage),
+            Err(err) => {
+                eprintln!("Keychain unavailable ({}), storing the vault in files", err);
+                Box::new(FileStorage::new())
+            }
+        },
+        _ => Box::new(FileStorage::new()),
+    }
+}
+
+/// The stored credentials exist but can't be read, e.g. after a partial write
+/// or a manual edit
+#[derive(Debug, Error)]
+#[error("Local credentials in {} are corrupted: {reason}", path.display())]
+pub struct CorruptedAppData {
+    pub path: PathBuf,
+    /// Backup of the credentials last written before, when there's one
+    pub backup: Option<PathBuf>,
+    pub reason: String,
+}
+
+/// The stored credentials were written by a newer PassPhraseX, they're fine
+/// but this version can't read them
+#[derive(Debug, Error)]
+#[error("Stored in version {version} of the format, update PassPhraseX to read it")]
+pub struct NewerAppData {
+    pub version: u64,
+}
+
+/// A change made while the API was unreachable, kept until `App::sync` sends it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PendingChange {
+    /// Store an encrypted password
+    Add {
+        password: Password,
+        /// Sent with every attempt, so the API stores the password only once.
+        /// `None` for changes queued before keys were sent.
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    /// Replace the encrypted password of `password_id`
+    Edit {
+        password_id: String,
+        password: String,
+    },
+    Delete {
+        password_id: String,
+    },
+}
+
+/// Where a vault keeps its device password hash, secret key and credentials
+///
+/// The secret key and seed phrase are handed to backends already encrypted
+/// with the device password hash, see [`VaultStorage::write_sk`] and
+/// [`VaultStorage::write_seed`].
+pub trait VaultStorage: Send + Sync {
+    /// Whether a vault (device password hash and secret key) was stored
+    fn exists(&self) -> anyhow::Result<bool>;
+
+    /// Store the device password hash, a PHC string from `hash_password`
+    fn write_password_hash(&self, hash: &str) -> anyhow::Result<()>;
+
+    fn read_password_hash(&self) -> anyhow::Result<String>;
+
+    fn write_encrypted_sk(&self, enc: &[u8]) -> anyhow::Result<()>;
+
+    fn read_encrypted_sk(&self) -> anyhow::Result<Vec<u8>>;
+
+    fn write_encrypted_seed(&self, enc: &[u8]) -> anyhow::Result<()>;
+
+    /// Fails for vaults created before seed phrases were stored
+    fn read_encrypted_seed(&self) -> anyhow::Result<Vec<u8>>;
+
+    fn write_app_data(&self, data: &CredentialsMap) -> anyhow::Result<()>;
+
+    /// Fails with [`CorruptedAppData`] when the credentials can't be parsed, or
+    /// [`NewerAppData`] when they need a newer version to be read
+    fn read_app_data(&self) -> anyhow::Result<CredentialsMap>;
+
+    /// Replace the credentials with the [`CorruptedAppData::backup`]
+    fn restore_app_data_backup(&self) -> anyhow::Result<()> {
+        Err(format_err!("No backup of the local credentials"))
+    }
+
+    /// `ETag` of the credentials last synced with the API, `None` forgets it
+    fn write_etag(&self, etag: Option<&str>) -> anyhow::Result<()>;
+
+    fn read_etag(&self) -> anyhow::Result<Option<String>>;
+
+    /// Replace the queue of changes waiting for the API, oldest first
+    fn write_pending(&self, changes: &[PendingChange]) -> anyhow::Result<()>;
+
+    /// Empty when nothing is waiting
+    fn read_pending(&self) -> anyhow::Result<Vec<PendingChange>>;
+
+    /// Delete everything stored for the vault, overwriting files with zeros
+    /// first when `overwrite`. Nothing stored isn't an error.
+    fn purge(&self, overwrite: bool) -> anyhow::Result<()>;
+
+    fn write_sk(&self, sk: &[u8; 32], device_pass_hash: &str) -> anyhow::Result<()> {
+        let mut data = sk.to_vec();
+        data.extend_from_slice(&sk_checksum(sk));
+
+        let enc = encrypt_data(device_pass_hash, &data)?;
+        self.write_encrypted_sk(&enc)
+    }
+
+    /// The secret key, checked against the checksum stored with it so a
+    /// corrupted file fails here instead of unlocking a key pair that can't
+    /// decrypt anything
+    fn read_sk(&self, device_pass_hash: &str) -> anyhow::Result<[u8; 32]> {
+        let bytes = self.read_encrypted_sk()?;
+
+        let dec = decrypt_data(device_pass_hash, bytes)?;
+        // Keys stored before checksums were added are the 32 bytes alone
+        let checksum = match dec.len() {
+            32 => None,
+            len if len == 32 + SK_CHECKSUM_BYTES => Some(&dec[32..]),
+            _ => return Err(corrupted_sk()),
+        };
+
+        let mut content: [u8; 32] = [0; 32];
+        content.copy_from_slice(&dec[..32]);
+        if checksum.is_some_and(|checksum| checksum != sk_checksum(&content)) {
+            return Err(corrupted_sk());
+        }
+        Ok(content)
+    }
+
+    fn write_seed(&self, seed_phrase: &SeedPhrase, device_pass_hash: &str) -> anyhow::Result<()> {
+        // `encrypt_data` only works on whole blocks, pad with NULs (never part of a phrase)
+        let mut data = seed_phrase.get_phrase().into_bytes();
+        data.resize(data.len().div_ceil(SEED_BLOCK_SIZE) * SEED_BLOCK_SIZE, 0);
+
+        let enc = encrypt_data(device_pass_hash, &data)?;
+        self.write_encrypted_seed(&enc)
+    }
+
+    fn read_seed(&self, device_pass_hash: &str) -> anyhow::Result<SeedPhrase> {
+        let bytes = self.read_encrypted_seed()?;
+
+        let dec = decrypt_data(device_pass_hash, bytes)?;
+        let phrase = String::from_utf8(dec)?;
+        Ok(SeedPhrase::from(phrase.trim_end_matches('\0').to_string()))
+    }
+}
+
+fn sk_checksum(sk: &[u8; 32]) -> [u8; SK_CHECKSUM_BYTES] {
+    let mut checksum = [0; SK_CHECKSUM_BYTES];
+    checksum.copy_from_slice(&Sha256::digest(sk)[..SK_CHECKSUM_BYTES]);
+    checksum
+}
+
+fn corrupted_sk() -> anyhow::Error {
+    format_err!("Stored secret key is corrupted, log in again with the seed phrase to restore it")
+}
+
+/// Vault kept in memory, for embedding and tests
+#[derive(Default)]
+pub struct MemoryStorage {
+    password_hash: Mutex<Option<String>>,
+    sk: Mutex<Option<Vec<u8>>>,
+    seed: Mutex<Option<Vec<u8>>>,
+    app_data: Mutex<Option<CredentialsMap>>,
+    etag: Mutex<Option<String>>,
+    pending: Mutex<Vec<PendingChange>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl VaultStorage for MemoryStorage {
+    fn exists(&self) -> anyhow::Result<bool> {
+        Ok(lock(&self.password_hash)?.is_some() && lock(&self.sk)?.is_some())
+    }
+
+    fn write_password_hash(&self, hash: &str) -> anyhow::Result<()> {
+        *lock(&self.password_hash)? = Some(hash.to_string());
+        Ok(())
+    }
+
+    fn read_password_hash(&self) -> anyhow::Result<String> {
+        lock(&self.password_hash)?
+            .clone()
+            .ok_or(format_err!("No password hash stored"))
+    }
+
+    fn write_encrypted_sk(&self, enc: &[u8]) -> anyhow::Result<()> {
+        *lock(&self.sk)? = Some(enc.to_vec());
+        Ok(())
+    }
+
+    fn read_encrypted_sk(&self) -> anyhow::Result<Vec<u8>> {
+        lock(&self.sk)?
+            .clone()
+            .ok_or(format_err!("No secret key stored"))
+    }
+
+    fn write_encrypted_seed(&self, enc: &[u8]) -> anyhow::Result<()> {
+        *lock(&self.seed)? = Some(enc.to_vec());
+        Ok(())
+    }
+
+    fn read_encrypted_seed(&self) -> anyhow::Result<Vec<u8>> {
+        lock(&self.seed)?
+            .clone()
+            .ok_or(format_err!("No seed phrase stored"))
+    }
+
+    fn write_app_data(&self, data: &CredentialsMap) -> anyhow::Result<()> {
+        *lock(&self.app_data)? = Some(data.clone());
+        Ok(())
+    }
+
+    fn read_app_data(&self) -> anyhow::Result<CredentialsMap> {
+        lock(&self.app_data)?
+            .clone()
+            .ok_or(format_err!("No app data stored"))
+    }
+
+    fn write_etag(&self, etag: Option<&str>) -> anyhow::Result<()> {
+        *lock(&self.etag)? = etag.map(String::from);
+        Ok(())
+    }
+
+    fn read_etag(&self) -> anyhow::Result<Option<String>> {
+        Ok(lock(&self.etag)?.clone())
+    }
+
+    fn write_pending(&self, changes: &[PendingChange]) -> anyhow::Result<()> {
+        *lock(&self.pending)? = changes.to_vec();
+        Ok(())
+    }
+
+    fn read_pending(&self) -> anyhow::Result<Vec<PendingChange>> {
+        Ok(lock(&self.pending)?.clone())
+    }
+
+    fn purge(&self, _overwrite: bool) -> anyhow::Result<()> {
+        *lock(&self.password_hash)? = None;
+        *lock(&self.sk)? = None;
+        *lock(&self.seed)? = None;
+        *lock(&self.app_data)? = None;
+        *lock(&self.etag)? = None;
+        lock(&self.pending)?.clear();
+        Ok(())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> anyhow::Result<std::sync::MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| format_err!("Vault storage lock poisoned"))
+}
+
+/// Keeps the encrypted secret key and seed phrase in the platform keychain
+/// (macOS Keychain, Windows Credential Manager, Secret Service) and everything
+/// else in files
+pub struct KeyringStorage {
+    entry: keyring::Entry,
+    seed_entry: keyring::Entry,
+    files: FileStorage,
+}
+
+impl KeyringStorage {
+    /// Fails when the platform has no usable keychain
+    pub fn new() -> anyhow::Result<Self> {
+        let entry = keyring::Entry::new(APP_INFO.name, KEYRING_USER)?;
+        let seed_entry = keyring::Entry::new(APP_INFO.name, KEYRING_SEED_USER)?;
+
+        // Probe the keychain so a missing one is detected before registering
+        match entry.get_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(Self {
+                entry,
+                seed_entry,
+                files: FileStorage::new(),
+            }),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl VaultStorage for KeyringStorage {
+    fn exists(&self) -> anyhow::Result<bool> {
+        match self.entry.get_password() {
+            Ok(_) => Ok(self.files.read_password_hash().is_ok()),
+            Err(keyring::Error::NoEntry) => self.files.exists(),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write_password_hash(&self, hash: &str) -> anyhow::Result<()> {
+        self.files.write_password_hash(hash)
+    }
+
+    fn read_password_hash(&self) -> anyhow::Result<String> {
+        self.files.read_password_hash()
+    }
+
+    fn write_encrypted_sk(&self, enc: &[u8]) -> anyhow::Result<()> {
+        self.entry.set_password(&URL_SAFE.encode(enc))?;
+        Ok(())
+    }
+
+    fn read_encrypted_sk(&self) -> anyhow::Result<Vec<u8>> {
+        match self.entry.get_password() {
+            Ok(enc) => Ok(URL_SAFE.decode(enc)?),
+            // Vaults created before switching to the keychain
+            Err(keyring::Error::NoEntry) => self.files.read_encrypted_sk(),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write_encrypted_seed(&self, enc: &[u8]) -> anyhow::Result<()> {
+        self.seed_entry.set_password(&URL_SAFE.encode(enc))?;
+        Ok(())
+    }
+
+    fn read_encrypted_seed(&self) -> anyhow::Result<Vec<u8>> {
+        match self.seed_entry.get_password() {
+            Ok(enc) => Ok(URL_SAFE.decode(enc)?),
+            Err(keyring::Error::NoEntry) => self.files.read_encrypted_seed(),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write_app_data(&self, data: &CredentialsMap) -> anyhow::Result<()> {
+        self.files.write_app_data(data)
+    }
+
+    fn read_app_data(&self) -> anyhow::Result<CredentialsMap> {
+        self.files.read_app_data()
+    }
+
+    fn restore_app_data_backup(&self) -> anyhow::Result<()> {
+        self.files.restore_app_data_backup()
+    }
+
+    fn write_etag(&self, etag: Option<&str>) -> anyhow::Result<()> {
+        self.files.write_etag(etag)
+    }
+
+    fn read_etag(&self) -> anyhow::Result<Option<String>> {
+        self.files.read_etag()
+    }
+
+    fn write_pending(&self, changes: &[PendingChange]) -> anyhow::Result<()> {
+        self.files.write_pending(changes)
+    }
+
+    fn read_pending(&self) -> anyhow::Result<Vec<PendingChange>> {
+        self.files.read_pending()
+    }
+
+    fn purge(&self, overwrite: bool) -> anyhow::Result<()> {
+        for entry in [&self.entry, &self.seed_entry] {
+            match entry.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        // Also holds the files of vaults created before switching to the keychain
+        self.files.purge(overwrite)
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::process::Command;
+
+use passphrasex::api::Api;
+use passphrasex::file::FileStorage;
+use passphrasex::storage::VaultStorage;
+use passphrasex::App;
+use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
+use passphrasex_common::crypto::symmetric::{generate_salt, hash_password, password_hash_key};
+
+const DEVICE_PASS: &str = "device pass";
+const UNREACHABLE_API: &str = "http://127.0.0.1:9";
+
+#[tokio::test]
+async fn get_field_is_the_only_output_when_offline() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let storage = FileStorage::in_dir(dir.path());
+
+    let pass_hash = hash_password(DEVICE_PASS, &generate_salt()?)?;
+    let key = password_hash_key(&pass_hash)?;
+    let seed_phrase = SeedPhrase::new();
+    let key_pair = KeyPair::new(seed_phrase.clone(), None);
+    storage.write_password_hash(&pass_hash)?;
+    storage.write_sk(key_pair.private_key.as_bytes(), &key)?;
+    storage.write_seed(&seed_phrase, &key)?;
+    storage.write_app_data(&HashMap::new())?;
+
+    let api = Api::with_base_url(key_pair.clone(), UNREACHABLE_API)?;
+    let mut app = App::from_parts(key_pair, HashMap::new(), api, Box::new(storage));
+    app.add(
+        "example.com".to_string(),
+        "alice".to_string(),
+        "secret".to_string(),
+        None,
+        false,
+    )
+    .await?;
+
+    for (flag, expected) in [
+        ("--print-password", "secret"),
+        ("--print-username", "alice"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_passphrasex"))
+            .args([
+                "get",
+                "--site",
+                "example.com",
+                "--device-pass",
+                DEVICE_PASS,
+                flag,
+            ])
+            .env("PASSPHRASEX_DATA_DIR", dir.path())
+            .env("API_URI", UNREACHABLE_API)
+            .env("API_RETRY_ATTEMPTS", "1")
+            .output()?;
+
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(String::from_utf8(output.stdout)?, format!("{}\n", expected));
+        // The sync failure is still reported, just not where scripts read
+        assert!(String::from_utf8(output.stderr)?.contains("Failed to sync"));
+    }
+    Ok(())
+}
@@ -2,13 +2,18 @@ mod time;
 
 // Wrapper functions to call api
 use crate::crypto::asymmetric::KeyPair;
+use crate::model::auth::{AuthToken, Challenge};
 use crate::model::password::Password;
+use crate::model::user::User;
 use anyhow::format_err;
 use reqwest::{Client, Response, StatusCode, Url};
-use std::collections::HashMap;
 use std::env;
 use time::SystemTime;
 
+const DEFAULT_API_URL: &str = "https://api.passphrasex.srosati.xyz";
+/// Version of the API endpoints this client speaks
+const API_VERSION_PREFIX: &str = "/v1";
+
 #[derive(Clone)]
 pub struct Api {
     client: Client,
@@ -18,8 +23,7 @@ pub struct Api {
 
 impl Api {
     pub fn new(key_pair: KeyPair) -> Self {
-        let base_url =
-            env::var("API_URI").unwrap_or("https://api.passphrasex.srosati.xyz".to_string());
+        let base_url = env::var("API_URI").unwrap_or(DEFAULT_API_URL.to_string());
 
         Self {
             client: Client::new(),
@@ -28,42 +32,74 @@ impl Api {
         }
     }
 
-    pub async fn create_user(&self, public_key: String) -> anyhow::Result<()> {
-        let url = self.base_url.join("/users")?;
+    /// Client for the API server at `base_url`, which must pass [`parse_base_url`]
+    pub fn with_base_url(key_pair: KeyPair, base_url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: Client::new(),
+            base_url: parse_base_url(base_url)?,
+            key_pair,
+        })
+    }
+
+    /// URL of an API endpoint, given its unversioned path
+    fn endpoint(&self, path: &str) -> anyhow::Result<Url> {
+        Ok(self
+            .base_url
+            .join(&format!("{}{}", API_VERSION_PREFIX, path))?)
+    }
+
+    pub async fn create_user(&self, user: &User) -> anyhow::Result<()> {
+        let url = self.endpoint("/users")?;
 
-        let mut body = HashMap::new();
-        body.insert("public_key", public_key);
-        let res = self.client.post(url).json(&body).send().await?;
+        let res = self.client.post(url).json(user).send().await?;
         validate_response(res, StatusCode::CREATED).await
     }
 
-    pub async fn add_password(&self, public_key: String, password: Password) -> anyhow::Result<()> {
-        let url = self
-            .base_url
-            .join(&format!("/users/{}/passwords", public_key))?;
+    /// The account of `public_key`, with its email and display name
+    pub async fn get_user(&self, public_key: String) -> anyhow::Result<User> {
+        let url = self.endpoint(&format!("/users/{}", public_key))?;
 
-        let mut body = HashMap::new();
-        body.insert("_id", password._id);
-        body.insert("user_id", password.user_id);
-        body.insert("site", password.site);
-        body.insert("username", password.username);
-        body.insert("password", password.password);
+        let res = self
+            .client
+            .get(url)
+            .header("Authorization", self.auth_header())
+            .send()
+            .await?;
 
+        if res.status() != StatusCode::OK {
+            return Err(format_err!("Error from API: {}", res.text().await?));
+        }
+
+        Ok(res.json::<User>().await?)
+    }
+
+    /// Store an (already encrypted) password, returning it as stored by the API
+    pub async fn add_password(
+        &self,
+        public_key: String,
+        password: Password,
+    ) -> anyhow::Result<Password> {
+        let url = self.endpoint(&format!("/users/{}/passwords", public_key))?;
+
+        let auth_header = self.challenge_auth_header(&public_key).await?;
         let res = self
             .client
             .post(url)
-            .header("Authorization", self.auth_header())
-            .json(&body)
+            .header("Authorization", auth_header)
+            .json(&password)
             .send()
             .await?;
 
-        validate_response(res, StatusCode::CREATED).await
+        if res.status() != StatusCode::CREATED {
+            return Err(format_err!("Error from API: {}", res.text().await?));
+        }
+
+        let body = res.json::<Password>().await?;
+        Ok(body)
     }
 
     pub async fn get_passwords(&self, public_key: String) -> anyhow::Result<Vec<Password>> {
-        let url = self
-            .base_url
-            .join(&format!("/users/{}/passwords", public_key))?;
+        let url = self.endpoint(&format!("/users/{}/passwords", public_key))?;
 
         let res = self
             .client
@@ -89,15 +125,16 @@ impl Api {
         password_id: String,
         password: String,
     ) -> anyhow::Result<()> {
-        let url = self.base_url.join(&format!(
+        let url = self.endpoint(&format!(
             "/users/{}/passwords/{}/password",
             public_key, password_id
         ))?;
 
+        let auth_header = self.challenge_auth_header(&public_key).await?;
         let res = self
             .client
             .put(url)
-            .header("Authorization", self.auth_header())
+            .header("Authorization", auth_header)
             .body(password)
             .send()
             .await?;
@@ -110,20 +147,42 @@ impl Api {
         public_key: String,
         password_id: String,
     ) -> anyhow::Result<()> {
-        let url = self
-            .base_url
-            .join(&format!("/users/{}/passwords/{}", public_key, password_id))?;
+        let url = self.endpoint(&format!("/users/{}/passwords/{}", public_key, password_id))?;
 
+        let auth_header = self.challenge_auth_header(&public_key).await?;
         let res = self
             .client
             .delete(url)
-            .header("Authorization", self.auth_header())
+            .header("Authorization", auth_header)
             .send()
             .await?;
 
         validate_response(res, StatusCode::NO_CONTENT).await
     }
 
+    /// Auth header for one request that changes data, signing a challenge the
+    /// server just issued
+    async fn challenge_auth_header(&self, public_key: &str) -> anyhow::Result<String> {
+        let url = self.endpoint(&format!("/users/{}/challenge", public_key))?;
+
+        let res = self
+            .client
+            .get(url)
+            .header("Authorization", self.auth_header())
+            .send()
+            .await?;
+        if res.status() != StatusCode::OK {
+            return Err(format_err!("Error from API: {}", res.text().await?));
+        }
+
+        let challenge = res.json::<Challenge>().await?;
+        Ok(format!(
+            "Bearer {}",
+            AuthToken::new(&self.key_pair, &challenge.challenge)
+        ))
+    }
+
+    /// Auth header for reads, signing the current time
     fn auth_header(&self) -> String {
         format!("Bearer {}", self.auth_token())
     }
@@ -133,7 +192,21 @@ impl Api {
             .duration_since(time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        self.key_pair.sign(&time.to_string()).to_string()
+        AuthToken::new(&self.key_pair, &time.to_string()).to_string()
+    }
+}
+
+/// Parse the URL of an API server, which has to be an absolute http(s) URL
+pub fn parse_base_url(base_url: &str) -> anyhow::Result<Url> {
+    let url = Url::parse(base_url.trim())
+        .map_err(|err| format_err!("Invalid server URL {}: {}", base_url, err))?;
+
+    match url.scheme() {
+        "http" | "https" if url.has_host() => Ok(url),
+        _ => Err(format_err!(
+            "Invalid server URL {}: expected an http(s) address",
+            base_url
+        )),
     }
 }
 
@@ -1,9 +1,12 @@
 use crate::crypto::common::EncryptedValue;
 use crate::crypto::symmetric::hash;
 use base64::{engine::general_purpose::URL_SAFE, Engine};
-use bip32::{Mnemonic, XPrv};
+use bip32::{DerivationPath, Mnemonic, Seed, XPrv};
 use crypto_box::aead::{Aead, AeadCore, OsRng, Payload};
 use crypto_box::{ChaChaBox, Nonce, PublicKey, SecretKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::str;
 
 #[derive(Clone)]
@@ -19,6 +22,22 @@ impl SeedPhrase {
         }
     }
 
+    /// Parse a phrase as typed or pasted, with its words separated by any
+    /// whitespace and in any case, into its canonical form (lowercase words
+    /// separated by single spaces). Fails unless it's a valid BIP39 phrase,
+    /// checksum included.
+    pub fn validate(phrase: &str) -> anyhow::Result<SeedPhrase> {
+        let phrase = phrase
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(" ");
+        Mnemonic::new(&phrase, Default::default())
+            .map_err(|_| anyhow::format_err!("Invalid seed phrase"))?;
+
+        Ok(SeedPhrase { phrase })
+    }
+
     pub fn get_phrase(&self) -> String {
         self.phrase.clone()
     }
@@ -30,6 +49,12 @@ impl Default for SeedPhrase {
     }
 }
 
+impl fmt::Debug for SeedPhrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SeedPhrase([redacted])")
+    }
+}
+
 impl From<String> for SeedPhrase {
     fn from(value: String) -> SeedPhrase {
         SeedPhrase { phrase: value }
@@ -42,35 +67,56 @@ pub struct KeyPair {
     pub public_key: PublicKey,
 }
 
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("pk", &self.get_pk())
+            .field("sk", &format_args!("[redacted]"))
+            .finish()
+    }
+}
+
 /*
 * Implement asymmetric encryption functions for struct KeyPair
 * 2 methods -> encrypt & decrypt
 */
 impl KeyPair {
-    pub fn new(seed_phrase: SeedPhrase) -> KeyPair {
-        Self::try_new(seed_phrase).expect("Failed to create key pair")
+    pub fn new(seed_phrase: SeedPhrase, passphrase: Option<&str>) -> KeyPair {
+        Self::try_new(seed_phrase, passphrase).expect("Failed to create key pair")
     }
 
-    pub fn try_new(seed_phrase: SeedPhrase) -> anyhow::Result<KeyPair> {
-        // Get Mnemonic using the default language (English)
-        let mnemonic = Mnemonic::new(seed_phrase.get_phrase(), Default::default())
-            .map_err(|_| anyhow::format_err!("Failed to create mnemonic"))?;
-
-        // Derive a BIP39 seed value using the given password
-        let seed = mnemonic.to_seed("");
+    /// Derive the key pair of `seed_phrase`, along with the optional BIP39
+    /// `passphrase` (the "25th word"). Any passphrase derives a valid key pair,
+    /// so a mistyped one silently gives another, empty, vault.
+    pub fn try_new(seed_phrase: SeedPhrase, passphrase: Option<&str>) -> anyhow::Result<KeyPair> {
+        let seed = bip39_seed(&seed_phrase, passphrase)?;
 
         // Derive the root `XPrv` from the `seed` value
         let derived_sk =
             XPrv::new(&seed).map_err(|_| anyhow::format_err!("Failed to derive sk"))?;
 
         // Convert the `XPrv` to a `SecretKey` and `PublicKey`
-        let private_key = SecretKey::from(derived_sk.to_bytes());
-        let public_key = private_key.public_key();
+        Ok(Self::from_sk(derived_sk.to_bytes()))
+    }
 
-        Ok(KeyPair {
-            private_key,
-            public_key,
-        })
+    /// Derive the key pair of account `index` of `seed_phrase`, at the BIP32
+    /// path `m/<index>'`. Each index gives an independent vault, none of them
+    /// the one [`KeyPair::try_new`] derives from the root key.
+    pub fn from_seed_phrase_with_account(
+        seed_phrase: SeedPhrase,
+        passphrase: Option<&str>,
+        index: u32,
+    ) -> anyhow::Result<KeyPair> {
+        let seed = bip39_seed(&seed_phrase, passphrase)?;
+
+        // Hardened, so that one account's keys don't give the others away
+        let path: DerivationPath = format!("m/{}'", index)
+            .parse()
+            .map_err(|_| anyhow::format_err!("Invalid account index {}", index))?;
+        let derived_sk = XPrv::derive_from_path(&seed, &path)
+            .map_err(|_| anyhow::format_err!("Failed to derive sk"))?;
+
+        Ok(Self::from_sk(derived_sk.to_bytes()))
     }
 
     pub fn from_sk(sk: [u8; 32]) -> KeyPair {
@@ -83,10 +129,16 @@ impl KeyPair {
     }
 
     pub fn encrypt(&self, message: &str) -> EncryptedValue {
+        self.encrypt_for(&self.public_key, message)
+    }
+
+    /// Encrypt `message` so that only the owner of `recipient` can read it,
+    /// with [`KeyPair::decrypt_from`] and our public key
+    pub fn encrypt_for(&self, recipient: &PublicKey, message: &str) -> EncryptedValue {
         let nonce = ChaChaBox::generate_nonce(&mut OsRng);
 
-        let personal_box = ChaChaBox::new(&self.public_key, &self.private_key);
-        let enc = personal_box
+        let shared_box = ChaChaBox::new(recipient, &self.private_key);
+        let enc = shared_box
             .encrypt(
                 &nonce,
                 Payload {
@@ -101,16 +153,30 @@ impl KeyPair {
         }
     }
 
+    /// Decrypt a value from [`KeyPair::encrypt`]. An empty cipher, e.g. of a
+    /// field that was never set, decrypts to an empty string.
     pub fn decrypt(&self, enc: &EncryptedValue) -> String {
-        let cipher = URL_SAFE.decode(enc.cipher.as_bytes()).unwrap();
-        let personal_box = ChaChaBox::new(&self.public_key, &self.private_key);
-
-        let nonce = URL_SAFE
-            .decode(enc.nonce.as_bytes())
-            .expect("Failed to decode nonce");
-        let mut content: [u8; 24] = [0; 24];
-        content.copy_from_slice(&nonce);
-        let dec = personal_box
+        self.decrypt_from(&self.public_key, enc)
+            .expect("Failed to decrypt")
+    }
+
+    /// Decrypt a value the owner of `sender` encrypted for us with [`KeyPair::encrypt_for`]
+    pub fn decrypt_from(&self, sender: &PublicKey, enc: &EncryptedValue) -> anyhow::Result<String> {
+        // Every encrypted value has a tag, even an empty message's
+        if enc.cipher.trim().is_empty() {
+            return Ok(String::new());
+        }
+
+        let shared_box = ChaChaBox::new(sender, &self.private_key);
+
+        let nonce = URL_SAFE.decode(enc.nonce.as_bytes())?;
+        let content: [u8; 24] = nonce
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow::format_err!("Nonce must be 24 bytes long"))?;
+        let cipher = URL_SAFE.decode(enc.cipher.as_bytes())?;
+
+        let dec = shared_box
             .decrypt(
                 &Nonce::from(content),
                 Payload {
@@ -118,9 +184,8 @@ impl KeyPair {
                     aad: b"",
                 },
             )
-            .unwrap();
-
-        str::from_utf8(&dec).unwrap().to_owned()
+            .map_err(|_| anyhow::format_err!("Failed to decrypt"))?;
+        Ok(str::from_utf8(&dec)?.to_owned())
     }
 
     pub fn sign(&self, message: &str) -> EncryptedValue {
@@ -142,6 +207,27 @@ impl KeyPair {
         }
     }
 
+    /// Ed25519 key derived from the secret key, rather than reusing it for both
+    /// schemes. Unlike the boxes of [`KeyPair::sign`], which anyone knowing the
+    /// public key could forge since the zero key's secret is no secret, only the
+    /// owner can sign with it.
+    fn signing_key(&self) -> SigningKey {
+        let mut hasher = Sha256::new();
+        hasher.update(b"passphrasex-signing-key");
+        hasher.update(self.private_key.as_bytes());
+        SigningKey::from_bytes(&hasher.finalize().into())
+    }
+
+    /// Base64 of the Ed25519 public key that checks our detached signatures
+    pub fn get_signing_pk(&self) -> String {
+        URL_SAFE.encode(self.signing_key().verifying_key().as_bytes())
+    }
+
+    /// Base64 Ed25519 signature of `message`, checked with [`verify_detached`]
+    pub fn sign_detached(&self, message: &[u8]) -> String {
+        URL_SAFE.encode(self.signing_key().sign(message).to_bytes())
+    }
+
     pub fn hash(&self, message: &str) -> anyhow::Result<String> {
         Ok(hash(message, &URL_SAFE.encode(&self.public_key))
             .map_err(|_| anyhow::format_err!("Failed to hash"))?
@@ -153,21 +239,41 @@ impl KeyPair {
     }
 }
 
-pub fn public_key_from_base64(pk: &str) -> PublicKey {
-    let pk_bytes = URL_SAFE.decode(pk.as_bytes()).unwrap();
-    let mut buff: [u8; 32] = [0; 32];
-    buff.copy_from_slice(pk_bytes.as_slice());
-    PublicKey::from(buff)
+/// BIP39 seed of `seed_phrase` with the optional `passphrase`
+fn bip39_seed(seed_phrase: &SeedPhrase, passphrase: Option<&str>) -> anyhow::Result<Seed> {
+    // Get Mnemonic using the default language (English)
+    let mnemonic = Mnemonic::new(seed_phrase.get_phrase(), Default::default())
+        .map_err(|_| anyhow::format_err!("Failed to create mnemonic"))?;
+
+    // Derive a BIP39 seed value using the given passphrase, none by default
+    Ok(mnemonic.to_seed(passphrase.unwrap_or("")))
+}
+
+pub fn public_key_from_base64(pk: &str) -> anyhow::Result<PublicKey> {
+    let pk_bytes = URL_SAFE
+        .decode(pk.as_bytes())
+        .map_err(|_| anyhow::format_err!("Public key is not valid base64"))?;
+    let buff: [u8; 32] = pk_bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::format_err!("Public key must be 32 bytes long"))?;
+    Ok(PublicKey::from(buff))
 }
 
 pub fn verify(public_key: &PublicKey, value: EncryptedValue) -> anyhow::Result<String> {
     let verifiable_box = ChaChaBox::new(public_key, &SecretKey::from([0; 32]));
 
     let nonce = URL_SAFE.decode(value.nonce.as_bytes())?;
-    let mut content: [u8; 24] = [0; 24];
-    content.copy_from_slice(&nonce);
+    let content: [u8; 24] = nonce
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::format_err!("Nonce must be 24 bytes long"))?;
 
     let cipher = URL_SAFE.decode(value.cipher.as_bytes())?;
+    // The ciphertext always carries the 16 byte authentication tag
+    if cipher.len() < 16 {
+        return Err(anyhow::format_err!("Ciphertext is too short"));
+    }
     let payload = Payload {
         msg: cipher.as_slice(),
         aad: b"",
@@ -178,3 +284,62 @@ pub fn verify(public_key: &PublicKey, value: EncryptedValue) -> anyhow::Result<S
         Err(_) => Err(anyhow::format_err!("Failed to decrypt")),
     }
 }
+
+/// Ed25519 public key from its base64, as [`KeyPair::get_signing_pk`] gives it
+pub fn signing_key_from_base64(signing_pk: &str) -> anyhow::Result<VerifyingKey> {
+    let pk_bytes: [u8; 32] = URL_SAFE
+        .decode(signing_pk.as_bytes())
+        .map_err(|_| anyhow::format_err!("Signing key is not valid base64"))?
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::format_err!("Signing key must be 32 bytes long"))?;
+    VerifyingKey::from_bytes(&pk_bytes).map_err(|_| anyhow::format_err!("Invalid signing key"))
+}
+
+/// Check a [`KeyPair::sign_detached`] signature of `message` by the owner of
+/// the `signing_pk` Ed25519 public key
+pub fn verify_detached(signing_pk: &str, message: &[u8], signature: &str) -> anyhow::Result<()> {
+    let verifying_key = signing_key_from_base64(signing_pk)?;
+
+    let signature_bytes: [u8; 64] = URL_SAFE
+        .decode(signature.as_bytes())
+        .map_err(|_| anyhow::format_err!("Signature is not valid base64"))?
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::format_err!("Signature must be 64 bytes long"))?;
+
+    verifying_key
+        .verify(message, &Signature::from_bytes(&signature_bytes))
+        .map_err(|_| anyhow::format_err!("Signature doesn't match"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypt_empty_value() {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+
+        for cipher in ["", " "] {
+            let enc = EncryptedValue {
+                cipher: cipher.to_string(),
+                nonce: String::new(),
+            };
+            assert_eq!(key_pair.decrypt(&enc), "");
+        }
+        assert_eq!(key_pair.decrypt(&key_pair.encrypt("")), "");
+    }
+
+    #[test]
+    fn validate_seed_phrase() {
+        let phrase = SeedPhrase::new().get_phrase();
+        let pasted = format!("  {}\n", phrase.to_uppercase().replace(' ', " \t "));
+        assert_eq!(SeedPhrase::validate(&pasted).unwrap().get_phrase(), phrase);
+
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words.pop();
+        assert!(SeedPhrase::validate(&words.join(" ")).is_err());
+        assert!(SeedPhrase::validate("not a seed phrase").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
+use std::str::FromStr;
 
 #[derive(Debug, Serialize, Deserialize, Hash, Eq, PartialEq, Clone)]
 pub struct EncryptedValue {
@@ -7,11 +8,38 @@ pub struct EncryptedValue {
     pub nonce: String,
 }
 
+impl EncryptedValue {
+    /// Split `nonce.cipher`, or the `cipher;nonce` form values were stored in
+    /// before. Neither separator is in the base64url alphabet.
+    fn split(value: &str) -> Option<(&str, &str)> {
+        match value.split_once('.') {
+            Some((nonce, cipher)) => Some((cipher, nonce)),
+            None => value.split_once(';'),
+        }
+    }
+}
+
+impl FromStr for EncryptedValue {
+    type Err = anyhow::Error;
+
+    /// Parse the `nonce.cipher` form produced by `Display`, or the legacy
+    /// `cipher;nonce` one
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (cipher, nonce) =
+            Self::split(value).ok_or(anyhow::format_err!("Missing nonce in encrypted value"))?;
+
+        Ok(Self {
+            cipher: cipher.to_owned(),
+            nonce: nonce.to_owned(),
+        })
+    }
+}
+
 impl From<String> for EncryptedValue {
+    /// Like `parse`, but a value without a nonce (e.g. an empty field) gets
+    /// an empty one instead of failing
     fn from(value: String) -> Self {
-        let mut parts = value.split(';');
-        let cipher = parts.next().expect("Missing cipher");
-        let nonce = parts.next().expect("Missing nonce");
+        let (cipher, nonce) = Self::split(&value).unwrap_or((&value, ""));
         Self {
             cipher: cipher.to_owned(),
             nonce: nonce.to_owned(),
@@ -21,12 +49,141 @@ impl From<String> for EncryptedValue {
 
 impl From<EncryptedValue> for String {
     fn from(value: EncryptedValue) -> Self {
-        format!("{};{}", value.cipher, value.nonce)
+        value.to_string()
     }
 }
 
 impl Display for EncryptedValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{};{}", self.cipher, self.nonce)
+        write!(f, "{}.{}", self.nonce, self.cipher)
+    }
+}
+
+/// Serialize an [`EncryptedValue`] as a single `nonce.cipher` string instead of
+/// an object, for use with `#[serde(with = "compact")]`. Values serialized as
+/// objects or in the legacy `cipher;nonce` form are still read.
+pub mod compact {
+    use super::EncryptedValue;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AnyForm {
+        Compact(String),
+        Object(EncryptedValue),
+    }
+
+    impl AnyForm {
+        fn into_value<E: Error>(self) -> Result<EncryptedValue, E> {
+            match self {
+                AnyForm::Compact(value) => value.parse().map_err(E::custom),
+                AnyForm::Object(value) => Ok(value),
+            }
+        }
+    }
+
+    pub fn serialize<S>(value: &EncryptedValue, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<EncryptedValue, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        AnyForm::deserialize(deserializer)?.into_value()
+    }
+
+    /// Same for maps of encrypted values, e.g. one per recipient
+    pub mod map {
+        use super::*;
+
+        pub fn serialize<S>(
+            values: &HashMap<String, EncryptedValue>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            values
+                .iter()
+                .map(|(key, value)| (key, value.to_string()))
+                .collect::<HashMap<_, _>>()
+                .serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D>(
+            deserializer: D,
+        ) -> Result<HashMap<String, EncryptedValue>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            HashMap::<String, AnyForm>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(key, value)| Ok((key, value.into_value()?)))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn value() -> EncryptedValue {
+        EncryptedValue {
+            cipher: "Y2lwaGVy".to_string(),
+            nonce: "bm9uY2U=".to_string(),
+        }
+    }
+
+    #[test]
+    fn compact_string_roundtrip() {
+        assert_eq!(value().to_string(), "bm9uY2U=.Y2lwaGVy");
+        assert_eq!(
+            value().to_string().parse::<EncryptedValue>().unwrap(),
+            value()
+        );
+        assert_eq!(EncryptedValue::from(value().to_string()), value());
+    }
+
+    #[test]
+    fn legacy_strings_are_read() {
+        assert_eq!(
+            "Y2lwaGVy;bm9uY2U=".parse::<EncryptedValue>().unwrap(),
+            value()
+        );
+        assert_eq!(
+            EncryptedValue::from("Y2lwaGVy;bm9uY2U=".to_string()),
+            value()
+        );
+        assert!("Y2lwaGVy".parse::<EncryptedValue>().is_err());
+        assert_eq!(EncryptedValue::from(String::new()).cipher, "");
+    }
+
+    #[test]
+    fn compact_serde() {
+        #[derive(Serialize, Deserialize)]
+        struct Stored {
+            #[serde(with = "compact")]
+            value: EncryptedValue,
+        }
+
+        let stored = serde_json::to_value(Stored { value: value() }).unwrap();
+        assert_eq!(stored, json!({"value": "bm9uY2U=.Y2lwaGVy"}));
+
+        for stored in [
+            stored,
+            json!({"value": "Y2lwaGVy;bm9uY2U="}),
+            json!({"value": {"cipher": "Y2lwaGVy", "nonce": "bm9uY2U="}}),
+        ] {
+            let read: Stored = serde_json::from_value(stored).unwrap();
+            assert_eq!(read.value, value());
+        }
     }
 }
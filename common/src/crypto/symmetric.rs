@@ -3,14 +3,41 @@ use aes::cipher::generic_array::GenericArray;
 use aes::cipher::{BlockDecrypt, BlockEncrypt, BlockSizeUser};
 use aes::Aes256;
 use anyhow::format_err;
-use argon2::{self, hash_raw, verify_raw, Config};
+use argon2::{self, hash_encoded, hash_raw, verify_encoded, Config, Variant};
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::XChaCha20Poly1305;
 use crypto_box::aead::KeyInit;
+use std::io::{ErrorKind, Read, Write};
 
-use base64::{engine::general_purpose::URL_SAFE, Engine};
+use base64::{
+    engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE},
+    Engine,
+};
 use rand_core::{OsRng, RngCore};
+
+/// Bytes of the salts from [`generate_salt`], before their URL-safe base64
+/// encoding. Every client must generate salts the same way, the vault of one
+/// is unlocked by the others.
+pub const SALT_BYTES: usize = 16;
+/// Shortest salt [`hash`] and [`hash_password`] accept, the length Argon2
+/// recommends
+pub const MIN_SALT_BYTES: usize = 16;
+const KEY_BYTES: usize = 32;
+/// Bytes of plaintext in each chunk of [`encrypt_stream`], the last one is shorter
+pub const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+/// Poly1305 tag added to each chunk
+const STREAM_TAG_BYTES: usize = 16;
+/// Random nonce prefix starting a stream, the XChaCha20 nonce without the
+/// 4-byte chunk counter and 1-byte last chunk flag STREAM adds
+const STREAM_NONCE_BYTES: usize = 19;
+
+const _: () = assert!(SALT_BYTES >= MIN_SALT_BYTES);
+
+/// Argon2 hash of `message`, with the URL-safe base64 `salt` of at least
+/// [`MIN_SALT_BYTES`]
 pub fn hash(message: &str, salt: &str) -> anyhow::Result<EncryptedValue> {
     let config = Config::default();
-    let salt = URL_SAFE.decode(salt)?;
+    let salt = decode_salt(salt)?;
 
     let hash = hash_raw(message.as_bytes(), salt.as_slice(), &config)?;
     Ok(EncryptedValue {
@@ -19,33 +46,88 @@ pub fn hash(message: &str, salt: &str) -> anyhow::Result<EncryptedValue> {
     })
 }
 
-pub fn verify_password(password: &str, hash: &str, salt: &str) -> anyhow::Result<()> {
-    let hash = URL_SAFE.decode(hash)?;
+/// Argon2id hash of a device password as a PHC string, e.g.
+/// `$argon2id$v=19$m=4096,t=3,p=1$<salt>$<hash>`, which carries the
+/// parameters [`verify_password`] needs to check it
+pub fn hash_password(password: &str, salt: &str) -> anyhow::Result<String> {
+    let config = Config {
+        variant: Variant::Argon2id,
+        ..Config::default()
+    };
+    let salt = decode_salt(salt)?;
 
-    let salt = URL_SAFE.decode(salt)?;
+    Ok(hash_encoded(password.as_bytes(), salt.as_slice(), &config)?)
+}
 
-    let config = Config::default();
-    let valid = verify_raw(
-        password.as_bytes(),
-        salt.as_slice(),
-        hash.as_slice(),
-        &config,
-    )?;
-
-    if valid {
+/// Check `password` against a PHC string from [`hash_password`], with the
+/// parameters it was hashed with. The hashes are compared in constant time by
+/// the Argon2 verifier, never with `==`, so how long a wrong password takes
+/// to be refused tells nothing about how close it was.
+pub fn verify_password(password: &str, encoded: &str) -> anyhow::Result<()> {
+    if verify_encoded(encoded, password.as_bytes())? {
         Ok(())
     } else {
         Err(format_err!("Invalid password"))
     }
 }
 
-const SALT_BYTES: usize = 16;
+/// The hash in a PHC string from [`hash_password`], URL-safe base64 encoded as
+/// the key `encrypt_data` and `decrypt_data` take
+pub fn password_hash_key(encoded: &str) -> anyhow::Result<String> {
+    let hash = encoded
+        .rsplit('$')
+        .next()
+        .filter(|hash| !hash.is_empty())
+        .ok_or(format_err!("Invalid password hash"))?;
+
+    Ok(URL_SAFE.encode(STANDARD_NO_PAD.decode(hash)?))
+}
+
+/// PHC string of a password hashed by [`hash`], as device passwords were
+/// before their parameters were stored, so [`verify_password`] checks it
+pub fn encode_legacy_hash(hash: &EncryptedValue) -> anyhow::Result<String> {
+    let config = Config::default();
+    let salt = decode_salt(&hash.nonce)?;
+    let hash = URL_SAFE.decode(&hash.cipher)?;
+
+    Ok(format!(
+        "$argon2i$v={}$m={},t={},p={}${}${}",
+        config.version.as_u32(),
+        config.mem_cost,
+        config.time_cost,
+        config.lanes,
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    ))
+}
+
+/// Random salt of [`SALT_BYTES`], URL-safe base64 encoded as [`hash`] takes it
 pub fn generate_salt() -> anyhow::Result<String> {
     let mut salt: [u8; SALT_BYTES] = [0; SALT_BYTES];
     OsRng.fill_bytes(&mut salt);
     Ok(URL_SAFE.encode(salt.as_slice()))
 }
 
+fn decode_salt(salt: &str) -> anyhow::Result<Vec<u8>> {
+    let salt = URL_SAFE.decode(salt)?;
+    if salt.len() < MIN_SALT_BYTES {
+        return Err(format_err!(
+            "Salt must be at least {} bytes long, got {}",
+            MIN_SALT_BYTES,
+            salt.len()
+        ));
+    }
+
+    Ok(salt)
+}
+
+/// Random AES-256 key, encoded as `encrypt_data` and `decrypt_data` take it
+pub fn generate_key() -> anyhow::Result<String> {
+    let mut key: [u8; KEY_BYTES] = [0; KEY_BYTES];
+    OsRng.fill_bytes(&mut key);
+    Ok(URL_SAFE.encode(key.as_slice()))
+}
+
 pub fn encrypt_data(key: &str, data: &[u8]) -> anyhow::Result<Vec<u8>> {
     let key = URL_SAFE.decode(key)?;
     let aes = match Aes256::new_from_slice(key.as_slice()) {
@@ -90,3 +172,236 @@ pub fn decrypt_data(key: &str, enc: Vec<u8>) -> anyhow::Result<Vec<u8>> {
 
     Ok(dec)
 }
+
+/// Encrypt everything `reader` gives into `writer` with `key` (as
+/// [`generate_key`] makes them), a [`STREAM_CHUNK_BYTES`] chunk at a time so
+/// large values are never held in memory whole.
+///
+/// Writes a random nonce prefix, then each chunk encrypted with
+/// XChaCha20-Poly1305 under its own nonce (the STREAM construction: the prefix,
+/// the chunk's position and whether it's the last). Reordered, dropped or
+/// truncated chunks fail [`decrypt_stream`]. Small values should keep using
+/// [`encrypt_data`].
+pub fn encrypt_stream(
+    key: &str,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let aead = stream_cipher(key)?;
+    let mut nonce = [0u8; STREAM_NONCE_BYTES];
+    OsRng.fill_bytes(&mut nonce);
+    writer.write_all(&nonce)?;
+
+    let mut encryptor = EncryptorBE32::from_aead(aead, nonce.as_slice().into());
+    let mut chunk = vec![0u8; STREAM_CHUNK_BYTES];
+    loop {
+        let len = read_chunk(reader, &mut chunk)?;
+        // Only the last chunk is short, empty when the data fills the ones before
+        if len < STREAM_CHUNK_BYTES {
+            let enc = encryptor
+                .encrypt_last(&chunk[..len])
+                .map_err(|_| format_err!("Failed to encrypt"))?;
+            writer.write_all(&enc)?;
+            break;
+        }
+
+        let enc = encryptor
+            .encrypt_next(chunk.as_slice())
+            .map_err(|_| format_err!("Failed to encrypt"))?;
+        writer.write_all(&enc)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypt what [`encrypt_stream`] wrote into `writer`, a chunk at a time.
+/// Fails on the first chunk that wasn't encrypted with `key` or was tampered
+/// with, after writing the chunks before it.
+pub fn decrypt_stream(
+    key: &str,
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let aead = stream_cipher(key)?;
+    let mut nonce = [0u8; STREAM_NONCE_BYTES];
+    if read_chunk(reader, &mut nonce)? < STREAM_NONCE_BYTES {
+        return Err(format_err!("Encrypted stream is too short"));
+    }
+
+    let mut decryptor = DecryptorBE32::from_aead(aead, nonce.as_slice().into());
+    let mut chunk = vec![0u8; STREAM_CHUNK_BYTES + STREAM_TAG_BYTES];
+    loop {
+        let len = read_chunk(reader, &mut chunk)?;
+        if len < chunk.len() {
+            let dec = decryptor
+                .decrypt_last(&chunk[..len])
+                .map_err(|_| format_err!("Invalid key or corrupted data"))?;
+            writer.write_all(&dec)?;
+            break;
+        }
+
+        let dec = decryptor
+            .decrypt_next(chunk.as_slice())
+            .map_err(|_| format_err!("Invalid key or corrupted data"))?;
+        writer.write_all(&dec)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn stream_cipher(key: &str) -> anyhow::Result<XChaCha20Poly1305> {
+    let key = URL_SAFE.decode(key)?;
+    XChaCha20Poly1305::new_from_slice(key.as_slice()).map_err(|_| format_err!("Invalid key"))
+}
+
+/// Fill `buf` from `reader`, short only at the end of the data, returning how
+/// many bytes were read
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> anyhow::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn time_verify(password: &str, encoded: &str) -> Duration {
+        let start = Instant::now();
+        let _ = verify_password(password, encoded);
+        start.elapsed()
+    }
+
+    #[test]
+    fn verify_password_checks_the_password() {
+        let encoded = hash_password("correct horse", &generate_salt().unwrap()).unwrap();
+
+        assert!(verify_password("correct horse", &encoded).is_ok());
+        assert!(verify_password("correct hors", &encoded).is_err());
+        assert!(verify_password("", &encoded).is_err());
+    }
+
+    // Timing depends on the machine's load, run it on purpose with `--ignored`
+    #[test]
+    #[ignore]
+    fn verify_password_takes_as_long_for_wrong_passwords() {
+        let encoded = hash_password("correct horse", &generate_salt().unwrap()).unwrap();
+
+        // Interleaved so load on the machine affects both alike
+        let mut correct = Duration::ZERO;
+        let mut wrong = Duration::ZERO;
+        for _ in 0..5 {
+            correct += time_verify("correct horse", &encoded);
+            wrong += time_verify("correct horsf", &encoded);
+            wrong += time_verify("x", &encoded);
+        }
+        let wrong = wrong / 2;
+
+        // The hashing dominates, an early exit would make wrong ones much faster
+        let ratio = wrong.as_secs_f64() / correct.as_secs_f64();
+        assert!(
+            (0.5..2.0).contains(&ratio),
+            "wrong passwords took {:?}, correct ones {:?}",
+            wrong,
+            correct
+        );
+    }
+
+    fn encrypt_all(key: &str, data: &[u8]) -> Vec<u8> {
+        let mut enc = Vec::new();
+        encrypt_stream(key, &mut &data[..], &mut enc).unwrap();
+        enc
+    }
+
+    fn decrypt_all(key: &str, enc: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut dec = Vec::new();
+        decrypt_stream(key, &mut &enc[..], &mut dec)?;
+        Ok(dec)
+    }
+
+    /// Offset of chunk `index` in a stream, after the nonce prefix
+    fn chunk_offset(index: usize) -> usize {
+        STREAM_NONCE_BYTES + index * (STREAM_CHUNK_BYTES + STREAM_TAG_BYTES)
+    }
+
+    #[test]
+    fn streams_roundtrip() {
+        let key = generate_key().unwrap();
+        for len in [
+            0,
+            1,
+            STREAM_CHUNK_BYTES - 1,
+            STREAM_CHUNK_BYTES,
+            STREAM_CHUNK_BYTES + 1,
+            3 * STREAM_CHUNK_BYTES,
+        ] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let enc = encrypt_all(&key, &data);
+
+            // A full chunk is followed by an empty last one
+            let chunks = len / STREAM_CHUNK_BYTES + 1;
+            assert_eq!(
+                enc.len(),
+                STREAM_NONCE_BYTES + len + chunks * STREAM_TAG_BYTES
+            );
+            assert_eq!(decrypt_all(&key, &enc).unwrap(), data, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn streams_need_the_key() {
+        let enc = encrypt_all(&generate_key().unwrap(), b"secret");
+
+        assert!(decrypt_all(&generate_key().unwrap(), &enc).is_err());
+    }
+
+    #[test]
+    fn tampered_chunks_are_rejected() {
+        let key = generate_key().unwrap();
+        let mut enc = encrypt_all(&key, &vec![7; 2 * STREAM_CHUNK_BYTES + 10]);
+
+        enc[chunk_offset(1) + 5] ^= 1;
+        assert!(decrypt_all(&key, &enc).is_err());
+    }
+
+    #[test]
+    fn truncated_streams_are_rejected() {
+        let key = generate_key().unwrap();
+        let enc = encrypt_all(&key, &vec![7; 2 * STREAM_CHUNK_BYTES + 10]);
+
+        // Missing the last chunk, or cut short in it
+        assert!(decrypt_all(&key, &enc[..chunk_offset(2)]).is_err());
+        assert!(decrypt_all(&key, &enc[..enc.len() - 1]).is_err());
+        assert!(decrypt_all(&key, &enc[..STREAM_NONCE_BYTES - 1]).is_err());
+
+        // Ending on a full chunk, the empty last one dropped
+        let enc = encrypt_all(&key, &vec![7; STREAM_CHUNK_BYTES]);
+        assert!(decrypt_all(&key, &enc[..chunk_offset(1)]).is_err());
+    }
+
+    #[test]
+    fn reordered_chunks_are_rejected() {
+        let key = generate_key().unwrap();
+        let data: Vec<u8> = (0..3 * STREAM_CHUNK_BYTES)
+            .map(|i| (i / 1000) as u8)
+            .collect();
+        let enc = encrypt_all(&key, &data);
+
+        let mut swapped = enc[..chunk_offset(0)].to_vec();
+        swapped.extend_from_slice(&enc[chunk_offset(1)..chunk_offset(2)]);
+        swapped.extend_from_slice(&enc[chunk_offset(0)..chunk_offset(1)]);
+        swapped.extend_from_slice(&enc[chunk_offset(2)..]);
+        assert!(decrypt_all(&key, &swapped).is_err());
+    }
+}
@@ -1,7 +1,64 @@
 pub mod dist;
 
+use anyhow::anyhow;
+use bip32::{Language, Mnemonic};
 use dist::PasswordDist;
 use rand::distributions::DistString;
+use rand::seq::SliceRandom;
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+
+const UPPERCASE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const LOWERCASE: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const DIGITS: &[u8] = b"0123456789";
+/// Symbols most sites accept in passwords, leaving out quotes, brackets and
+/// slashes that some forms reject
+const SYMBOLS: &[u8] = b"!#$%&*+-.=?@^_~";
+
+/// Printable ASCII characters that are neither letters nor digits
+const ASCII_SYMBOLS: usize = 33;
+
+/// Passphrase words are drawn from the BIP39 English word list
+const PASSPHRASE_WORDLIST_SIZE: u16 = 2048;
+/// Leading words of a 24 word mnemonic that only encode entropy, the last one
+/// also encodes the checksum
+const MNEMONIC_RANDOM_WORDS: usize = 23;
+
+/// Character classes a generated password is drawn from. Classes left out
+/// when deserializing are enabled, like in [`PasswordOptions::default`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordOptions {
+    pub uppercase: bool,
+    pub lowercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+}
+
+impl Default for PasswordOptions {
+    fn default() -> Self {
+        Self {
+            uppercase: true,
+            lowercase: true,
+            digits: true,
+            symbols: true,
+        }
+    }
+}
+
+impl PasswordOptions {
+    fn classes(&self) -> Vec<&'static [u8]> {
+        [
+            (self.uppercase, UPPERCASE),
+            (self.lowercase, LOWERCASE),
+            (self.digits, DIGITS),
+            (self.symbols, SYMBOLS),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, class)| enabled.then_some(class))
+        .collect()
+    }
+}
 
 pub fn generate_password(length: usize) -> String {
     let mut rng = rand::thread_rng();
@@ -9,3 +66,179 @@ pub fn generate_password(length: usize) -> String {
     PasswordDist.append_string(&mut rng, &mut password, length);
     password
 }
+
+/// Generate a password using only the enabled character classes, containing at
+/// least one character of each of them when the length allows it
+pub fn generate_password_with(length: usize, opts: &PasswordOptions) -> anyhow::Result<String> {
+    let classes = opts.classes();
+    if classes.is_empty() {
+        return Err(anyhow!("At least one character class must be enabled"));
+    }
+    if length == 0 {
+        return Err(anyhow!("Password length must be greater than 0"));
+    }
+
+    let charset: Vec<u8> = classes.concat();
+    let mut rng = rand::thread_rng();
+
+    loop {
+        let password: Vec<u8> = (0..length)
+            .map(|_| *charset.choose(&mut rng).unwrap())
+            .collect();
+
+        let has_every_class = classes
+            .iter()
+            .all(|class| password.iter().any(|c| class.contains(c)));
+        if has_every_class || length < classes.len() {
+            return Ok(password.into_iter().map(char::from).collect());
+        }
+    }
+}
+
+/// Generate a diceware-style passphrase of `words` random words joined by `separator`
+pub fn generate_passphrase(words: usize, separator: &str) -> anyhow::Result<String> {
+    if words == 0 {
+        return Err(anyhow!("Passphrase must have at least one word"));
+    }
+
+    let mut phrase: Vec<String> = Vec::with_capacity(words);
+    while phrase.len() < words {
+        let mnemonic = Mnemonic::random(OsRng, Language::English);
+        let missing = (words - phrase.len()).min(MNEMONIC_RANDOM_WORDS);
+        phrase.extend(
+            mnemonic
+                .phrase()
+                .split(' ')
+                .take(missing)
+                .map(str::to_string),
+        );
+    }
+    Ok(phrase.join(separator))
+}
+
+/// Bits of entropy of a password from [`generate_password_with`], ignoring
+/// the small loss from requiring every character class
+pub fn password_entropy(length: usize, opts: &PasswordOptions) -> f64 {
+    let charset: usize = opts.classes().iter().map(|class| class.len()).sum();
+    if charset == 0 {
+        return 0.0;
+    }
+
+    length as f64 * (charset as f64).log2()
+}
+
+/// Bits of entropy of a passphrase from [`generate_passphrase`]
+pub fn passphrase_entropy(words: usize) -> f64 {
+    words as f64 * f64::from(PASSPHRASE_WORDLIST_SIZE).log2()
+}
+
+/// Rough bits of entropy of any password, as if it was drawn at random from
+/// the character classes it uses. An upper bound: words, names and patterns
+/// make real passwords weaker than this.
+pub fn estimate_entropy(password: &str) -> f64 {
+    let has = |matches: fn(&char) -> bool| password.chars().any(|c| matches(&c));
+    let charset = [
+        (has(char::is_ascii_uppercase), UPPERCASE.len()),
+        (has(char::is_ascii_lowercase), LOWERCASE.len()),
+        (has(char::is_ascii_digit), DIGITS.len()),
+        (has(|c| !c.is_ascii_alphanumeric()), ASCII_SYMBOLS),
+    ]
+    .into_iter()
+    .filter_map(|(used, size)| used.then_some(size))
+    .sum::<usize>();
+    if charset == 0 {
+        return 0.0;
+    }
+
+    password.chars().count() as f64 * (charset as f64).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(class: &'static [u8]) -> impl Fn(char) -> bool {
+        move |c| class.contains(&(c as u8))
+    }
+
+    #[test]
+    fn password_has_length_and_every_class() {
+        for length in [4, 16, 64] {
+            let password = generate_password_with(length, &PasswordOptions::default()).unwrap();
+
+            assert_eq!(password.len(), length);
+            for class in [UPPERCASE, LOWERCASE, DIGITS, SYMBOLS] {
+                assert!(password.chars().any(only(class)), "{password}");
+            }
+        }
+    }
+
+    #[test]
+    fn password_uses_only_enabled_classes() {
+        let opts = PasswordOptions {
+            uppercase: false,
+            lowercase: true,
+            digits: true,
+            symbols: false,
+        };
+        let password = generate_password_with(32, &opts).unwrap();
+
+        assert!(password
+            .chars()
+            .all(|c| only(LOWERCASE)(c) || only(DIGITS)(c)));
+    }
+
+    #[test]
+    fn password_needs_a_class_and_a_length() {
+        let none = PasswordOptions {
+            uppercase: false,
+            lowercase: false,
+            digits: false,
+            symbols: false,
+        };
+
+        assert!(generate_password_with(16, &none).is_err());
+        assert!(generate_password_with(0, &PasswordOptions::default()).is_err());
+    }
+
+    #[test]
+    fn options_default_to_every_class() {
+        let opts: PasswordOptions = serde_json::from_str(r#"{"symbols": false}"#).unwrap();
+
+        assert_eq!(
+            opts,
+            PasswordOptions {
+                symbols: false,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn passphrase_has_the_words() {
+        let phrase = generate_passphrase(30, "-").unwrap();
+
+        assert_eq!(phrase.split('-').count(), 30);
+        assert!(generate_passphrase(0, "-").is_err());
+    }
+
+    #[test]
+    fn entropy() {
+        let digits = PasswordOptions {
+            uppercase: false,
+            lowercase: false,
+            digits: true,
+            symbols: false,
+        };
+        let all = UPPERCASE.len() + LOWERCASE.len() + DIGITS.len() + SYMBOLS.len();
+
+        assert!((password_entropy(10, &digits) - 10.0 * 10f64.log2()).abs() < 1e-9);
+        assert!(
+            (password_entropy(16, &PasswordOptions::default()) - 16.0 * (all as f64).log2()).abs()
+                < 1e-9
+        );
+        assert_eq!(passphrase_entropy(6), 66.0);
+        assert_eq!(estimate_entropy(""), 0.0);
+        assert_eq!(estimate_entropy("1234"), 4.0 * 10f64.log2());
+    }
+}
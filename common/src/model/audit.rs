@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+/// A read or change of a user's passwords, recorded by the server. The log
+/// only grows: entries are never edited, and expire after a retention period.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    /// Owner of the passwords
+    pub user_id: String,
+    /// The password read or changed, `None` for actions on all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_id: Option<String>,
+    /// Id the password had before a [`AuditAction::Rename`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_id: Option<String>,
+    pub action: AuditAction,
+    /// Seconds since the epoch, set by the server
+    pub timestamp: u64,
+    /// Signing key the request was authenticated with, see
+    /// [`AuthToken`](crate::model::auth::AuthToken)
+    pub client_pk: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    /// All the passwords were listed
+    List,
+    /// All the passwords were exported along with the account
+    Export,
+    Add,
+    Modify,
+    Patch,
+    /// Moved to a new id, the entry records both
+    Rename,
+    Delete,
+    /// Shared with other users, or with more of them
+    Share,
+    /// No longer shared with one of its recipients
+    Revoke,
+    /// The credentials shared with the user were listed
+    ListShared,
+}
@@ -0,0 +1,133 @@
+use crate::crypto::asymmetric::{verify_detached, KeyPair};
+use anyhow::format_err;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Prefix of the messages signed by [`AuthToken`], so that their signatures
+/// can't pass for those of anything else signed with the same key
+const AUTH_CONTEXT: &str = "passphrasex-auth:";
+
+/// Random value the API issues for a client to sign in an [`AuthToken`], the
+/// bearer token of a request that changes data. Each one is accepted once, so
+/// a captured request can't be sent again.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Challenge {
+    pub challenge: String,
+    /// Seconds the challenge can be used for
+    pub expires_in: u64,
+}
+
+/// Bearer token authenticating a request: `message` (the current time in
+/// seconds for reads, a [`Challenge`] for changes) signed with the account's
+/// Ed25519 key, written as `message.signing_key.signature`
+///
+/// The API only accepts the signing key stored with the account, carrying it
+/// along proves nothing on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthToken {
+    pub message: String,
+    /// Base64 Ed25519 public key, see [`KeyPair::get_signing_pk`]
+    pub signing_key: String,
+    pub signature: String,
+}
+
+impl AuthToken {
+    pub fn new(key_pair: &KeyPair, message: &str) -> Self {
+        Self {
+            message: message.to_string(),
+            signing_key: key_pair.get_signing_pk(),
+            signature: key_pair.sign_detached(signed_message(message).as_bytes()),
+        }
+    }
+
+    /// Check that the message was signed with the token's signing key
+    pub fn verify(&self) -> anyhow::Result<()> {
+        verify_detached(
+            &self.signing_key,
+            signed_message(&self.message).as_bytes(),
+            &self.signature,
+        )
+    }
+}
+
+fn signed_message(message: &str) -> String {
+    format!("{}{}", AUTH_CONTEXT, message)
+}
+
+impl fmt::Display for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.message, self.signing_key, self.signature
+        )
+    }
+}
+
+impl FromStr for AuthToken {
+    type Err = anyhow::Error;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        // None of the parts contain dots, timestamps are digits and the rest URL-safe base64
+        match token.split('.').collect::<Vec<_>>().as_slice() {
+            [message, signing_key, signature]
+                if [message, signing_key, signature]
+                    .iter()
+                    .all(|part| !part.is_empty()) =>
+            {
+                Ok(Self {
+                    message: message.to_string(),
+                    signing_key: signing_key.to_string(),
+                    signature: signature.to_string(),
+                })
+            }
+            _ => Err(format_err!("Expected message.signing_key.signature")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::asymmetric::SeedPhrase;
+
+    #[test]
+    fn tokens_roundtrip_and_verify() {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let token = AuthToken::new(&key_pair, "challenge");
+
+        let parsed: AuthToken = token.to_string().parse().unwrap();
+        assert_eq!(parsed, token);
+        assert!(parsed.verify().is_ok());
+        assert_eq!(parsed.signing_key, key_pair.get_signing_pk());
+    }
+
+    #[test]
+    fn tokens_only_verify_their_message_and_key() {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let token = AuthToken::new(&key_pair, "challenge");
+
+        let other_message = AuthToken {
+            message: "other".to_string(),
+            ..token.clone()
+        };
+        assert!(other_message.verify().is_err());
+
+        let other_key = AuthToken {
+            signing_key: KeyPair::new(SeedPhrase::new(), None).get_signing_pk(),
+            ..token.clone()
+        };
+        assert!(other_key.verify().is_err());
+
+        // Not usable as a signature of the bare message, e.g. an export
+        assert!(verify_detached(&token.signing_key, b"challenge", &token.signature).is_err());
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        for token in ["", "a.b", "a..c", "a.b.c.d", "nonce.cipher"] {
+            assert!(token.parse::<AuthToken>().is_err(), "{token}");
+        }
+    }
+}
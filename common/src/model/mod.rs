@@ -3,5 +3,8 @@ use std::collections::HashMap;
 
 pub type CredentialsMap = HashMap<String, HashMap<String, Password>>;
 
+pub mod audit;
+pub mod auth;
 pub mod password;
+pub mod share;
 pub mod user;
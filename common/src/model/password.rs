@@ -1,6 +1,10 @@
 use crate::crypto::asymmetric::KeyPair;
 use crate::crypto::common::EncryptedValue;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How many previous values of a password are kept
+pub const MAX_PASSWORD_HISTORY: usize = 10;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Password {
@@ -9,9 +13,118 @@ pub struct Password {
     pub site: String,
     pub username: String,
     pub password: String,
+    /// Seconds since the epoch, set by the server. `None` for passwords
+    /// created before timestamps were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// Seconds since the epoch of the last change, set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+    /// Pinned by the user, listed before the others
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub favorite: bool,
+    /// Previous values, newest first, kept by the server when the password is
+    /// modified and capped to [`MAX_PASSWORD_HISTORY`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<PasswordHistoryEntry>,
+    /// Slash-separated path grouping the password, e.g. `Work/Cloud`. Stored
+    /// in plain text like the site: it only organizes passwords and the server
+    /// can filter by it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+}
+
+/// A previous value of a [`Password`]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PasswordHistoryEntry {
+    /// Encrypted like [`Password::password`], which the server only stores
+    pub value: String,
+    /// Seconds since the epoch when the value was replaced
+    pub changed_at: u64,
+}
+
+/// Fields of a [`Password`] to change, only the ones given are. The site and
+/// username make up the id, so they're changed by renaming the password instead.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordPatch {
+    /// Encrypted like [`Password::password`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favorite: Option<bool>,
+}
+
+/// How many passwords a user has, counted by the server without sending them
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PasswordCount {
+    pub total: u64,
+    /// Passwords of each site
+    pub sites: BTreeMap<String, u64>,
 }
 
 impl Password {
+    /// Create a (not yet encrypted) password owned by `key_pair`, with the id
+    /// every device derives for the same site and username
+    pub fn new(
+        key_pair: &KeyPair,
+        site: String,
+        username: String,
+        password: String,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            _id: Self::id_for(key_pair, &site, &username)?,
+            user_id: key_pair.get_pk(),
+            site,
+            username,
+            password,
+            created_at: None,
+            updated_at: None,
+            favorite: false,
+            history: Vec::new(),
+            folder: None,
+        })
+    }
+
+    /// Id of the password stored for `site` and `username`, keyed on the
+    /// owner's public key so ids don't reveal the username
+    pub fn id_for(key_pair: &KeyPair, site: &str, username: &str) -> anyhow::Result<String> {
+        key_pair.hash(&format!("{}{}", site, username))
+    }
+
+    /// Put the password in `folder`, with surrounding whitespace and slashes
+    /// trimmed, or out of any folder when it's empty
+    pub fn with_folder(mut self, folder: Option<String>) -> Self {
+        self.folder = folder
+            .map(|folder| folder.trim().trim_matches('/').to_string())
+            .filter(|folder| !folder.is_empty());
+        self
+    }
+
+    /// Whether the password is in `folder` or one of its subfolders
+    pub fn in_folder(&self, folder: &str) -> bool {
+        let folder = folder.trim().trim_matches('/');
+        match &self.folder {
+            Some(own) => {
+                own == folder
+                    || folder.is_empty()
+                    || own
+                        .strip_prefix(folder)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            }
+            None => folder.is_empty(),
+        }
+    }
+
+    /// Move the current (encrypted) password to the front of the history before
+    /// it's replaced, as the server does, dropping the oldest entries
+    pub fn push_history(&mut self, changed_at: u64) {
+        let value = self.password.clone();
+        self.history
+            .insert(0, PasswordHistoryEntry { value, changed_at });
+        self.history.truncate(MAX_PASSWORD_HISTORY);
+    }
+
     pub fn encrypt(&self, key_pair: &KeyPair) -> Self {
         let username_enc = key_pair.encrypt(&self.username);
         let password_enc = key_pair.encrypt(&self.password);
@@ -35,3 +148,27 @@ impl Password {
         password
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::asymmetric::SeedPhrase;
+
+    #[test]
+    fn decrypt_empty_fields() {
+        let key_pair = KeyPair::new(SeedPhrase::new(), None);
+        let mut password = Password::new(
+            &key_pair,
+            "example.com".to_string(),
+            String::new(),
+            String::new(),
+        )
+        .unwrap();
+        password.username = String::new();
+        password.password = " ".to_string();
+
+        let password = password.decrypt(&key_pair);
+        assert_eq!(password.username, "");
+        assert_eq!(password.password, "");
+    }
+}
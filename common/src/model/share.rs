@@ -0,0 +1,71 @@
+use crate::crypto::asymmetric::{public_key_from_base64, KeyPair};
+use crate::crypto::common::{compact, EncryptedValue};
+use crate::model::password::Password;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A credential its owner shared with other users. Unlike a [`Password`],
+/// which only its owner can read, the content is encrypted once for each
+/// recipient, so each of them decrypts their own copy.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SharedCredential {
+    /// Same as the id of the shared [`Password`]
+    pub _id: String,
+    /// Public key of the user who shared the credential
+    pub sender_id: String,
+    /// [`SharedContent`] as JSON, encrypted by the sender for each recipient,
+    /// by their public key
+    #[serde(with = "compact::map")]
+    pub recipients: HashMap<String, EncryptedValue>,
+    /// Seconds since the epoch, set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+}
+
+/// The decrypted content of a [`SharedCredential`]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SharedContent {
+    pub site: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl SharedCredential {
+    /// Share `content` from the owner of `key_pair` with the owners of the
+    /// `recipient_ids` public keys
+    pub fn new(
+        key_pair: &KeyPair,
+        recipient_ids: &[String],
+        content: &SharedContent,
+    ) -> anyhow::Result<Self> {
+        let json = serde_json::to_string(content)?;
+
+        let mut recipients = HashMap::new();
+        for recipient_id in recipient_ids {
+            let recipient = public_key_from_base64(recipient_id)?;
+            recipients.insert(
+                recipient_id.clone(),
+                key_pair.encrypt_for(&recipient, &json),
+            );
+        }
+
+        Ok(Self {
+            _id: Password::id_for(key_pair, &content.site, &content.username)?,
+            sender_id: key_pair.get_pk(),
+            recipients,
+            created_at: None,
+        })
+    }
+
+    /// Decrypt the copy of the content for the owner of `key_pair`
+    pub fn decrypt(&self, key_pair: &KeyPair) -> anyhow::Result<SharedContent> {
+        let content = self
+            .recipients
+            .get(&key_pair.get_pk())
+            .ok_or(anyhow::format_err!("Credential isn't shared with this key"))?;
+        let sender = public_key_from_base64(&self.sender_id)?;
+
+        let json = key_pair.decrypt_from(&sender, content)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
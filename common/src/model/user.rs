@@ -1,3 +1,4 @@
+use crate::model::password::Password;
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize, Serializer};
 
@@ -5,6 +6,29 @@ use serde::{Deserialize, Serialize, Serializer};
 pub struct User {
     #[serde(alias = "public_key")]
     pub _id: String,
+    /// Contact address, for account recovery. Accounts may have none.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Name to show for the account instead of its public key
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Ed25519 key the account's requests are signed with, see
+    /// [`AuthToken`](crate::model::auth::AuthToken). `None` for accounts created
+    /// before it was stored, which the API refuses until registered again.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+}
+
+impl User {
+    /// An account identified by `public_key` only
+    pub fn new(public_key: String) -> Self {
+        Self {
+            _id: public_key,
+            email: None,
+            display_name: None,
+            signing_key: None,
+        }
+    }
 }
 
 impl Serialize for User {
@@ -13,7 +37,7 @@ impl Serialize for User {
         S: Serializer,
     {
         let is_human_readable = serializer.is_human_readable();
-        let mut state = serializer.serialize_struct("User", 1)?;
+        let mut state = serializer.serialize_struct("User", 4)?;
 
         // If serializer is json then rename _id to public_key
         if is_human_readable {
@@ -22,6 +46,45 @@ impl Serialize for User {
             state.serialize_field("_id", &self._id)?;
         }
 
+        match &self.email {
+            Some(email) => state.serialize_field("email", email)?,
+            None => state.skip_field("email")?,
+        }
+        match &self.display_name {
+            Some(display_name) => state.serialize_field("display_name", display_name)?,
+            None => state.skip_field("display_name")?,
+        }
+        match &self.signing_key {
+            Some(signing_key) => state.serialize_field("signing_key", signing_key)?,
+            None => state.skip_field("signing_key")?,
+        }
+
         state.end()
     }
 }
+
+/// Everything stored for a user, as served by the export endpoint. The
+/// passwords stay encrypted with the user's key, as stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserExport {
+    pub public_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub passwords: Vec<Password>,
+    /// Seconds since the epoch
+    pub exported_at: u64,
+}
+
+/// A [`UserExport`] as saved by the CLI, signed by its owner so whoever gets
+/// the file can check it wasn't tampered with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedExport {
+    /// The export as the API served it, kept as is since it's what's signed
+    pub export: String,
+    /// Base64 Ed25519 public key of the signer
+    pub signer: String,
+    /// Base64 Ed25519 signature of `export`
+    pub signature: String,
+}
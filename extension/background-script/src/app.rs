@@ -1,21 +1,65 @@
-use crate::storage::{StorageCredentials, StorageCredentialsAction, StorageSecretKey};
-use crate::{ConnectedPorts, PortError, PortId};
+use crate::storage::{
+    StorageCredentials, StorageCredentialsAction, StorageSecretKey, StorageWebAuthn,
+};
+use crate::{ConnectedPorts, PortError, PortId, TabId};
 use anyhow::anyhow;
-use messages::{next_request_id, Credential, RequestId};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use js_sys::Date;
+use messages::{next_request_id, Credential, RequestId, Settings};
 use passphrasex_common::api::Api;
 use passphrasex_common::crypto::asymmetric::KeyPair;
-use passphrasex_common::crypto::symmetric::{decrypt_data, hash};
+use passphrasex_common::crypto::symmetric::{decrypt_data, encrypt_data, hash};
 use passphrasex_common::model::password::Password;
 use passphrasex_common::model::CredentialsMap;
+use passphrasex_common::site::{matching_sites, normalize_site, site_host};
 use std::collections::HashMap;
+use thiserror::Error;
 use wasm_bindgen::JsValue;
 use web_extensions_sys::Port;
 
+/// Bytes returned by a WebAuthn PRF evaluation
+const WEBAUTHN_PRF_OUTPUT_LEN: usize = 32;
+
+/// AES key (as `encrypt_data` takes it) from the output of a WebAuthn PRF evaluation
+fn webauthn_key(prf_output: &[u8]) -> anyhow::Result<String> {
+    if prf_output.len() != WEBAUTHN_PRF_OUTPUT_LEN {
+        return Err(anyhow!("Invalid WebAuthn PRF output"));
+    }
+
+    Ok(URL_SAFE.encode(prf_output))
+}
+
+/// Why logging in or unlocking failed, worded to be shown in the popup
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Wrong password")]
+    WrongPassword,
+    #[error("No account on this browser, log in or register first")]
+    NoAccount,
+    #[error("Invalid seed phrase")]
+    InvalidSeedPhrase,
+    #[error("The account stored on this browser is corrupted, log in again")]
+    Corrupted,
+    #[error("Browser storage is unavailable: {0}")]
+    StorageUnavailable(String),
+    #[error("Unable to reach the server: {0}")]
+    Server(String),
+}
+
+/// API client for `key_pair`, against `server_url` or the default server
+pub fn api_for(key_pair: KeyPair, server_url: Option<&str>) -> anyhow::Result<Api> {
+    match server_url {
+        Some(server_url) => Api::with_base_url(key_pair, server_url),
+        None => Ok(Api::new(key_pair)),
+    }
+}
+
 pub struct UnlockedAppData {
     key_pair: KeyPair,
     credentials_map: CredentialsMap,
-    tmp_credentials: HashMap<String, (String, String)>,
     api: Api,
+    /// Time of the last request, in milliseconds since the epoch
+    last_activity: f64,
 }
 
 #[derive(Default)]
@@ -26,14 +70,12 @@ pub enum AppData {
 }
 
 impl AppData {
-    fn new(key_pair: KeyPair, credentials_map: CredentialsMap) -> Self {
-        let api = Api::new(key_pair.clone());
-
+    fn new(key_pair: KeyPair, credentials_map: CredentialsMap, api: Api) -> Self {
         Self::Unlocked(UnlockedAppData {
             key_pair,
             credentials_map,
-            tmp_credentials: HashMap::new(),
             api,
+            last_activity: Date::now(),
         })
     }
 }
@@ -43,6 +85,12 @@ pub struct App {
     pub last_request_id: RequestId,
     pub connected_ports: ConnectedPorts,
     pub app_data: AppData,
+    pub settings: Settings,
+    /// Site open in each tab, to show how many credentials it has
+    pub tab_sites: HashMap<TabId, String>,
+    pub active_tab: Option<TabId>,
+    /// Usernames the fill context menu was last built with
+    pub context_menu_usernames: Option<Vec<String>>,
 }
 
 impl App {
@@ -68,14 +116,29 @@ impl App {
         self.connected_ports.post_message_js(port_id, msg)
     }
 
-    pub fn get_status(&self, sk: StorageSecretKey) -> anyhow::Result<(bool, bool)> {
-        match self.app_data {
-            AppData::Locked => match sk.secret_key {
-                Some(_) => Ok((true, false)),
-                None => Ok((false, false)),
-            },
-            AppData::Unlocked { .. } => Ok((true, true)),
+    /// Returns whether there's an account, whether it's unlocked and,
+    /// when unlocked, its public key.
+    /// Whether there's an account and whether it's unlocked, with its public key then
+    pub fn get_status(&self, sk: StorageSecretKey) -> (bool, bool, Option<String>) {
+        match &self.app_data {
+            AppData::Locked => (sk.secret_key.is_some(), false, None),
+            AppData::Unlocked(app_data) => (true, true, Some(app_data.key_pair.get_pk())),
+        }
+    }
+
+    /// API client for `key_pair`, against the server from the settings
+    pub fn api_for(&self, key_pair: KeyPair) -> anyhow::Result<Api> {
+        api_for(key_pair, self.settings.server_url.as_deref())
+    }
+
+    /// Apply new settings, pointing the unlocked vault at the new server if it changed
+    pub fn set_settings(&mut self, settings: Settings) -> anyhow::Result<()> {
+        if let AppData::Unlocked(app_data) = &mut self.app_data {
+            app_data.api = api_for(app_data.key_pair.clone(), settings.server_url.as_deref())?;
         }
+
+        self.settings = settings;
+        Ok(())
     }
 
     pub fn get_api(&self) -> anyhow::Result<Api> {
@@ -91,27 +154,94 @@ impl App {
         creds: StorageCredentials,
         device_password: String,
     ) -> anyhow::Result<()> {
-        let pk = sk.public_key.ok_or(anyhow!("No pk found"))?;
-        let salt = sk.salt.ok_or(anyhow!("No salt found"))?;
-        let pass_hash = hash(&device_password, &salt)?;
+        let salt = sk.salt.clone().ok_or(AuthError::NoAccount)?;
+        let pass_hash = hash(&device_password, &salt).map_err(|_| AuthError::Corrupted)?;
+
+        self.unlock_with_key(sk, creds, &pass_hash.cipher)
+    }
+
+    /// Unlock with the secret key wrapped by [`App::wrap_secret_key`], given the
+    /// PRF output of the same WebAuthn credential
+    pub fn unlock_with_webauthn(
+        &mut self,
+        sk: StorageSecretKey,
+        creds: StorageCredentials,
+        webauthn: StorageWebAuthn,
+        prf_output: &[u8],
+    ) -> anyhow::Result<()> {
+        let wrapped_sk = webauthn
+            .webauthn_secret_key
+            .ok_or(anyhow!("WebAuthn unlock is not enabled"))?;
+
+        self.unlock_with_wrapped_sk(sk, creds, wrapped_sk, &webauthn_key(prf_output)?)
+    }
+
+    /// Unlock with a copy of the secret key encrypted by [`App::encrypt_secret_key`]
+    pub fn unlock_with_wrapped_sk(
+        &mut self,
+        sk: StorageSecretKey,
+        creds: StorageCredentials,
+        wrapped_sk: String,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let sk = StorageSecretKey {
+            secret_key: Some(wrapped_sk),
+            ..sk
+        };
+        self.unlock_with_key(sk, creds, key)
+    }
+
+    /// Encrypt the unlocked secret key with a WebAuthn PRF output
+    pub fn wrap_secret_key(&self, prf_output: &[u8]) -> anyhow::Result<String> {
+        self.encrypt_secret_key(&webauthn_key(prf_output)?)
+    }
+
+    /// Encrypt the unlocked secret key with `key`, hex encoded like the stored secret key
+    pub fn encrypt_secret_key(&self, key: &str) -> anyhow::Result<String> {
+        match &self.app_data {
+            AppData::Locked => Err(anyhow!("Not Logged In")),
+            AppData::Unlocked(app_data) => {
+                let enc = encrypt_data(key, app_data.key_pair.private_key.as_bytes())?;
+                Ok(hex::encode(enc))
+            }
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        matches!(self.app_data, AppData::Unlocked(_))
+    }
+
+    /// Unlock with the stored secret key, encrypted with `key`
+    fn unlock_with_key(
+        &mut self,
+        sk: StorageSecretKey,
+        creds: StorageCredentials,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let pk = sk.public_key.ok_or(AuthError::NoAccount)?;
 
-        let sk = sk.secret_key.ok_or(anyhow!("No sk found"))?;
-        let sk = hex::decode(sk).map_err(|err| anyhow!("Unable to decode sk: {:?}", err))?;
-        let sk = decrypt_data(&pass_hash.cipher, sk)?;
+        let sk = sk.secret_key.ok_or(AuthError::NoAccount)?;
+        let sk = hex::decode(sk).map_err(|_| AuthError::Corrupted)?;
+        let sk = decrypt_data(key, sk)?;
+        if sk.len() < 32 {
+            return Err(AuthError::Corrupted.into());
+        }
 
         let mut content: [u8; 32] = [0; 32];
         content.copy_from_slice(&sk[..32]);
 
         let key_pair = KeyPair::from_sk(content);
+        // Decrypting with the wrong key doesn't fail, it gives another key
         if key_pair.get_pk() != pk {
-            return Err(anyhow!("Invalid key pair"));
+            return Err(AuthError::WrongPassword.into());
         }
 
         let credentials_map = creds.credentials;
+        let api = self.api_for(key_pair.clone())?;
 
         match self.app_data {
             AppData::Locked => {
-                self.app_data = AppData::new(key_pair, credentials_map);
+                self.app_data = AppData::new(key_pair, credentials_map, api);
             }
             AppData::Unlocked { .. } => {
                 return Err(anyhow!("Already unlocked"));
@@ -134,8 +264,38 @@ impl App {
         Ok(())
     }
 
-    pub fn login(&mut self, key_pair: KeyPair, credentials: CredentialsMap) {
-        self.app_data = AppData::new(key_pair, credentials);
+    /// Postpone the auto-lock, called on every request that needs the vault.
+    pub fn touch(&mut self) {
+        if let AppData::Unlocked(app_data) = &mut self.app_data {
+            app_data.last_activity = Date::now();
+        }
+    }
+
+    /// Lock the vault if it has not been used for longer than the auto-lock timeout,
+    /// dropping (and zeroizing) the key pair.
+    ///
+    /// Returns whether the vault was locked.
+    pub fn lock_if_inactive(&mut self) -> bool {
+        if self.settings.auto_lock_minutes == 0 {
+            return false;
+        }
+
+        match &self.app_data {
+            AppData::Unlocked(app_data) => {
+                let timeout = f64::from(self.settings.auto_lock_minutes) * 60_000.0;
+                if Date::now() - app_data.last_activity < timeout {
+                    return false;
+                }
+
+                self.app_data = AppData::Locked;
+                true
+            }
+            AppData::Locked => false,
+        }
+    }
+
+    pub fn login(&mut self, key_pair: KeyPair, credentials: CredentialsMap, api: Api) {
+        self.app_data = AppData::new(key_pair, credentials, api);
     }
 
     pub fn logout(&mut self) -> StorageCredentialsAction {
@@ -143,36 +303,113 @@ impl App {
         StorageCredentialsAction::Logout
     }
 
-    pub fn get_credential(
+    /// Get the decrypted credentials stored for a site, optionally only the
+    /// one for the given username.
+    pub fn get_credentials(
         &self,
         site: String,
         username: Option<String>,
-    ) -> anyhow::Result<(String, String)> {
+    ) -> anyhow::Result<Vec<Password>> {
+        let site = normalize_site(&site);
         match &self.app_data {
             AppData::Locked => Err(anyhow!("Not Logged In")),
-            AppData::Unlocked(app_data) => match app_data.credentials_map.get(&site) {
-                Some(passwords) => match username {
-                    Some(username) => {
-                        let id = app_data.key_pair.hash(&format!("{}{}", site, username))?;
-                        let credential = passwords.get(&id).ok_or(anyhow!("Password not found"))?;
-                        let credential = credential.decrypt(&app_data.key_pair);
-                        Ok((credential.username, credential.password))
+            AppData::Unlocked(app_data) => {
+                let sites = matching_sites(
+                    app_data.credentials_map.keys(),
+                    &site,
+                    self.settings.strict_site_matching,
+                );
+
+                let mut result: Vec<Password> = Vec::new();
+                for stored_site in sites {
+                    let passwords = &app_data.credentials_map[stored_site];
+                    match &username {
+                        Some(username) => {
+                            let id = Password::id_for(&app_data.key_pair, stored_site, username)?;
+                            if let Some(password) = passwords.get(&id) {
+                                result.push(password.decrypt(&app_data.key_pair));
+                            }
+                        }
+                        None => result.extend(
+                            passwords
+                                .values()
+                                .map(|password| password.decrypt(&app_data.key_pair)),
+                        ),
                     }
-                    None => {
-                        let result: Vec<Password> = passwords
-                            .iter()
-                            .map(|(_, password)| password.decrypt(&app_data.key_pair))
-                            .collect();
+                }
 
-                        let result = result.first().ok_or(anyhow!("No passwords found"))?;
-                        Ok((result.username.clone(), result.password.clone()))
-                    }
-                },
-                None => Err(anyhow!("No passwords found")),
-            },
+                if result.is_empty() {
+                    return match username {
+                        Some(_) => Err(anyhow!("Password not found")),
+                        None => Err(anyhow!("No passwords found")),
+                    };
+                }
+
+                result.sort_by(|a, b| {
+                    b.favorite
+                        .cmp(&a.favorite)
+                        .then(a.username.cmp(&b.username))
+                        .then(a.site.cmp(&b.site))
+                });
+                Ok(result)
+            }
         }
     }
 
+    /// Whether `site` is one of the ignored sites or under one of them
+    pub fn is_site_ignored(&self, site: &str) -> bool {
+        let host = site_host(site);
+        self.settings
+            .ignored_sites
+            .iter()
+            .any(|ignored| host == *ignored || host.ends_with(&format!(".{}", ignored)))
+    }
+
+    /// Site open in the active tab
+    pub fn active_site(&self) -> Option<String> {
+        self.active_tab
+            .and_then(|tab_id| self.tab_sites.get(&tab_id))
+            .cloned()
+    }
+
+    /// Number of credentials offered for a site, 0 while locked
+    pub fn count_credentials(&self, site: &str) -> usize {
+        match &self.app_data {
+            AppData::Locked => 0,
+            AppData::Unlocked(app_data) => matching_sites(
+                app_data.credentials_map.keys(),
+                &normalize_site(site),
+                self.settings.strict_site_matching,
+            )
+            .into_iter()
+            .map(|site| app_data.credentials_map[site].len())
+            .sum(),
+        }
+    }
+
+    /// Number of credentials offered for the site open in each known tab
+    pub fn tab_credential_counts(&self) -> Vec<(TabId, usize)> {
+        self.tab_sites
+            .iter()
+            .map(|(tab_id, site)| (*tab_id, self.count_credentials(site)))
+            .collect()
+    }
+
+    /// Usernames of the credentials offered for the active tab, empty while locked
+    pub fn active_tab_usernames(&self) -> Vec<String> {
+        let site = match self.active_site() {
+            Some(site) => site,
+            None => return Vec::new(),
+        };
+
+        let mut usernames: Vec<String> = match self.get_credentials(site, None) {
+            Ok(credentials) => credentials.into_iter().map(|cred| cred.username).collect(),
+            Err(_) => Vec::new(),
+        };
+        usernames.dedup();
+        usernames
+    }
+
     pub fn list_credentials(&self) -> anyhow::Result<Vec<Credential>> {
         match &self.app_data {
             AppData::Locked => Err(anyhow!("Not Logged In")),
@@ -205,6 +442,7 @@ impl App {
         username: String,
         password: String,
     ) -> anyhow::Result<StorageCredentialsAction> {
+        let site = normalize_site(&site);
         match &mut self.app_data {
             AppData::Locked => Err(anyhow!("Not Logged In")),
             AppData::Unlocked(app_data) => {
@@ -212,16 +450,8 @@ impl App {
                     return Err(anyhow!("Username & site cannot be empty"));
                 }
 
-                let password_id = app_data.key_pair.hash(&format!("{}{}", site, username))?;
-                let user_id = app_data.key_pair.get_pk();
-
-                let password = Password {
-                    _id: password_id.clone(),
-                    user_id,
-                    site: site.clone(),
-                    username,
-                    password,
-                };
+                let password = Password::new(&app_data.key_pair, site.clone(), username, password)?;
+                let password_id = password._id.clone();
 
                 let password = password.encrypt(&app_data.key_pair);
                 app_data
@@ -270,46 +500,45 @@ impl App {
         }
     }
 
-    pub fn add_or_edit_credential(
+    /// Store a credential captured from a login form, updating the password
+    /// when the site already has an entry for the username.
+    ///
+    /// Returns `None` when the stored credential is already up to date.
+    pub fn save_credential(
         &mut self,
         site: String,
         username: String,
         password: String,
-    ) -> anyhow::Result<StorageCredentialsAction> {
-        match &self.app_data {
-            AppData::Locked => Err(anyhow!("Not Logged In")),
+    ) -> anyhow::Result<Option<StorageCredentialsAction>> {
+        let site = normalize_site(&site);
+        let password_id = match &self.app_data {
+            AppData::Locked => return Err(anyhow!("Not Logged In")),
             AppData::Unlocked(app_data) => {
-                let site_map = app_data.credentials_map.get(&site);
-
                 if password.is_empty() {
                     return Err(anyhow!("Password cannot be empty"));
                 }
 
-                match site_map {
-                    Some(site_map) => match site_map.values().next() {
-                        Some(pass) => {
-                            let password_id = pass._id.clone();
-                            self.edit_credential(site, password_id, password)
+                let password_id = Password::id_for(&app_data.key_pair, &site, &username)?;
+                let stored = app_data
+                    .credentials_map
+                    .get(&site)
+                    .and_then(|passwords| passwords.get(&password_id));
+
+                match stored {
+                    Some(stored) => {
+                        if stored.decrypt(&app_data.key_pair).password == password {
+                            return Ok(None);
                         }
-                        None => self.add_credential(site, username, password),
-                    },
-                    None => self.add_credential(site, username, password),
+                        Some(password_id)
+                    }
+                    None => None,
                 }
-
-                //
-                //
-                //
-                // let has_password = app_data
-                //     .credentials_map
-                //     .get(&site)
-                //     .map_or(false, |map| map.contains_key(&password_id));
-                //
-                // if has_password {
-                //     self.edit_credential(site, password_id, password)
-                // } else {
-                //     self.add_credential(site, username, password)
-                // }
             }
+        };
+
+        match password_id {
+            Some(password_id) => self.edit_credential(site, password_id, password).map(Some),
+            None => self.add_credential(site, username, password).map(Some),
         }
     }
 
@@ -334,59 +563,4 @@ impl App {
             }
         }
     }
-
-    pub fn set_tmp_credential_username(
-        &mut self,
-        site: String,
-        username: String,
-    ) -> anyhow::Result<()> {
-        match &mut self.app_data {
-            AppData::Locked => Err(anyhow!("Not Logged In")),
-            AppData::Unlocked(app_data) => {
-                let mut password = "".to_string();
-                if let Some((_, p)) = app_data.tmp_credentials.get(&site) {
-                    password = p.clone();
-                }
-                app_data.tmp_credentials.insert(site, (username, password));
-
-                Ok(())
-            }
-        }
-    }
-
-    pub fn set_tmp_credential_password(
-        &mut self,
-        site: String,
-        password: String,
-    ) -> anyhow::Result<()> {
-        match &mut self.app_data {
-            AppData::Locked => Err(anyhow!("Not Logged In")),
-            AppData::Unlocked(app_data) => {
-                let mut username = "".to_string();
-                if let Some((u, _)) = app_data.tmp_credentials.get(&site) {
-                    username = u.clone();
-                }
-                app_data.tmp_credentials.insert(site, (username, password));
-
-                Ok(())
-            }
-        }
-    }
-
-    pub fn store_tmp_credential(
-        &mut self,
-        site: String,
-    ) -> anyhow::Result<StorageCredentialsAction> {
-        match &mut self.app_data {
-            AppData::Locked => Err(anyhow!("Not Logged In")),
-            AppData::Unlocked(app_data) => {
-                let (username, password) = app_data
-                    .tmp_credentials
-                    .remove(&site)
-                    .ok_or(anyhow!("No tmp credential found"))?;
-
-                self.add_or_edit_credential(site, username, password)
-            }
-        }
-    }
 }
@@ -1,45 +1,93 @@
 mod app;
 mod storage;
 
-use app::App;
+use app::{App, AuthError};
+use gloo_timers::callback::Interval;
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
+use anyhow::anyhow;
 use gloo_console as console;
 use gloo_utils::format::JsValueSerdeExt;
-use js_sys::Function;
+use js_sys::{Array, Date, Function, Object};
 use messages::{
-    next_request_id, AppRequest, AppRequestPayload, AppResponse, AppResponsePayload, PortRequest,
-    PortRequestPayload, PortResponse, PortResponsePayload, Request, RequestId, Response,
-    INITIAL_REQUEST_ID,
+    next_request_id, AppRequest, AppRequestPayload, AppResponse, AppResponsePayload, Credential,
+    CredentialOption, PortRequest, PortRequestPayload, PortResponse, PortResponsePayload, Request,
+    RequestHeader, RequestId, Response, Settings, INITIAL_REQUEST_ID,
 };
 use serde::Serialize;
 use thiserror::Error;
 use wasm_bindgen::{prelude::*, JsCast};
 
-use crate::storage::{execute_storage_credentials_action, StorageCredentials, StorageSecretKey};
-use passphrasex_common::api::Api;
+use crate::storage::{
+    execute_storage_credentials_action, StorageCredentials, StorageRememberedUnlock,
+    StorageSecretKey, StorageSessionKey, StorageSettings, StorageWebAuthn,
+};
+use passphrasex_common::api::parse_base_url;
 use passphrasex_common::crypto::asymmetric::KeyPair;
-use web_extensions_sys::{chrome, Port, Tab, TabChangeInfo};
+use passphrasex_common::crypto::symmetric::generate_key;
+use passphrasex_common::generator::{
+    generate_passphrase, generate_password_with, passphrase_entropy, password_entropy,
+};
+use passphrasex_common::site::{normalize_site, site_host};
+use web_extensions_sys::{chrome, OnClickData, Port, Tab, TabActiveInfo, TabChangeInfo};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// How often the auto-lock timeout is checked
+const AUTO_LOCK_CHECK_MILLIS: u32 = 30_000;
+
 type TabId = i32;
 
 type PortId = usize;
 
 const FIRST_PORT_ID: RequestId = 1;
 
+/// Context menu entry to fill the clicked field, with a child entry per
+/// username (`fill:<username>`) when the site has several credentials
+const FILL_MENU_ID: &str = "fill";
+
+const PASSPHRASE_SEPARATOR: &str = "-";
+
 #[derive(Debug)]
 pub struct PortContext {
     port: Port,
     last_request_id: RequestId,
+    /// Site of the frame the port was opened from
+    frame_site: Option<String>,
+    /// Site of the tab containing that frame
+    tab_site: Option<String>,
+    tab_id: Option<TabId>,
+    frame_id: Option<u32>,
 }
 
 impl PortContext {
-    const fn new(port: Port) -> Self {
+    fn new(port: Port) -> Self {
+        let sender = port.sender();
+        let frame_site = sender
+            .as_ref()
+            .and_then(|sender| sender.url())
+            .map(|url| normalize_site(&url));
+        let frame_id = sender.as_ref().and_then(|sender| sender.frame_id());
+        let tab = sender.and_then(|sender| sender.tab());
+        let tab_id = tab.as_ref().and_then(|tab| tab.id());
+        let tab_site = tab
+            .and_then(|tab| tab.url())
+            .map(|url| normalize_site(&url));
+
         Self {
             port,
             last_request_id: INITIAL_REQUEST_ID,
+            frame_site,
+            tab_site,
+            tab_id,
+            frame_id,
+        }
+    }
+
+    fn is_cross_origin_frame(&self) -> bool {
+        match (&self.frame_site, &self.tab_site) {
+            (Some(frame_site), Some(tab_site)) => frame_site != tab_site,
+            _ => false,
         }
     }
 
@@ -68,6 +116,7 @@ impl ConnectedPorts {
         debug_assert!(id >= FIRST_PORT_ID);
         let ctx = PortContext::new(port);
         self.ctx_by_id.insert(id, ctx);
+        self.last_id = id;
         Some(id)
     }
 
@@ -82,15 +131,38 @@ impl ConnectedPorts {
             .get(&id)
             .ok_or(PortError::NotConnected)
             .map(|ctx| {
-                let PortContext {
-                    port,
-                    last_request_id: _,
-                } = ctx;
+                let PortContext { port, .. } = ctx;
                 console::debug!("Posting message on port", port, msg);
                 port.post_message(msg);
             })
     }
 
+    /// Site of the frame behind the port, and whether that frame is embedded
+    /// in a page from another site
+    fn frame_site(&self, id: PortId) -> Result<(Option<String>, bool), PortError> {
+        self.ctx_by_id
+            .get(&id)
+            .ok_or(PortError::NotConnected)
+            .map(|ctx| (ctx.frame_site.clone(), ctx.is_cross_origin_frame()))
+    }
+
+    /// Ports connected from any frame of a tab
+    fn find_all(&self, tab_id: TabId) -> Vec<PortId> {
+        self.ctx_by_id
+            .iter()
+            .filter(|(_, ctx)| ctx.tab_id == Some(tab_id))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Port connected from a frame of a tab, 0 being the top frame
+    fn find(&self, tab_id: TabId, frame_id: u32) -> Option<PortId> {
+        self.ctx_by_id
+            .iter()
+            .find(|(_, ctx)| ctx.tab_id == Some(tab_id) && ctx.frame_id == Some(frame_id))
+            .map(|(id, _)| *id)
+    }
+
     fn next_request_id(&mut self, id: PortId) -> Result<RequestId, PortError> {
         self.ctx_by_id
             .get_mut(&id)
@@ -105,6 +177,36 @@ pub fn start() {
 
     let app = Rc::new(RefCell::new(App::default()));
 
+    {
+        let app = Rc::clone(&app);
+        wasm_bindgen_futures::spawn_local(async move {
+            match StorageSettings::load().await {
+                Ok(settings) => {
+                    app.borrow_mut().settings = settings.into_settings();
+                }
+                Err(err) => console::error!("Failed to load settings", err.to_string()),
+            }
+
+            // The browser stops idle background workers, this one may be
+            // replacing one that was unlocked
+            restore_remembered_unlock(&app).await;
+        });
+    }
+
+    {
+        let app = Rc::clone(&app);
+        wasm_bindgen_futures::spawn_local(async move { load_tab_sites(&app).await });
+    }
+
+    {
+        let app = Rc::clone(&app);
+        Interval::new(AUTO_LOCK_CHECK_MILLIS, move || {
+            let app = Rc::clone(&app);
+            wasm_bindgen_futures::spawn_local(async move { lock_if_inactive(&app).await });
+        })
+        .forget();
+    }
+
     let on_message = {
         let app = Rc::clone(&app);
         move |request, sender, send_response| on_message(&app, request, sender, send_response)
@@ -116,6 +218,10 @@ pub fn start() {
         .add_listener(closure.as_ref().unchecked_ref());
     closure.forget();
 
+    let on_tab_changed = {
+        let app = Rc::clone(&app);
+        move |tab_id, change_info, tab| on_tab_changed(&app, tab_id, change_info, tab)
+    };
     let closure: Closure<dyn Fn(TabId, TabChangeInfo, Tab)> = Closure::new(on_tab_changed);
     chrome()
         .tabs()
@@ -123,6 +229,44 @@ pub fn start() {
         .add_listener(closure.as_ref().unchecked_ref());
     closure.forget();
 
+    let on_tab_activated = {
+        let app = Rc::clone(&app);
+        move |active_info: TabActiveInfo| {
+            app.borrow_mut().active_tab = Some(active_info.tab_id());
+            update_context_menu(&app);
+        }
+    };
+    let closure: Closure<dyn Fn(TabActiveInfo)> = Closure::new(on_tab_activated);
+    chrome()
+        .tabs()
+        .on_activated()
+        .add_listener(closure.as_ref().unchecked_ref());
+    closure.forget();
+
+    let on_context_menu_clicked = {
+        let app = Rc::clone(&app);
+        move |info, tab| on_context_menu_clicked(&app, info, tab)
+    };
+    let closure: Closure<dyn Fn(OnClickData, Option<Tab>)> = Closure::new(on_context_menu_clicked);
+    chrome()
+        .context_menus()
+        .on_clicked()
+        .add_listener(closure.as_ref().unchecked_ref());
+    closure.forget();
+
+    let on_tab_removed = {
+        let app = Rc::clone(&app);
+        move |tab_id, _remove_info| {
+            app.borrow_mut().tab_sites.remove(&tab_id);
+        }
+    };
+    let closure: Closure<dyn Fn(TabId, JsValue)> = Closure::new(on_tab_removed);
+    chrome()
+        .tabs()
+        .on_removed()
+        .add_listener(closure.as_ref().unchecked_ref());
+    closure.forget();
+
     let on_connect = move |port| {
         on_connect_port(&app, port);
     };
@@ -167,8 +311,181 @@ fn on_message(
     }
 }
 
-fn on_tab_changed(tab_id: i32, change_info: TabChangeInfo, tab: Tab) {
-    console::info!("Tab changed", tab_id, &tab, &change_info);
+fn on_tab_changed(app: &Rc<RefCell<App>>, tab_id: TabId, change_info: TabChangeInfo, tab: Tab) {
+    console::debug!("Tab changed", tab_id, &tab, &change_info);
+    let Some(url) = tab.url() else {
+        return;
+    };
+
+    let site = normalize_site(&url);
+    let count = app.borrow().count_credentials(&site);
+    app.borrow_mut().tab_sites.insert(tab_id, site);
+    set_badge_count(tab_id, count);
+
+    if tab.active() {
+        app.borrow_mut().active_tab = Some(tab_id);
+        update_context_menu(app);
+    }
+}
+
+/// Track the tabs that were open before the background (re)started
+async fn load_tab_sites(app: &Rc<RefCell<App>>) {
+    let tabs = match chrome().tabs().query(&Object::new()).await {
+        Ok(tabs) => Array::from(&tabs),
+        Err(err) => {
+            console::error!("Failed to query tabs", err);
+            return;
+        }
+    };
+
+    for tab in tabs.iter() {
+        let tab: Tab = tab.unchecked_into();
+        if let (Some(tab_id), Some(url)) = (tab.id(), tab.url()) {
+            app.borrow_mut()
+                .tab_sites
+                .insert(tab_id, normalize_site(&url));
+            if tab.active() {
+                app.borrow_mut().active_tab = Some(tab_id);
+            }
+        }
+    }
+    update_badges(app);
+    update_context_menu(app);
+}
+
+/// Rebuild the fill context menu for the credentials of the active tab
+fn update_context_menu(app: &Rc<RefCell<App>>) {
+    let usernames = app.borrow().active_tab_usernames();
+    if app.borrow().context_menu_usernames.as_ref() == Some(&usernames) {
+        return;
+    }
+
+    let mut items = vec![ContextMenuItem {
+        id: FILL_MENU_ID.to_string(),
+        parent_id: None,
+        title: "Fill with PassPhraseX".to_string(),
+        contexts: &["editable"],
+        enabled: !usernames.is_empty(),
+    }];
+    if usernames.len() > 1 {
+        items.extend(usernames.iter().map(|username| ContextMenuItem {
+            id: format!("{FILL_MENU_ID}:{username}"),
+            parent_id: Some(FILL_MENU_ID),
+            title: username.clone(),
+            contexts: &["editable"],
+            enabled: true,
+        }));
+    }
+
+    let menus = chrome().context_menus();
+    menus.remove_all(None);
+    for item in items {
+        match JsValue::from_serde(&item) {
+            Ok(item) => {
+                menus.create(&Object::from(item), None);
+            }
+            Err(err) => {
+                console::error!("Failed to serialize context menu item", err.to_string());
+                return;
+            }
+        }
+    }
+    app.borrow_mut().context_menu_usernames = Some(usernames);
+}
+
+/// Send the chosen credential to the frame the context menu was opened in
+fn on_context_menu_clicked(app: &Rc<RefCell<App>>, info: OnClickData, tab: Option<Tab>) {
+    let username = match info.menu_item_id() {
+        Some(id) if id == FILL_MENU_ID => None,
+        Some(id) => match id
+            .strip_prefix(FILL_MENU_ID)
+            .and_then(|id| id.strip_prefix(':'))
+        {
+            Some(username) => Some(username.to_string()),
+            None => return,
+        },
+        None => return,
+    };
+    let Some(tab_id) = tab.and_then(|tab| tab.id()) else {
+        return;
+    };
+    let frame_id = info.frame_id().unwrap_or(0);
+
+    let app = Rc::clone(app);
+    wasm_bindgen_futures::spawn_local(async move {
+        lock_if_inactive(&app).await;
+        restore_remembered_unlock(&app).await;
+        app.borrow_mut().touch();
+
+        if let Err(err) = fill_from_context_menu(&app, tab_id, frame_id, username) {
+            console::error!("Failed to fill from the context menu", err.to_string());
+        }
+    });
+}
+
+fn fill_from_context_menu(
+    app: &Rc<RefCell<App>>,
+    tab_id: TabId,
+    frame_id: u32,
+    username: Option<String>,
+) -> anyhow::Result<()> {
+    let port_id = app
+        .borrow()
+        .connected_ports
+        .find(tab_id, frame_id)
+        .ok_or(anyhow!("The frame isn't connected"))?;
+    let (frame_site, _) = app.borrow().connected_ports.frame_site(port_id)?;
+    let site = frame_site
+        .or_else(|| app.borrow().tab_sites.get(&tab_id).cloned())
+        .ok_or(anyhow!("Unknown site"))?;
+
+    let mut credentials = app.borrow().get_credentials(site, username)?;
+    if credentials.len() > 1 {
+        return Err(anyhow!("Pick a username to fill"));
+    }
+    let credential = credentials.remove(0);
+
+    let request_id = app.borrow_mut().next_port_request_id(port_id)?;
+    let response = PortResponse {
+        header: RequestHeader::new().into_response(request_id),
+        payload: PortResponsePayload::Fill {
+            username: credential.username,
+            password: credential.password,
+        },
+    };
+    app.borrow()
+        .post_port_message_js(port_id, &JsValue::from_serde(&response)?)?;
+    Ok(())
+}
+
+/// Refresh the credential count of every tab, e.g. after unlocking or saving a credential
+fn update_badges(app: &Rc<RefCell<App>>) {
+    for (tab_id, count) in app.borrow().tab_credential_counts() {
+        set_badge_count(tab_id, count);
+    }
+}
+
+/// Show the number of credentials for the tab's site on the extension icon,
+/// or nothing when there are none
+fn set_badge_count(tab_id: TabId, count: usize) {
+    let text = match count {
+        0 => String::new(),
+        count => count.to_string(),
+    };
+    let details = match JsValue::from_serde(&BadgeTextDetails { tab_id, text }) {
+        Ok(details) => details,
+        Err(err) => {
+            console::error!("Failed to serialize badge text", err.to_string());
+            return;
+        }
+    };
+
+    wasm_bindgen_futures::spawn_local(async move {
+        // Fails when the tab was closed in the meantime
+        if let Err(err) = set_badge_text(details).await {
+            console::debug!("Failed to set badge text", tab_id, err);
+        }
+    });
 }
 
 fn on_connect_port(app: &Rc<RefCell<App>>, port: Port) {
@@ -251,6 +568,9 @@ async fn on_request(
         })
         .ok()?;
     let response = handle_app_request(app, request_id, request).await;
+    // Unlocking, locking and credential changes all change the counts
+    update_badges(app);
+    update_context_menu(app);
     JsValue::from_serde(&response)
         .map_err(|err| {
             console::error!("Failed to serialize response message", &err.to_string());
@@ -274,6 +594,8 @@ async fn on_port_request(
         })
         .ok()?;
     let response = handle_port_request(app, port_id, request_id, request).await;
+    update_badges(app);
+    update_context_menu(app);
     JsValue::from_serde(&response)
         .map_err(|err| {
             console::error!(
@@ -286,30 +608,65 @@ async fn on_port_request(
 
 async fn auth(
     app: &Rc<RefCell<App>>,
-    storage_key: StorageSecretKey,
+    mut storage_key: StorageSecretKey,
     key_pair: KeyPair,
 ) -> AppResponsePayload {
-    match Api::new(key_pair.clone())
-        .get_passwords(key_pair.get_pk())
-        .await
-    {
+    let api = match app.borrow().api_for(key_pair.clone()) {
+        Ok(api) => api,
+        Err(err) => {
+            return AppResponsePayload::Auth {
+                error: Some(err.to_string()),
+            }
+        }
+    };
+
+    // Logging in on a new device, only shown in the popup so it's not worth failing for
+    if storage_key.email.is_none() {
+        storage_key.email = match api.get_user(key_pair.get_pk()).await {
+            Ok(user) => user.email,
+            Err(err) => {
+                console::warn!("Failed to get account details", err.to_string());
+                None
+            }
+        };
+    }
+
+    match api.get_passwords(key_pair.get_pk()).await {
         Ok(passwords) => {
             let creds = StorageCredentials::from(passwords);
-            app.borrow_mut().login(key_pair, creds.credentials.clone());
+            app.borrow_mut()
+                .login(key_pair, creds.credentials.clone(), api);
 
             match creds.save().await.and(storage_key.save().await) {
-                Ok(()) => AppResponsePayload::Auth { error: None },
+                Ok(()) => {
+                    remember_unlock(app).await;
+                    AppResponsePayload::Auth { error: None }
+                }
                 Err(err) => AppResponsePayload::Auth {
-                    error: Some(err.to_string()),
+                    error: Some(AuthError::StorageUnavailable(err.to_string()).to_string()),
                 },
             }
         }
         Err(err) => AppResponsePayload::Auth {
-            error: Some(err.to_string()),
+            error: Some(AuthError::Server(err.to_string()).to_string()),
         },
     }
 }
 
+/// Load what unlocking needs from storage, failing with
+/// [`AuthError::StorageUnavailable`] when it can't be read
+async fn load_for_unlock() -> anyhow::Result<(StorageSecretKey, StorageCredentials)> {
+    let storage_unavailable = |err: anyhow::Error| AuthError::StorageUnavailable(err.to_string());
+    Ok((
+        StorageSecretKey::load()
+            .await
+            .map_err(storage_unavailable)?,
+        StorageCredentials::load()
+            .await
+            .map_err(storage_unavailable)?,
+    ))
+}
+
 /// Handle a (global) request.
 ///
 /// Optionally returns a single response.
@@ -320,70 +677,180 @@ async fn handle_app_request(
     request: AppRequest,
 ) -> Option<AppResponse> {
     let Request { header, payload } = request;
+
+    lock_if_inactive(app).await;
+    restore_remembered_unlock(app).await;
+    if !matches!(
+        payload,
+        AppRequestPayload::GetOptionsInfo
+            | AppRequestPayload::GetSettings
+            | AppRequestPayload::GetStatus
+            | AppRequestPayload::GetActiveSite
+    ) {
+        app.borrow_mut().touch();
+    }
+
     let payload: AppResponsePayload = match payload {
         AppRequestPayload::GetOptionsInfo => AppResponsePayload::OptionsInfo {
             version: VERSION.to_string(),
+            auto_lock_minutes: app.borrow().settings.auto_lock_minutes,
         },
+        AppRequestPayload::SetAutoLock { minutes } => {
+            let settings = Settings {
+                auto_lock_minutes: minutes,
+                ..app.borrow().settings.clone()
+            };
+            save_settings(app, settings).await
+        }
+        AppRequestPayload::GetSettings => {
+            AppResponsePayload::Settings(app.borrow().settings.clone())
+        }
+        AppRequestPayload::SetSettings { settings } => save_settings(app, settings).await,
+        AppRequestPayload::GetActiveSite => {
+            let site = app.borrow().active_site();
+            let ignored = site
+                .as_ref()
+                .is_some_and(|site| app.borrow().is_site_ignored(site));
+            AppResponsePayload::ActiveSite { site, ignored }
+        }
+        AppRequestPayload::SetSiteIgnored { site, ignored } => {
+            let mut settings = app.borrow().settings.clone();
+            let site = site_host(&site);
+            settings
+                .ignored_sites
+                .retain(|ignored_site| *ignored_site != site);
+            if ignored {
+                settings.ignored_sites.push(site);
+            }
+            save_settings(app, settings).await
+        }
+        // Always answered, the popup waits for it before showing anything
         AppRequestPayload::GetStatus => match StorageSecretKey::load().await {
-            Ok(sk) => match app.borrow().get_status(sk) {
-                Ok((is_logged_in, is_unlocked)) => AppResponsePayload::Status {
+            Ok(sk) => {
+                let email = sk.email.clone();
+                let (is_logged_in, is_unlocked, public_key) = app.borrow().get_status(sk);
+                AppResponsePayload::Status {
                     is_logged_in,
                     is_unlocked,
-                },
-                Err(_) => {
-                    return None;
+                    public_key,
+                    email: email.filter(|_| is_unlocked),
                 }
+            }
+            Err(err) => AppResponsePayload::Error {
+                message: AuthError::StorageUnavailable(err.to_string()).to_string(),
             },
-            Err(_) => {
-                return None;
+        },
+        AppRequestPayload::Unlock { device_password } => {
+            let result = match load_for_unlock().await {
+                Ok((sk, creds)) => app.borrow_mut().unlock(sk, creds, device_password),
+                Err(err) => Err(err),
+            };
+
+            if result.is_ok() {
+                remember_unlock(app).await;
             }
+
+            AppResponsePayload::Auth {
+                error: result.err().map(|err| err.to_string()),
+            }
+        }
+        AppRequestPayload::GetWebAuthnUnlock => match StorageWebAuthn::load().await {
+            Ok(webauthn) => AppResponsePayload::WebAuthnUnlock(webauthn.credential()),
+            Err(err) => AppResponsePayload::Error {
+                message: err.to_string(),
+            },
         },
-        AppRequestPayload::Unlock { device_password } => match StorageSecretKey::load().await {
-            Ok(sk) => match StorageCredentials::load().await {
-                Ok(creds) => match app.borrow_mut().unlock(sk, creds, device_password) {
-                    Ok(()) => AppResponsePayload::Auth { error: None },
-                    Err(err) => AppResponsePayload::Auth {
-                        error: Some(err.to_string()),
-                    },
-                },
-                Err(err) => AppResponsePayload::Auth {
-                    error: Some(err.to_string()),
+        AppRequestPayload::EnableWebAuthnUnlock {
+            credential,
+            prf_output,
+        } => {
+            let result = app.borrow().wrap_secret_key(&prf_output);
+            let result = match result {
+                Ok(wrapped_sk) => StorageWebAuthn::new(credential, wrapped_sk).save().await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => AppResponsePayload::Ok,
+                Err(err) => AppResponsePayload::Error {
+                    message: err.to_string(),
                 },
-            },
-            Err(err) => AppResponsePayload::Auth {
-                error: Some(err.to_string()),
+            }
+        }
+        AppRequestPayload::DisableWebAuthnUnlock => match StorageWebAuthn::remove().await {
+            Ok(()) => AppResponsePayload::Ok,
+            Err(err) => AppResponsePayload::Error {
+                message: err.to_string(),
             },
         },
+        AppRequestPayload::UnlockWithWebAuthn { prf_output } => {
+            let stored = async {
+                let (sk, creds) = load_for_unlock().await?;
+                let webauthn = StorageWebAuthn::load()
+                    .await
+                    .map_err(|err| AuthError::StorageUnavailable(err.to_string()))?;
+                Ok::<_, anyhow::Error>((sk, creds, webauthn))
+            };
+
+            let result = match stored.await {
+                Ok((sk, creds, webauthn)) => {
+                    app.borrow_mut()
+                        .unlock_with_webauthn(sk, creds, webauthn, &prf_output)
+                }
+                Err(err) => Err(err),
+            };
+
+            if result.is_ok() {
+                remember_unlock(app).await;
+            }
+
+            AppResponsePayload::Auth {
+                error: result.err().map(|err| err.to_string()),
+            }
+        }
         AppRequestPayload::Lock {} => {
-            match app.borrow_mut().lock() {
-                Ok(()) => AppResponsePayload::Ok,
+            let result = app.borrow_mut().lock();
+            match result {
+                Ok(()) => {
+                    forget_unlock().await;
+                    AppResponsePayload::Ok
+                }
                 Err(err) => {
                     console::error!("Failed to lock", err.to_string());
-                    return None; // TODO: Error
+                    AppResponsePayload::Error {
+                        message: err.to_string(),
+                    }
                 }
             }
         }
         AppRequestPayload::Login {
             seed_phrase,
             device_password,
-        } => match StorageSecretKey::from_seed_phrase(seed_phrase, device_password).await {
+            passphrase,
+        } => match StorageSecretKey::from_seed_phrase(
+            seed_phrase,
+            passphrase.as_deref(),
+            device_password,
+        )
+        .await
+        {
             Ok((sk, key_pair)) => auth(app, sk, key_pair).await,
             Err(err) => AppResponsePayload::Auth {
                 error: Some(err.to_string()),
             },
         },
-        AppRequestPayload::Register { device_password } => {
-            match StorageSecretKey::generate(device_password).await {
+        AppRequestPayload::Register {
+            device_password,
+            email,
+        } => {
+            let server_url = app.borrow().settings.server_url.clone();
+            let email = email.filter(|email| !email.trim().is_empty());
+            match StorageSecretKey::generate(device_password, email, server_url.as_deref()).await {
                 Ok((sk, seed_phrase, key_pair)) => match auth(app, sk, key_pair).await {
                     AppResponsePayload::Auth { error: None } => {
                         AppResponsePayload::SeedPhrase(seed_phrase)
                     }
-                    AppResponsePayload::Auth { error: Some(err) } => {
-                        AppResponsePayload::Auth { error: Some(err) }
-                    }
-                    _ => {
-                        return None;
-                    }
+                    response => response,
                 },
                 Err(err) => AppResponsePayload::Auth {
                     error: Some(err.to_string()),
@@ -401,8 +868,25 @@ async fn handle_app_request(
             }
         }
         AppRequestPayload::GetCredential { site, username } => {
-            match app.borrow().get_credential(site, username) {
-                Ok((username, password)) => AppResponsePayload::Credential { username, password },
+            match app.borrow().get_credentials(site, username) {
+                Ok(mut credentials) if credentials.len() == 1 => {
+                    let credential = credentials.remove(0);
+                    AppResponsePayload::Credential {
+                        username: credential.username,
+                        password: credential.password,
+                    }
+                }
+                Ok(credentials) => AppResponsePayload::Credentials(
+                    credentials
+                        .into_iter()
+                        .map(|cred| Credential {
+                            id: cred._id,
+                            site: cred.site,
+                            username: cred.username,
+                            password: cred.password,
+                        })
+                        .collect(),
+                ),
                 Err(err) => AppResponsePayload::Error {
                     message: err.to_string(),
                 },
@@ -473,6 +957,35 @@ async fn handle_app_request(
                 },
             }
         }
+        AppRequestPayload::GeneratePassword {
+            length,
+            opts,
+            passphrase,
+        } => {
+            let generated = if passphrase {
+                generate_passphrase(length, PASSPHRASE_SEPARATOR)
+                    .map(|password| (password, passphrase_entropy(length)))
+            } else {
+                generate_password_with(length, &opts)
+                    .map(|password| (password, password_entropy(length, &opts)))
+            };
+
+            match generated {
+                Ok((password, entropy_bits)) => AppResponsePayload::GeneratedPassword {
+                    password,
+                    entropy_bits,
+                },
+                Err(err) => AppResponsePayload::Error {
+                    message: err.to_string(),
+                },
+            }
+        }
+        AppRequestPayload::FillPassword { password } => match fill_password(app, password) {
+            Ok(()) => AppResponsePayload::Ok,
+            Err(err) => AppResponsePayload::Error {
+                message: err.to_string(),
+            },
+        },
     };
 
     Response {
@@ -482,6 +995,152 @@ async fn handle_app_request(
     .into()
 }
 
+/// Send a password to every frame of the active tab, the one with a focused
+/// password field fills it
+fn fill_password(app: &Rc<RefCell<App>>, password: String) -> anyhow::Result<()> {
+    let tab_id = app.borrow().active_tab.ok_or(anyhow!("No active tab"))?;
+    let port_ids = app.borrow().connected_ports.find_all(tab_id);
+    if port_ids.is_empty() {
+        return Err(anyhow!("The active tab has no login form"));
+    }
+
+    for port_id in port_ids {
+        let request_id = app.borrow_mut().next_port_request_id(port_id)?;
+        let response = PortResponse {
+            header: RequestHeader::new().into_response(request_id),
+            payload: PortResponsePayload::FillPassword {
+                password: password.clone(),
+            },
+        };
+        app.borrow()
+            .post_port_message_js(port_id, &JsValue::from_serde(&response)?)?;
+    }
+    Ok(())
+}
+
+/// Validate and persist `settings`, applying them once stored
+async fn save_settings(app: &Rc<RefCell<App>>, settings: Settings) -> AppResponsePayload {
+    if let Some(Err(err)) = settings.server_url.as_deref().map(parse_base_url) {
+        return AppResponsePayload::Error {
+            message: err.to_string(),
+        };
+    }
+
+    let result = match StorageSettings::from(settings.clone()).save().await {
+        Ok(()) => app.borrow_mut().set_settings(settings),
+        Err(err) => Err(err),
+    };
+
+    // Apply the new duration to the current unlock
+    if result.is_ok() {
+        if app.borrow().settings.remember_unlock_minutes == 0 {
+            forget_unlock().await;
+        } else if app.borrow().is_unlocked() {
+            remember_unlock(app).await;
+        }
+    }
+
+    match result {
+        Ok(()) => AppResponsePayload::Ok,
+        Err(err) => AppResponsePayload::Error {
+            message: err.to_string(),
+        },
+    }
+}
+
+/// Lock the vault if it's been inactive for too long, and forget the
+/// remembered unlock so it isn't restored right away
+async fn lock_if_inactive(app: &Rc<RefCell<App>>) {
+    if app.borrow_mut().lock_if_inactive() {
+        console::info!("Locked after inactivity");
+        update_badges(app);
+        update_context_menu(app);
+        forget_unlock().await;
+    }
+}
+
+/// Keep the vault unlocked across background restarts for the configured duration.
+///
+/// The secret key is stored encrypted with a fresh key that only lives in
+/// session storage, so the remembered unlock is lost with the browser session.
+async fn remember_unlock(app: &Rc<RefCell<App>>) {
+    let minutes = app.borrow().settings.remember_unlock_minutes;
+    if minutes == 0 {
+        return;
+    }
+
+    let result = async {
+        let session_key = generate_key()?;
+        let secret_key = app.borrow().encrypt_secret_key(&session_key)?;
+        let until = Date::now() + f64::from(minutes) * 60_000.0;
+
+        StorageSessionKey::new(session_key).save().await?;
+        StorageRememberedUnlock::new(secret_key, until).save().await
+    };
+
+    if let Err(err) = result.await {
+        console::error!("Failed to remember unlock", err.to_string());
+    }
+}
+
+async fn forget_unlock() {
+    if let Err(err) = StorageRememberedUnlock::remove().await {
+        console::error!("Failed to forget unlock", err.to_string());
+    }
+}
+
+/// Unlock with the remembered secret key if the vault got locked by a
+/// background restart and the remembered unlock hasn't expired yet.
+///
+/// Called on startup and before handling requests, which may come in before
+/// `start` loaded the settings, so they're loaded here too.
+async fn restore_remembered_unlock(app: &Rc<RefCell<App>>) {
+    if app.borrow().is_unlocked() {
+        return;
+    }
+
+    let result = async {
+        let remembered = StorageRememberedUnlock::load().await?;
+        let (Some(secret_key), Some(until)) = (
+            remembered.remembered_secret_key,
+            remembered.remembered_until,
+        ) else {
+            return Ok(false);
+        };
+
+        let session_key = match StorageSessionKey::load().await?.session_key {
+            Some(session_key) if Date::now() < until => session_key,
+            _ => {
+                StorageRememberedUnlock::remove().await?;
+                return Ok(false);
+            }
+        };
+
+        // The unlocked vault talks to the server from the settings
+        let settings = StorageSettings::load().await?.into_settings();
+        let sk = StorageSecretKey::load().await?;
+        let creds = StorageCredentials::load().await?;
+
+        let mut app = app.borrow_mut();
+        app.settings = settings;
+        app.unlock_with_wrapped_sk(sk, creds, secret_key, &session_key)?;
+        Ok::<_, anyhow::Error>(true)
+    };
+
+    match result.await {
+        Ok(true) => {
+            console::info!("Restored remembered unlock");
+            update_badges(app);
+            update_context_menu(app);
+        }
+        Ok(false) => {}
+        Err(err) => {
+            console::error!("Failed to restore remembered unlock", err.to_string());
+            forget_unlock().await;
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 enum StreamingTaskError {
     #[error(transparent)]
@@ -495,55 +1154,87 @@ enum StreamingTaskError {
 /// TODO: Extract into domain crate
 async fn handle_port_request(
     app: &Rc<RefCell<App>>,
-    _port_id: PortId,
+    port_id: PortId,
     request_id: RequestId,
     request: PortRequest,
 ) -> PortResponse {
     let Request { header, payload } = request;
+
+    lock_if_inactive(app).await;
+    restore_remembered_unlock(app).await;
+    app.borrow_mut().touch();
+
+    // Trust the url the browser reports for the frame over the one sent by the page
+    let (frame_site, is_cross_origin_frame) = app
+        .borrow()
+        .connected_ports
+        .frame_site(port_id)
+        .unwrap_or((None, false));
+
+    let request_site = match &payload {
+        PortRequestPayload::GetCredential { site, .. }
+        | PortRequestPayload::SaveCredential { site, .. } => {
+            frame_site.clone().unwrap_or(site.clone())
+        }
+    };
+    let is_ignored = app.borrow().is_site_ignored(&request_site);
+
     let payload = match payload {
-        PortRequestPayload::GetCredential { site } => {
-            match app.borrow().get_credential(site, None) {
-                Ok((username, password)) => PortResponsePayload::Credential { username, password },
-                Err(err) => {
-                    console::error!("Failed to get credential", err.to_string());
-                    PortResponsePayload::Error(err.to_string())
-                }
-            }
+        // Neither filled nor saved, filling from the context menu still works there
+        _ if is_ignored => PortResponsePayload::Ok,
+        PortRequestPayload::GetCredential { .. } if is_cross_origin_frame => {
+            PortResponsePayload::Error("Not filling a frame embedded from another site".into())
         }
-        PortRequestPayload::SetTmpCredentialUsername { site, username } => {
-            match app.borrow_mut().set_tmp_credential_username(site, username) {
-                Ok(()) => PortResponsePayload::Ok,
-                Err(err) => {
-                    console::error!("Failed to set tmp credential username", err.to_string());
-                    PortResponsePayload::Error(err.to_string())
-                }
-            }
+        PortRequestPayload::GetCredential { on_load: true, .. }
+            if !app.borrow().settings.autofill_on_load =>
+        {
+            PortResponsePayload::Ok
         }
-        PortRequestPayload::SetTmpCredentialPassword { site, password } => {
-            match app.borrow_mut().set_tmp_credential_password(site, password) {
-                Ok(()) => PortResponsePayload::Ok,
+        PortRequestPayload::GetCredential { site, username, .. } => {
+            let site = frame_site.unwrap_or(site);
+            match app.borrow().get_credentials(site, username) {
+                Ok(mut credentials) if credentials.len() == 1 => {
+                    let credential = credentials.remove(0);
+                    PortResponsePayload::Credential {
+                        username: credential.username,
+                        password: credential.password,
+                        submit: app.borrow().settings.auto_submit,
+                    }
+                }
+                Ok(credentials) => PortResponsePayload::Credentials(
+                    credentials
+                        .into_iter()
+                        .map(|cred| CredentialOption {
+                            label: format!("{} ({})", cred.username, cred.site),
+                            username: cred.username,
+                        })
+                        .collect(),
+                ),
                 Err(err) => {
-                    console::error!("Failed to set tmp credential password", err.to_string());
+                    console::error!("Failed to get credential", err.to_string());
                     PortResponsePayload::Error(err.to_string())
                 }
             }
         }
-        PortRequestPayload::StoreTmpCredential { site } => {
-            let result = { app.borrow_mut().store_tmp_credential(site) };
+        PortRequestPayload::SaveCredential {
+            site,
+            username,
+            password,
+        } => {
+            let site = frame_site.unwrap_or(site);
+            let result = { app.borrow_mut().save_credential(site, username, password) };
 
             match result {
-                Ok(action) => {
-                    let result = execute_storage_credentials_action(app, action).await;
-                    match result {
-                        Ok(()) => PortResponsePayload::Ok,
-                        Err(err) => {
-                            console::error!("Failed to store tmp credential", err.to_string());
-                            PortResponsePayload::Error(err.to_string())
-                        }
+                Ok(Some(action)) => match execute_storage_credentials_action(app, action).await {
+                    Ok(()) => PortResponsePayload::Ok,
+                    Err(err) => {
+                        console::error!("Failed to save credential", err.to_string());
+                        PortResponsePayload::Error(err.to_string())
                     }
-                }
+                },
+                Ok(None) => PortResponsePayload::Ok,
                 Err(err) => {
-                    console::error!("Failed to store tmp credential", err.to_string());
+                    console::error!("Failed to save credential", err.to_string());
                     PortResponsePayload::Error(err.to_string())
                 }
             }
@@ -557,6 +1248,33 @@ async fn handle_port_request(
     }
 }
 
+#[wasm_bindgen]
+extern "C" {
+    // https://developer.chrome.com/docs/extensions/reference/action/#method-setBadgeText
+    #[wasm_bindgen(catch, js_namespace = ["chrome", "action"], js_name = setBadgeText)]
+    async fn set_badge_text(details: JsValue) -> Result<JsValue, JsValue>;
+}
+
+// https://developer.chrome.com/docs/extensions/reference/action/#method-setBadgeText
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BadgeTextDetails {
+    tab_id: TabId,
+    text: String,
+}
+
+// https://developer.chrome.com/docs/extensions/reference/api/contextMenus#method-create
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ContextMenuItem<'a> {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_id: Option<&'a str>,
+    title: String,
+    contexts: &'a [&'a str],
+    enabled: bool,
+}
+
 // https://developer.chrome.com/docs/extensions/reference/scripting/#type-CSSInjection
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
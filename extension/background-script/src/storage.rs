@@ -1,26 +1,47 @@
-use crate::app::App;
+use crate::app::{api_for, App, AuthError};
 use anyhow::anyhow;
 use gloo_utils::format::JsValueSerdeExt;
 use js_sys::Object;
+use messages::{Settings, WebAuthnCredential};
 use passphrasex_common::api::Api;
 use passphrasex_common::crypto::asymmetric::{KeyPair, SeedPhrase};
 use passphrasex_common::crypto::symmetric::{encrypt_data, generate_salt, hash};
 use passphrasex_common::model::password::Password;
+use passphrasex_common::model::user::User;
 use passphrasex_common::model::CredentialsMap;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::rc::Rc;
 use wasm_bindgen::JsValue;
-use web_extensions_sys::chrome;
+use web_extensions_sys::{chrome, StorageArea};
 
-pub static STORAGE_KEYS: [&str; 3] = ["public_key", "secret_key", "salt"];
+pub static STORAGE_KEYS: [&str; 4] = ["public_key", "secret_key", "salt", "email"];
 pub static CREDENTIALS_KEYS: [&str; 1] = ["credentials"];
+pub static WEBAUTHN_KEYS: [&str; 3] = [
+    "webauthn_credential_id",
+    "webauthn_salt",
+    "webauthn_secret_key",
+];
+pub static REMEMBERED_UNLOCK_KEYS: [&str; 2] = ["remembered_secret_key", "remembered_until"];
+pub static SESSION_KEY_KEYS: [&str; 1] = ["session_key"];
+pub static SETTINGS_KEYS: [&str; 7] = [
+    "auto_lock_minutes",
+    "remember_unlock_minutes",
+    "autofill_on_load",
+    "auto_submit",
+    "strict_site_matching",
+    "server_url",
+    "ignored_sites",
+];
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct StorageSecretKey {
     pub public_key: Option<String>,
     pub secret_key: Option<String>,
     pub salt: Option<String>,
+    /// Email of the account, kept to show who's logged in
+    #[serde(default)]
+    pub email: Option<String>,
 }
 
 impl TryInto<Object> for StorageSecretKey {
@@ -42,26 +63,42 @@ impl StorageSecretKey {
             public_key,
             secret_key,
             salt,
+            email: None,
         }
     }
 
-    pub async fn generate(device_password: String) -> anyhow::Result<(Self, String, KeyPair)> {
+    pub async fn generate(
+        device_password: String,
+        email: Option<String>,
+        server_url: Option<&str>,
+    ) -> anyhow::Result<(Self, String, KeyPair)> {
         let salt = generate_salt()?;
         let pass_hash = hash(&device_password, &salt)?;
 
         let seed_phrase = SeedPhrase::new();
-        let key_pair = KeyPair::try_new(seed_phrase.clone())?;
+        let key_pair = KeyPair::try_new(seed_phrase.clone(), None)?;
 
         let enc_sk = encrypt_data(&pass_hash.cipher, key_pair.private_key.as_bytes())?;
         let secret_key = hex::encode(enc_sk.as_slice());
 
         let public_key = key_pair.get_pk();
 
-        let api = Api::new(key_pair.clone());
-        api.create_user(public_key.clone()).await?;
+        let user = User {
+            email,
+            signing_key: Some(key_pair.get_signing_pk()),
+            ..User::new(public_key.clone())
+        };
+        let api = api_for(key_pair.clone(), server_url)?;
+        api.create_user(&user)
+            .await
+            .map_err(|err| AuthError::Server(err.to_string()))?;
 
         Ok((
-            Self::new(Some(public_key), Some(secret_key), Some(salt)),
+            Self {
+                // As the server stored it, trimmed
+                email: user.email.map(|email| email.trim().to_string()),
+                ..Self::new(Some(public_key), Some(secret_key), Some(salt))
+            },
             seed_phrase.get_phrase(),
             key_pair,
         ))
@@ -69,13 +106,16 @@ impl StorageSecretKey {
 
     pub async fn from_seed_phrase(
         seed_phrase: String,
+        passphrase: Option<&str>,
         device_password: String,
     ) -> anyhow::Result<(Self, KeyPair)> {
         let salt = generate_salt()?;
         let pass_hash = hash(&device_password, &salt)?;
 
-        let seed_phrase = SeedPhrase::from(seed_phrase);
-        let key_pair = KeyPair::try_new(seed_phrase)?;
+        let seed_phrase =
+            SeedPhrase::validate(&seed_phrase).map_err(|_| AuthError::InvalidSeedPhrase)?;
+        let key_pair =
+            KeyPair::try_new(seed_phrase, passphrase).map_err(|_| AuthError::InvalidSeedPhrase)?;
 
         let enc_sk = encrypt_data(&pass_hash.cipher, key_pair.private_key.as_bytes())?;
         let secret_key = hex::encode(enc_sk.as_slice());
@@ -101,6 +141,188 @@ impl StorageSecretKey {
     }
 }
 
+/// User settings, each under its own key so missing ones fall back to their default
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageSettings {
+    pub auto_lock_minutes: Option<u32>,
+    pub remember_unlock_minutes: Option<u32>,
+    pub autofill_on_load: Option<bool>,
+    pub auto_submit: Option<bool>,
+    pub strict_site_matching: Option<bool>,
+    pub server_url: Option<String>,
+    pub ignored_sites: Option<Vec<String>>,
+}
+
+impl TryInto<Object> for StorageSettings {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<Object, Self::Error> {
+        let js_value = JsValue::from_serde(&self)?;
+        Ok(Object::from(js_value))
+    }
+}
+
+impl From<Settings> for StorageSettings {
+    fn from(settings: Settings) -> Self {
+        Self {
+            auto_lock_minutes: Some(settings.auto_lock_minutes),
+            remember_unlock_minutes: Some(settings.remember_unlock_minutes),
+            autofill_on_load: Some(settings.autofill_on_load),
+            auto_submit: Some(settings.auto_submit),
+            strict_site_matching: Some(settings.strict_site_matching),
+            server_url: settings.server_url,
+            ignored_sites: Some(settings.ignored_sites),
+        }
+    }
+}
+
+impl StorageSettings {
+    pub fn into_settings(self) -> Settings {
+        let defaults = Settings::default();
+        Settings {
+            auto_lock_minutes: self.auto_lock_minutes.unwrap_or(defaults.auto_lock_minutes),
+            remember_unlock_minutes: self
+                .remember_unlock_minutes
+                .unwrap_or(defaults.remember_unlock_minutes),
+            autofill_on_load: self.autofill_on_load.unwrap_or(defaults.autofill_on_load),
+            auto_submit: self.auto_submit.unwrap_or(defaults.auto_submit),
+            strict_site_matching: self
+                .strict_site_matching
+                .unwrap_or(defaults.strict_site_matching),
+            server_url: self.server_url,
+            ignored_sites: self.ignored_sites.unwrap_or_default(),
+        }
+    }
+
+    pub async fn load() -> anyhow::Result<Self> {
+        load_from_local_storage(&SETTINGS_KEYS).await
+    }
+
+    pub async fn save(self) -> anyhow::Result<()> {
+        save_to_local_storage(self).await
+    }
+}
+
+/// Secret key wrapped with a WebAuthn PRF output, kept in session storage so
+/// it's gone once the browser closes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageWebAuthn {
+    pub webauthn_credential_id: Option<String>,
+    pub webauthn_salt: Option<String>,
+    pub webauthn_secret_key: Option<String>,
+}
+
+impl TryInto<Object> for StorageWebAuthn {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<Object, Self::Error> {
+        let js_value = JsValue::from_serde(&self)?;
+        Ok(Object::from(js_value))
+    }
+}
+
+impl StorageWebAuthn {
+    pub fn new(credential: WebAuthnCredential, secret_key: String) -> Self {
+        Self {
+            webauthn_credential_id: Some(credential.credential_id),
+            webauthn_salt: Some(credential.salt),
+            webauthn_secret_key: Some(secret_key),
+        }
+    }
+
+    pub fn credential(&self) -> Option<WebAuthnCredential> {
+        Some(WebAuthnCredential {
+            credential_id: self.webauthn_credential_id.clone()?,
+            salt: self.webauthn_salt.clone()?,
+        })
+    }
+
+    pub async fn load() -> anyhow::Result<Self> {
+        load_from_storage(session_storage(), &WEBAUTHN_KEYS).await
+    }
+
+    pub async fn save(self) -> anyhow::Result<()> {
+        save_to_storage(session_storage(), self).await
+    }
+
+    pub async fn remove() -> anyhow::Result<()> {
+        remove_from_storage(session_storage(), &WEBAUTHN_KEYS).await
+    }
+}
+
+/// Copy of the secret key that keeps the vault unlocked across background
+/// restarts until `remembered_until` (ms since the epoch). It's encrypted with
+/// the [`StorageSessionKey`], so it can't be used once the browser is closed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageRememberedUnlock {
+    pub remembered_secret_key: Option<String>,
+    pub remembered_until: Option<f64>,
+}
+
+impl TryInto<Object> for StorageRememberedUnlock {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<Object, Self::Error> {
+        let js_value = JsValue::from_serde(&self)?;
+        Ok(Object::from(js_value))
+    }
+}
+
+impl StorageRememberedUnlock {
+    pub fn new(secret_key: String, until: f64) -> Self {
+        Self {
+            remembered_secret_key: Some(secret_key),
+            remembered_until: Some(until),
+        }
+    }
+
+    pub async fn load() -> anyhow::Result<Self> {
+        load_from_local_storage(&REMEMBERED_UNLOCK_KEYS).await
+    }
+
+    pub async fn save(self) -> anyhow::Result<()> {
+        save_to_local_storage(self).await
+    }
+
+    /// Forget the remembered unlock along with the key protecting it
+    pub async fn remove() -> anyhow::Result<()> {
+        remove_from_storage(session_storage(), &SESSION_KEY_KEYS).await?;
+        remove_from_local_storage(&REMEMBERED_UNLOCK_KEYS).await
+    }
+}
+
+/// Random key encrypting the [`StorageRememberedUnlock`], kept in session
+/// storage so it's cleared when the browser closes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageSessionKey {
+    pub session_key: Option<String>,
+}
+
+impl TryInto<Object> for StorageSessionKey {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<Object, Self::Error> {
+        let js_value = JsValue::from_serde(&self)?;
+        Ok(Object::from(js_value))
+    }
+}
+
+impl StorageSessionKey {
+    pub fn new(session_key: String) -> Self {
+        Self {
+            session_key: Some(session_key),
+        }
+    }
+
+    pub async fn load() -> anyhow::Result<Self> {
+        load_from_storage(session_storage(), &SESSION_KEY_KEYS).await
+    }
+
+    pub async fn save(self) -> anyhow::Result<()> {
+        save_to_storage(session_storage(), self).await
+    }
+}
+
 pub enum StorageCredentialsAction {
     Add(CredentialsMap, Password),
     Edit(CredentialsMap, Password),
@@ -174,6 +396,8 @@ impl StorageCredentialsAction {
             StorageCredentialsAction::Logout => {
                 StorageSecretKey::remove().await?;
                 StorageCredentials::remove().await?;
+                StorageWebAuthn::remove().await?;
+                StorageRememberedUnlock::remove().await?;
                 Ok(())
             }
         }
@@ -184,6 +408,8 @@ impl StorageCredentialsAction {
             StorageCredentialsAction::Logout => {
                 StorageSecretKey::remove().await?;
                 StorageCredentials::remove().await?;
+                StorageWebAuthn::remove().await?;
+                StorageRememberedUnlock::remove().await?;
                 Ok(())
             }
             _ => Err(anyhow!("Cannot execute action without API")),
@@ -193,38 +419,53 @@ impl StorageCredentialsAction {
 
 async fn save_to_local_storage(
     obj: impl TryInto<Object, Error = anyhow::Error>,
+) -> anyhow::Result<()> {
+    save_to_storage(chrome().storage().local(), obj).await
+}
+
+async fn load_from_local_storage<T: for<'a> Deserialize<'a>>(keys: &[&str]) -> anyhow::Result<T> {
+    load_from_storage(chrome().storage().local(), keys).await
+}
+
+async fn remove_from_local_storage(keys: &[&str]) -> anyhow::Result<()> {
+    remove_from_storage(chrome().storage().local(), keys).await
+}
+
+/// Storage cleared when the browser closes
+fn session_storage() -> StorageArea {
+    chrome().storage().session()
+}
+
+async fn save_to_storage(
+    area: StorageArea,
+    obj: impl TryInto<Object, Error = anyhow::Error>,
 ) -> anyhow::Result<()> {
     let obj: Object = obj.try_into()?;
-    chrome()
-        .storage()
-        .local()
-        .set(&obj)
+    area.set(&obj)
         .await
-        .map_err(|err| anyhow!("Error writing to local storage: {:?}", err))?;
+        .map_err(|err| anyhow!("Error writing to storage: {:?}", err))?;
 
     Ok(())
 }
 
-async fn load_from_local_storage<T: for<'a> Deserialize<'a>>(keys: &[&str]) -> anyhow::Result<T> {
-    let js_value = chrome()
-        .storage()
-        .local()
+async fn load_from_storage<T: for<'a> Deserialize<'a>>(
+    area: StorageArea,
+    keys: &[&str],
+) -> anyhow::Result<T> {
+    let js_value = area
         .get(&JsValue::from_serde(keys)?)
         .await
-        .map_err(|err| anyhow!("Error reading from local storage: {:?}", err))?;
+        .map_err(|err| anyhow!("Error reading from storage: {:?}", err))?;
 
     js_value
         .into_serde()
-        .map_err(|err| anyhow!("Error deserializing local storage: {:?}", err))
+        .map_err(|err| anyhow!("Error deserializing storage: {:?}", err))
 }
 
-async fn remove_from_local_storage(keys: &[&str]) -> anyhow::Result<()> {
-    chrome()
-        .storage()
-        .local()
-        .remove(&JsValue::from_serde(keys)?)
+async fn remove_from_storage(area: StorageArea, keys: &[&str]) -> anyhow::Result<()> {
+    area.remove(&JsValue::from_serde(keys)?)
         .await
-        .map_err(|err| anyhow!("Error reading from local storage: {:?}", err))?;
+        .map_err(|err| anyhow!("Error removing from storage: {:?}", err))?;
 
     Ok(())
 }
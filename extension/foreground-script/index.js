@@ -1,51 +1,98 @@
+// Wait for the DOM to settle before looking for login forms again
+const MUTATION_DEBOUNCE_MS = 250;
 
 function start() {
     const port = connect();
     const site = getSite();
 
-    let added_listener_username = false;
-    let added_listener_password = false;
+    // Inputs of the login form being handled, replaced when the page renders a new one
+    let username_input = null;
+    let password_input = null;
+    // Credential filled in by the extension, used to tell new logins from updated ones
+    let filled = null;
+    const submit_targets = new WeakSet();
+    // Field the context menu was last opened on, filled by "Fill with PassPhraseX"
+    let context_menu_target = null;
 
-    const on_change = function () {
-        const [username_input, password_input] = getInputs();
-        if (!username_input && !password_input) {
-            return;
-        }
+    document.addEventListener("contextmenu", (event) => {
+        context_menu_target = event.target;
+    }, true);
 
-        if (username_input && !added_listener_username) {
-            added_listener_username = true;
-            username_input.addEventListener(
-                "input",
-                (e) => setTmpCredentialUsername(port, site, e.target.value)
+    port.onMessage.addListener(function (msg) {
+        if (msg.payload.Credentials) {
+            showCredentialPicker(
+                username_input || password_input,
+                msg.payload.Credentials,
+                (username) => getCredential(port, site, username)
             );
         }
 
-        if (password_input && !added_listener_password) {
-            added_listener_password = true;
-            password_input.addEventListener(
-                "input",
-                (e) => setTmpCredentialPassword(port, site, e.target.value)
-            );
-        }
+        if (msg.payload.Credential) {
+            const { username, password, submit } = msg.payload.Credential;
+            filled = { username, password };
 
-        port.onMessage.addListener(function (msg) {
-            if (msg.payload.Credential) {
-                const { username, password } = msg.payload.Credential;
+            if (username_input) {
+                setValue(username_input, username);
+            }
 
-                if (username_input) {
-                    username_input.value = username;
+            if (password_input) {
+                setValue(password_input, password);
+                if (submit) {
+                    submitForm(password_input);
                 }
+            }
+        }
 
-                if (password_input) {
-                    password_input.value = password;
-                }
+        if (msg.payload.Fill && context_menu_target) {
+            const { username, password } = msg.payload.Fill;
+            filled = { username, password };
+            fillFromTarget(context_menu_target, username, password);
+        }
+
+        // Not recorded as filled, so submitting the form offers to save it
+        if (msg.payload.FillPassword) {
+            fillPassword(password_input, msg.payload.FillPassword.password);
+        }
+    });
+
+    const on_change = function () {
+        const [new_username_input, new_password_input] = getInputs();
+        if (!new_username_input && !new_password_input) {
+            return;
+        }
+
+        // Same form as before, nothing new to fill
+        if (new_username_input === username_input && new_password_input === password_input) {
+            return;
+        }
+
+        username_input = new_username_input;
+        password_input = new_password_input;
+
+        if (password_input) {
+            const target = password_input.form || window;
+            if (!submit_targets.has(target)) {
+                submit_targets.add(target);
+                target.addEventListener(
+                    "submit",
+                    () => onSubmit(port, site, username_input, password_input, filled)
+                );
             }
-        });
+        }
 
-        getCredential(port, site);
+        // Frames from other sites (ads, widgets) are only filled on request
+        if (!isCrossOriginFrame()) {
+            getCredential(port, site, null, true);
+        }
+    };
+
+    let debounce_timeout = null;
+    const on_mutation = function () {
+        clearTimeout(debounce_timeout);
+        debounce_timeout = setTimeout(on_change, MUTATION_DEBOUNCE_MS);
     };
 
-    const mutation_observer = new MutationObserver(on_change);
+    const mutation_observer = new MutationObserver(on_mutation);
 
     const config = {
         attributes: false,              // Observe changes to attributes
@@ -56,11 +103,6 @@ function start() {
         characterDataOldValue: false    // Record the previous value of text nodes
     };
 
-    window.onsubmit = function () {
-        mutation_observer.disconnect();
-        storeTmpCredential(port, site);
-    }
-
     try {
         mutation_observer.observe(document.body, config);
     } catch (e) {
@@ -70,81 +112,281 @@ function start() {
     on_change();
 }
 
+function onSubmit(port, site, username_input, password_input, filled) {
+    const username = username_input ? username_input.value : "";
+    const password = password_input.value;
+    if (!password) {
+        return;
+    }
+
+    if (filled && filled.username === username && filled.password === password) {
+        return;
+    }
+
+    const is_update = filled && filled.username === username;
+    const message = is_update
+        ? `Update the saved password for ${username} on ${site}?`
+        : `Save the password for ${username || "this account"} on ${site}?`;
+
+    if (window.confirm(message)) {
+        saveCredential(port, site, username, password);
+    }
+}
+
+// Set the value through the native setter and fire the events typing would,
+// so frameworks tracking the input (React, Vue, ...) pick the new value up
+function setValue(input, value) {
+    const setter = Object.getOwnPropertyDescriptor(HTMLInputElement.prototype, "value").set;
+    setter.call(input, value);
+    input.dispatchEvent(new Event("input", { bubbles: true }));
+    input.dispatchEvent(new Event("change", { bubbles: true }));
+}
+
+const SUBMIT_SELECTOR = 'button[type="submit"], input[type="submit"], button:not([type])';
+
+// Click the submit button of the password field's form, doing nothing when
+// there's no form or button to click
+function submitForm(password_input) {
+    const form = password_input.form;
+    const button = form && form.querySelector(SUBMIT_SELECTOR);
+    if (button) {
+        button.click();
+    }
+}
+
+// Fill the clicked field and its counterpart in the same login form
+function fillFromTarget(target, username, password) {
+    if (!(target instanceof HTMLInputElement)) {
+        return;
+    }
+
+    if (target.matches(PASSWORD_SELECTOR)) {
+        setValue(target, password);
+        const username_input = findUsernameInput(target);
+        if (username_input) {
+            setValue(username_input, username);
+        }
+        return;
+    }
+
+    setValue(target, username);
+    const password_input = (target.form || document).querySelector(PASSWORD_SELECTOR);
+    if (password_input) {
+        setValue(password_input, password);
+    }
+}
+
+// Fill the focused password field, or the detected one when nothing in the
+// page has focus, along with the other password fields of its form (e.g. a
+// "confirm password" field)
+function fillPassword(detected_input, password) {
+    const focused = document.activeElement;
+    let target = null;
+    if (focused instanceof HTMLInputElement && focused.matches(PASSWORD_SELECTOR)) {
+        target = focused;
+    } else if (window === window.top && !(focused instanceof HTMLIFrameElement)) {
+        target = detected_input;
+    }
+
+    if (!target) {
+        return;
+    }
+
+    const inputs = target.form ? target.form.querySelectorAll(PASSWORD_SELECTOR) : [target];
+    for (const input of inputs) {
+        setValue(input, password);
+    }
+}
+
 function getSite() {
-    let href = window.location.href.replace("https://", "")
-        .replace("http://", "")
-        .replace("www.", "");
+    // The background normalizes the site again, this only avoids sending the full url
+    return window.location.host.replace(/^www\./, "");
+}
+
+// Whether this script runs in a frame embedded by a page from a different origin
+function isCrossOriginFrame() {
+    if (window === window.top) {
+        return false;
+    }
 
-    let index = href.indexOf("/");
-    if (index > 0) {
-        href = href.substring(0, index);
+    const ancestors = window.location.ancestorOrigins;
+    if (ancestors && ancestors.length > 0) {
+        return [...ancestors].some((origin) => origin !== window.location.origin);
     }
 
-    return href;
+    try {
+        return window.top.location.origin !== window.location.origin;
+    } catch (e) {
+        // Reading the top location throws when it's cross-origin
+        return true;
+    }
 }
 
+const PASSWORD_SELECTOR = 'input[type="password"], input[name="password"], input[autocomplete="current-password"]';
+const USERNAME_CANDIDATE_SELECTOR = 'input:not([type]), input[type="text"], input[type="email"], input[type="tel"]';
+const USERNAME_HINTS = /user|login|email|e-mail|mail|account|identifier|usuario|correo/i;
+
 function getInputs() {
-    return [
-        document.querySelector('input[name="email"], input[name="username"], input[type="email"], input[autocomplete="username"], input[autocomplete="email"]'),
-        document.querySelector('input[type="password"], input[name="password"], input[autocomplete="current-password"]'),
-    ];
+    const password_input = findPasswordInput();
+    return [findUsernameInput(password_input), password_input];
 }
 
-function connect() {
-    const connect_info = null;
-    return chrome.runtime.connect(null, connect_info);
+function isVisible(input) {
+    const style = window.getComputedStyle(input);
+    return style.display !== "none"
+        && style.visibility !== "hidden"
+        && input.getClientRects().length > 0;
 }
 
-function getCredential(port, site) {
-    console.debug("Getting credential");
-    const payload = {
-        header: {},
-        payload: {
-            GetCredential: {
-                site
-            }
+function findPasswordInput() {
+    const inputs = [...document.querySelectorAll(PASSWORD_SELECTOR)];
+    return inputs.find(isVisible) || inputs[0] || null;
+}
+
+// Pick the highest scoring username candidate near the password field,
+// or anywhere in the page when there's no password field (e.g. multi-step logins)
+function findUsernameInput(password_input) {
+    const scope = password_input ? searchScope(password_input) : document;
+
+    let best = null;
+    let best_score = 0;
+    for (const input of scope.querySelectorAll(USERNAME_CANDIDATE_SELECTOR)) {
+        const score = scoreUsernameInput(input, password_input);
+        if (score > best_score) {
+            best = input;
+            best_score = score;
         }
     }
 
-    port.postMessage(payload)
+    return best;
 }
 
-function setTmpCredentialUsername(port, site, username) {
-    console.debug("Saving temp username", username);
-    const payload = {
-        header: {},
-        payload: {
-            SetTmpCredentialUsername: {
-                site,
-                username
+// Nearest ancestor of the password field that also contains a username candidate,
+// which is the form itself unless the username lives outside of it (or there's no form)
+function searchScope(password_input) {
+    let scope = password_input.form || password_input.parentElement;
+    while (scope && scope !== document.body) {
+        if (scope.querySelector(USERNAME_CANDIDATE_SELECTOR)) {
+            return scope;
+        }
+        scope = scope.parentElement;
+    }
+
+    return document;
+}
+
+function scoreUsernameInput(input, password_input) {
+    let score = 1;
+
+    const autocomplete = (input.getAttribute("autocomplete") || "").toLowerCase();
+    if (autocomplete.includes("username")) {
+        score += 10;
+    } else if (autocomplete.includes("email")) {
+        score += 8;
+    } else if (autocomplete === "off" || autocomplete.startsWith("cc-")) {
+        score -= 2;
+    }
+
+    if (input.type === "email") {
+        score += 6;
+    }
+
+    const hints = [
+        input.name,
+        input.id,
+        input.placeholder,
+        input.getAttribute("aria-label"),
+    ].join(" ");
+    if (USERNAME_HINTS.test(hints)) {
+        score += 4;
+    }
+
+    if (!isVisible(input)) {
+        score -= 10;
+    }
+
+    if (input.readOnly || input.disabled) {
+        score -= 5;
+    }
+
+    if (password_input) {
+        if (password_input.form && input.form === password_input.form) {
+            score += 3;
+        }
+
+        // Usernames come before the password field, preferably right before it
+        if (input.compareDocumentPosition(password_input) & Node.DOCUMENT_POSITION_FOLLOWING) {
+            score += 2;
+
+            const input_rect = input.getBoundingClientRect();
+            const password_rect = password_input.getBoundingClientRect();
+            if (Math.abs(password_rect.top - input_rect.bottom) < 150) {
+                score += 2;
             }
+        } else {
+            score -= 3;
         }
     }
 
-    port.postMessage(payload)
+    return score;
 }
 
-function setTmpCredentialPassword(port, site, password) {
-    console.debug("Saving temp password", password);
+function connect() {
+    const connect_info = null;
+    return chrome.runtime.connect(null, connect_info);
+}
+
+// Show a dropdown below the input to choose which of the site's credentials to fill
+function showCredentialPicker(input, options, on_pick) {
+    document.querySelectorAll(".passphrasex-picker").forEach((picker) => picker.remove());
+
+    const rect = input.getBoundingClientRect();
+    const picker = document.createElement("div");
+    picker.className = "passphrasex-picker";
+    picker.style.top = `${rect.bottom + window.scrollY}px`;
+    picker.style.left = `${rect.left + window.scrollX}px`;
+    picker.style.minWidth = `${rect.width}px`;
+
+    for (const { username, label } of options) {
+        const option = document.createElement("button");
+        option.type = "button";
+        option.textContent = label;
+        option.addEventListener("click", () => {
+            picker.remove();
+            on_pick(username);
+        });
+        picker.appendChild(option);
+    }
+
+    document.body.appendChild(picker);
+}
+
+// `on_load` requests are ignored by the background when autofill on load is disabled
+function getCredential(port, site, username = null, on_load = false) {
+    console.debug("Getting credential");
     const payload = {
         header: {},
         payload: {
-            SetTmpCredentialPassword: {
+            GetCredential: {
                 site,
-                password
+                username,
+                on_load
             }
         }
     }
+
     port.postMessage(payload)
 }
 
-function storeTmpCredential(port, site) {
-    console.debug("Saving temp credential");
+function saveCredential(port, site, username, password) {
+    console.debug("Saving credential");
     const payload = {
         header: {},
         payload: {
-            StoreTmpCredential: {
-                site
+            SaveCredential: {
+                site,
+                username,
+                password
             }
         }
     }
@@ -1,11 +1,16 @@
 use serde::{Deserialize, Serialize};
 
+pub use passphrasex_common::generator::PasswordOptions;
+
 pub type RequestId = usize;
 
 pub const FIRST_REQUEST_ID: RequestId = 1;
 
 pub const INITIAL_REQUEST_ID: RequestId = FIRST_REQUEST_ID - 1;
 
+/// Minutes without requests after which an unlocked vault is locked again
+pub const DEFAULT_AUTO_LOCK_MINUTES: u32 = 15;
+
 pub fn next_request_id(last_request_id: RequestId) -> RequestId {
     last_request_id.wrapping_add(1).max(FIRST_REQUEST_ID)
 }
@@ -60,17 +65,39 @@ pub struct Response<T> {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum AppRequestPayload {
     GetOptionsInfo,
+    SetAutoLock {
+        minutes: u32,
+    },
+    GetSettings,
+    SetSettings {
+        settings: Settings,
+    },
     GetStatus,
     Unlock {
         device_password: String,
     },
+    GetWebAuthnUnlock,
+    EnableWebAuthnUnlock {
+        credential: WebAuthnCredential,
+        prf_output: Vec<u8>,
+    },
+    DisableWebAuthnUnlock,
+    UnlockWithWebAuthn {
+        prf_output: Vec<u8>,
+    },
     Lock {},
     Login {
         seed_phrase: String,
         device_password: String,
+        /// BIP39 passphrase on top of the seed phrase, a wrong one opens another (empty) vault
+        #[serde(default)]
+        passphrase: Option<String>,
     },
     Register {
         device_password: String,
+        /// Contact address for the account, for recovery
+        #[serde(default)]
+        email: Option<String>,
     },
     Logout {},
     ListCredentials {},
@@ -92,6 +119,25 @@ pub enum AppRequestPayload {
         site: String,
         password_id: String,
     },
+    GeneratePassword {
+        /// Characters, or words in passphrase mode
+        length: usize,
+        opts: PasswordOptions,
+        /// Generate a passphrase of `length` words instead, ignoring `opts`
+        #[serde(default)]
+        passphrase: bool,
+    },
+    /// Fill a (generated) password into the focused password field of the active tab
+    FillPassword {
+        password: String,
+    },
+    /// Site of the active tab and whether it's ignored
+    GetActiveSite,
+    /// Add `site` to the ignored sites, or remove it
+    SetSiteIgnored {
+        site: String,
+        ignored: bool,
+    },
 }
 
 pub type AppRequest = Request<AppRequestPayload>;
@@ -104,6 +150,47 @@ pub struct Credential {
     pub password: String,
 }
 
+/// Platform authenticator credential whose PRF output wraps the secret key
+/// for WebAuthn unlock, both base64url encoded.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WebAuthnCredential {
+    pub credential_id: String,
+    pub salt: String,
+}
+
+/// User preferences, persisted by the background script.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Settings {
+    /// Auto-lock timeout in minutes, 0 disables it
+    pub auto_lock_minutes: u32,
+    /// Minutes an unlock is remembered across background restarts, 0 disables it
+    pub remember_unlock_minutes: u32,
+    /// Fill credentials as soon as a login form shows up
+    pub autofill_on_load: bool,
+    /// Submit login forms once filled, by clicking their submit button
+    pub auto_submit: bool,
+    /// Only offer credentials saved for the exact host, not for the whole domain
+    pub strict_site_matching: bool,
+    /// API server to use instead of the default one, e.g. a self-hosted server
+    pub server_url: Option<String>,
+    /// Hosts (and their subdomains) where login forms are neither filled nor saved
+    pub ignored_sites: Vec<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            auto_lock_minutes: DEFAULT_AUTO_LOCK_MINUTES,
+            remember_unlock_minutes: 0,
+            autofill_on_load: true,
+            auto_submit: false,
+            strict_site_matching: false,
+            server_url: None,
+            ignored_sites: Vec::new(),
+        }
+    }
+}
+
 /// App response message.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum AppResponsePayload {
@@ -115,9 +202,15 @@ pub enum AppResponsePayload {
     Status {
         is_logged_in: bool,
         is_unlocked: bool,
+        /// Public key of the active account, only sent while unlocked
+        public_key: Option<String>,
+        /// Email of the active account when it has one, only sent while unlocked
+        #[serde(default)]
+        email: Option<String>,
     },
     OptionsInfo {
         version: String,
+        auto_lock_minutes: u32,
     },
     Auth {
         error: Option<String>,
@@ -127,17 +220,45 @@ pub enum AppResponsePayload {
         password: String,
     },
     Credentials(Vec<Credential>),
+    GeneratedPassword {
+        password: String,
+        /// Estimated strength of the generated password
+        entropy_bits: f64,
+    },
+    Settings(Settings),
+    /// The credential to unlock with, if WebAuthn unlock is enabled for this session
+    WebAuthnUnlock(Option<WebAuthnCredential>),
+    ActiveSite {
+        /// `None` when the active tab isn't a web page
+        site: Option<String>,
+        ignored: bool,
+    },
 }
 
 pub type AppResponse = Response<AppResponsePayload>;
 
+/// A credential that can be picked to fill a login form, without its password.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CredentialOption {
+    pub username: String,
+    pub label: String,
+}
+
 /// Port-local request message.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PortRequestPayload {
-    GetCredential { site: String },
-    SetTmpCredentialUsername { site: String, username: String },
-    SetTmpCredentialPassword { site: String, password: String },
-    StoreTmpCredential { site: String },
+    GetCredential {
+        site: String,
+        username: Option<String>,
+        /// Sent when the page loads rather than on request, ignored unless autofill on load is enabled
+        #[serde(default)]
+        on_load: bool,
+    },
+    SaveCredential {
+        site: String,
+        username: String,
+        password: String,
+    },
 }
 
 pub type PortRequest = Request<PortRequestPayload>;
@@ -145,7 +266,22 @@ pub type PortRequest = Request<PortRequestPayload>;
 /// Port-local response message.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PortResponsePayload {
-    Credential { username: String, password: String },
+    Credential {
+        username: String,
+        password: String,
+        /// Submit the form once filled
+        submit: bool,
+    },
+    Credentials(Vec<CredentialOption>),
+    /// Fill the field the context menu was opened on, sent without a request
+    Fill {
+        username: String,
+        password: String,
+    },
+    /// Fill a password into the focused password field and its confirmation
+    FillPassword {
+        password: String,
+    },
     Ok,
     Error(String),
 }
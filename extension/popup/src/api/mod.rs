@@ -1,3 +1,5 @@
+pub mod webauthn;
+
 use gloo_utils::format::JsValueSerdeExt;
 use messages::{AppRequestPayload, AppResponsePayload, Request, Response};
 use wasm_bindgen::JsValue;
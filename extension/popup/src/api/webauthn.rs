@@ -0,0 +1,113 @@
+use gloo_utils::format::JsValueSerdeExt;
+use messages::WebAuthnCredential;
+use wasm_bindgen::prelude::*;
+
+// The PRF extension isn't part of the web-sys bindings, so the WebAuthn calls are made from JS
+#[wasm_bindgen(inline_js = r#"
+function toBase64Url(buffer) {
+    return btoa(String.fromCharCode(...new Uint8Array(buffer)))
+        .replace(/\+/g, "-")
+        .replace(/\//g, "_")
+        .replace(/=+$/, "");
+}
+
+function fromBase64Url(value) {
+    const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+    return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+}
+
+export async function isAvailable() {
+    return !!window.PublicKeyCredential
+        && await PublicKeyCredential.isUserVerifyingPlatformAuthenticatorAvailable();
+}
+
+export async function createCredential() {
+    const credential = await navigator.credentials.create({
+        publicKey: {
+            challenge: crypto.getRandomValues(new Uint8Array(32)),
+            rp: { name: "PassPhraseX" },
+            user: {
+                id: crypto.getRandomValues(new Uint8Array(16)),
+                name: "PassPhraseX",
+                displayName: "PassPhraseX",
+            },
+            pubKeyCredParams: [
+                { type: "public-key", alg: -7 },
+                { type: "public-key", alg: -257 },
+            ],
+            authenticatorSelection: {
+                authenticatorAttachment: "platform",
+                userVerification: "required",
+                residentKey: "discouraged",
+            },
+            extensions: { prf: {} },
+        },
+    });
+
+    if (!credential.getClientExtensionResults().prf?.enabled) {
+        throw new Error("This authenticator can't be used to unlock the vault");
+    }
+
+    return {
+        credential_id: toBase64Url(credential.rawId),
+        salt: toBase64Url(crypto.getRandomValues(new Uint8Array(32))),
+    };
+}
+
+export async function evaluatePrf(credential) {
+    const assertion = await navigator.credentials.get({
+        publicKey: {
+            challenge: crypto.getRandomValues(new Uint8Array(32)),
+            allowCredentials: [{ type: "public-key", id: fromBase64Url(credential.credential_id) }],
+            userVerification: "required",
+            extensions: { prf: { eval: { first: fromBase64Url(credential.salt) } } },
+        },
+    });
+
+    const output = assertion.getClientExtensionResults().prf?.results?.first;
+    if (!output) {
+        throw new Error("The authenticator didn't return a key");
+    }
+
+    return Array.from(new Uint8Array(output));
+}
+"#)]
+extern "C" {
+    #[wasm_bindgen(catch, js_name = isAvailable)]
+    async fn is_available_js() -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, js_name = createCredential)]
+    async fn create_credential_js() -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, js_name = evaluatePrf)]
+    async fn evaluate_prf_js(credential: JsValue) -> Result<JsValue, JsValue>;
+}
+
+/// Whether a platform authenticator (fingerprint, face, PIN) can verify the user
+pub async fn is_available() -> bool {
+    matches!(is_available_js().await, Ok(available) if available.is_truthy())
+}
+
+/// Register a platform authenticator credential supporting the PRF extension
+pub async fn create_credential() -> Result<WebAuthnCredential, String> {
+    let credential = create_credential_js().await.map_err(js_error)?;
+    credential
+        .into_serde()
+        .map_err(|err| format!("Unable to read credential: {:?}", err))
+}
+
+/// Ask the user to verify with `credential` and get its PRF output for the credential's salt
+pub async fn evaluate_prf(credential: &WebAuthnCredential) -> Result<Vec<u8>, String> {
+    let credential = JsValue::from_serde(credential)
+        .map_err(|err| format!("Unable to send credential: {:?}", err))?;
+    let output = evaluate_prf_js(credential).await.map_err(js_error)?;
+    output
+        .into_serde()
+        .map_err(|err| format!("Unable to read authenticator output: {:?}", err))
+}
+
+fn js_error(err: JsValue) -> String {
+    err.dyn_into::<js_sys::Error>()
+        .map(|err| String::from(err.message()))
+        .unwrap_or_else(|err| format!("{:?}", err))
+}
@@ -1,10 +1,15 @@
-use messages::{AppRequestPayload, AppResponsePayload};
+use messages::{AppRequestPayload, AppResponsePayload, PasswordOptions};
 use yew::{function_component, html, use_state, Html};
 
 use crate::api::app_request;
-use crate::components::helpers::{button::Button, input::Input};
+use crate::components::helpers::{
+    button::{Button, ButtonVariants},
+    input::Input,
+};
 use crate::pages::unlocked::{SectionProps, Sections};
 
+const GENERATED_PASSWORD_LENGTH: usize = 20;
+
 #[function_component]
 pub fn Add(props: &SectionProps) -> Html {
     let site = use_state(|| "".to_string());
@@ -51,12 +56,47 @@ pub fn Add(props: &SectionProps) -> Html {
         }
     };
 
+    let on_generate = {
+        let password = password.clone();
+        let error = error.clone();
+
+        move |_| {
+            let password = password.clone();
+            let error = error.clone();
+
+            let payload = AppRequestPayload::GeneratePassword {
+                length: GENERATED_PASSWORD_LENGTH,
+                opts: PasswordOptions::default(),
+                passphrase: false,
+            };
+
+            app_request(payload, move |res| match res {
+                Ok(AppResponsePayload::GeneratedPassword {
+                    password: generated,
+                    ..
+                }) => {
+                    password.set(generated);
+                }
+                Ok(AppResponsePayload::Error { message }) => {
+                    error.set(Some(message));
+                }
+                Ok(_) => {
+                    error.set(Some("Unknown Error".to_string()));
+                }
+                Err(err) => {
+                    error.set(Some(err));
+                }
+            });
+        }
+    };
+
     html! {
         <div>
             <form>
                 <Input label="Site" value={site} />
                 <Input label="Username" value={username} />
                 <Input input_type="password" label="Password" value={password} />
+                <Button onclick={on_generate} text={"Generate Password"} variant={ButtonVariants::Dark} class={"mb-2"} />
                 {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
                 <Button {onclick} text={"Add Credential"} />
             </form>
@@ -0,0 +1,167 @@
+use messages::{AppRequestPayload, AppResponsePayload, PasswordOptions};
+use yew::{function_component, html, use_state, Html};
+
+use crate::api::app_request;
+use crate::components::helpers::{
+    button::{Button, ButtonVariants},
+    checkbox::Checkbox,
+    input::Input,
+};
+use crate::pages::unlocked::SectionProps;
+
+const DEFAULT_PASSWORD_LENGTH: usize = 20;
+const DEFAULT_PASSPHRASE_WORDS: usize = 6;
+
+/// Rough strength label for a password with the given entropy
+fn strength(entropy_bits: f64) -> &'static str {
+    match entropy_bits {
+        bits if bits < 40.0 => "Weak",
+        bits if bits < 60.0 => "Fair",
+        bits if bits < 80.0 => "Strong",
+        _ => "Very strong",
+    }
+}
+
+/// Generate a password or passphrase and fill it into the current page
+#[function_component]
+pub fn Generate(_props: &SectionProps) -> Html {
+    let length = use_state(|| DEFAULT_PASSWORD_LENGTH.to_string());
+    let passphrase = use_state(|| false);
+    let uppercase = use_state(|| true);
+    let lowercase = use_state(|| true);
+    let digits = use_state(|| true);
+    let symbols = use_state(|| true);
+    let generated = use_state(|| None::<(String, f64)>);
+    let message = use_state(|| None);
+    let error = use_state(|| None);
+
+    let on_generate = {
+        let length = length.clone();
+        let passphrase = passphrase.clone();
+        let uppercase = uppercase.clone();
+        let lowercase = lowercase.clone();
+        let digits = digits.clone();
+        let symbols = symbols.clone();
+        let generated = generated.clone();
+        let message = message.clone();
+        let error = error.clone();
+
+        move |_| {
+            let generated = generated.clone();
+            let error = error.clone();
+            message.set(None);
+
+            let length = match length.trim().parse() {
+                Ok(length) => length,
+                Err(_) => {
+                    error.set(Some("Length must be a number".to_string()));
+                    return;
+                }
+            };
+
+            let payload = AppRequestPayload::GeneratePassword {
+                length,
+                opts: PasswordOptions {
+                    uppercase: *uppercase,
+                    lowercase: *lowercase,
+                    digits: *digits,
+                    symbols: *symbols,
+                },
+                passphrase: *passphrase,
+            };
+
+            app_request(payload, move |res| match res {
+                Ok(AppResponsePayload::GeneratedPassword {
+                    password,
+                    entropy_bits,
+                }) => {
+                    error.set(None);
+                    generated.set(Some((password, entropy_bits)));
+                }
+                Ok(AppResponsePayload::Error { message }) => error.set(Some(message)),
+                Ok(_) => error.set(Some("Unknown Error".to_string())),
+                Err(err) => error.set(Some(err)),
+            });
+        }
+    };
+
+    let on_fill = {
+        let generated = generated.clone();
+        let message = message.clone();
+        let error = error.clone();
+
+        move |_| {
+            let message = message.clone();
+            let error = error.clone();
+
+            let password = match &*generated {
+                Some((password, _)) => password.clone(),
+                None => return,
+            };
+
+            app_request(
+                AppRequestPayload::FillPassword { password },
+                move |res| match res {
+                    Ok(AppResponsePayload::Ok) => {
+                        error.set(None);
+                        message.set(Some(
+                            "Filled, you'll be asked to save it when the form is submitted"
+                                .to_string(),
+                        ));
+                    }
+                    Ok(AppResponsePayload::Error { message }) => error.set(Some(message)),
+                    Ok(_) => error.set(Some("Unknown Error".to_string())),
+                    Err(err) => error.set(Some(err)),
+                },
+            );
+        }
+    };
+
+    let on_mode = {
+        let passphrase = passphrase.clone();
+        let length = length.clone();
+
+        move |_| {
+            let default_length = if *passphrase {
+                DEFAULT_PASSWORD_LENGTH
+            } else {
+                DEFAULT_PASSPHRASE_WORDS
+            };
+            length.set(default_length.to_string());
+            passphrase.set(!*passphrase);
+        }
+    };
+
+    let (length_label, mode_text) = if *passphrase {
+        ("Words", "Generate a Password Instead")
+    } else {
+        ("Length", "Generate a Passphrase Instead")
+    };
+
+    html! {
+        <div>
+            <form>
+                <Input label={length_label} value={length} />
+                if !*passphrase {
+                    <Checkbox label="Uppercase letters" value={uppercase} />
+                    <Checkbox label="Lowercase letters" value={lowercase} />
+                    <Checkbox label="Digits" value={digits} />
+                    <Checkbox label="Symbols" value={symbols} />
+                }
+                <Button onclick={on_mode} text={mode_text} variant={ButtonVariants::Dark} class={"mb-2"} />
+                <Button onclick={on_generate} text={"Generate"} class={"mb-2"} />
+                {(*generated).clone().map(|(password, entropy_bits)| html! {
+                    <div class={"mb-2"}>
+                        <p class={"text-sm font-mono break-all"}>{password}</p>
+                        <p class={"text-xs"}>{format!("{} ({entropy_bits:.0} bits)", strength(entropy_bits))}</p>
+                    </div>
+                })}
+                {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
+                {(*message).clone().map(|message| html! { <p class={"text-green-600 text-xs mb-2"}>{message}</p> })}
+                if generated.is_some() {
+                    <Button onclick={on_fill} text={"Fill in Page"} variant={ButtonVariants::Dark} />
+                }
+            </form>
+        </div>
+    }
+}
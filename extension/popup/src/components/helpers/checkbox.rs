@@ -0,0 +1,32 @@
+use wasm_bindgen::JsCast;
+use web_sys::{EventTarget, HtmlInputElement};
+use yew::html::onchange::Event;
+use yew::{classes, function_component, html, Callback, Html, Properties, UseStateHandle};
+
+#[derive(Properties, PartialEq)]
+pub struct CheckboxProps {
+    pub label: String,
+    pub value: UseStateHandle<bool>,
+}
+
+#[function_component]
+pub fn Checkbox(props: &CheckboxProps) -> Html {
+    let value = props.value.clone();
+
+    let onchange: Callback<Event> = {
+        let value = value.clone();
+        Callback::from(move |e: Event| {
+            let target: EventTarget = e
+                .target()
+                .expect("Event should have a target when dispatched");
+            value.set(target.unchecked_into::<HtmlInputElement>().checked());
+        })
+    };
+
+    html! {
+        <div class={classes!("mb-2", "flex", "items-center")}>
+            <input {onchange} type="checkbox" checked={*value} class={classes!("mr-2")} />
+            <label class={classes!("text-sm", "font-medium")}>{props.label.clone()}</label>
+        </div>
+    }
+}
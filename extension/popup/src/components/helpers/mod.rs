@@ -1,2 +1,3 @@
 pub mod button;
+pub mod checkbox;
 pub mod input;
@@ -12,11 +12,14 @@ pub struct Props {
 pub fn Login(props: &Props) -> Html {
     let seed_phrase = use_state(|| "".to_string());
     let device_password = use_state(|| "".to_string());
+    let passphrase = use_state(|| "".to_string());
     let error = use_state(|| Some("".to_string()));
 
     let onclick = {
         let seed_phrase = (*seed_phrase).clone();
         let device_password = (*device_password).clone();
+        // Empty when the account has none
+        let passphrase = Some((*passphrase).clone()).filter(|passphrase| !passphrase.is_empty());
         let cb = props.cb.clone();
         let error = error.clone();
 
@@ -25,10 +28,12 @@ pub fn Login(props: &Props) -> Html {
             let cb = cb.clone();
             let seed_phrase = seed_phrase.clone();
             let device_password = device_password.clone();
+            let passphrase = passphrase.clone();
 
             try_login(
                 seed_phrase,
                 device_password,
+                passphrase,
                 move |payload: Option<String>| {
                     if payload.is_some() {
                         return error.set(Some("Invalid Credentials".to_string()));
@@ -44,6 +49,7 @@ pub fn Login(props: &Props) -> Html {
             <form>
                 <Input input_type="text" value={seed_phrase} label={"Seed Phrase"}/>
                 <Input input_type="password" value={device_password} label={"Device Password"}/>
+                <Input input_type="password" value={passphrase} label={"Passphrase (only if you set one, a wrong one opens an empty vault)"}/>
             </form>
             {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
             <Button {onclick} text={"Login"} />
@@ -51,13 +57,18 @@ pub fn Login(props: &Props) -> Html {
     }
 }
 
-fn try_login<F>(seed_phrase: String, device_password: String, callback: F)
-where
+fn try_login<F>(
+    seed_phrase: String,
+    device_password: String,
+    passphrase: Option<String>,
+    callback: F,
+) where
     F: Fn(Option<String>) + 'static,
 {
     let payload = AppRequestPayload::Login {
         seed_phrase,
         device_password,
+        passphrase,
     };
 
     try_auth(payload, callback);
@@ -1,9 +1,11 @@
 pub mod add;
 pub mod edit;
+pub mod generate;
 pub mod helpers;
 pub mod list;
 pub mod login;
 pub mod nav;
 pub mod register;
 pub mod seed_phrase;
+pub mod settings;
 pub mod unlock;
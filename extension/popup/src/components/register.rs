@@ -11,10 +11,12 @@ pub struct Props {
 #[function_component]
 pub fn Register(props: &Props) -> Html {
     let device_password = use_state(|| "".to_string());
+    let email = use_state(|| "".to_string());
     let error = use_state(|| Some("".to_string()));
 
     let onclick = {
         let device_password = (*device_password).clone();
+        let email = (*email).clone();
         let cb = props.cb.clone();
         let error = error.clone();
 
@@ -22,9 +24,12 @@ pub fn Register(props: &Props) -> Html {
             let error = error.clone();
             let cb = cb.clone();
             let device_password = device_password.clone();
+            // Optional, the background leaves an empty one out
+            let email = Some(email.clone());
 
             try_register(
                 device_password,
+                email,
                 move |res: Result<String, String>| match res {
                     Ok(seed_phrase) => {
                         cb.emit(seed_phrase);
@@ -41,6 +46,7 @@ pub fn Register(props: &Props) -> Html {
         <div>
             <form>
                 <Input input_type="password" value={device_password} label={"Device Password"}/>
+                <Input input_type="email" value={email} label={"Email (optional)"}/>
             </form>
             {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
             <Button {onclick} text={"Register"} />
@@ -48,11 +54,14 @@ pub fn Register(props: &Props) -> Html {
     }
 }
 
-fn try_register<F>(device_password: String, callback: F)
+fn try_register<F>(device_password: String, email: Option<String>, callback: F)
 where
     F: Fn(Result<String, String>) + 'static,
 {
-    let payload = AppRequestPayload::Register { device_password };
+    let payload = AppRequestPayload::Register {
+        device_password,
+        email,
+    };
     app_request(payload, move |result| match result {
         Ok(payload) => match payload {
             AppResponsePayload::Auth { error: Some(error) } => {
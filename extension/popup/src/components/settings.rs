@@ -0,0 +1,342 @@
+use messages::{AppRequestPayload, AppResponsePayload, Settings as SettingsData};
+use yew::{
+    classes, function_component, html, use_effect_with_deps, use_state, Html, Properties,
+    UseStateHandle,
+};
+
+use crate::api::{app_request, webauthn};
+use crate::components::helpers::{
+    button::{Button, ButtonVariants},
+    checkbox::Checkbox,
+    input::Input,
+};
+use crate::pages::unlocked::SectionProps;
+
+#[function_component]
+pub fn Settings(_props: &SectionProps) -> Html {
+    let auto_lock_minutes = use_state(|| "".to_string());
+    let remember_unlock_minutes = use_state(|| "".to_string());
+    let autofill_on_load = use_state(|| true);
+    let auto_submit = use_state(|| false);
+    let strict_site_matching = use_state(|| false);
+    let server_url = use_state(|| "".to_string());
+    // Edited by the ignore toggle, kept here so saving the form doesn't undo it
+    let ignored_sites = use_state(Vec::new);
+    let message = use_state(|| None);
+    let error = use_state(|| None);
+
+    use_effect_with_deps(
+        {
+            let auto_lock_minutes = auto_lock_minutes.clone();
+            let remember_unlock_minutes = remember_unlock_minutes.clone();
+            let autofill_on_load = autofill_on_load.clone();
+            let auto_submit = auto_submit.clone();
+            let strict_site_matching = strict_site_matching.clone();
+            let server_url = server_url.clone();
+            let ignored_sites = ignored_sites.clone();
+            let error = error.clone();
+
+            move |_| {
+                app_request(AppRequestPayload::GetSettings, move |res| match res {
+                    Ok(AppResponsePayload::Settings(settings)) => {
+                        auto_lock_minutes.set(settings.auto_lock_minutes.to_string());
+                        remember_unlock_minutes.set(settings.remember_unlock_minutes.to_string());
+                        autofill_on_load.set(settings.autofill_on_load);
+                        auto_submit.set(settings.auto_submit);
+                        strict_site_matching.set(settings.strict_site_matching);
+                        server_url.set(settings.server_url.unwrap_or_default());
+                        ignored_sites.set(settings.ignored_sites);
+                    }
+                    Ok(_) => error.set(Some("Unknown Error".to_string())),
+                    Err(err) => error.set(Some(err)),
+                });
+            }
+        },
+        (),
+    );
+
+    let onclick = {
+        let auto_lock_minutes = auto_lock_minutes.clone();
+        let remember_unlock_minutes = remember_unlock_minutes.clone();
+        let autofill_on_load = autofill_on_load.clone();
+        let auto_submit = auto_submit.clone();
+        let strict_site_matching = strict_site_matching.clone();
+        let server_url = server_url.clone();
+        let ignored_sites = ignored_sites.clone();
+        let message = message.clone();
+        let error = error.clone();
+
+        move |_| {
+            let message = message.clone();
+            let error = error.clone();
+
+            let auto_lock_minutes = match auto_lock_minutes.trim().parse() {
+                Ok(minutes) => minutes,
+                Err(_) => {
+                    error.set(Some("Auto-lock must be a number of minutes".to_string()));
+                    return;
+                }
+            };
+            let remember_unlock_minutes = match remember_unlock_minutes.trim().parse() {
+                Ok(minutes) => minutes,
+                Err(_) => {
+                    error.set(Some(
+                        "Remember unlock must be a number of minutes".to_string(),
+                    ));
+                    return;
+                }
+            };
+
+            // An empty URL means the default server
+            let server_url = Some(server_url.trim().to_string()).filter(|url| !url.is_empty());
+
+            let payload = AppRequestPayload::SetSettings {
+                settings: SettingsData {
+                    auto_lock_minutes,
+                    remember_unlock_minutes,
+                    autofill_on_load: *autofill_on_load,
+                    auto_submit: *auto_submit,
+                    strict_site_matching: *strict_site_matching,
+                    server_url,
+                    ignored_sites: (*ignored_sites).clone(),
+                },
+            };
+
+            app_request(payload, move |res| match res {
+                Ok(AppResponsePayload::Ok) => {
+                    error.set(None);
+                    message.set(Some("Settings saved".to_string()));
+                }
+                Ok(AppResponsePayload::Error { message }) => error.set(Some(message)),
+                Ok(_) => error.set(Some("Unknown Error".to_string())),
+                Err(err) => error.set(Some(err)),
+            });
+        }
+    };
+
+    html! {
+        <div>
+            <form>
+                <Input label="Auto-lock after (minutes, 0 to disable)" value={auto_lock_minutes} />
+                <Input label="Stay unlocked across restarts for (minutes, 0 to disable)" value={remember_unlock_minutes} />
+                <Checkbox label="Fill credentials when a login form loads" value={autofill_on_load} />
+                <Checkbox label="Submit login forms after filling them" value={auto_submit} />
+                <Checkbox label="Only fill credentials saved for the exact site" value={strict_site_matching} />
+                <Input label="Server URL (empty for the default server)" value={server_url} />
+                {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
+                {(*message).clone().map(|message| html! { <p class={"text-green-600 text-xs mb-2"}>{message}</p> })}
+                <Button {onclick} text={"Save Settings"} />
+            </form>
+            <Account />
+            <IgnoreSite {ignored_sites} />
+            <WebAuthnUnlock />
+        </div>
+    }
+}
+
+/// Shows which account is logged in, by its email when it has one
+#[function_component]
+fn Account() -> Html {
+    let account = use_state(|| None);
+
+    use_effect_with_deps(
+        {
+            let account = account.clone();
+
+            move |_| {
+                app_request(AppRequestPayload::GetStatus, move |res| {
+                    if let Ok(AppResponsePayload::Status {
+                        public_key, email, ..
+                    }) = res
+                    {
+                        account.set(email.or(public_key));
+                    }
+                });
+            }
+        },
+        (),
+    );
+
+    match (*account).clone() {
+        Some(account) => html! {
+            <p class={classes!("text-xs", "mt-2", "break-all")}>{format!("Logged in as {}", account)}</p>
+        },
+        None => html! {},
+    }
+}
+
+#[derive(Properties, PartialEq)]
+struct IgnoreSiteProps {
+    ignored_sites: UseStateHandle<Vec<String>>,
+}
+
+/// Turns filling and saving credentials on the active tab's site off and on
+#[function_component]
+fn IgnoreSite(props: &IgnoreSiteProps) -> Html {
+    let site = use_state(|| None);
+    let ignored = use_state(|| false);
+    let error = use_state(|| None);
+
+    use_effect_with_deps(
+        {
+            let site = site.clone();
+            let ignored = ignored.clone();
+
+            move |_| {
+                app_request(AppRequestPayload::GetActiveSite, move |res| {
+                    if let Ok(AppResponsePayload::ActiveSite {
+                        site: active_site,
+                        ignored: is_ignored,
+                    }) = res
+                    {
+                        site.set(active_site);
+                        ignored.set(is_ignored);
+                    }
+                });
+            }
+        },
+        (),
+    );
+
+    let Some(active_site) = (*site).clone() else {
+        return html! {};
+    };
+
+    let onclick = {
+        let ignored = ignored.clone();
+        let ignored_sites = props.ignored_sites.clone();
+        let error = error.clone();
+        let site = active_site.clone();
+
+        move |_| {
+            let ignored = ignored.clone();
+            let ignored_sites = ignored_sites.clone();
+            let error = error.clone();
+
+            let payload = AppRequestPayload::SetSiteIgnored {
+                site: site.clone(),
+                ignored: !*ignored,
+            };
+            app_request(payload, move |res| match res {
+                Ok(AppResponsePayload::Ok) => {
+                    error.set(None);
+                    ignored.set(!*ignored);
+                    let ignored_sites = ignored_sites.clone();
+                    app_request(AppRequestPayload::GetSettings, move |res| {
+                        if let Ok(AppResponsePayload::Settings(settings)) = res {
+                            ignored_sites.set(settings.ignored_sites);
+                        }
+                    });
+                }
+                Ok(AppResponsePayload::Error { message }) => error.set(Some(message)),
+                Ok(_) => error.set(Some("Unknown Error".to_string())),
+                Err(err) => error.set(Some(err)),
+            });
+        }
+    };
+
+    let text = if *ignored {
+        format!("Fill passwords on {} again", active_site)
+    } else {
+        format!("Never fill passwords on {}", active_site)
+    };
+
+    html! {
+        <div class={classes!("mt-2")}>
+            {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
+            <Button {onclick} {text} variant={ButtonVariants::Dark} />
+        </div>
+    }
+}
+
+/// Turns unlocking with the platform authenticator on and off for this browser session
+#[function_component]
+fn WebAuthnUnlock() -> Html {
+    let available = use_state(|| false);
+    let enabled = use_state(|| false);
+    let error = use_state(|| None);
+
+    use_effect_with_deps(
+        {
+            let available = available.clone();
+            let enabled = enabled.clone();
+
+            move |_| {
+                wasm_bindgen_futures::spawn_local(async move {
+                    available.set(webauthn::is_available().await);
+                });
+                app_request(AppRequestPayload::GetWebAuthnUnlock, move |res| {
+                    if let Ok(AppResponsePayload::WebAuthnUnlock(credential)) = res {
+                        enabled.set(credential.is_some());
+                    }
+                });
+            }
+        },
+        (),
+    );
+
+    let onclick = {
+        let enabled = enabled.clone();
+        let error = error.clone();
+
+        move |_| {
+            let enabled = enabled.clone();
+            let error = error.clone();
+
+            if *enabled {
+                app_request(
+                    AppRequestPayload::DisableWebAuthnUnlock,
+                    move |res| match res {
+                        Ok(AppResponsePayload::Ok) => enabled.set(false),
+                        Ok(AppResponsePayload::Error { message }) => error.set(Some(message)),
+                        Ok(_) => error.set(Some("Unknown Error".to_string())),
+                        Err(err) => error.set(Some(err)),
+                    },
+                );
+                return;
+            }
+
+            wasm_bindgen_futures::spawn_local(async move {
+                let credential = match webauthn::create_credential().await {
+                    Ok(credential) => credential,
+                    Err(err) => return error.set(Some(err)),
+                };
+                let prf_output = match webauthn::evaluate_prf(&credential).await {
+                    Ok(prf_output) => prf_output,
+                    Err(err) => return error.set(Some(err)),
+                };
+
+                let payload = AppRequestPayload::EnableWebAuthnUnlock {
+                    credential,
+                    prf_output,
+                };
+                app_request(payload, move |res| match res {
+                    Ok(AppResponsePayload::Ok) => {
+                        error.set(None);
+                        enabled.set(true);
+                    }
+                    Ok(AppResponsePayload::Error { message }) => error.set(Some(message)),
+                    Ok(_) => error.set(Some("Unknown Error".to_string())),
+                    Err(err) => error.set(Some(err)),
+                });
+            });
+        }
+    };
+
+    if !*available {
+        return html! {};
+    }
+
+    let text = if *enabled {
+        "Disable Fingerprint Unlock"
+    } else {
+        "Enable Fingerprint Unlock"
+    };
+
+    html! {
+        <div class={classes!("mt-2")}>
+            {(*error).clone().map(|error| html! { <p class={"text-red-500 text-xs mb-2"}>{error}</p> })}
+            <Button {onclick} {text} variant={ButtonVariants::Dark} />
+        </div>
+    }
+}
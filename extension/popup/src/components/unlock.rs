@@ -1,8 +1,8 @@
-use crate::api::{app_request, try_auth};
+use crate::api::{app_request, try_auth, webauthn};
 use crate::components::helpers::button::ButtonVariants;
 use crate::components::helpers::{button::Button, input::Input};
-use messages::AppRequestPayload;
-use yew::{function_component, html, use_state, Callback, Html, Properties};
+use messages::{AppRequestPayload, AppResponsePayload, WebAuthnCredential};
+use yew::{function_component, html, use_effect_with_deps, use_state, Callback, Html, Properties};
 
 pub enum Msg {
     Unlock,
@@ -22,6 +22,52 @@ pub fn Unlock(props: &Props) -> Html {
     let device_password_state = use_state(String::new);
     let device_password = (*device_password_state).clone();
 
+    let webauthn_credential = use_state(|| Option::<WebAuthnCredential>::None);
+
+    use_effect_with_deps(
+        {
+            let webauthn_credential = webauthn_credential.clone();
+
+            move |_| {
+                app_request(AppRequestPayload::GetWebAuthnUnlock, move |res| {
+                    if let Ok(AppResponsePayload::WebAuthnUnlock(credential)) = res {
+                        webauthn_credential.set(credential);
+                    }
+                });
+            }
+        },
+        (),
+    );
+
+    let webauthn_unlock = (*webauthn_credential).clone().map(|credential| {
+        let error_state = error_state.clone();
+        let cb = props.cb.clone();
+
+        let onclick = move |_| {
+            let credential = credential.clone();
+            let error_state = error_state.clone();
+            let cb = cb.clone();
+
+            wasm_bindgen_futures::spawn_local(async move {
+                // Without the authenticator the device password still works
+                let prf_output = match webauthn::evaluate_prf(&credential).await {
+                    Ok(prf_output) => prf_output,
+                    Err(err) => return error_state.set(Some(err)),
+                };
+
+                let payload = AppRequestPayload::UnlockWithWebAuthn { prf_output };
+                try_auth(payload, move |error: Option<String>| match error {
+                    Some(error) => error_state.set(Some(error)),
+                    None => cb.emit(Msg::Unlock),
+                });
+            });
+        };
+
+        html! {
+            <Button {onclick} text="Unlock with Fingerprint" variant={ButtonVariants::Dark} class="mb-2" />
+        }
+    });
+
     let onclick = {
         let device_password = device_password.clone();
         let cb = props.cb.clone();
@@ -63,6 +109,7 @@ pub fn Unlock(props: &Props) -> Html {
         <div>
             <Input input_type="password" label="Device Password" value={device_password_state} error={error} />
             <Button {onclick} text="Unlock" class="mb-2" />
+            {webauthn_unlock}
             <Button onclick={logout} text="Logout" variant={ButtonVariants::Dark} />
         </div>
     }
@@ -24,6 +24,7 @@ fn App() -> Html {
                     Ok(AppResponsePayload::Status {
                         is_logged_in,
                         is_unlocked,
+                        ..
                     }) => {
                         if is_unlocked {
                             current_page.set(Pages::Unlocked);
@@ -33,6 +34,10 @@ fn App() -> Html {
                             current_page.set(Pages::Login);
                         }
                     }
+                    Ok(AppResponsePayload::Error { message }) => {
+                        console::error!("Error: {}", message);
+                        current_page.set(Pages::Error);
+                    }
                     Err(err) => {
                         console::error!("Error: {:?}", err);
                         current_page.set(Pages::Error);
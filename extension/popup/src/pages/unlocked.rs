@@ -1,7 +1,9 @@
 use crate::components::add::Add;
 use crate::components::edit::Edit;
+use crate::components::generate::Generate;
 use crate::components::list::List;
 use crate::components::nav::{Nav, NavTab, NavTabButtonProps};
+use crate::components::settings::Settings;
 use crate::pages::{PageProps, Pages, Render};
 use messages::{AppRequestPayload, Credential};
 use yew::{function_component, html, use_state, Html, Properties, UseStateHandle};
@@ -16,6 +18,8 @@ pub enum Sections {
     Add,
     List,
     Edit(Credential),
+    Generate,
+    Settings,
 }
 
 impl Render<SectionProps> for Sections {
@@ -31,6 +35,12 @@ impl Render<SectionProps> for Sections {
             Sections::Edit(cred) => {
                 html!(<Edit {section} credential={cred.clone()} />)
             }
+            Sections::Generate => {
+                html!(<Generate {section} />)
+            }
+            Sections::Settings => {
+                html!(<Settings {section} />)
+            }
         }
     }
 }
@@ -55,6 +65,16 @@ pub fn UnlockedApp(props: &PageProps) -> Html {
             section: Sections::Add,
             button: None,
         },
+        NavTab {
+            text: "Generate".to_string(),
+            section: Sections::Generate,
+            button: None,
+        },
+        NavTab {
+            text: "Settings".to_string(),
+            section: Sections::Settings,
+            button: None,
+        },
         NavTab {
             text: "Lock".to_string(),
             section: Sections::List,